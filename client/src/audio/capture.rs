@@ -1,49 +1,135 @@
+use anyhow::{anyhow, bail, Context, Result};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use std::io::Write;
-use std::sync::mpsc::Sender;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use cpal::{FromSample, SampleFormat, SizedSample, StreamConfig};
+use rtrb::Producer;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::oneshot;
 
-pub async fn capture_audio(tx_audio: Arc<Mutex<Sender<Vec<u8>>>>) {
-    println!("capture_audio function started");
-    std::io::stdout().flush().unwrap();
+use super::resample::{closest_config, Resampler};
 
+pub const SAMPLE_RATE: u32 = 48000;
+
+/// Format of the samples pushed into the capture ring buffer, which are
+/// always f32 at [`SAMPLE_RATE`].
+#[derive(Clone, Copy, Debug)]
+pub struct CaptureFormat {
+    pub channels: u16,
+}
+
+/// Number of samples dropped because the ring buffer was full.
+pub static DROPPED_SAMPLES: AtomicUsize = AtomicUsize::new(0);
+
+/// Starts capturing the default input device on a dedicated thread.
+///
+/// The device is opened in whatever format it has closest to 48kHz f32,
+/// and the cpal callback converts and resamples into `producer`; it never
+/// allocates, locks or blocks. When the consumer falls behind, samples are dropped and
+/// counted in [`DROPPED_SAMPLES`].
+pub async fn capture_audio(producer: Producer<f32>) -> Result<CaptureFormat> {
+    let (format_tx, format_rx) = oneshot::channel();
+
+    // cpal streams are not `Send` on every host, so the stream lives and dies
+    // on the thread that created it.
+    std::thread::Builder::new()
+        .name("audio-capture".to_owned())
+        .spawn(move || {
+            let stream = match build_input_stream(producer) {
+                Ok((stream, format)) => {
+                    let _ = format_tx.send(Ok(format));
+                    stream
+                }
+                Err(e) => {
+                    let _ = format_tx.send(Err(e));
+                    return;
+                }
+            };
+
+            if let Err(e) = stream.play() {
+                eprintln!("Failed to start audio stream: {}", e);
+                return;
+            }
+
+            loop {
+                std::thread::park();
+            }
+        })
+        .context("Couldn't spawn the audio capture thread.")?;
+
+    let format = format_rx
+        .await
+        .map_err(|_| anyhow!("Audio capture thread exited before starting"))??;
+
+    println!("\n\rAudio stream started: {:?}", format);
+
+    Ok(format)
+}
+
+fn build_input_stream(producer: Producer<f32>) -> Result<(cpal::Stream, CaptureFormat)> {
     let host = cpal::default_host();
     let device = host
         .default_input_device()
-        .expect("Failed to get default input device");
+        .context("Failed to get default input device")?;
 
-    let config = device.default_input_config().unwrap();
+    let supported = closest_config(device.supported_input_configs()?)
+        .context("Input device has no sample format we can read")?;
+    let sample_format = supported.sample_format();
+    let config: StreamConfig = supported.into();
 
-    let tx_audio = tx_audio.lock().await.clone();
+    if config.sample_rate.0 != SAMPLE_RATE || sample_format != SampleFormat::F32 {
+        println!(
+            "\n\rCapturing {} at {} Hz, converting to f32 at {} Hz",
+            sample_format, config.sample_rate.0, SAMPLE_RATE
+        );
+    }
 
-    let stream = device
-        .build_input_stream(
-            &config.into(),
-            move |data: &[f32], _: &cpal::InputCallbackInfo| {
-                std::io::stdout().flush().unwrap();
+    let stream = match sample_format {
+        SampleFormat::F32 => build_stream::<f32>(&device, &config, producer)?,
+        SampleFormat::I16 => build_stream::<i16>(&device, &config, producer)?,
+        SampleFormat::U16 => build_stream::<u16>(&device, &config, producer)?,
+        other => bail!("Unsupported input sample format {}", other),
+    };
 
-                let mut buffer = vec![];
-                for &sample in data.iter() {
-                    buffer.extend_from_slice(&sample.to_ne_bytes());
-                }
+    let format = CaptureFormat {
+        channels: config.channels,
+    };
 
-                if let Err(e) = tx_audio.send(buffer) {
-                    eprintln!("Failed to send audio buffer: {}", e);
-                    std::io::stdout().flush().unwrap();
-                }
-            },
-            move |err| {
-                eprintln!("Stream error: {}", err);
-                std::io::stdout().flush().unwrap();
-            },
-            None,
-        )
-        .expect("Failed to build input stream");
-
-    stream.play().expect("Failed to start audio stream");
-    println!("Audio stream started");
-    std::io::stdout().flush().unwrap();
-
-    std::thread::sleep(std::time::Duration::from_secs(5)); // Adjust as needed
+    Ok((stream, format))
+}
+
+fn build_stream<T>(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    mut producer: Producer<f32>,
+) -> Result<cpal::Stream>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let mut resampler = Resampler::new(config.sample_rate.0, SAMPLE_RATE, config.channels as usize);
+
+    let stream = device.build_input_stream(
+        config,
+        move |data: &[T], _: &cpal::InputCallbackInfo| {
+            let mut dropped = 0;
+
+            resampler.process(
+                data.iter().map(|sample| sample.to_sample::<f32>()),
+                |sample| {
+                    if producer.push(sample).is_err() {
+                        dropped += 1;
+                    }
+                },
+            );
+
+            if dropped > 0 {
+                DROPPED_SAMPLES.fetch_add(dropped, Ordering::Relaxed);
+            }
+        },
+        move |err| {
+            eprintln!("Stream error: {}", err);
+        },
+        None,
+    )?;
+
+    Ok(stream)
 }
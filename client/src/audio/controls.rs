@@ -0,0 +1,97 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::watch;
+
+use super::mixer::Volume;
+use super::mode::AudioMode;
+use crate::config::AudioConfig;
+
+const VOLUME_STEP: f32 = 0.1;
+
+/// Runtime switches for the local audio pipeline, flipped from the call UI
+/// and observed by the audio tasks.
+pub struct AudioControls {
+    pub mode: watch::Sender<AudioMode>,
+    pub sidetone: AtomicBool,
+    pub sidetone_volume: Arc<Volume>,
+    /// Whether the noise gate currently hears us, for ducking.
+    pub talking: AtomicBool,
+}
+
+impl AudioControls {
+    pub fn new(config: &AudioConfig) -> Arc<Self> {
+        let (mode, _) = watch::channel(AudioMode::default());
+
+        Arc::new(Self {
+            mode,
+            sidetone: AtomicBool::new(config.sidetone),
+            sidetone_volume: Arc::new(Volume::new(config.sidetone_volume)),
+            talking: AtomicBool::new(false),
+        })
+    }
+
+    pub fn toggle_mode(&self) -> AudioMode {
+        self.mode.send_modify(|mode| *mode = mode.toggled());
+        *self.mode.borrow()
+    }
+
+    pub fn toggle_sidetone(&self) -> bool {
+        !self.sidetone.fetch_xor(true, Ordering::Relaxed)
+    }
+
+    pub fn sidetone_enabled(&self) -> bool {
+        self.sidetone.load(Ordering::Relaxed)
+    }
+
+    pub fn set_talking(&self, talking: bool) {
+        self.talking.store(talking, Ordering::Relaxed);
+    }
+
+    pub fn talking(&self) -> bool {
+        self.talking.load(Ordering::Relaxed)
+    }
+
+    /// Nudges the sidetone volume up or down and returns the new value.
+    pub fn step_sidetone_volume(&self, up: bool) -> f32 {
+        let step = if up { VOLUME_STEP } else { -VOLUME_STEP };
+        self.sidetone_volume.set(self.sidetone_volume.get() + step);
+        self.sidetone_volume.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mode_changes_reach_subscribers() {
+        let controls = AudioControls::new(&AudioConfig::default());
+        let mut mode = controls.mode.subscribe();
+
+        assert_eq!(controls.toggle_mode(), AudioMode::Music);
+        assert!(mode.has_changed().unwrap());
+        assert_eq!(*mode.borrow_and_update(), AudioMode::Music);
+
+        assert_eq!(controls.toggle_mode(), AudioMode::Voice);
+        assert_eq!(*mode.borrow_and_update(), AudioMode::Voice);
+    }
+
+    #[test]
+    fn sidetone_toggles_and_its_volume_stays_in_range() {
+        let controls = AudioControls::new(&AudioConfig::default());
+
+        assert!(controls.toggle_sidetone());
+        assert!(controls.sidetone_enabled());
+        assert!(!controls.toggle_sidetone());
+
+        for _ in 0..20 {
+            controls.step_sidetone_volume(true);
+        }
+        assert_eq!(controls.sidetone_volume.get(), 1.0);
+
+        for _ in 0..20 {
+            controls.step_sidetone_volume(false);
+        }
+        assert_eq!(controls.sidetone_volume.get(), 0.0);
+    }
+}
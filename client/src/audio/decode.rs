@@ -1,13 +1,47 @@
 use opus::{Channels, Decoder};
 
-pub fn decode_opus(encoded_data: &[u8]) -> Result<Vec<i16>, opus::Error> {
-    let mut decoder = Decoder::new(48000, Channels::Stereo)?;
+use super::capture::SAMPLE_RATE;
+use super::send::FRAME_SIZE;
 
-    let mut decoded_data = Vec::new();
+/// 120ms, the longest duration a single Opus packet can carry.
+const MAX_FRAME_SIZE: usize = 5760;
+const CHANNELS: usize = 2;
 
-    let len = decoder.decode(encoded_data, &mut decoded_data, false)?;
+/// Decoder state for one incoming Opus stream, decoding to 48kHz stereo.
+pub struct OpusStream {
+    decoder: Decoder,
+    pcm: Vec<f32>,
+}
+
+impl OpusStream {
+    pub fn new() -> Result<Self, opus::Error> {
+        Ok(Self {
+            decoder: Decoder::new(SAMPLE_RATE, Channels::Stereo)?,
+            pcm: vec![0.0; MAX_FRAME_SIZE * CHANNELS],
+        })
+    }
+
+    /// Decodes one packet into interleaved stereo samples.
+    pub fn decode(&mut self, packet: &[u8]) -> Result<&[f32], opus::Error> {
+        let len = self.decoder.decode_float(packet, &mut self.pcm, false)?;
+
+        Ok(&self.pcm[..len * CHANNELS])
+    }
+
+    /// Rebuilds the lost frame just before `packet` from the in-band FEC
+    /// data it carries. Without any, the decoder conceals the frame instead.
+    pub fn recover(&mut self, packet: &[u8]) -> Result<&[f32], opus::Error> {
+        let frame = &mut self.pcm[..FRAME_SIZE * CHANNELS];
+        let len = self.decoder.decode_float(packet, frame, true)?;
+
+        Ok(&self.pcm[..len * CHANNELS])
+    }
 
-    decoded_data.truncate(len);
+    /// Makes up a lost frame from what was decoded before (PLC).
+    pub fn conceal(&mut self) -> Result<&[f32], opus::Error> {
+        let frame = &mut self.pcm[..FRAME_SIZE * CHANNELS];
+        let len = self.decoder.decode_float(&[], frame, false)?;
 
-    Ok(decoded_data)
+        Ok(&self.pcm[..len * CHANNELS])
+    }
 }
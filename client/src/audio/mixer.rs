@@ -0,0 +1,380 @@
+use anyhow::{anyhow, bail, Context, Result};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, SampleFormat, SizedSample, StreamConfig};
+use rtrb::{Consumer, Producer, RingBuffer};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use tokio::sync::oneshot;
+
+use super::capture::SAMPLE_RATE;
+use super::resample::{closest_config, Resampler};
+
+/// Sources are interleaved stereo.
+const SOURCE_CHANNELS: usize = 2;
+/// Half a second of buffered audio per source.
+const SOURCE_CAPACITY: usize = SAMPLE_RATE as usize / 2 * SOURCE_CHANNELS;
+/// Frames a source may queue beyond the current callback before the oldest
+/// audio is skipped to keep latency bounded (60ms).
+const MAX_BACKLOG_FRAMES: usize = SAMPLE_RATE as usize * 60 / 1000;
+const MAX_SOURCES: usize = 32;
+/// Most 48kHz frames mixed at once when the device runs at another rate.
+const MAX_BLOCK_FRAMES: usize = SAMPLE_RATE as usize / 10;
+
+/// A gain shared between the UI and the real-time mixer.
+pub struct Volume(AtomicU32);
+
+impl Volume {
+    pub fn new(volume: f32) -> Self {
+        Self(AtomicU32::new(volume.clamp(0.0, 1.0).to_bits()))
+    }
+
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, volume: f32) {
+        self.0
+            .store(volume.clamp(0.0, 1.0).to_bits(), Ordering::Relaxed);
+    }
+}
+
+struct MixerSource {
+    consumer: Consumer<f32>,
+    volume: Arc<Volume>,
+}
+
+/// Local output mixer. Every source is a ring buffer of 48kHz stereo samples
+/// summed into the default output device.
+pub struct Mixer {
+    /// `None` when there is no output device to play to.
+    new_sources: Option<std::sync::Mutex<Producer<MixerSource>>>,
+}
+
+impl Mixer {
+    /// Opens the default output device on a dedicated thread. Without a
+    /// usable one the call goes on without local playback.
+    pub async fn start() -> Arc<Self> {
+        let new_sources = match Self::open_output().await {
+            Ok(new_sources) => Some(std::sync::Mutex::new(new_sources)),
+            Err(e) => {
+                eprintln!("No audio output, continuing without playback: {:#}", e);
+                None
+            }
+        };
+
+        Arc::new(Self { new_sources })
+    }
+
+    async fn open_output() -> Result<Producer<MixerSource>> {
+        let (new_sources, sources_rx) = RingBuffer::new(MAX_SOURCES);
+        let (started_tx, started_rx) = oneshot::channel();
+
+        std::thread::Builder::new()
+            .name("audio-output".to_owned())
+            .spawn(move || {
+                let stream = match build_output_stream(sources_rx) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        let _ = started_tx.send(Err(e));
+                        return;
+                    }
+                };
+
+                if let Err(e) = stream.play() {
+                    let _ = started_tx.send(Err(e.into()));
+                    return;
+                }
+
+                let _ = started_tx.send(Ok(()));
+
+                loop {
+                    std::thread::park();
+                }
+            })
+            .context("Couldn't spawn the audio output thread.")?;
+
+        started_rx
+            .await
+            .map_err(|_| anyhow!("Audio output thread exited before starting"))??;
+
+        Ok(new_sources)
+    }
+
+    /// Registers a new source and returns the producer to feed it with.
+    /// Dropping the producer removes the source once it has drained.
+    /// Without playback whatever is pushed is dropped.
+    pub fn add_source(&self, volume: Arc<Volume>) -> Result<Producer<f32>> {
+        let (producer, consumer) = RingBuffer::new(SOURCE_CAPACITY);
+
+        let Some(new_sources) = &self.new_sources else {
+            return Ok(producer);
+        };
+
+        new_sources
+            .lock()
+            .unwrap()
+            .push(MixerSource { consumer, volume })
+            .map_err(|_| anyhow!("Too many audio sources"))?;
+
+        Ok(producer)
+    }
+}
+
+/// The mixing side of the output stream. Sources are mixed at 48kHz, then
+/// resampled and converted to whatever the device plays.
+struct Output {
+    new_sources: Consumer<MixerSource>,
+    sources: Vec<MixerSource>,
+    channels: usize,
+    rate: u32,
+    resampler: Resampler,
+    /// Mixed 48kHz frames.
+    block: Vec<f32>,
+    /// Resampled samples not played yet.
+    pending: Vec<f32>,
+}
+
+impl Output {
+    fn new(new_sources: Consumer<MixerSource>, config: &StreamConfig) -> Self {
+        let channels = config.channels as usize;
+        let rate = config.sample_rate.0;
+
+        Self {
+            new_sources,
+            sources: Vec::with_capacity(MAX_SOURCES),
+            channels,
+            rate,
+            resampler: Resampler::new(SAMPLE_RATE, rate, channels),
+            block: vec![0.0; MAX_BLOCK_FRAMES * channels],
+            // Twice what is ever kept, so a block never has to grow it.
+            pending: Vec::with_capacity(rate as usize * channels * 2),
+        }
+    }
+
+    /// Fills one device callback. Nothing here allocates: `sources`,
+    /// `block` and `pending` never grow past their capacity.
+    fn fill<T>(&mut self, output: &mut [T])
+    where
+        T: SizedSample + FromSample<f32>,
+    {
+        while self.sources.len() < MAX_SOURCES {
+            let Ok(source) = self.new_sources.pop() else {
+                break;
+            };
+            self.sources.push(source);
+        }
+
+        self.sources
+            .retain(|source| !(source.consumer.is_abandoned() && source.consumer.is_empty()));
+
+        let channels = self.channels;
+        let room = self.pending.capacity() / 2;
+
+        while self.pending.len() < output.len().min(room) {
+            let missing = (output.len() - self.pending.len()).div_ceil(channels);
+            let frames = ((missing as u64 * SAMPLE_RATE as u64).div_ceil(self.rate as u64)
+                as usize)
+                .clamp(1, MAX_BLOCK_FRAMES);
+
+            let block = &mut self.block[..frames * channels];
+            block.fill(0.0);
+
+            for source in self.sources.iter_mut() {
+                mix_source(source, block, channels, frames);
+            }
+
+            let pending = &mut self.pending;
+            self.resampler
+                .process(block.iter().copied(), |sample| pending.push(sample));
+        }
+
+        let n = output.len().min(self.pending.len());
+        for (out, sample) in output.iter_mut().zip(self.pending.drain(..n)) {
+            *out = T::from_sample(sample.clamp(-1.0, 1.0));
+        }
+        for out in output[n..].iter_mut() {
+            *out = T::EQUILIBRIUM;
+        }
+    }
+}
+
+fn build_output_stream(new_sources: Consumer<MixerSource>) -> Result<cpal::Stream> {
+    let host = cpal::default_host();
+    let device = host
+        .default_output_device()
+        .context("Failed to get default output device")?;
+
+    let supported = closest_config(device.supported_output_configs()?)
+        .context("Output device has no sample format we can play")?;
+    let sample_format = supported.sample_format();
+    let config: StreamConfig = supported.into();
+
+    if config.sample_rate.0 != SAMPLE_RATE || sample_format != SampleFormat::F32 {
+        println!(
+            "\n\rPlaying {} at {} Hz, converting from f32 at {} Hz",
+            sample_format, config.sample_rate.0, SAMPLE_RATE
+        );
+    }
+
+    let output = Output::new(new_sources, &config);
+
+    match sample_format {
+        SampleFormat::F32 => build_stream::<f32>(&device, &config, output),
+        SampleFormat::I16 => build_stream::<i16>(&device, &config, output),
+        SampleFormat::U16 => build_stream::<u16>(&device, &config, output),
+        other => bail!("Unsupported output sample format {}", other),
+    }
+}
+
+fn build_stream<T>(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    mut output: Output,
+) -> Result<cpal::Stream>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let stream = device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| output.fill(data),
+        move |err| {
+            eprintln!("Stream error: {}", err);
+        },
+        None,
+    )?;
+
+    Ok(stream)
+}
+
+fn mix_source(source: &mut MixerSource, output: &mut [f32], out_channels: usize, frames: usize) {
+    let available = source.consumer.slots() / SOURCE_CHANNELS;
+
+    if available > frames + MAX_BACKLOG_FRAMES {
+        let skip = (available - frames - MAX_BACKLOG_FRAMES) * SOURCE_CHANNELS;
+        if let Ok(chunk) = source.consumer.read_chunk(skip) {
+            chunk.commit_all();
+        }
+    }
+
+    let n = frames.min(source.consumer.slots() / SOURCE_CHANNELS);
+    let Ok(chunk) = source.consumer.read_chunk(n * SOURCE_CHANNELS) else {
+        return;
+    };
+
+    let volume = source.volume.get();
+    let (first, second) = chunk.as_slices();
+    let mut samples = first.iter().chain(second);
+
+    for out in output.chunks_exact_mut(out_channels).take(n) {
+        let left = samples.next().copied().unwrap_or(0.0) * volume;
+        let right = samples.next().copied().unwrap_or(0.0) * volume;
+
+        match out {
+            [mono] => *mono += (left + right) * 0.5,
+            [l, r, ..] => {
+                *l += left;
+                *r += right;
+            }
+            [] => (),
+        }
+    }
+
+    chunk.commit_all();
+}
+
+/// Pushes `len` samples into a source, dropping whatever does not fit.
+pub fn push_samples(producer: &mut Producer<f32>, len: usize, samples: impl Iterator<Item = f32>) {
+    let n = len.min(producer.slots());
+
+    if let Ok(chunk) = producer.write_chunk_uninit(n) {
+        chunk.fill_from_iter(samples);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpal::{BufferSize, SampleRate};
+
+    fn output(channels: u16, rate: u32) -> (Output, Producer<MixerSource>) {
+        let (new_sources, sources_rx) = RingBuffer::new(MAX_SOURCES);
+        let config = StreamConfig {
+            channels,
+            sample_rate: SampleRate(rate),
+            buffer_size: BufferSize::Default,
+        };
+        (Output::new(sources_rx, &config), new_sources)
+    }
+
+    fn source(new_sources: &mut Producer<MixerSource>, volume: f32) -> Producer<f32> {
+        let (producer, consumer) = RingBuffer::new(SOURCE_CAPACITY);
+        let volume = Arc::new(Volume::new(volume));
+        assert!(new_sources.push(MixerSource { consumer, volume }).is_ok());
+        producer
+    }
+
+    fn push_frames(producer: &mut Producer<f32>, frames: usize, left: f32, right: f32) {
+        let samples = [left, right].into_iter().cycle();
+        push_samples(producer, frames * SOURCE_CHANNELS, samples);
+    }
+
+    #[test]
+    fn sources_play_unchanged_at_48khz() {
+        let (mut output, mut new_sources) = output(2, SAMPLE_RATE);
+        let mut a = source(&mut new_sources, 1.0);
+        let mut b = source(&mut new_sources, 0.5);
+        push_frames(&mut a, 4, 0.25, -0.25);
+        push_frames(&mut b, 4, 0.5, 0.5);
+
+        let mut data = [0f32; 8];
+        output.fill(&mut data);
+
+        assert_eq!(data, [0.5, 0.0, 0.5, 0.0, 0.5, 0.0, 0.5, 0.0]);
+    }
+
+    #[test]
+    fn other_rates_fill_every_callback() {
+        let (mut output, mut new_sources) = output(2, 44100);
+        let mut producer = source(&mut new_sources, 1.0);
+        // 20ms ahead, then 10ms of 48kHz audio for every 10ms callback.
+        push_frames(&mut producer, 960, 0.5, -0.5);
+
+        let mut played = Vec::new();
+        for _ in 0..100 {
+            push_frames(&mut producer, 480, 0.5, -0.5);
+            let mut data = [0f32; 441 * 2];
+            output.fill(&mut data);
+            played.extend(data);
+        }
+
+        // The first frame fades in from silence, the rest is the source.
+        assert!(played[2..]
+            .chunks(2)
+            .all(|frame| (frame[0] - 0.5).abs() < 1e-6 && (frame[1] + 0.5).abs() < 1e-6));
+    }
+
+    #[test]
+    fn integer_devices_get_converted_samples() {
+        let (mut output, mut new_sources) = output(1, SAMPLE_RATE);
+        let mut producer = source(&mut new_sources, 1.0);
+        push_frames(&mut producer, 2, 1.0, 0.0);
+        push_frames(&mut producer, 1, 2.0, 2.0);
+
+        let mut data = [0i16; 4];
+        output.fill(&mut data);
+
+        // Stereo is averaged to mono, clipped, and silence follows the end
+        // of the source.
+        assert_eq!(data, [16384, 16384, i16::MAX, 0]);
+    }
+
+    #[tokio::test]
+    async fn without_playback_sources_are_still_accepted() {
+        let mixer = Mixer { new_sources: None };
+
+        for _ in 0..MAX_SOURCES * 2 {
+            let mut producer = mixer.add_source(Arc::new(Volume::new(1.0))).unwrap();
+            push_samples(&mut producer, 4, [0.5; 4].into_iter());
+        }
+    }
+}
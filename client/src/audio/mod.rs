@@ -1,4 +1,13 @@
 pub mod capture;
+pub mod controls;
 pub mod decode;
+pub mod mixer;
+pub mod mode;
+pub mod pool;
+pub mod process;
 pub mod receive;
+pub mod red;
+pub mod replay;
+pub mod resample;
 pub mod send;
+pub mod soundboard;
@@ -0,0 +1,172 @@
+use anyhow::Result;
+use opus::{Application, Bitrate, Channels, Encoder};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use super::capture::SAMPLE_RATE;
+use crate::commands::ClientCommand;
+use crate::config::AudioConfig;
+use crate::peer::session::{PeerContext, PeerSession};
+
+const MIN_MUSIC_BITRATE: i32 = 128_000;
+const MAX_MUSIC_BITRATE: i32 = 256_000;
+
+/// What the local microphone is currently used for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AudioMode {
+    #[default]
+    Voice,
+    Music,
+}
+
+impl AudioMode {
+    pub fn toggled(self) -> Self {
+        match self {
+            AudioMode::Voice => AudioMode::Music,
+            AudioMode::Music => AudioMode::Voice,
+        }
+    }
+}
+
+/// Encoder settings and processing chain for an [`AudioMode`].
+#[derive(Clone, Copy, Debug)]
+pub struct AudioProfile {
+    pub application: Application,
+    pub channels: Channels,
+    pub bitrate: i32,
+    pub inband_fec: bool,
+    pub packet_loss_perc: i32,
+    /// Skip sending frames the noise gate considers silent.
+    pub dtx: bool,
+    /// Run the voice processors (noise gate / VAD) before encoding.
+    pub voice_processing: bool,
+}
+
+impl AudioProfile {
+    pub fn new(mode: AudioMode, config: &AudioConfig) -> Self {
+        match mode {
+            AudioMode::Voice => Self {
+                application: Application::Voip,
+                channels: Channels::Mono,
+                bitrate: config.voice_bitrate,
+                inband_fec: true,
+                packet_loss_perc: 10,
+                dtx: true,
+                voice_processing: true,
+            },
+            // Music is sensitive to FEC's bitrate stealing and DTX cutting
+            // off quiet passages, so both are disabled.
+            AudioMode::Music => Self {
+                application: Application::Audio,
+                channels: Channels::Stereo,
+                bitrate: config
+                    .music_bitrate
+                    .clamp(MIN_MUSIC_BITRATE, MAX_MUSIC_BITRATE),
+                inband_fec: false,
+                packet_loss_perc: 0,
+                dtx: false,
+                voice_processing: false,
+            },
+        }
+    }
+
+    pub fn channel_count(&self) -> usize {
+        match self.channels {
+            Channels::Mono => 1,
+            Channels::Stereo => 2,
+        }
+    }
+
+    pub fn encoder(&self) -> Result<Encoder> {
+        let mut encoder = Encoder::new(SAMPLE_RATE, self.channels, self.application)?;
+
+        encoder.set_bitrate(Bitrate::Bits(self.bitrate))?;
+        encoder.set_inband_fec(self.inband_fec)?;
+        encoder.set_packet_loss_perc(self.packet_loss_perc)?;
+
+        Ok(encoder)
+    }
+}
+
+/// Tells every connected peer which mode we switched to, so they can adapt
+/// their own playback (e.g. stop ducking us).
+pub async fn announce_audio_mode(
+    room_id: &str,
+    mode: AudioMode,
+    peer_connections: Arc<Mutex<HashMap<String, Arc<PeerSession>>>>,
+    context: &PeerContext,
+) -> Result<()> {
+    let peers: Vec<String> = peer_connections.lock().await.keys().cloned().collect();
+
+    for other_id in peers {
+        context
+            .send(ClientCommand::SendAudioMode(
+                other_id,
+                room_id.to_string(),
+                mode,
+            ))
+            .await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toggling_switches_between_voice_and_music() {
+        assert_eq!(AudioMode::Voice.toggled(), AudioMode::Music);
+        assert_eq!(AudioMode::Music.toggled(), AudioMode::Voice);
+    }
+
+    #[test]
+    fn voice_is_processed_mono_with_fec_and_dtx() {
+        let profile = AudioProfile::new(AudioMode::Voice, &AudioConfig::default());
+
+        assert_eq!(profile.application, Application::Voip);
+        assert_eq!(profile.channel_count(), 1);
+        assert_eq!(profile.bitrate, 32_000);
+        assert!(profile.inband_fec && profile.dtx && profile.voice_processing);
+    }
+
+    #[test]
+    fn music_is_stereo_and_untouched() {
+        let profile = AudioProfile::new(AudioMode::Music, &AudioConfig::default());
+
+        assert_eq!(profile.application, Application::Audio);
+        assert_eq!(profile.channel_count(), 2);
+        assert_eq!(profile.bitrate, 192_000);
+        assert!(!profile.inband_fec && !profile.dtx && !profile.voice_processing);
+    }
+
+    #[test]
+    fn music_bitrate_stays_in_range() {
+        let bitrate = |music_bitrate| {
+            let config = AudioConfig {
+                music_bitrate,
+                ..Default::default()
+            };
+            AudioProfile::new(AudioMode::Music, &config).bitrate
+        };
+
+        assert_eq!(bitrate(64_000), MIN_MUSIC_BITRATE);
+        assert_eq!(bitrate(512_000), MAX_MUSIC_BITRATE);
+    }
+
+    #[test]
+    fn both_profiles_build_an_encoder() {
+        for mode in [AudioMode::Voice, AudioMode::Music] {
+            let profile = AudioProfile::new(mode, &AudioConfig::default());
+            let mut encoder = profile.encoder().unwrap();
+
+            assert_eq!(
+                encoder.get_bitrate().unwrap(),
+                Bitrate::Bits(profile.bitrate)
+            );
+        }
+    }
+}
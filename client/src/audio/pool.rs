@@ -0,0 +1,42 @@
+use bytes::{Bytes, BytesMut};
+
+/// Reusable backing storage for outgoing RTP payloads.
+///
+/// Each packet is split off a single `BytesMut` allocation. Once every packet
+/// handed out has been dropped, `reserve` reclaims the whole allocation, so
+/// the steady state does not touch the allocator.
+pub struct PacketPool {
+    buf: BytesMut,
+    packet_size: usize,
+}
+
+impl PacketPool {
+    pub fn new(packet_size: usize, packets: usize) -> Self {
+        Self {
+            buf: BytesMut::with_capacity(packet_size * packets),
+            packet_size,
+        }
+    }
+
+    /// Lets `write` fill up to `packet_size` bytes and returns them as a
+    /// frozen payload.
+    pub fn fill<E>(
+        &mut self,
+        write: impl FnOnce(&mut [u8]) -> Result<usize, E>,
+    ) -> Result<Bytes, E> {
+        self.buf.reserve(self.packet_size);
+        self.buf.resize(self.packet_size, 0);
+
+        let len = match write(&mut self.buf[..]) {
+            Ok(len) => len,
+            Err(e) => {
+                self.buf.clear();
+                return Err(e);
+            }
+        };
+
+        self.buf.truncate(len);
+
+        Ok(self.buf.split().freeze())
+    }
+}
@@ -0,0 +1,74 @@
+/// Frames of silence kept open after speech stops, so word endings are not
+/// clipped.
+const HANGOVER_FRAMES: usize = 10;
+
+/// Energy-based noise gate doubling as a voice activity detector.
+pub struct NoiseGate {
+    threshold: f32,
+    quiet_frames: usize,
+}
+
+impl NoiseGate {
+    pub fn new(threshold: f32) -> Self {
+        Self {
+            threshold,
+            quiet_frames: HANGOVER_FRAMES,
+        }
+    }
+
+    /// Silences `frame` when it stays under the threshold past the hangover
+    /// and returns whether voice is active.
+    pub fn process(&mut self, frame: &mut [f32]) -> bool {
+        let energy = frame.iter().map(|s| s * s).sum::<f32>() / frame.len().max(1) as f32;
+
+        if energy.sqrt() >= self.threshold {
+            self.quiet_frames = 0;
+            return true;
+        }
+
+        self.quiet_frames = self.quiet_frames.saturating_add(1);
+
+        if self.quiet_frames <= HANGOVER_FRAMES {
+            return true;
+        }
+
+        frame.fill(0.0);
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loud_frames_pass() {
+        let mut gate = NoiseGate::new(0.1);
+        let mut frame = [0.5; 960];
+
+        assert!(gate.process(&mut frame));
+        assert_eq!(frame[0], 0.5);
+    }
+
+    #[test]
+    fn quiet_frames_are_silenced_after_the_hangover() {
+        let mut gate = NoiseGate::new(0.1);
+        assert!(gate.process(&mut [0.5; 960]));
+
+        for _ in 0..HANGOVER_FRAMES {
+            let mut frame = [0.01; 960];
+            assert!(gate.process(&mut frame));
+            assert_eq!(frame[0], 0.01);
+        }
+
+        let mut frame = [0.01; 960];
+        assert!(!gate.process(&mut frame));
+        assert!(frame.iter().all(|sample| *sample == 0.0));
+    }
+
+    #[test]
+    fn the_gate_starts_closed() {
+        let mut gate = NoiseGate::new(0.1);
+        assert!(!gate.process(&mut [0.0; 960]));
+    }
+}
@@ -1,80 +1,231 @@
+use rtrb::Producer;
+use std::borrow::Cow;
 use std::sync::Arc;
-use webrtc::{
-    peer_connection::RTCPeerConnection,
-    rtp_transceiver::{rtp_receiver::RTCRtpReceiver, RTCRtpTransceiver},
-    track::track_remote::TrackRemote,
-};
-
-use super::decode::decode_opus;
-
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-
-fn play_audio(pcm_data: Vec<i16>) {
-    let host = cpal::default_host();
-    let device = host
-        .default_output_device()
-        .expect("Failed to get default output device");
-    let config = device
-        .default_output_config()
-        .expect("Failed to get default output format");
-
-    let stream = device
-        .build_output_stream(
-            &config.into(),
-            move |output: &mut [f32], _: &cpal::OutputCallbackInfo| {
-                for (i, sample) in pcm_data.iter().enumerate() {
-                    if i < output.len() {
-                        output[i] = *sample as f32 / i16::MAX as f32;
-                    }
+use tokio::sync::watch;
+use webrtc::track::track_remote::TrackRemote;
+
+use super::controls::AudioControls;
+use super::decode::OpusStream;
+use super::mixer::{push_samples, Mixer, Volume};
+use super::mode::AudioMode;
+use super::red::split_red;
+use super::replay::ReplayRecorder;
+use crate::e2ee::FrameCrypto;
+use crate::peer::codecs::RED_PAYLOAD_TYPE;
+use crate::peer::stats::ReceiveStats;
+use crate::peer::sync::StreamSync;
+
+/// Playback volume of a talking peer while we talk over them.
+const DUCKED_VOLUME: f32 = 0.4;
+/// Longest run of lost frames filled in before a packet, longer gaps are
+/// left silent.
+const MAX_CONCEALED_FRAMES: usize = 5;
+
+fn push_pcm(
+    pcm: Result<&[f32], opus::Error>,
+    producer: &mut Producer<f32>,
+    replay_tap: &mut Producer<f32>,
+) {
+    match pcm {
+        Ok(pcm) => {
+            push_samples(producer, pcm.len(), pcm.iter().copied());
+            push_samples(replay_tap, pcm.len(), pcm.iter().copied());
+        }
+        Err(e) => {
+            eprintln!("Failed to decode OPUS data: {:?}", e);
+        }
+    }
+}
+
+/// Decrypts the frames of one peer. A peer's frames fail until its key
+/// arrives, so that is only reported once, and again after it recovered.
+struct PeerDecryptor {
+    crypto: Arc<FrameCrypto>,
+    user_id: String,
+    failing: bool,
+}
+
+impl PeerDecryptor {
+    fn decrypt<'a>(&mut self, payload: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        match self.crypto.decrypt(&self.user_id, payload) {
+            Ok(payload) => {
+                self.failing = false;
+                Some(payload)
+            }
+            Err(e) => {
+                if !self.failing {
+                    eprintln!("Dropping audio frames from {}: {}", self.user_id, e);
+                    self.failing = true;
                 }
-            },
-            move |err| {
-                eprintln!("Stream error: {}", err);
-            },
-            None,
-        )
-        .unwrap();
-
-    stream.play().unwrap();
-    // Keep the stream alive to play the audio
-    std::thread::sleep(std::time::Duration::from_secs(5));
+                None
+            }
+        }
+    }
+}
+
+/// Plays one Opus frame, first filling in the `lost` frames before it: the
+/// last one from the frame's in-band FEC, older ones with concealment.
+/// Peers in voice mode are ducked while we talk, music plays on.
+fn playback_volume(mode: AudioMode, talking: bool) -> f32 {
+    if mode == AudioMode::Voice && talking {
+        DUCKED_VOLUME
+    } else {
+        1.0
+    }
+}
+
+fn play_payload(
+    stream: &mut OpusStream,
+    decryptor: &mut PeerDecryptor,
+    payload: &[u8],
+    lost: usize,
+    producer: &mut Producer<f32>,
+    replay_tap: &mut Producer<f32>,
+) {
+    let Some(payload) = decryptor.decrypt(payload) else {
+        return;
+    };
+
+    if lost > 0 {
+        for _ in 1..lost.min(MAX_CONCEALED_FRAMES) {
+            push_pcm(stream.conceal(), producer, replay_tap);
+        }
+        push_pcm(stream.recover(&payload), producer, replay_tap);
+    }
+
+    push_pcm(stream.decode(&payload), producer, replay_tap);
 }
 
-pub async fn receive_audio(peer_connection: &RTCPeerConnection) {
-    println!("Receiving audio from {:?}", peer_connection.get_stats_id());
-
-    peer_connection.on_track(Box::new(
-        move |track: Arc<TrackRemote>,
-              _receiver: Arc<RTCRtpReceiver>,
-              _transceiver: Arc<RTCRtpTransceiver>| {
-            println!("Received remote track: {:?}", track);
-
-            Box::pin(async move {
-                let mut buffer = vec![0u8; 2048];
-                loop {
-                    match track.read(&mut buffer).await {
-                        Ok(n) => {
-                            let (packet, _info) = n;
-                            let encoded_data = packet.payload;
-                            println!("Received RTP packet with size: {}", encoded_data.len());
-
-                            match decode_opus(&encoded_data) {
-                                Ok(decoded_pcm) => {
-                                    println!("Decoded PCM data with length: {}", decoded_pcm.len());
-                                    play_audio(decoded_pcm);
-                                }
-                                Err(e) => {
-                                    eprintln!("Failed to decode OPUS data: {:?}", e);
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("Error reading from track: {:?}", e);
-                            break;
-                        }
-                    }
+/// Decodes an incoming Opus (or RED) track into the mixer and the replay
+/// buffer until the session closes. With frame encryption on, each Opus
+/// frame is decrypted first, RED blocks included. What is decoded is
+/// reported to `sync`, for video to line up with.
+///
+/// The peer is ducked while we talk, unless it announced music mode.
+#[allow(clippy::too_many_arguments)]
+pub async fn play_track(
+    track: Arc<TrackRemote>,
+    user_id: String,
+    mixer: Arc<Mixer>,
+    replay: Arc<ReplayRecorder>,
+    crypto: Arc<FrameCrypto>,
+    controls: Arc<AudioControls>,
+    mode: watch::Receiver<AudioMode>,
+    stats: Arc<ReceiveStats>,
+    sync: Arc<StreamSync>,
+    mut closed: watch::Receiver<bool>,
+) {
+    let volume = Arc::new(Volume::new(1.0));
+    let mut producer = match mixer.add_source(volume.clone()) {
+        Ok(producer) => producer,
+        Err(e) => {
+            eprintln!("Failed to add track to the mixer: {:?}", e);
+            return;
+        }
+    };
+
+    let mut replay_tap = replay.add_participant(&user_id);
+
+    let mut stream = match OpusStream::new() {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!("Failed to create OPUS decoder: {:?}", e);
+            return;
+        }
+    };
+
+    let mut decryptor = PeerDecryptor {
+        crypto,
+        user_id,
+        failing: false,
+    };
+
+    let mut buffer = vec![0u8; 2048];
+    let mut last_sequence_number: Option<u16> = None;
+
+    loop {
+        let read = tokio::select! {
+            read = track.read(&mut buffer) => read,
+            _ = closed.wait_for(|closed| *closed) => break,
+        };
+
+        match read {
+            Ok((packet, _)) => {
+                stats.record(&packet.header, track.codec().capability.clock_rate);
+
+                volume.set(playback_volume(*mode.borrow(), controls.talking()));
+
+                if let Some(captured) = sync.capture_time(track.ssrc(), packet.header.timestamp) {
+                    sync.audio_decoded(captured);
+                }
+
+                let sequence_number = packet.header.sequence_number;
+                let missing = last_sequence_number
+                    .map(|last| sequence_number.wrapping_sub(last).wrapping_sub(1))
+                    .filter(|missing| *missing < 0x8000)
+                    .unwrap_or(0) as usize;
+                last_sequence_number = Some(sequence_number);
+
+                if packet.header.payload_type != RED_PAYLOAD_TYPE {
+                    stats.record_frames(1, missing as u64);
+                    play_payload(
+                        &mut stream,
+                        &mut decryptor,
+                        &packet.payload,
+                        missing,
+                        &mut producer,
+                        &mut replay_tap,
+                    );
+                    continue;
                 }
-            })
-        },
-    ));
+
+                let Some(blocks) = split_red(&packet.payload) else {
+                    eprintln!("Dropping malformed RED packet");
+                    continue;
+                };
+
+                // Redundant blocks are older frames, only the ones we missed
+                // are played. Whatever is older than those is concealed.
+                let (primary, redundant) = blocks.split_last().unwrap();
+                let recovered = missing.min(redundant.len());
+                let mut lost = missing - recovered;
+                stats.record_frames(1 + recovered as u64, lost as u64);
+                for block in redundant[redundant.len() - recovered..]
+                    .iter()
+                    .chain([primary])
+                {
+                    play_payload(
+                        &mut stream,
+                        &mut decryptor,
+                        block,
+                        lost,
+                        &mut producer,
+                        &mut replay_tap,
+                    );
+                    lost = 0;
+                }
+            }
+            Err(e) => {
+                eprintln!("Error reading from track: {:?}", e);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn voice_peers_are_ducked_while_we_talk() {
+        assert_eq!(playback_volume(AudioMode::Voice, true), DUCKED_VOLUME);
+        assert_eq!(playback_volume(AudioMode::Voice, false), 1.0);
+    }
+
+    #[test]
+    fn music_is_never_ducked() {
+        assert_eq!(playback_volume(AudioMode::Music, true), 1.0);
+        assert_eq!(playback_volume(AudioMode::Music, false), 1.0);
+    }
 }
@@ -0,0 +1,88 @@
+/// Splits an RFC 2198 redundant audio payload into its blocks, oldest
+/// first with the primary encoding last. Returns `None` if the payload is
+/// malformed.
+pub fn split_red(payload: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut lengths = Vec::new();
+    let mut offset = 0;
+
+    // Redundant block headers are 4 bytes with the F bit set, the primary
+    // one is a single byte.
+    loop {
+        let header = *payload.get(offset)?;
+
+        if header & 0x80 == 0 {
+            offset += 1;
+            break;
+        }
+
+        let bytes = payload.get(offset..offset + 4)?;
+        lengths.push((((bytes[2] & 0x03) as usize) << 8) | bytes[3] as usize);
+        offset += 4;
+    }
+
+    let mut blocks = Vec::with_capacity(lengths.len() + 1);
+
+    for length in lengths {
+        blocks.push(payload.get(offset..offset + length)?);
+        offset += length;
+    }
+
+    blocks.push(&payload[offset..]);
+
+    Some(blocks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A redundant block header: F bit and payload type, 14 bits of
+    /// timestamp offset and 10 bits of length.
+    fn header(payload_type: u8, offset: u16, length: usize) -> [u8; 4] {
+        [
+            0x80 | payload_type,
+            (offset >> 6) as u8,
+            ((offset as u8 & 0x3f) << 2) | (length >> 8) as u8,
+            length as u8,
+        ]
+    }
+
+    #[test]
+    fn primary_only() {
+        let payload = [111, 1, 2, 3];
+
+        assert_eq!(split_red(&payload), Some(vec![&[1, 2, 3][..]]));
+    }
+
+    #[test]
+    fn redundant_blocks_come_first() {
+        let mut payload = Vec::new();
+        payload.extend(header(111, 1920, 2));
+        payload.extend(header(111, 960, 300));
+        payload.push(111);
+        payload.extend([1, 2]);
+        payload.extend([3; 300]);
+        payload.extend([4, 5, 6]);
+
+        let blocks = split_red(&payload).unwrap();
+
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[0], &[1, 2]);
+        assert_eq!(blocks[1], &[3; 300]);
+        assert_eq!(blocks[2], &[4, 5, 6]);
+    }
+
+    #[test]
+    fn malformed_payloads_are_rejected() {
+        // Truncated block header.
+        assert_eq!(split_red(&[0x80 | 111, 0, 0]), None);
+        // No primary header at all.
+        assert_eq!(split_red(&[]), None);
+
+        // A block longer than what is left.
+        let mut payload = header(111, 960, 10).to_vec();
+        payload.push(111);
+        payload.extend([1, 2, 3]);
+        assert_eq!(split_red(&payload), None);
+    }
+}
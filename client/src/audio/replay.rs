@@ -0,0 +1,412 @@
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use opus::{Application, Bitrate, Channels, Encoder};
+use rtp::packet::Packet;
+use rtrb::{Consumer, Producer, RingBuffer};
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{interval, MissedTickBehavior};
+use webrtc::media::io::ogg_writer::OggWriter;
+use webrtc::media::io::Writer;
+
+use super::capture::SAMPLE_RATE;
+use super::send::FRAME_SIZE;
+use crate::config::{get_app_dir, AudioConfig};
+
+const MIN_REPLAY_SECONDS: u32 = 30;
+const MAX_REPLAY_SECONDS: u32 = 120;
+const CHANNELS: usize = 2;
+const FRAME_DURATION: Duration = Duration::from_millis(20);
+const REPLAY_BITRATE: i32 = 64_000;
+const MAX_PACKET_SIZE: usize = 1275;
+/// Two seconds of decoded audio per participant tap.
+const TAP_CAPACITY: usize = SAMPLE_RATE as usize * 2 * CHANNELS;
+
+enum ReplayCommand {
+    AddParticipant(String, Consumer<f32>),
+    Save(oneshot::Sender<Result<Vec<PathBuf>>>),
+}
+
+/// Last `max_packets` encoded frames of one stream.
+struct ReplayTrack {
+    encoder: Encoder,
+    packets: VecDeque<Bytes>,
+    max_packets: usize,
+    scratch: Vec<u8>,
+}
+
+impl ReplayTrack {
+    fn new(max_packets: usize) -> Result<Self> {
+        let mut encoder = Encoder::new(SAMPLE_RATE, Channels::Stereo, Application::Audio)?;
+        encoder.set_bitrate(Bitrate::Bits(REPLAY_BITRATE))?;
+
+        Ok(Self {
+            encoder,
+            packets: VecDeque::with_capacity(max_packets),
+            max_packets,
+            scratch: vec![0; MAX_PACKET_SIZE],
+        })
+    }
+
+    fn push(&mut self, frame: &[f32]) {
+        let len = match self.encoder.encode_float(frame, &mut self.scratch) {
+            Ok(len) => len,
+            Err(e) => {
+                eprintln!("Failed to encode replay frame: {:?}", e);
+                return;
+            }
+        };
+
+        if self.packets.len() == self.max_packets {
+            self.packets.pop_front();
+        }
+
+        self.packets
+            .push_back(Bytes::copy_from_slice(&self.scratch[..len]));
+    }
+}
+
+struct Participant {
+    user_id: String,
+    tap: Consumer<f32>,
+    frame: Vec<f32>,
+    track: Option<ReplayTrack>,
+}
+
+/// Rolling buffer of the decoded room audio, saved to Ogg/Opus on demand.
+pub struct ReplayRecorder {
+    commands: mpsc::UnboundedSender<ReplayCommand>,
+}
+
+impl ReplayRecorder {
+    pub fn start(config: &AudioConfig) -> Result<Arc<Self>> {
+        let seconds = config
+            .replay_seconds
+            .clamp(MIN_REPLAY_SECONDS, MAX_REPLAY_SECONDS);
+        let max_packets = (seconds * 1000 / FRAME_DURATION.as_millis() as u32) as usize;
+
+        let dir = match &config.replay_dir {
+            Some(dir) => dir.clone(),
+            None => get_app_dir()?.join("replays"),
+        };
+
+        let (commands, commands_rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(record(
+            commands_rx,
+            ReplayTrack::new(max_packets)?,
+            max_packets,
+            config.replay_per_participant,
+            dir,
+        ));
+
+        Ok(Arc::new(Self { commands }))
+    }
+
+    /// A recorder that keeps nothing, for when the replay buffer can't run.
+    /// Saving reports that it is not running.
+    pub fn disabled() -> Arc<Self> {
+        let (commands, _) = mpsc::unbounded_channel();
+        Arc::new(Self { commands })
+    }
+
+    /// Returns the producer a participant's decoded 48kHz stereo audio is
+    /// written to.
+    pub fn add_participant(&self, user_id: &str) -> Producer<f32> {
+        let (producer, consumer) = RingBuffer::new(TAP_CAPACITY);
+
+        let _ = self
+            .commands
+            .send(ReplayCommand::AddParticipant(user_id.to_string(), consumer));
+
+        producer
+    }
+
+    /// Writes the buffered audio to disk and returns the created files.
+    pub async fn save(&self) -> Result<Vec<PathBuf>> {
+        let (tx, rx) = oneshot::channel();
+
+        self.commands
+            .send(ReplayCommand::Save(tx))
+            .map_err(|_| anyhow!("Replay recorder is not running"))?;
+
+        rx.await.map_err(|_| anyhow!("Replay recorder stopped"))?
+    }
+}
+
+/// Reads up to one frame from `tap`, padding with silence when the
+/// participant has not sent enough audio yet.
+fn read_frame(tap: &mut Consumer<f32>, frame: &mut [f32]) {
+    let n = frame.len().min(tap.slots());
+
+    if let Ok(chunk) = tap.read_chunk(n) {
+        let (first, second) = chunk.as_slices();
+        frame[..first.len()].copy_from_slice(first);
+        frame[first.len()..n].copy_from_slice(second);
+        chunk.commit_all();
+    }
+
+    frame[n..].fill(0.0);
+}
+
+async fn record(
+    mut commands: mpsc::UnboundedReceiver<ReplayCommand>,
+    mut room: ReplayTrack,
+    max_packets: usize,
+    per_participant: bool,
+    dir: PathBuf,
+) {
+    let mut participants: Vec<Participant> = Vec::new();
+    let mut mixed = vec![0f32; FRAME_SIZE * CHANNELS];
+
+    let mut ticker = interval(FRAME_DURATION);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        ticker.tick().await;
+
+        loop {
+            match commands.try_recv() {
+                Ok(ReplayCommand::AddParticipant(user_id, tap)) => {
+                    let track = if per_participant {
+                        ReplayTrack::new(max_packets).ok()
+                    } else {
+                        None
+                    };
+
+                    participants.push(Participant {
+                        user_id,
+                        tap,
+                        frame: vec![0.0; FRAME_SIZE * CHANNELS],
+                        track,
+                    });
+                }
+                Ok(ReplayCommand::Save(reply)) => {
+                    let streams = snapshot(&dir, &room, &participants);
+                    let dir = dir.clone();
+
+                    tokio::spawn(async move {
+                        let _ = reply.send(write_streams(dir, streams).await);
+                    });
+                }
+                Err(mpsc::error::TryRecvError::Empty) => break,
+                Err(mpsc::error::TryRecvError::Disconnected) => return,
+            }
+        }
+
+        participants.retain(|p| !(p.tap.is_abandoned() && p.tap.is_empty()));
+
+        mixed.fill(0.0);
+
+        for participant in participants.iter_mut() {
+            read_frame(&mut participant.tap, &mut participant.frame);
+
+            for (out, sample) in mixed.iter_mut().zip(&participant.frame) {
+                *out += sample;
+            }
+
+            if let Some(track) = participant.track.as_mut() {
+                track.push(&participant.frame);
+            }
+        }
+
+        for sample in mixed.iter_mut() {
+            *sample = sample.clamp(-1.0, 1.0);
+        }
+
+        room.push(&mixed);
+    }
+}
+
+/// Copies the buffered packets out, so writing them does not hold up
+/// recording.
+fn snapshot(
+    dir: &Path,
+    room: &ReplayTrack,
+    participants: &[Participant],
+) -> Vec<(PathBuf, Vec<Bytes>)> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let mut streams = vec![(
+        dir.join(format!("replay-{}.opus", timestamp)),
+        room.packets.iter().cloned().collect::<Vec<_>>(),
+    )];
+
+    for participant in participants {
+        if let Some(track) = &participant.track {
+            streams.push((
+                dir.join(format!("replay-{}-{}.opus", timestamp, participant.user_id)),
+                track.packets.iter().cloned().collect(),
+            ));
+        }
+    }
+
+    streams
+}
+
+async fn write_streams(dir: PathBuf, streams: Vec<(PathBuf, Vec<Bytes>)>) -> Result<Vec<PathBuf>> {
+    tokio::task::spawn_blocking(move || {
+        fs::create_dir_all(&dir).context("Couldn't create the replay directory.")?;
+
+        streams
+            .into_iter()
+            .map(|(path, packets)| write_ogg(&path, packets).map(|_| path))
+            .collect()
+    })
+    .await?
+}
+
+fn write_ogg(path: &Path, packets: Vec<Bytes>) -> Result<()> {
+    let file = File::create(path).context("Couldn't create the replay file.")?;
+    let mut writer = OggWriter::new(file, SAMPLE_RATE, CHANNELS as u8)?;
+
+    for (index, payload) in packets.into_iter().enumerate() {
+        writer.write_rtp(&Packet {
+            header: rtp::header::Header {
+                timestamp: (index * FRAME_SIZE) as u32,
+                ..Default::default()
+            },
+            payload,
+        })?;
+    }
+
+    writer.close()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webrtc::media::io::ogg_reader::OggReader;
+
+    fn replay_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("replay-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn tracks_keep_only_the_last_packets() {
+        let mut track = ReplayTrack::new(3).unwrap();
+        let frame = vec![0.1; FRAME_SIZE * CHANNELS];
+
+        for _ in 0..5 {
+            track.push(&frame);
+        }
+
+        assert_eq!(track.packets.len(), 3);
+    }
+
+    #[test]
+    fn short_reads_are_padded_with_silence() {
+        let (mut producer, mut tap) = RingBuffer::new(8);
+        let mut frame = [1.0f32; 6];
+
+        // Wrap the ring buffer so the read spans both of its slices.
+        for sample in [0.0; 6] {
+            producer.push(sample).unwrap();
+        }
+        read_frame(&mut tap, &mut frame);
+        for sample in [0.1, 0.2, 0.3, 0.4] {
+            producer.push(sample).unwrap();
+        }
+
+        read_frame(&mut tap, &mut frame);
+        assert_eq!(frame, [0.1, 0.2, 0.3, 0.4, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn participants_get_their_own_file_only_when_tracked() {
+        let room = ReplayTrack::new(1).unwrap();
+        let participant = |user_id: &str, track| Participant {
+            user_id: user_id.to_owned(),
+            tap: RingBuffer::new(1).1,
+            frame: Vec::new(),
+            track,
+        };
+        let participants = [
+            participant("alice", Some(ReplayTrack::new(1).unwrap())),
+            participant("bob", None),
+        ];
+
+        let streams = snapshot(Path::new("/replays"), &room, &participants);
+        let names: Vec<_> = streams
+            .iter()
+            .map(|(path, _)| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+
+        assert_eq!(names.len(), 2);
+        assert!(names[0].starts_with("replay-") && !names[0].contains("alice"));
+        assert!(names[1].ends_with("-alice.opus"));
+    }
+
+    #[test]
+    fn saved_replays_are_ogg_opus() {
+        let dir = replay_dir("ogg");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("replay.opus");
+
+        let mut track = ReplayTrack::new(10).unwrap();
+        for _ in 0..10 {
+            track.push(&vec![0.0; FRAME_SIZE * CHANNELS]);
+        }
+        write_ogg(&path, track.packets.into_iter().collect()).unwrap();
+
+        let (mut reader, header) = OggReader::new(File::open(&path).unwrap(), true).unwrap();
+        assert_eq!(header.channel_map, 0);
+        assert_eq!(header.channels, CHANNELS as u8);
+        assert_eq!(header.sample_rate, SAMPLE_RATE);
+
+        // The tags page, one page per frame and the end of stream page,
+        // which repeats the last frame.
+        let mut pages = 0;
+        while reader.parse_next_page().is_ok() {
+            pages += 1;
+        }
+        assert_eq!(pages, 12);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn recorded_audio_is_saved_on_request() {
+        let dir = replay_dir("save");
+        let recorder = ReplayRecorder::start(&AudioConfig {
+            replay_dir: Some(dir.clone()),
+            replay_per_participant: true,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let mut tap = recorder.add_participant("alice");
+        for sample in std::iter::repeat_n(0.2, FRAME_SIZE * CHANNELS * 5) {
+            let _ = tap.push(sample);
+        }
+        tokio::time::sleep(FRAME_DURATION * 5).await;
+
+        let saved = recorder.save().await.unwrap();
+        assert_eq!(saved.len(), 2);
+        assert!(saved
+            .iter()
+            .all(|path| path.starts_with(&dir) && path.exists()));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_disabled_recorder_has_nothing_to_save() {
+        let recorder = ReplayRecorder::disabled();
+        let mut tap = recorder.add_participant("alice");
+        let _ = tap.push(0.5);
+
+        assert!(recorder.save().await.is_err());
+    }
+}
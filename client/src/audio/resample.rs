@@ -0,0 +1,194 @@
+use cpal::{SampleFormat, SampleRate, SupportedStreamConfig, SupportedStreamConfigRange};
+
+use super::capture::SAMPLE_RATE;
+
+/// Sample formats the capture and output streams convert from and to f32.
+pub const SAMPLE_FORMATS: [SampleFormat; 3] =
+    [SampleFormat::F32, SampleFormat::I16, SampleFormat::U16];
+
+/// The device config closest to 48kHz f32. The rate matters most, since a
+/// different one means resampling, then the sample format in the order of
+/// [`SAMPLE_FORMATS`].
+pub fn closest_config(
+    ranges: impl Iterator<Item = SupportedStreamConfigRange>,
+) -> Option<SupportedStreamConfig> {
+    ranges
+        .filter_map(|range| {
+            let format = SAMPLE_FORMATS
+                .iter()
+                .position(|format| *format == range.sample_format())?;
+            let rate = SAMPLE_RATE.clamp(range.min_sample_rate().0, range.max_sample_rate().0);
+            Some((rate.abs_diff(SAMPLE_RATE), format, range, rate))
+        })
+        .min_by_key(|(distance, format, _, _)| (*distance, *format))
+        .map(|(_, _, range, rate)| range.with_sample_rate(SampleRate(rate)))
+}
+
+/// Linear interpolation from one sample rate to another, over interleaved
+/// samples pushed in blocks of any size. Nothing is allocated after
+/// [`Resampler::new`], so it can run in an audio callback.
+pub struct Resampler {
+    /// Input frames per output frame.
+    step: f64,
+    /// Where the next output frame falls between `previous` (0) and the
+    /// next input frame (1).
+    position: f64,
+    previous: Vec<f32>,
+    current: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(from: u32, to: u32, channels: usize) -> Self {
+        Self {
+            step: from as f64 / to as f64,
+            position: 0.0,
+            previous: vec![0.0; channels],
+            current: vec![0.0; channels],
+        }
+    }
+
+    /// Resamples `input`, passing each output sample to `output`. A trailing
+    /// partial frame is dropped.
+    pub fn process(&mut self, mut input: impl Iterator<Item = f32>, mut output: impl FnMut(f32)) {
+        if self.step == 1.0 {
+            input.for_each(output);
+            return;
+        }
+
+        'frames: loop {
+            for sample in self.current.iter_mut() {
+                match input.next() {
+                    Some(next) => *sample = next,
+                    None => break 'frames,
+                }
+            }
+
+            while self.position < 1.0 {
+                let t = self.position as f32;
+                for (a, b) in self.previous.iter().zip(&self.current) {
+                    output(a + (b - a) * t);
+                }
+                self.position += self.step;
+            }
+
+            self.position -= 1.0;
+            std::mem::swap(&mut self.previous, &mut self.current);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpal::SupportedBufferSize;
+
+    fn range(format: SampleFormat, min: u32, max: u32) -> SupportedStreamConfigRange {
+        SupportedStreamConfigRange::new(
+            2,
+            SampleRate(min),
+            SampleRate(max),
+            SupportedBufferSize::Unknown,
+            format,
+        )
+    }
+
+    fn resample(resampler: &mut Resampler, input: &[f32]) -> Vec<f32> {
+        let mut output = Vec::new();
+        resampler.process(input.iter().copied(), |sample| output.push(sample));
+        output
+    }
+
+    #[test]
+    fn the_rate_matters_more_than_the_format() {
+        let config = closest_config(
+            [
+                range(SampleFormat::F32, 44100, 44100),
+                range(SampleFormat::I16, 8000, 96000),
+            ]
+            .into_iter(),
+        )
+        .unwrap();
+
+        assert_eq!(config.sample_format(), SampleFormat::I16);
+        assert_eq!(config.sample_rate().0, SAMPLE_RATE);
+    }
+
+    #[test]
+    fn f32_wins_at_the_same_rate() {
+        let config = closest_config(
+            [
+                range(SampleFormat::U16, 48000, 48000),
+                range(SampleFormat::F32, 48000, 48000),
+            ]
+            .into_iter(),
+        )
+        .unwrap();
+
+        assert_eq!(config.sample_format(), SampleFormat::F32);
+    }
+
+    #[test]
+    fn the_closest_rate_is_picked() {
+        let config = closest_config(
+            [
+                range(SampleFormat::F32, 8000, 16000),
+                range(SampleFormat::F32, 64000, 96000),
+            ]
+            .into_iter(),
+        )
+        .unwrap();
+
+        assert_eq!(config.sample_rate().0, 64000);
+    }
+
+    #[test]
+    fn unknown_formats_are_skipped() {
+        assert!(closest_config([range(SampleFormat::I8, 48000, 48000)].into_iter()).is_none());
+    }
+
+    #[test]
+    fn the_same_rate_passes_samples_through() {
+        let mut resampler = Resampler::new(48000, 48000, 2);
+        let input = [0.1, -0.1, 0.2, -0.2, 0.3, -0.3];
+
+        assert_eq!(resample(&mut resampler, &input), input);
+    }
+
+    #[test]
+    fn halving_the_rate_keeps_every_other_frame() {
+        let mut resampler = Resampler::new(96000, 48000, 1);
+        let input: Vec<f32> = (1..=8).map(|i| i as f32).collect();
+
+        // The first frame comes from the silence before the input.
+        assert_eq!(resample(&mut resampler, &input), [0.0, 2.0, 4.0, 6.0]);
+    }
+
+    #[test]
+    fn doubling_the_rate_interpolates_between_frames() {
+        let mut resampler = Resampler::new(24000, 48000, 1);
+
+        assert_eq!(
+            resample(&mut resampler, &[2.0, 4.0, 6.0]),
+            [0.0, 1.0, 2.0, 3.0, 4.0, 5.0]
+        );
+    }
+
+    #[test]
+    fn blocks_of_any_size_give_the_same_output() {
+        let input: Vec<f32> = (0..882).map(|i| (i as f32 * 0.05).sin()).collect();
+
+        let mut whole = Resampler::new(44100, 48000, 2);
+        let expected = resample(&mut whole, &input);
+
+        let mut split = Resampler::new(44100, 48000, 2);
+        let mut output = Vec::new();
+        for block in input.chunks(6) {
+            output.extend(resample(&mut split, block));
+        }
+
+        assert_eq!(output, expected);
+        // 441 stereo frames at 44.1kHz are 480 at 48kHz, give or take the
+        // rounding of the step.
+        assert!(expected.len().abs_diff(480 * 2) <= 2, "{}", expected.len());
+    }
+}
@@ -1,51 +1,261 @@
 use anyhow::Result;
-use bytes::Bytes;
+use opus::Bitrate;
 use rtp::packet::Packet;
-use std::{
-    io::Write,
-    sync::{mpsc::Receiver, Arc},
-};
-use tokio::sync::Mutex;
-use webrtc::track::track_local::TrackLocalWriter;
-use webrtc::{
-    peer_connection::RTCPeerConnection,
-    track::track_local::track_local_static_rtp::TrackLocalStaticRTP,
-};
-
-pub async fn send_audio(
-    peer_connection: Arc<RTCPeerConnection>,
-    rx: Arc<Mutex<Receiver<Vec<u8>>>>,
-    audio_track: Arc<TrackLocalStaticRTP>,
-) -> Result<()> {
-    println!("Sending audio to {:?}", peer_connection.get_stats_id());
-    std::io::stdout().flush().unwrap();
-
-    let rx = rx.lock().await;
+use rtrb::{Consumer, Producer};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::time::{interval, MissedTickBehavior};
+use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
+use webrtc::track::track_local::{TrackLocal, TrackLocalWriter};
+
+use super::capture::{CaptureFormat, SAMPLE_RATE};
+use super::controls::AudioControls;
+use super::mixer::push_samples;
+use super::mode::AudioProfile;
+use super::pool::PacketPool;
+use super::process::NoiseGate;
+use super::soundboard::SoundboardPlayer;
+use crate::config::{AudioConfig, CodecConfig};
+use crate::e2ee::FrameCrypto;
+use crate::peer::bandwidth::UploadBudget;
+use crate::peer::codecs::{opus_capability, OPUS_PAYLOAD_TYPE};
+use crate::peer::sync::MediaClock;
+
+/// 20ms of audio at 48kHz.
+pub const FRAME_SIZE: usize = 960;
+pub const FRAME_DURATION: Duration = Duration::from_millis(20);
+const CHANNELS: usize = 2;
+/// Largest packet a single Opus frame can produce.
+const MAX_PACKET_SIZE: usize = 1275;
+const POOLED_PACKETS: usize = 64;
+/// How often the encoder bitrate follows the congestion controller.
+const BITRATE_UPDATE_FRAMES: u32 = 50;
+/// How often RTP time is tied to capture time again, so the audio device
+/// clock can't drift away from the wall clock (5s).
+const CLOCK_ANCHOR_FRAMES: u32 = 250;
+
+/// The local audio track, shared by every peer connection.
+pub fn create_audio_track(codecs: &CodecConfig) -> Arc<TrackLocalStaticRTP> {
+    Arc::new(TrackLocalStaticRTP::new(
+        opus_capability(codecs),
+        "audio_track".to_owned(),
+        "webrtc-rs".to_owned(),
+    ))
+}
+
+/// Copies one frame of interleaved `channels`-channel samples into `frame`,
+/// which holds `out_channels` (1 or 2) interleaved channels. Mono input is
+/// duplicated, stereo is averaged down to mono and extra channels are dropped.
+fn remix<'a>(
+    mut samples: impl Iterator<Item = &'a f32>,
+    channels: usize,
+    out_channels: usize,
+    frame: &mut [f32],
+) {
+    for out in frame.chunks_exact_mut(out_channels) {
+        let left = samples.next().copied().unwrap_or(0.0);
+        let right = if channels > 1 {
+            samples.next().copied().unwrap_or(left)
+        } else {
+            left
+        };
+
+        for _ in CHANNELS..channels {
+            samples.next();
+        }
+
+        match out {
+            [mono] => *mono = (left + right) * 0.5,
+            [l, r] => {
+                *l = left;
+                *r = right;
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Feeds the processed frame back to the local mixer as stereo.
+fn write_sidetone(sidetone: &mut Producer<f32>, frame: &[f32], channels: usize) {
+    if channels == 1 {
+        push_samples(
+            sidetone,
+            frame.len() * 2,
+            frame.iter().flat_map(|s| [*s, *s]),
+        );
+    } else {
+        push_samples(sidetone, frame.len(), frame.iter().copied());
+    }
+}
+
+/// What [`send_audio`] reads from and writes to.
+pub struct AudioSender {
+    pub consumer: Consumer<f32>,
+    pub format: CaptureFormat,
+    pub audio_track: Arc<TrackLocalStaticRTP>,
+    pub controls: Arc<AudioControls>,
+    pub upload: Arc<UploadBudget>,
+    pub crypto: Arc<FrameCrypto>,
+    pub clock: Arc<MediaClock>,
+    pub config: AudioConfig,
+    pub sidetone: Producer<f32>,
+    pub soundboard: SoundboardPlayer,
+}
+
+/// Encodes captured audio into Opus and writes it to `audio_track`.
+///
+/// Samples are drained from the capture ring buffer on a 20ms tick, so the
+/// task only ever awaits the timer and never blocks a runtime worker. The
+/// encoder is rebuilt whenever the controls switch between voice and music,
+/// and processed frames are mirrored to `sidetone` while it is enabled.
+/// Soundboard clips are mixed in after voice processing, right before the
+/// encoder, whose bitrate is lowered while `upload` says the slowest peer
+/// can't take the profile's. Encoded frames go through `crypto` before
+/// `write_rtp`, and are dropped while it has no key for the room yet.
+/// RTP timestamps count samples, `clock` learns when they were captured
+/// for the sender reports.
+pub async fn send_audio(sender: AudioSender) -> Result<()> {
+    let AudioSender {
+        mut consumer,
+        format,
+        audio_track,
+        controls,
+        upload,
+        crypto,
+        clock,
+        config,
+        mut sidetone,
+        mut soundboard,
+    } = sender;
+
+    println!("\n\rSending audio on track {:?}", audio_track.id());
+
+    let mut mode_rx = controls.mode.subscribe();
+
+    let mut profile = AudioProfile::new(*mode_rx.borrow_and_update(), &config);
+    let mut encoder = profile.encoder()?;
+    let mut bitrate = profile.bitrate;
+    let mut frames_since_update = 0;
+    let mut frames_since_anchor = CLOCK_ANCHOR_FRAMES;
+    let mut gate = NoiseGate::new(config.noise_gate_threshold);
+    let mut pool = PacketPool::new(MAX_PACKET_SIZE, POOLED_PACKETS);
+
+    let input_channels = format.channels as usize;
+    let frame_samples = FRAME_SIZE * input_channels;
+    let mut frame = vec![0f32; FRAME_SIZE * CHANNELS];
+
     let mut sequence_number: u16 = 0;
     let mut timestamp: u32 = 0;
+    // Set on the first packet after a DTX gap, marking a new talkspurt.
+    let mut marker = false;
 
-    while let Ok(audio_data) = rx.recv() {
-        let packet = Packet {
-            header: rtp::header::Header {
-                version: 2,
-                padding: false,
-                extension: false,
-                marker: false,
-                payload_type: 111,
-                sequence_number,
-                timestamp,
-                ssrc: 200566587,
-                ..Default::default()
-            },
-            payload: Bytes::from(audio_data),
-        };
+    let mut ticker = interval(FRAME_DURATION);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        ticker.tick().await;
 
-        if let Err(e) = audio_track.write_rtp(&packet).await {
-            eprintln!("Failed to write sample: {:?}", e);
+        if consumer.is_abandoned() && consumer.slots() < frame_samples {
+            break;
         }
 
-        sequence_number = sequence_number.wrapping_add(1);
-        timestamp = timestamp.wrapping_add(960); // 960 =r 20ms of Opus at 48kHz
+        if mode_rx.has_changed()? {
+            let mode = *mode_rx.borrow_and_update();
+            profile = AudioProfile::new(mode, &config);
+            encoder = profile.encoder()?;
+            bitrate = profile.bitrate;
+            frames_since_update = BITRATE_UPDATE_FRAMES;
+            println!("\n\rSwitched to {:?} mode", mode);
+        }
+
+        frames_since_update += 1;
+        if frames_since_update >= BITRATE_UPDATE_FRAMES {
+            frames_since_update = 0;
+
+            let target = upload.audio_bitrate(profile.bitrate);
+            if target != bitrate {
+                encoder.set_bitrate(Bitrate::Bits(target))?;
+                bitrate = target;
+            }
+        }
+
+        let frame = &mut frame[..FRAME_SIZE * profile.channel_count()];
+
+        while consumer.slots() >= frame_samples {
+            frames_since_anchor += 1;
+            if frames_since_anchor >= CLOCK_ANCHOR_FRAMES {
+                frames_since_anchor = 0;
+
+                // The frame's first sample was captured before everything
+                // still waiting in the ring buffer.
+                let queued = Duration::from_secs_f64(
+                    consumer.slots() as f64 / input_channels as f64 / SAMPLE_RATE as f64,
+                );
+                clock.anchor_audio(timestamp, SystemTime::now() - queued);
+            }
+
+            let chunk = consumer.read_chunk(frame_samples)?;
+            let (first, second) = chunk.as_slices();
+            remix(
+                first.iter().chain(second),
+                input_channels,
+                profile.channel_count(),
+                frame,
+            );
+            chunk.commit_all();
+
+            let voice_active = !profile.voice_processing || gate.process(frame);
+            controls.set_talking(profile.voice_processing && voice_active);
+
+            if controls.sidetone_enabled() {
+                write_sidetone(&mut sidetone, frame, profile.channel_count());
+            }
+
+            soundboard.mix_into(frame, profile.channel_count());
+
+            if !voice_active && !soundboard.is_playing() && profile.dtx {
+                timestamp = timestamp.wrapping_add(FRAME_SIZE as u32);
+                marker = true;
+                continue;
+            }
+
+            let payload = match pool.fill(|buf| encoder.encode_float(frame, buf)) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    eprintln!("Failed to encode audio frame: {:?}", e);
+                    timestamp = timestamp.wrapping_add(FRAME_SIZE as u32);
+                    continue;
+                }
+            };
+
+            let Some(payload) = crypto.encrypt(payload) else {
+                timestamp = timestamp.wrapping_add(FRAME_SIZE as u32);
+                continue;
+            };
+
+            let packet = Packet {
+                header: rtp::header::Header {
+                    version: 2,
+                    padding: false,
+                    extension: false,
+                    marker,
+                    payload_type: OPUS_PAYLOAD_TYPE,
+                    sequence_number,
+                    timestamp,
+                    ssrc: 200566587,
+                    ..Default::default()
+                },
+                payload,
+            };
+
+            if let Err(e) = audio_track.write_rtp(&packet).await {
+                eprintln!("Failed to write sample: {:?}", e);
+            }
+
+            marker = false;
+            sequence_number = sequence_number.wrapping_add(1);
+            timestamp = timestamp.wrapping_add(FRAME_SIZE as u32);
+        }
     }
 
     Ok(())
@@ -0,0 +1,273 @@
+use anyhow::{bail, Context, Result};
+use rodio::source::UniformSourceIterator;
+use rodio::Decoder;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+use super::capture::SAMPLE_RATE;
+use crate::config::SoundboardClip;
+use crate::input::CALL_KEYS;
+
+const CHANNELS: usize = 2;
+/// Clips are meant to be short; anything longer is cut.
+const MAX_CLIP_SECONDS: usize = 10;
+
+struct Clip {
+    samples: Arc<[f32]>,
+    volume: f32,
+}
+
+struct PlayingClip {
+    samples: Arc<[f32]>,
+    volume: f32,
+    position: usize,
+}
+
+/// Clips bound to keys, decoded to 48kHz stereo once at startup.
+pub struct Soundboard {
+    clips: HashMap<char, Clip>,
+    triggers: mpsc::UnboundedSender<PlayingClip>,
+}
+
+/// The send side of the soundboard, mixing triggered clips into outgoing
+/// frames.
+pub struct SoundboardPlayer {
+    triggers: mpsc::UnboundedReceiver<PlayingClip>,
+    playing: Vec<PlayingClip>,
+}
+
+fn load_clip(clip: &SoundboardClip) -> Result<Arc<[f32]>> {
+    if CALL_KEYS.contains(&clip.key) {
+        bail!("Soundboard key '{}' is taken by a call shortcut", clip.key);
+    }
+
+    let file = File::open(&clip.path)
+        .with_context(|| format!("Couldn't open soundboard clip {}", clip.path.display()))?;
+    let decoder = Decoder::new(BufReader::new(file))
+        .with_context(|| format!("Couldn't decode soundboard clip {}", clip.path.display()))?;
+
+    let samples: Vec<f32> =
+        UniformSourceIterator::<_, f32>::new(decoder, CHANNELS as u16, SAMPLE_RATE)
+            .take(SAMPLE_RATE as usize * CHANNELS * MAX_CLIP_SECONDS)
+            .collect();
+
+    Ok(samples.into())
+}
+
+impl Soundboard {
+    /// Decodes every configured clip. Clips that can't be loaded are
+    /// reported and left out, they don't keep the call from starting.
+    pub async fn load(config: &[SoundboardClip]) -> (Arc<Self>, SoundboardPlayer) {
+        let config = config.to_vec();
+
+        let clips = tokio::task::spawn_blocking(move || {
+            config
+                .iter()
+                .filter_map(|clip| match load_clip(clip) {
+                    Ok(samples) => Some((
+                        clip.key,
+                        Clip {
+                            samples,
+                            volume: clip.volume.clamp(0.0, 1.0),
+                        },
+                    )),
+                    Err(e) => {
+                        eprintln!("Skipping soundboard clip: {:#}", e);
+                        None
+                    }
+                })
+                .collect::<HashMap<_, _>>()
+        })
+        .await
+        .unwrap_or_else(|e| {
+            eprintln!("Continuing without the soundboard: {}", e);
+            HashMap::new()
+        });
+
+        let (triggers, triggers_rx) = mpsc::unbounded_channel();
+
+        (
+            Arc::new(Self { clips, triggers }),
+            SoundboardPlayer {
+                triggers: triggers_rx,
+                playing: Vec::new(),
+            },
+        )
+    }
+
+    pub fn has_clip(&self, key: char) -> bool {
+        self.clips.contains_key(&key)
+    }
+
+    /// Starts playing the clip bound to `key` into the outgoing stream.
+    pub fn trigger(&self, key: char) -> bool {
+        let Some(clip) = self.clips.get(&key) else {
+            return false;
+        };
+
+        self.triggers
+            .send(PlayingClip {
+                samples: clip.samples.clone(),
+                volume: clip.volume,
+                position: 0,
+            })
+            .is_ok()
+    }
+}
+
+impl SoundboardPlayer {
+    pub fn is_playing(&self) -> bool {
+        !self.playing.is_empty()
+    }
+
+    /// Adds the next slice of every playing clip to `frame`, which holds
+    /// `channels` (1 or 2) interleaved channels.
+    pub fn mix_into(&mut self, frame: &mut [f32], channels: usize) {
+        while let Ok(clip) = self.triggers.try_recv() {
+            self.playing.push(clip);
+        }
+
+        for clip in self.playing.iter_mut() {
+            let remaining = &clip.samples[clip.position..];
+
+            for (out, pair) in frame
+                .chunks_exact_mut(channels)
+                .zip(remaining.chunks_exact(CHANNELS))
+            {
+                match out {
+                    [mono] => *mono += (pair[0] + pair[1]) * 0.5 * clip.volume,
+                    [l, r] => {
+                        *l += pair[0] * clip.volume;
+                        *r += pair[1] * clip.volume;
+                    }
+                    _ => unreachable!(),
+                }
+            }
+
+            clip.position =
+                (clip.position + frame.len() / channels * CHANNELS).min(clip.samples.len());
+        }
+
+        self.playing
+            .retain(|clip| clip.position < clip.samples.len());
+
+        for sample in frame.iter_mut() {
+            *sample = sample.clamp(-1.0, 1.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn soundboard(samples: &[f32], volume: f32) -> (Soundboard, SoundboardPlayer) {
+        let (triggers, triggers_rx) = mpsc::unbounded_channel();
+        let clips = HashMap::from([(
+            'a',
+            Clip {
+                samples: samples.into(),
+                volume,
+            },
+        )]);
+
+        (
+            Soundboard { clips, triggers },
+            SoundboardPlayer {
+                triggers: triggers_rx,
+                playing: Vec::new(),
+            },
+        )
+    }
+
+    #[test]
+    fn call_keys_cant_hold_clips() {
+        let clip = SoundboardClip {
+            key: CALL_KEYS[0],
+            path: "missing.wav".into(),
+            volume: 1.0,
+        };
+
+        let error = load_clip(&clip).unwrap_err();
+        assert!(error.to_string().contains("call shortcut"), "{}", error);
+    }
+
+    #[test]
+    fn missing_files_fail_to_load() {
+        let clip = SoundboardClip {
+            key: 'a',
+            path: std::env::temp_dir().join(format!("missing-clip-{}.wav", std::process::id())),
+            volume: 1.0,
+        };
+
+        assert!(load_clip(&clip).is_err());
+    }
+
+    #[test]
+    fn only_bound_keys_trigger() {
+        let (soundboard, mut player) = soundboard(&[0.5, 0.5], 1.0);
+
+        assert!(soundboard.has_clip('a'));
+        assert!(!soundboard.has_clip('b'));
+        assert!(!soundboard.trigger('b'));
+        assert!(soundboard.trigger('a'));
+
+        assert!(!player.is_playing());
+        player.mix_into(&mut [0.0; 4], 2);
+        // The clip was a single frame, so it ended within this one.
+        assert!(!player.is_playing());
+    }
+
+    #[test]
+    fn clips_mix_into_stereo_frames_until_they_end() {
+        let (soundboard, mut player) = soundboard(&[0.2, 0.4, 0.6, 0.8, 1.0, 1.0], 0.5);
+        soundboard.trigger('a');
+
+        let mut frame = [0.1; 4];
+        player.mix_into(&mut frame, 2);
+        assert_eq!(frame, [0.2, 0.3, 0.4, 0.5]);
+        assert!(player.is_playing());
+
+        let mut frame = [0.0; 4];
+        player.mix_into(&mut frame, 2);
+        assert_eq!(frame, [0.5, 0.5, 0.0, 0.0]);
+        assert!(!player.is_playing());
+    }
+
+    #[test]
+    fn clips_are_downmixed_for_mono_frames() {
+        let (soundboard, mut player) = soundboard(&[0.2, 0.6, 1.0, 0.0], 1.0);
+        soundboard.trigger('a');
+
+        let mut frame = [0.0; 2];
+        player.mix_into(&mut frame, 1);
+        assert_eq!(frame, [0.4, 0.5]);
+    }
+
+    #[test]
+    fn overlapping_clips_are_clamped() {
+        let (soundboard, mut player) = soundboard(&[0.8, 0.8], 1.0);
+        soundboard.trigger('a');
+        soundboard.trigger('a');
+
+        let mut frame = [0.0; 2];
+        player.mix_into(&mut frame, 2);
+        assert_eq!(frame, [1.0, 1.0]);
+    }
+
+    #[tokio::test]
+    async fn clips_that_fail_to_load_are_left_out() {
+        let (soundboard, player) = Soundboard::load(&[SoundboardClip {
+            key: 'a',
+            path: std::env::temp_dir().join(format!("missing-clip-{}.wav", std::process::id())),
+            volume: 1.0,
+        }])
+        .await;
+
+        assert!(!soundboard.has_clip('a'));
+        assert!(!player.is_playing());
+    }
+}
@@ -0,0 +1,98 @@
+use std::sync::Arc;
+use tokio::sync::{broadcast, watch};
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use webrtc::data_channel::data_channel_state::RTCDataChannelState;
+use webrtc::data_channel::RTCDataChannel;
+
+use super::Chat;
+
+/// Wires a peer's chat channel to the room chat: shows what arrives on it,
+/// catches the peer up when it opens and passes on what others send until
+/// the session closes. Meanwhile only the peer itself is trusted with its
+/// messages.
+pub fn attach_chat(
+    chat: Arc<Chat>,
+    channel: Arc<RTCDataChannel>,
+    other_id: String,
+    room_id: String,
+    mut closed: watch::Receiver<bool>,
+) {
+    chat.add_peer(&other_id);
+
+    {
+        let chat = chat.clone();
+        let sender = channel.clone();
+        let room_id = room_id.clone();
+
+        channel.on_open(Box::new(move || {
+            let history = chat.history(&room_id);
+            let sender = sender.clone();
+
+            Box::pin(async move {
+                for packet in history {
+                    let text = match serde_json::to_string(&packet) {
+                        Ok(text) => text,
+                        Err(e) => {
+                            eprintln!("Failed to serialize chat message: {}", e);
+                            continue;
+                        }
+                    };
+
+                    if let Err(e) = sender.send_text(text).await {
+                        eprintln!("Failed to send chat history: {}", e);
+                        return;
+                    }
+                }
+            })
+        }));
+    }
+
+    {
+        let chat = chat.clone();
+        let other_id = other_id.clone();
+
+        channel.on_message(Box::new(move |message: DataChannelMessage| {
+            let text = String::from_utf8_lossy(&message.data);
+
+            match chat.receive(&other_id, &room_id, &text) {
+                Ok(Some(message)) => println!("\n\r{}", chat.format(&message)),
+                Ok(None) => (),
+                Err(e) => eprintln!("Dropping chat message from {}: {}", other_id, e),
+            }
+
+            Box::pin(async {})
+        }));
+    }
+
+    let mut outgoing = chat.subscribe();
+
+    tokio::spawn(async move {
+        loop {
+            let packet = tokio::select! {
+                packet = outgoing.recv() => packet,
+                _ = closed.wait_for(|closed| *closed) => break,
+            };
+
+            let packet = match packet {
+                Ok(packet) => packet,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    eprintln!("Dropped {} chat messages to {}", skipped, other_id);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+
+            if packet.origin.as_deref() == Some(other_id.as_str())
+                || channel.ready_state() != RTCDataChannelState::Open
+            {
+                continue;
+            }
+
+            if let Err(e) = channel.send_text(packet.text.as_str().to_owned()).await {
+                eprintln!("Failed to send chat message to {}: {}", other_id, e);
+            }
+        }
+
+        chat.remove_peer(&other_id);
+    });
+}
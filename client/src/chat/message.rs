@@ -0,0 +1,120 @@
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Milliseconds since the Unix epoch.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub id: String,
+    pub room_id: String,
+    pub sender: String,
+    pub sender_name: String,
+    pub timestamp: u64,
+    pub body: String,
+    /// Id of the message this one answers
+    pub reply_to: Option<String>,
+    /// Later versions of the body, oldest first
+    #[serde(default)]
+    pub edits: Vec<ChatEdit>,
+}
+
+impl ChatMessage {
+    pub fn new(
+        room_id: &str,
+        sender: &str,
+        sender_name: &str,
+        body: String,
+        reply_to: Option<String>,
+    ) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            room_id: room_id.to_owned(),
+            sender: sender.to_owned(),
+            sender_name: sender_name.to_owned(),
+            timestamp: now_millis(),
+            body,
+            reply_to,
+            edits: Vec::new(),
+        }
+    }
+
+    /// The body as of the latest edit.
+    pub fn current_body(&self) -> &str {
+        self.edits.last().map_or(&self.body, |edit| &edit.body)
+    }
+
+    /// The first characters of the id, enough to refer to a message.
+    pub fn short_id(&self) -> &str {
+        short_id(&self.id)
+    }
+}
+
+/// Ids from other peers aren't necessarily ASCII, so this cuts on a
+/// character boundary.
+pub fn short_id(id: &str) -> &str {
+    let end = id.char_indices().nth(6).map_or(id.len(), |(end, _)| end);
+    &id[..end]
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChatEdit {
+    pub id: String,
+    pub message_id: String,
+    pub sender: String,
+    pub timestamp: u64,
+    pub body: String,
+}
+
+impl ChatEdit {
+    pub fn new(message_id: &str, sender: &str, body: String) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            message_id: message_id.to_owned(),
+            sender: sender.to_owned(),
+            timestamp: now_millis(),
+            body,
+        }
+    }
+}
+
+/// What goes over the chat data channel. Each packet has its own id, so
+/// peers relaying it to each other can drop the copies.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ChatPacket {
+    Message(ChatMessage),
+    Edit(ChatEdit),
+}
+
+impl ChatPacket {
+    pub fn id(&self) -> &str {
+        match self {
+            ChatPacket::Message(message) => &message.id,
+            ChatPacket::Edit(edit) => &edit.id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_ids_are_six_characters() {
+        assert_eq!(short_id("3fa2c1d0-aaaa"), "3fa2c1");
+        assert_eq!(short_id("3fa"), "3fa");
+        assert_eq!(short_id(""), "");
+    }
+
+    #[test]
+    fn short_ids_cut_between_characters() {
+        assert_eq!(short_id("ééééééé"), "éééééé");
+        assert_eq!(short_id("a😀b😀c😀d"), "a😀b😀c😀");
+    }
+}
@@ -0,0 +1,452 @@
+use anyhow::{anyhow, Result};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+use message::{short_id, ChatEdit, ChatMessage, ChatPacket};
+
+pub mod channel;
+pub mod message;
+
+/// Messages kept in memory, across rooms.
+const MAX_HISTORY: usize = 500;
+/// Recent messages sent to a peer when its chat channel opens.
+const SYNC_MESSAGES: usize = 50;
+const OUTGOING_CAPACITY: usize = 64;
+/// Packet ids remembered to drop copies, a few per message kept.
+const MAX_SEEN: usize = 4 * MAX_HISTORY;
+/// Edits kept while waiting for their message to arrive.
+const MAX_PENDING_EDITS: usize = 64;
+
+/// A packet to pass on to every peer but the one it came from.
+#[derive(Clone)]
+pub struct Outgoing {
+    pub origin: Option<String>,
+    pub text: Arc<String>,
+}
+
+#[derive(Default)]
+struct ChatState {
+    seen: HashSet<String>,
+    /// `seen` in the order it was filled, to forget the oldest first
+    seen_order: VecDeque<String>,
+    messages: VecDeque<ChatMessage>,
+    /// Edits that overtook their message on another path through the mesh
+    pending_edits: VecDeque<ChatEdit>,
+    /// Chat channels open per peer, more than one while a connection is
+    /// being replaced
+    direct: HashMap<String, usize>,
+}
+
+impl ChatState {
+    /// Remembers a packet id, returning `false` if it was already known.
+    fn mark_seen(&mut self, id: &str) -> bool {
+        if !self.seen.insert(id.to_owned()) {
+            return false;
+        }
+
+        self.seen_order.push_back(id.to_owned());
+        if self.seen_order.len() > MAX_SEEN {
+            if let Some(oldest) = self.seen_order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+
+        true
+    }
+
+    /// Applies a packet we have not seen yet, returning the message it
+    /// created or changed.
+    fn apply(&mut self, packet: &ChatPacket) -> Option<ChatMessage> {
+        if !self.mark_seen(packet.id()) {
+            return None;
+        }
+
+        match packet {
+            ChatPacket::Message(message) => {
+                let mut message = message.clone();
+                for edit in &message.edits {
+                    self.mark_seen(&edit.id);
+                }
+
+                // Only the author can edit a message.
+                let (edits, pending): (VecDeque<_>, VecDeque<_>) = self
+                    .pending_edits
+                    .drain(..)
+                    .partition(|edit| edit.message_id == message.id);
+                self.pending_edits = pending;
+                message.edits.extend(
+                    edits
+                        .into_iter()
+                        .filter(|edit| edit.sender == message.sender),
+                );
+
+                self.messages.push_back(message.clone());
+
+                if self.messages.len() > MAX_HISTORY {
+                    self.messages.pop_front();
+                }
+
+                Some(message)
+            }
+            ChatPacket::Edit(edit) => {
+                let Some(message) = self
+                    .messages
+                    .iter_mut()
+                    .find(|message| message.id == edit.message_id)
+                else {
+                    self.pending_edits.push_back(edit.clone());
+                    if self.pending_edits.len() > MAX_PENDING_EDITS {
+                        self.pending_edits.pop_front();
+                    }
+                    return None;
+                };
+
+                if message.sender != edit.sender {
+                    return None;
+                }
+
+                message.edits.push(edit.clone());
+                Some(message.clone())
+            }
+        }
+    }
+
+    fn find(&self, room_id: &str, id: &str) -> Option<&ChatMessage> {
+        self.messages
+            .iter()
+            .rev()
+            .find(|message| message.room_id == room_id && message.id.starts_with(id))
+    }
+}
+
+/// Text chat of the room we are in. Every peer relays what it receives to
+/// the others, so messages still get through while some connections of the
+/// mesh are down, and each packet is only shown once.
+pub struct Chat {
+    user_id: String,
+    user_name: String,
+    state: Mutex<ChatState>,
+    outgoing: broadcast::Sender<Outgoing>,
+}
+
+impl Chat {
+    pub fn new(user_id: &str, user_name: &str) -> Arc<Self> {
+        Arc::new(Self {
+            user_id: user_id.to_owned(),
+            user_name: user_name.to_owned(),
+            state: Mutex::new(ChatState::default()),
+            outgoing: broadcast::channel(OUTGOING_CAPACITY).0,
+        })
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Outgoing> {
+        self.outgoing.subscribe()
+    }
+
+    fn broadcast(&self, packet: &ChatPacket, origin: Option<String>) -> Result<()> {
+        let text = serde_json::to_string(packet)?;
+
+        // Nobody listening just means no peer is connected yet.
+        let _ = self.outgoing.send(Outgoing {
+            origin,
+            text: Arc::new(text),
+        });
+
+        Ok(())
+    }
+
+    /// Sends a message to the room, as a reply when `reply_to` is the
+    /// (short) id of an earlier message.
+    pub fn send(&self, room_id: &str, body: String, reply_to: Option<&str>) -> Result<ChatMessage> {
+        let reply_to = match reply_to {
+            Some(id) => Some(
+                self.state
+                    .lock()
+                    .unwrap()
+                    .find(room_id, id)
+                    .ok_or_else(|| anyhow!("No message with id {}", id))?
+                    .id
+                    .clone(),
+            ),
+            None => None,
+        };
+
+        let message = ChatMessage::new(room_id, &self.user_id, &self.user_name, body, reply_to);
+        let packet = ChatPacket::Message(message.clone());
+
+        self.state.lock().unwrap().apply(&packet);
+        self.broadcast(&packet, None)?;
+
+        Ok(message)
+    }
+
+    /// Replaces the body of our last message in the room.
+    pub fn edit_last(&self, room_id: &str, body: String) -> Result<ChatMessage> {
+        let mut state = self.state.lock().unwrap();
+
+        let message_id = state
+            .messages
+            .iter()
+            .rev()
+            .find(|message| message.room_id == room_id && message.sender == self.user_id)
+            .ok_or_else(|| anyhow!("Nothing to edit yet"))?
+            .id
+            .clone();
+
+        let packet = ChatPacket::Edit(ChatEdit::new(&message_id, &self.user_id, body));
+        let message = state
+            .apply(&packet)
+            .ok_or_else(|| anyhow!("Failed to edit message"))?;
+        drop(state);
+
+        self.broadcast(&packet, None)?;
+
+        Ok(message)
+    }
+
+    /// Starts trusting only `peer` itself with its messages, now that we
+    /// have a channel to it.
+    pub fn add_peer(&self, peer: &str) {
+        *self
+            .state
+            .lock()
+            .unwrap()
+            .direct
+            .entry(peer.to_owned())
+            .or_default() += 1;
+    }
+
+    pub fn remove_peer(&self, peer: &str) {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(count) = state.direct.get_mut(peer) {
+            *count -= 1;
+            if *count == 0 {
+                state.direct.remove(peer);
+            }
+        }
+    }
+
+    /// Handles a packet from `from`, returning the message to show if it
+    /// is new.
+    ///
+    /// Packets are attributed by the channel they came on: a relayed one
+    /// is only taken for an author we have no channel to, so peers can't
+    /// post or edit in the name of someone we hear first-hand, or ours.
+    pub fn receive(&self, from: &str, room_id: &str, text: &str) -> Result<Option<ChatMessage>> {
+        let packet: ChatPacket = serde_json::from_str(text)?;
+
+        let author = match &packet {
+            ChatPacket::Message(message) => {
+                if message.room_id != room_id {
+                    return Ok(None);
+                }
+                &message.sender
+            }
+            ChatPacket::Edit(edit) => &edit.sender,
+        };
+
+        let mut state = self.state.lock().unwrap();
+
+        if author != from && (*author == self.user_id || state.direct.contains_key(author)) {
+            return Ok(None);
+        }
+
+        let Some(message) = state.apply(&packet) else {
+            return Ok(None);
+        };
+        drop(state);
+
+        self.broadcast(&packet, Some(from.to_owned()))?;
+
+        Ok(Some(message))
+    }
+
+    /// Recent messages of the room, with their edits, for a peer that just
+    /// connected.
+    pub fn history(&self, room_id: &str) -> Vec<ChatPacket> {
+        let state = self.state.lock().unwrap();
+
+        let messages: Vec<_> = state
+            .messages
+            .iter()
+            .filter(|message| message.room_id == room_id)
+            .collect();
+
+        messages[messages.len().saturating_sub(SYNC_MESSAGES)..]
+            .iter()
+            .flat_map(|message| {
+                let original = ChatMessage {
+                    edits: Vec::new(),
+                    ..(*message).clone()
+                };
+
+                std::iter::once(ChatPacket::Message(original))
+                    .chain(message.edits.iter().cloned().map(ChatPacket::Edit))
+            })
+            .collect()
+    }
+
+    /// One line per message, like `[14:02] #3fa2c1 alice: hi`.
+    pub fn format(&self, message: &ChatMessage) -> String {
+        let seconds = message.timestamp / 1000 % 86_400;
+        let mut line = format!(
+            "[{:02}:{:02}] #{} {}: {}",
+            seconds / 3600,
+            seconds % 3600 / 60,
+            message.short_id(),
+            message.sender_name,
+            message.current_body()
+        );
+
+        if !message.edits.is_empty() {
+            line.push_str(" (edited)");
+        }
+
+        if let Some(reply_to) = &message.reply_to {
+            let state = self.state.lock().unwrap();
+
+            match state
+                .messages
+                .iter()
+                .find(|message| &message.id == reply_to)
+            {
+                Some(original) => line.push_str(&format!(
+                    "\n\r    in reply to {}: {}",
+                    original.sender_name,
+                    original.current_body()
+                )),
+                None => line.push_str(&format!("\n\r    in reply to #{}", short_id(reply_to))),
+            }
+        }
+
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(packet: &ChatPacket) -> String {
+        serde_json::to_string(packet).unwrap()
+    }
+
+    #[test]
+    fn copies_are_shown_once() {
+        let chat = Chat::new("me", "Me");
+        let message = ChatMessage::new("room", "alice", "Alice", "hi".to_owned(), None);
+        let packet = text(&ChatPacket::Message(message));
+
+        assert!(chat.receive("alice", "room", &packet).unwrap().is_some());
+        assert!(chat.receive("bob", "room", &packet).unwrap().is_none());
+    }
+
+    #[test]
+    fn messages_of_other_rooms_are_ignored() {
+        let chat = Chat::new("me", "Me");
+        let message = ChatMessage::new("elsewhere", "alice", "Alice", "hi".to_owned(), None);
+
+        let shown = chat
+            .receive("alice", "room", &text(&ChatPacket::Message(message)))
+            .unwrap();
+        assert!(shown.is_none());
+        assert!(chat.history("elsewhere").is_empty());
+    }
+
+    #[test]
+    fn edits_wait_for_their_message() {
+        let chat = Chat::new("me", "Me");
+        let message = ChatMessage::new("room", "alice", "Alice", "hi".to_owned(), None);
+        let edit = ChatEdit::new(&message.id, "alice", "hello".to_owned());
+
+        let edit = text(&ChatPacket::Edit(edit));
+        assert!(chat.receive("alice", "room", &edit).unwrap().is_none());
+
+        let shown = chat
+            .receive("alice", "room", &text(&ChatPacket::Message(message)))
+            .unwrap()
+            .unwrap();
+        assert_eq!(shown.current_body(), "hello");
+
+        // The copy relayed by someone else is still a duplicate.
+        assert!(chat.receive("bob", "room", &edit).unwrap().is_none());
+    }
+
+    #[test]
+    fn only_the_author_edits() {
+        let chat = Chat::new("me", "Me");
+        let message = ChatMessage::new("room", "alice", "Alice", "hi".to_owned(), None);
+        let edit = ChatEdit::new(&message.id, "mallory", "pwned".to_owned());
+        chat.add_peer("alice");
+        chat.add_peer("mallory");
+
+        chat.receive("alice", "room", &text(&ChatPacket::Message(message)))
+            .unwrap();
+        assert!(chat
+            .receive("mallory", "room", &text(&ChatPacket::Edit(edit)))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn relays_cant_speak_for_direct_peers() {
+        let chat = Chat::new("me", "Me");
+        chat.add_peer("alice");
+        chat.add_peer("mallory");
+
+        let forged = ChatMessage::new("room", "alice", "Alice", "hi".to_owned(), None);
+        let forged = text(&ChatPacket::Message(forged));
+        assert!(chat.receive("mallory", "room", &forged).unwrap().is_none());
+
+        let ours = ChatMessage::new("room", "me", "Me", "hi".to_owned(), None);
+        let ours = text(&ChatPacket::Message(ours));
+        assert!(chat.receive("mallory", "room", &ours).unwrap().is_none());
+
+        // Once alice is gone, copies relayed by others are all we get.
+        chat.remove_peer("alice");
+        assert!(chat.receive("mallory", "room", &forged).unwrap().is_some());
+    }
+
+    #[test]
+    fn history_replays_recent_messages_then_their_edits() {
+        let chat = Chat::new("me", "Me");
+
+        for i in 0..SYNC_MESSAGES + 5 {
+            chat.send("room", i.to_string(), None).unwrap();
+        }
+        chat.send("other", "elsewhere".to_owned(), None).unwrap();
+        chat.edit_last("room", "edited".to_owned()).unwrap();
+
+        let history = chat.history("room");
+        assert_eq!(history.len(), SYNC_MESSAGES + 1);
+
+        let ChatPacket::Message(first) = &history[0] else {
+            panic!("history starts with an edit");
+        };
+        assert_eq!(first.body, "5");
+
+        let [.., ChatPacket::Message(last), ChatPacket::Edit(edit)] = history.as_slice() else {
+            panic!("the edit doesn't follow its message");
+        };
+        assert_eq!(last.body, (SYNC_MESSAGES + 4).to_string());
+        assert!(last.edits.is_empty());
+        assert_eq!(edit.message_id, last.id);
+        assert_eq!(edit.body, "edited");
+    }
+
+    #[test]
+    fn seen_ids_are_bounded() {
+        let mut state = ChatState::default();
+
+        for _ in 0..MAX_SEEN + 10 {
+            let message = ChatMessage::new("room", "alice", "Alice", "hi".to_owned(), None);
+            state.apply(&ChatPacket::Message(message));
+        }
+
+        assert_eq!(state.seen.len(), MAX_SEEN);
+        assert_eq!(state.seen_order.len(), MAX_SEEN);
+        assert_eq!(state.messages.len(), MAX_HISTORY);
+    }
+}
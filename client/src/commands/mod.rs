@@ -1,3 +1,5 @@
+use crate::audio::mode::AudioMode;
+use crate::config::IceServerConfig;
 use crate::rooms::Room;
 use serde::{Deserialize, Serialize};
 
@@ -25,6 +27,12 @@ pub enum ClientCommand {
         String, // room_id
         String, // candidate
     ),
+    SendAudioMode(
+        String, // user_id
+        String, // room_id
+        AudioMode,
+    ),
+    RefreshIceServers,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -48,6 +56,15 @@ pub enum ServerCommand {
         String, // room_id
         String, // candidate
     ),
+    IncomingAudioMode(
+        String, // user_id
+        String, // room_id
+        AudioMode,
+    ),
+    IceServers(
+        Vec<IceServerConfig>,
+        u64, // ttl in seconds
+    ),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -25,11 +25,282 @@ impl Default for UserCapabilities {
     }
 }
 
+fn default_clip_volume() -> f32 {
+    1.0
+}
+
+/// A short audio file played into the outgoing stream when `key` is pressed
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SoundboardClip {
+    pub key: char,
+    pub path: PathBuf,
+    /// Clip volume, from 0.0 to 1.0
+    #[serde(default = "default_clip_volume")]
+    pub volume: f32,
+}
+
+/// Audio pipeline settings
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AudioConfig {
+    /// Opus bitrate in voice mode, in bits per second
+    pub voice_bitrate: i32,
+    /// Opus bitrate in music mode, in bits per second (128-256 kbps)
+    pub music_bitrate: i32,
+    /// RMS level below which voice mode treats a frame as silence
+    pub noise_gate_threshold: f32,
+    /// Play our own processed microphone back locally
+    pub sidetone: bool,
+    /// Sidetone volume, from 0.0 to 1.0
+    pub sidetone_volume: f32,
+    /// Seconds of call audio kept for instant replay (30-120)
+    pub replay_seconds: u32,
+    /// Also keep a separate replay of each participant
+    pub replay_per_participant: bool,
+    /// Where replays are saved, defaults to the app data directory
+    pub replay_dir: Option<PathBuf>,
+    /// Soundboard clips, bound to keys not used by the call controls
+    pub soundboard: Vec<SoundboardClip>,
+    /// Encrypt Opus frames end to end, so relays only see ciphertext.
+    /// Everyone in the room needs it on to hear each other.
+    pub frame_encryption: bool,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        Self {
+            voice_bitrate: 32_000,
+            music_bitrate: 192_000,
+            noise_gate_threshold: 0.01,
+            sidetone: false,
+            sidetone_volume: 0.3,
+            replay_seconds: 60,
+            replay_per_participant: false,
+            replay_dir: None,
+            soundboard: Vec::new(),
+            frame_encryption: false,
+        }
+    }
+}
+
+/// A STUN or TURN server. Only `stun:` and `turn:` over UDP are used, TCP
+/// and TLS relays (`?transport=tcp`, `turns:`) are skipped with a warning
+/// since ICE here only gathers over UDP.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct IceServerConfig {
+    pub urls: Vec<String>,
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub credential: String,
+}
+
+/// Inclusive range of local UDP ports used for ICE
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct PortRange {
+    pub min: u16,
+    pub max: u16,
+}
+
+/// How `.local` mDNS names are used for host candidates
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub enum MdnsMode {
+    Disabled,
+    /// Resolve peers' `.local` candidates, but share our IP addresses
+    #[default]
+    QueryOnly,
+    /// Also hide our host IP addresses behind `.local` names
+    QueryAndGather,
+}
+
+/// Peer connection network settings
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct NetworkConfig {
+    /// Used along with any TURN servers handed out by the signaling server
+    pub ice_servers: Vec<IceServerConfig>,
+    /// Only connect through TURN relays, so peers never see our IP address
+    pub relay_only: bool,
+    /// Local UDP ports to gather candidates on, any port when unset
+    pub udp_ports: Option<PortRange>,
+    /// Only use interfaces whose name starts with one of these, all when empty
+    pub interfaces: Vec<String>,
+    /// Skip interfaces whose name starts with one of these, e.g. `docker`
+    pub exclude_interfaces: Vec<String>,
+    pub ipv4: bool,
+    pub ipv6: bool,
+    pub mdns: MdnsMode,
+    /// Public IPs to advertise instead of the local ones, for hosts behind
+    /// a known 1:1 NAT
+    pub nat_1to1_ips: Vec<String>,
+    /// Most we send to all peers together, in bits per second, unlimited
+    /// when unset
+    pub upload_bitrate: Option<u32>,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            ice_servers: vec![IceServerConfig {
+                urls: vec![
+                    "stun:localhost:3479".to_owned(),
+                    "stun:localhost:3478".to_owned(),
+                ],
+                username: String::new(),
+                credential: String::new(),
+            }],
+            relay_only: false,
+            udp_ports: None,
+            interfaces: Vec::new(),
+            exclude_interfaces: Vec::new(),
+            ipv4: true,
+            ipv6: true,
+            mdns: MdnsMode::default(),
+            nat_1to1_ips: Vec::new(),
+            upload_bitrate: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TransferConfig {
+    /// Where received files are saved, defaults to the downloads directory
+    pub download_dir: Option<PathBuf>,
+}
+
+/// Where outgoing video comes from
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub enum VideoSource {
+    #[default]
+    None,
+    /// A VP8 IVF or Annex B H.264 file, played in a loop
+    File(PathBuf),
+    /// Moving color bars, encoded as H.264. There is no VP8 encoder in
+    /// the client, so only peers taking H.264 see it.
+    TestPattern,
+}
+
+/// How received video is drawn in the terminal
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum RenderMode {
+    Off,
+    /// Kitty or sixel graphics when the terminal looks like it has them,
+    /// half blocks otherwise
+    #[default]
+    Auto,
+    HalfBlocks,
+    Sixel,
+    Kitty,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct VideoConfig {
+    pub source: VideoSource,
+    /// Size, frame rate and bitrate of the test pattern. The bitrate is
+    /// lowered to what the upload budget leaves after audio.
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+    pub bitrate: u32,
+    /// Save received video here, as IVF or raw H.264 depending on the codec
+    pub record_dir: Option<PathBuf>,
+    pub render: RenderMode,
+    /// Frame rate cap of the terminal view, lowered for large panes
+    pub render_fps: u32,
+}
+
+impl Default for VideoConfig {
+    fn default() -> Self {
+        Self {
+            source: VideoSource::None,
+            width: 640,
+            height: 360,
+            fps: 30,
+            bitrate: 500_000,
+            record_dir: None,
+            render: RenderMode::Auto,
+            render_fps: 15,
+        }
+    }
+}
+
+/// Video codecs that can be offered
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum VideoCodec {
+    VP8,
+    H264,
+}
+
+/// Codecs offered to peers, in order of preference. The Opus settings are
+/// what we ask peers to send us.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CodecConfig {
+    pub opus_stereo: bool,
+    pub opus_fec: bool,
+    pub opus_dtx: bool,
+    /// Highest Opus bitrate we want to receive, in bits per second
+    pub opus_max_average_bitrate: Option<u32>,
+    /// Also accept redundant audio (RFC 2198) wrapping Opus
+    pub red: bool,
+    /// Only H.264 can be shown in the terminal, VP8 video is only recorded
+    pub video: Vec<VideoCodec>,
+}
+
+impl Default for CodecConfig {
+    fn default() -> Self {
+        Self {
+            opus_stereo: true,
+            opus_fec: true,
+            opus_dtx: true,
+            opus_max_average_bitrate: None,
+            red: false,
+            video: vec![VideoCodec::H264, VideoCodec::VP8],
+        }
+    }
+}
+
+/// Connection statistics settings
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct StatsConfig {
+    /// Seconds between two collections
+    pub interval_seconds: u64,
+    /// Print every collection
+    pub log: bool,
+    /// Serve Prometheus metrics on this address, e.g. `127.0.0.1:9464`
+    pub metrics_address: Option<String>,
+}
+
+impl Default for StatsConfig {
+    fn default() -> Self {
+        Self {
+            interval_seconds: 2,
+            log: false,
+            metrics_address: None,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct UserConfig {
     pub name: String,
     pub id: String,
     pub capabilities: UserCapabilities,
+    #[serde(default)]
+    pub audio: AudioConfig,
+    #[serde(default)]
+    pub network: NetworkConfig,
+    #[serde(default)]
+    pub stats: StatsConfig,
+    #[serde(default)]
+    pub codecs: CodecConfig,
+    #[serde(default)]
+    pub transfers: TransferConfig,
+    #[serde(default)]
+    pub video: VideoConfig,
 }
 
 impl From<&str> for UserConfig {
@@ -38,11 +309,17 @@ impl From<&str> for UserConfig {
             name: name.to_string(),
             id: Uuid::new_v4().to_string(),
             capabilities: UserCapabilities::default(),
+            audio: AudioConfig::default(),
+            network: NetworkConfig::default(),
+            stats: StatsConfig::default(),
+            codecs: CodecConfig::default(),
+            transfers: TransferConfig::default(),
+            video: VideoConfig::default(),
         }
     }
 }
 
-pub fn get_config_path() -> Result<PathBuf> {
+pub fn get_app_dir() -> Result<PathBuf> {
     let app_data_dir = match dirs::data_dir() {
         Some(dir) => dir,
         None => {
@@ -54,7 +331,11 @@ pub fn get_config_path() -> Result<PathBuf> {
 
     fs::create_dir_all(&app_dir).context("Couldn't create %APPDATA%/nivalis directory.")?;
 
-    Ok(app_dir.join("config.json"))
+    Ok(app_dir)
+}
+
+pub fn get_config_path() -> Result<PathBuf> {
+    Ok(get_app_dir()?.join("config.json"))
 }
 
 pub fn create_config(username: &str) -> Result<UserConfig> {
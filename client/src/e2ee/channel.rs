@@ -0,0 +1,78 @@
+use std::sync::Arc;
+use tokio::sync::{broadcast, watch};
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use webrtc::data_channel::data_channel_state::RTCDataChannelState;
+use webrtc::data_channel::RTCDataChannel;
+
+use super::{FrameCrypto, KeyMessage};
+
+/// Wires a peer's keys channel to the frame encryption: a new key is made
+/// for the room when the peer connects and again once it is gone, and the
+/// peer's own keys are stored as they arrive.
+pub fn attach_frame_keys(
+    crypto: Arc<FrameCrypto>,
+    channel: Arc<RTCDataChannel>,
+    other_id: String,
+    room_id: String,
+    mut closed: watch::Receiver<bool>,
+) {
+    {
+        let crypto = crypto.clone();
+        let room_id = room_id.clone();
+
+        channel.on_open(Box::new(move || {
+            crypto.rotate(&room_id);
+            Box::pin(async {})
+        }));
+    }
+
+    {
+        let crypto = crypto.clone();
+        let other_id = other_id.clone();
+        let room_id = room_id.clone();
+
+        channel.on_message(Box::new(move |message: DataChannelMessage| {
+            let result = serde_json::from_slice::<KeyMessage>(&message.data)
+                .map_err(Into::into)
+                .and_then(|message| crypto.receive_key(&other_id, &room_id, message));
+
+            if let Err(e) = result {
+                eprintln!("Dropping media key from {}: {}", other_id, e);
+            }
+
+            Box::pin(async {})
+        }));
+    }
+
+    let mut outgoing = crypto.subscribe();
+
+    tokio::spawn(async move {
+        loop {
+            let message = tokio::select! {
+                message = outgoing.recv() => message,
+                _ = closed.wait_for(|closed| *closed) => break,
+            };
+
+            let message = match message {
+                Ok(message) => message,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    eprintln!("Dropped {} media keys to {}", skipped, other_id);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+
+            if message.room_id != room_id || channel.ready_state() != RTCDataChannelState::Open {
+                continue;
+            }
+
+            if let Err(e) = channel.send_text(message.text.as_str().to_owned()).await {
+                eprintln!("Failed to send media key to {}: {}", other_id, e);
+            }
+        }
+
+        // The new key never reaches the peer that left.
+        crypto.peer_left(&other_id);
+        crypto.rotate_now(&room_id);
+    });
+}
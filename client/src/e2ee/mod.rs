@@ -0,0 +1,342 @@
+use anyhow::{anyhow, bail, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+use sframe::{parse_header, FrameKey};
+
+pub mod channel;
+pub mod sframe;
+
+/// How long a key for a new member is handed out before we encrypt with
+/// it, so peers have it by the time the first frame using it arrives.
+const KEY_ACTIVATION_DELAY: Duration = Duration::from_secs(1);
+/// Keys kept per sender, older ones still open frames sent around a
+/// rotation.
+const RECEIVER_KEYS: usize = 3;
+const OUTGOING_CAPACITY: usize = 16;
+
+/// A sender key handed to the other members of a room.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeyMessage {
+    pub room_id: String,
+    pub kid: u64,
+    pub key: String,
+}
+
+/// A key message for every peer in `room_id`.
+#[derive(Clone)]
+pub struct Outgoing {
+    pub room_id: String,
+    pub text: Arc<String>,
+}
+
+struct SenderKey {
+    room_id: String,
+    key: FrameKey,
+    counter: u64,
+}
+
+#[derive(Default)]
+struct SenderState {
+    current: Option<SenderKey>,
+    /// Handed out, used once the activation delay is over.
+    pending: Option<SenderKey>,
+    next_kid: u64,
+}
+
+/// End-to-end encryption of Opus frames, on top of DTLS-SRTP which only
+/// protects each hop when media goes through a relay or forwarder.
+///
+/// Every client encrypts with its own sender key, SFrame style, and hands
+/// it to the room over the keys data channel. The key is replaced whenever
+/// someone joins or leaves, so members only hear what was said while they
+/// were in the room.
+pub struct FrameCrypto {
+    enabled: bool,
+    /// The room we are in, keys for any other room are never used
+    room_id: Mutex<Option<String>>,
+    sender: Mutex<SenderState>,
+    receivers: Mutex<HashMap<String, VecDeque<FrameKey>>>,
+    outgoing: broadcast::Sender<Outgoing>,
+}
+
+impl FrameCrypto {
+    pub fn new(enabled: bool) -> Arc<Self> {
+        Arc::new(Self {
+            enabled,
+            room_id: Mutex::new(None),
+            sender: Mutex::new(SenderState::default()),
+            receivers: Mutex::new(HashMap::new()),
+            outgoing: broadcast::channel(OUTGOING_CAPACITY).0,
+        })
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Outgoing> {
+        self.outgoing.subscribe()
+    }
+
+    /// Sets the room we are in, dropping the sender keys of the one we
+    /// were in before.
+    pub fn set_room(&self, room_id: Option<&str>) {
+        let mut current = self.room_id.lock().unwrap();
+        if current.as_deref() == room_id {
+            return;
+        }
+
+        *current = room_id.map(str::to_owned);
+
+        let mut sender = self.sender.lock().unwrap();
+        sender.current = None;
+        sender.pending = None;
+    }
+
+    fn in_room(&self, room_id: &str) -> bool {
+        self.room_id.lock().unwrap().as_deref() == Some(room_id)
+    }
+
+    /// Makes a new sender key for a member joining `room_id` and hands it
+    /// out. The first key is used right away, later ones after the
+    /// activation delay.
+    pub fn rotate(self: &Arc<Self>, room_id: &str) {
+        self.new_key(room_id, false);
+    }
+
+    /// Makes a new sender key after a member left `room_id` and uses it
+    /// right away, so the member can't hear anything more with the old
+    /// one. The others drop frames until the key reaches them.
+    pub fn rotate_now(self: &Arc<Self>, room_id: &str) {
+        self.new_key(room_id, true);
+    }
+
+    fn new_key(self: &Arc<Self>, room_id: &str, immediate: bool) {
+        // Sessions of a room we left may still be winding down.
+        if !self.enabled || !self.in_room(room_id) {
+            return;
+        }
+
+        let base_key: [u8; 32] = rand::random();
+
+        let kid = {
+            let mut sender = self.sender.lock().unwrap();
+            let kid = sender.next_kid;
+            sender.next_kid += 1;
+
+            let key = SenderKey {
+                room_id: room_id.to_owned(),
+                key: FrameKey::derive(&base_key, kid, room_id),
+                counter: 0,
+            };
+
+            if immediate || sender.current.is_none() {
+                sender.current = Some(key);
+                sender.pending = None;
+            } else {
+                sender.pending = Some(key);
+            }
+            kid
+        };
+
+        let message = KeyMessage {
+            room_id: room_id.to_owned(),
+            kid,
+            key: BASE64.encode(base_key),
+        };
+
+        match serde_json::to_string(&message) {
+            Ok(text) => {
+                let _ = self.outgoing.send(Outgoing {
+                    room_id: room_id.to_owned(),
+                    text: Arc::new(text),
+                });
+            }
+            Err(e) => eprintln!("Failed to serialize media key: {}", e),
+        }
+
+        if immediate {
+            return;
+        }
+
+        let crypto = Arc::downgrade(self);
+        tokio::spawn(async move {
+            tokio::time::sleep(KEY_ACTIVATION_DELAY).await;
+
+            if let Some(crypto) = crypto.upgrade() {
+                crypto.activate(kid);
+            }
+        });
+    }
+
+    /// Switches to key `kid` unless a newer one replaced it meanwhile.
+    fn activate(&self, kid: u64) {
+        let mut sender = self.sender.lock().unwrap();
+
+        if sender
+            .pending
+            .as_ref()
+            .is_some_and(|key| key.key.kid == kid)
+        {
+            sender.current = sender.pending.take();
+        }
+    }
+
+    /// Stores a key handed out by `from`, a member of `room_id`.
+    pub fn receive_key(&self, from: &str, room_id: &str, message: KeyMessage) -> Result<()> {
+        if message.room_id != room_id {
+            bail!("key is for another room");
+        }
+
+        let base_key = BASE64.decode(&message.key)?;
+        let key = FrameKey::derive(&base_key, message.kid, room_id);
+
+        let mut receivers = self.receivers.lock().unwrap();
+        let keys = receivers.entry(from.to_owned()).or_default();
+        keys.retain(|known| known.kid != message.kid);
+        keys.push_back(key);
+        if keys.len() > RECEIVER_KEYS {
+            keys.pop_front();
+        }
+
+        Ok(())
+    }
+
+    /// Drops the keys of a peer that left.
+    pub fn peer_left(&self, from: &str) {
+        self.receivers.lock().unwrap().remove(from);
+    }
+
+    /// Encrypts an outgoing Opus frame. Returns `None` while encryption is
+    /// on but there is no key for our room yet, the frame must not go out
+    /// in clear.
+    pub fn encrypt(&self, frame: Bytes) -> Option<Bytes> {
+        if !self.enabled {
+            return Some(frame);
+        }
+
+        let room_id = self.room_id.lock().unwrap().clone()?;
+        let mut sender = self.sender.lock().unwrap();
+        let sender = sender
+            .current
+            .as_mut()
+            .filter(|sender| sender.room_id == room_id)?;
+
+        let counter = sender.counter;
+        sender.counter += 1;
+
+        match sender.key.protect(counter, &frame) {
+            Ok(protected) => Some(Bytes::from(protected)),
+            Err(e) => {
+                eprintln!("Failed to encrypt audio frame: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Decrypts a frame from `from`, or passes it through when encryption
+    /// is off.
+    pub fn decrypt<'a>(&self, from: &str, frame: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+        if !self.enabled {
+            return Ok(Cow::Borrowed(frame));
+        }
+
+        let (kid, counter, header, ciphertext) = parse_header(frame)?;
+
+        let receivers = self.receivers.lock().unwrap();
+        let key = receivers
+            .get(from)
+            .and_then(|keys| keys.iter().find(|key| key.kid == kid))
+            .ok_or_else(|| anyhow!("no key {} from {}", kid, from))?;
+
+        Ok(Cow::Owned(key.unprotect(counter, header, ciphertext)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands the keys `crypto` sent out so far to `to`, as coming from
+    /// `from`.
+    fn deliver(
+        keys: &mut broadcast::Receiver<Outgoing>,
+        to: &FrameCrypto,
+        from: &str,
+        room_id: &str,
+    ) {
+        while let Ok(outgoing) = keys.try_recv() {
+            let message = serde_json::from_str(&outgoing.text).unwrap();
+            to.receive_key(from, room_id, message).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_round_trip_between_members() {
+        let alice = FrameCrypto::new(true);
+        let bob = FrameCrypto::new(true);
+        let mut keys = alice.subscribe();
+
+        alice.set_room(Some("room"));
+        assert!(alice.encrypt(Bytes::from_static(b"frame")).is_none());
+
+        alice.rotate("room");
+        deliver(&mut keys, &bob, "alice", "room");
+
+        let frame = alice.encrypt(Bytes::from_static(b"frame")).unwrap();
+        assert_ne!(&frame[..], b"frame");
+        assert_eq!(&bob.decrypt("alice", &frame).unwrap()[..], b"frame");
+        assert!(bob.decrypt("mallory", &frame).is_err());
+    }
+
+    #[tokio::test]
+    async fn rooms_we_left_get_no_keys() {
+        let crypto = FrameCrypto::new(true);
+
+        crypto.set_room(Some("old"));
+        crypto.rotate("old");
+        crypto.set_room(Some("new"));
+        assert!(crypto.encrypt(Bytes::from_static(b"frame")).is_none());
+
+        // A session of the old room closing late.
+        crypto.rotate_now("old");
+        assert!(crypto.encrypt(Bytes::from_static(b"frame")).is_none());
+
+        crypto.set_room(None);
+        crypto.rotate("new");
+        assert!(crypto.encrypt(Bytes::from_static(b"frame")).is_none());
+    }
+
+    #[tokio::test]
+    async fn keys_after_a_leave_are_used_at_once() {
+        let alice = FrameCrypto::new(true);
+        let bob = FrameCrypto::new(true);
+        let mut keys = alice.subscribe();
+
+        alice.set_room(Some("room"));
+        alice.rotate("room");
+        alice.rotate_now("room");
+        deliver(&mut keys, &bob, "alice", "room");
+
+        let frame = alice.encrypt(Bytes::from_static(b"frame")).unwrap();
+        let (kid, _, _, _) = parse_header(&frame).unwrap();
+        assert_eq!(kid, 1);
+        assert!(bob.decrypt("alice", &frame).is_ok());
+    }
+
+    #[tokio::test]
+    async fn keys_for_a_new_member_wait() {
+        let crypto = FrameCrypto::new(true);
+
+        crypto.set_room(Some("room"));
+        crypto.rotate("room");
+        crypto.rotate("room");
+
+        let frame = crypto.encrypt(Bytes::from_static(b"frame")).unwrap();
+        assert_eq!(parse_header(&frame).unwrap().0, 0);
+    }
+}
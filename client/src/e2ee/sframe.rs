@@ -0,0 +1,213 @@
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes128Gcm, Key, Nonce};
+use anyhow::{anyhow, bail, Result};
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+/// AES_128_GCM_SHA256_128 from RFC 9605.
+const CIPHER_SUITE: u16 = 0x0004;
+const KEY_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+
+/// One sender key, ready to protect or open frames.
+pub struct FrameKey {
+    pub kid: u64,
+    cipher: Aes128Gcm,
+    salt: [u8; NONCE_SIZE],
+}
+
+impl FrameKey {
+    /// Derives the key and salt for `kid` as RFC 9605 does, except that the
+    /// extract step is salted with the room id, so a key only works in the
+    /// room it was handed out for.
+    pub fn derive(base_key: &[u8], kid: u64, room_id: &str) -> Self {
+        let hkdf = Hkdf::<Sha256>::new(Some(room_id.as_bytes()), base_key);
+
+        let label = |name: &str| {
+            let mut info = format!("SFrame 1.0 Secret {} ", name).into_bytes();
+            info.extend_from_slice(&kid.to_be_bytes());
+            info.extend_from_slice(&CIPHER_SUITE.to_be_bytes());
+            info
+        };
+
+        let mut key = [0u8; KEY_SIZE];
+        let mut salt = [0u8; NONCE_SIZE];
+        // Both lengths are far below what HKDF-SHA256 can expand to.
+        hkdf.expand(&label("key"), &mut key).unwrap();
+        hkdf.expand(&label("salt"), &mut salt).unwrap();
+
+        Self {
+            kid,
+            cipher: Aes128Gcm::new(Key::<Aes128Gcm>::from_slice(&key)),
+            salt,
+        }
+    }
+
+    fn nonce(&self, counter: u64) -> [u8; NONCE_SIZE] {
+        let mut nonce = self.salt;
+        for (byte, counter) in nonce[NONCE_SIZE - 8..]
+            .iter_mut()
+            .zip(counter.to_be_bytes())
+        {
+            *byte ^= counter;
+        }
+        nonce
+    }
+
+    /// The SFrame header followed by the encrypted frame and its tag. The
+    /// header is authenticated along with the frame.
+    pub fn protect(&self, counter: u64, frame: &[u8]) -> Result<Vec<u8>> {
+        let mut out = encode_header(self.kid, counter);
+
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                Nonce::from_slice(&self.nonce(counter)),
+                Payload {
+                    msg: frame,
+                    aad: &out,
+                },
+            )
+            .map_err(|_| anyhow!("encryption failed"))?;
+
+        out.extend_from_slice(&ciphertext);
+        Ok(out)
+    }
+
+    /// Opens a frame whose header was read with `parse_header`.
+    pub fn unprotect(&self, counter: u64, header: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+        self.cipher
+            .decrypt(
+                Nonce::from_slice(&self.nonce(counter)),
+                Payload {
+                    msg: ciphertext,
+                    aad: header,
+                },
+            )
+            .map_err(|_| anyhow!("frame failed authentication"))
+    }
+}
+
+/// Values below 8 fit in the config byte, larger ones follow it in as few
+/// bytes as they need.
+fn encode_value(value: u64) -> (u8, Vec<u8>) {
+    if value < 8 {
+        return (value as u8, Vec::new());
+    }
+
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|byte| **byte == 0).count();
+    let length = bytes.len() - skip;
+
+    (0b1000 | (length - 1) as u8, bytes[skip..].to_vec())
+}
+
+/// `X|K|Y|C` config byte, then the key id and counter when extended.
+fn encode_header(kid: u64, counter: u64) -> Vec<u8> {
+    let (kid_bits, kid_bytes) = encode_value(kid);
+    let (counter_bits, counter_bytes) = encode_value(counter);
+
+    let mut header = vec![kid_bits << 4 | counter_bits];
+    header.extend(kid_bytes);
+    header.extend(counter_bytes);
+    header
+}
+
+fn decode_value(bits: u8, data: &[u8]) -> Result<(u64, usize)> {
+    if bits & 0b1000 == 0 {
+        return Ok(((bits & 0b0111) as u64, 0));
+    }
+
+    let length = (bits & 0b0111) as usize + 1;
+    if data.len() < length {
+        bail!("truncated SFrame header");
+    }
+
+    let value = data[..length]
+        .iter()
+        .fold(0u64, |value, byte| value << 8 | *byte as u64);
+    Ok((value, length))
+}
+
+/// Splits a protected frame into key id, counter, header and ciphertext.
+pub fn parse_header(frame: &[u8]) -> Result<(u64, u64, &[u8], &[u8])> {
+    let Some(config) = frame.first() else {
+        bail!("empty frame");
+    };
+
+    let (kid, kid_length) = decode_value(config >> 4, &frame[1..])?;
+    let (counter, counter_length) = decode_value(config & 0x0f, &frame[1 + kid_length..])?;
+
+    let (header, ciphertext) = frame.split_at(1 + kid_length + counter_length);
+    Ok((kid, counter, header, ciphertext))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_values_fit_in_the_config_byte() {
+        let header = encode_header(3, 5);
+        assert_eq!(header, [0x35]);
+
+        let (kid, counter, parsed, ciphertext) = parse_header(&[0x35, 0xaa]).unwrap();
+        assert_eq!((kid, counter), (3, 5));
+        assert_eq!(parsed, [0x35]);
+        assert_eq!(ciphertext, [0xaa]);
+    }
+
+    #[test]
+    fn large_values_are_extended() {
+        let mut frame = encode_header(0x1234, 0x0102_0304_0506);
+        assert_eq!(frame[0], 0x9d);
+        frame.push(0xaa);
+
+        let (kid, counter, header, ciphertext) = parse_header(&frame).unwrap();
+        assert_eq!((kid, counter), (0x1234, 0x0102_0304_0506));
+        assert_eq!(header.len(), 1 + 2 + 6);
+        assert_eq!(ciphertext, [0xaa]);
+    }
+
+    #[test]
+    fn truncated_headers_are_rejected() {
+        assert!(parse_header(&[]).is_err());
+        assert!(parse_header(&[0x90]).is_err());
+        assert!(parse_header(&[0x19, 0x01]).is_err());
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let key = FrameKey::derive(b"base key", 9, "room");
+        let protected = key.protect(1000, b"opus frame").unwrap();
+
+        let (kid, counter, header, ciphertext) = parse_header(&protected).unwrap();
+        assert_eq!((kid, counter), (9, 1000));
+        assert_eq!(
+            key.unprotect(counter, header, ciphertext).unwrap(),
+            b"opus frame"
+        );
+    }
+
+    #[test]
+    fn tampered_frames_fail() {
+        let key = FrameKey::derive(b"base key", 1, "room");
+        let mut protected = key.protect(7, b"opus frame").unwrap();
+
+        // A different counter in the header changes the nonce and the
+        // authenticated data.
+        protected[0] = 0x16;
+        let (_, counter, header, ciphertext) = parse_header(&protected).unwrap();
+        assert!(key.unprotect(counter, header, ciphertext).is_err());
+    }
+
+    #[test]
+    fn keys_only_work_in_their_room() {
+        let key = FrameKey::derive(b"base key", 1, "room");
+        let elsewhere = FrameKey::derive(b"base key", 1, "other room");
+
+        let protected = key.protect(0, b"opus frame").unwrap();
+        let (_, counter, header, ciphertext) = parse_header(&protected).unwrap();
+        assert!(elsewhere.unprotect(counter, header, ciphertext).is_err());
+    }
+}
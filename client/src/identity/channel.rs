@@ -0,0 +1,96 @@
+use std::sync::{Arc, Weak};
+use tokio::sync::watch;
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::peer_connection::RTCPeerConnection;
+
+use super::{certificate_fingerprint, Identity, IdentityProof};
+use crate::peer::session::fingerprint;
+
+/// Sends our proof on a peer's identity channel once it opens, and checks
+/// the one the peer sends back against its DTLS certificate. The channel
+/// runs over that same DTLS connection, so by then the certificates are
+/// known on both sides.
+pub fn attach_identity(
+    identity: Arc<Identity>,
+    channel: Arc<RTCDataChannel>,
+    peer_connection: Weak<RTCPeerConnection>,
+    other_id: String,
+    mut closed: watch::Receiver<bool>,
+) {
+    {
+        let identity = identity.clone();
+        let sender = channel.clone();
+        let peer_connection = peer_connection.clone();
+        let other_id = other_id.clone();
+
+        channel.on_open(Box::new(move || {
+            Box::pin(async move {
+                let Some(peer_connection) = peer_connection.upgrade() else {
+                    return;
+                };
+
+                let Some(description) = peer_connection.local_description().await else {
+                    return;
+                };
+
+                let Some(local_fingerprint) = fingerprint(&description.sdp) else {
+                    eprintln!("No DTLS fingerprint in our description");
+                    return;
+                };
+
+                let proof = identity.prove(&other_id, local_fingerprint);
+
+                let text = match serde_json::to_string(&proof) {
+                    Ok(text) => text,
+                    Err(e) => {
+                        eprintln!("Failed to serialize identity proof: {}", e);
+                        return;
+                    }
+                };
+
+                if let Err(e) = sender.send_text(text).await {
+                    eprintln!("Failed to send identity proof to {}: {}", other_id, e);
+                }
+            })
+        }));
+    }
+
+    {
+        let identity = identity.clone();
+        let other_id = other_id.clone();
+
+        channel.on_message(Box::new(move |message: DataChannelMessage| {
+            let identity = identity.clone();
+            let peer_connection = peer_connection.clone();
+            let other_id = other_id.clone();
+
+            Box::pin(async move {
+                let proof: IdentityProof = match serde_json::from_slice(&message.data) {
+                    Ok(proof) => proof,
+                    Err(e) => {
+                        eprintln!("Dropping identity proof from {}: {}", other_id, e);
+                        return;
+                    }
+                };
+
+                let Some(peer_connection) = peer_connection.upgrade() else {
+                    return;
+                };
+
+                let certificate = peer_connection
+                    .sctp()
+                    .transport()
+                    .get_remote_certificate()
+                    .await;
+
+                identity.check(&other_id, &proof, &certificate_fingerprint(&certificate));
+            })
+        }));
+    }
+
+    tokio::spawn(async move {
+        let _ = closed.wait_for(|closed| *closed).await;
+        identity.peer_left(&other_id);
+    });
+}
@@ -0,0 +1,479 @@
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::config::get_app_dir;
+
+pub mod channel;
+
+/// Signed along with the ids and fingerprint, so a proof can't be mistaken
+/// for anything else signed with the same key.
+const PROOF_CONTEXT: &str = "deezcord identity proof v1";
+const SAFETY_NUMBER_GROUPS: usize = 6;
+
+/// Ties the sender's long-term key to the DTLS certificate of this
+/// connection, sent over the identity data channel.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IdentityProof {
+    pub public_key: String,
+    /// Our local DTLS fingerprint, as in our SDP, e.g. `sha-256 AB:CD:...`
+    pub fingerprint: String,
+    pub signature: String,
+}
+
+/// A peer key we have seen before.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KnownPeer {
+    pub public_key: String,
+    /// Set once the user compared safety numbers with the peer.
+    pub verified: bool,
+}
+
+/// What we know about the peer of a connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PeerTrust {
+    /// No proof received yet, or the peer doesn't send one.
+    Unknown,
+    /// The proof checks out, the key may not be verified yet.
+    Authenticated,
+    /// The proof checks out, but for another key than the one we know.
+    /// Holds the new key until the user accepts it with `/verify`.
+    KeyChanged(String),
+    /// The proof doesn't match the connection, someone may be in between.
+    Failed(String),
+}
+
+/// Our long-term signing key and the keys of peers we have talked to.
+/// Keys are trusted on first use, comparing safety numbers out of band
+/// upgrades a peer to verified.
+pub struct Identity {
+    user_id: String,
+    signing_key: SigningKey,
+    known_path: PathBuf,
+    known: Mutex<HashMap<String, KnownPeer>>,
+    trust: Mutex<HashMap<String, PeerTrust>>,
+}
+
+impl Identity {
+    /// Loads our key, creating one on first start, and the known peers.
+    pub fn load(user_id: &str) -> Result<Arc<Self>> {
+        let app_dir = get_app_dir()?;
+        let key_path = app_dir.join("identity.key");
+
+        let signing_key = match fs::read_to_string(&key_path) {
+            Ok(encoded) => {
+                let seed: [u8; 32] = BASE64
+                    .decode(encoded.trim())?
+                    .try_into()
+                    .map_err(|_| anyhow!("{} is not a valid key", key_path.display()))?;
+                SigningKey::from_bytes(&seed)
+            }
+            Err(_) => {
+                let signing_key = SigningKey::generate(&mut rand::rngs::OsRng);
+                write_private(&key_path, &BASE64.encode(signing_key.to_bytes()))
+                    .context("Couldn't save your identity key.")?;
+                signing_key
+            }
+        };
+
+        restrict_permissions(&key_path)?;
+
+        let known_path = app_dir.join("known_peers.json");
+        let known = match fs::read_to_string(&known_path) {
+            Ok(data) => serde_json::from_str(&data)?,
+            Err(_) => HashMap::new(),
+        };
+
+        Ok(Arc::new(Self {
+            user_id: user_id.to_owned(),
+            signing_key,
+            known_path,
+            known: Mutex::new(known),
+            trust: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    /// A new key that is only kept for this run, for when ours can't be
+    /// loaded or saved. Peers see it as a changed key.
+    pub fn ephemeral(user_id: &str) -> Arc<Self> {
+        Arc::new(Self {
+            user_id: user_id.to_owned(),
+            signing_key: SigningKey::generate(&mut rand::rngs::OsRng),
+            known_path: std::env::temp_dir().join(format!(
+                "known_peers-{}-{}.json",
+                user_id,
+                std::process::id()
+            )),
+            known: Mutex::new(HashMap::new()),
+            trust: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn public_key(&self) -> String {
+        BASE64.encode(self.signing_key.verifying_key().to_bytes())
+    }
+
+    /// Signs our DTLS fingerprint for the connection to `other_id`.
+    pub fn prove(&self, other_id: &str, fingerprint: &str) -> IdentityProof {
+        let message = proof_message(&self.user_id, other_id, fingerprint);
+
+        IdentityProof {
+            public_key: self.public_key(),
+            fingerprint: fingerprint.to_owned(),
+            signature: BASE64.encode(self.signing_key.sign(&message).to_bytes()),
+        }
+    }
+
+    /// Checks a proof from `other_id` against the certificate its side of
+    /// the connection actually presented, and remembers its key.
+    pub fn check(&self, other_id: &str, proof: &IdentityProof, remote_fingerprint: &str) {
+        let trust = match self.check_proof(other_id, proof, remote_fingerprint) {
+            Ok(()) => self.remember(other_id, &proof.public_key),
+            Err(e) => {
+                println!(
+                    "\n\rWARNING: could not authenticate {}: {}. Someone may be intercepting the call.",
+                    other_id, e
+                );
+                PeerTrust::Failed(e.to_string())
+            }
+        };
+
+        self.trust
+            .lock()
+            .unwrap()
+            .insert(other_id.to_owned(), trust);
+    }
+
+    fn check_proof(
+        &self,
+        other_id: &str,
+        proof: &IdentityProof,
+        remote_fingerprint: &str,
+    ) -> Result<()> {
+        let key: [u8; 32] = BASE64
+            .decode(&proof.public_key)?
+            .try_into()
+            .map_err(|_| anyhow!("malformed key"))?;
+        let key = VerifyingKey::from_bytes(&key)?;
+        let signature = Signature::from_slice(&BASE64.decode(&proof.signature)?)?;
+
+        let message = proof_message(other_id, &self.user_id, &proof.fingerprint);
+        key.verify(&message, &signature)
+            .map_err(|_| anyhow!("bad signature"))?;
+
+        if normalize_fingerprint(&proof.fingerprint) != normalize_fingerprint(remote_fingerprint) {
+            bail!("the signed fingerprint doesn't match the connection's certificate");
+        }
+
+        Ok(())
+    }
+
+    /// Trusts a first seen key. A changed one is not trusted, nor saved,
+    /// until the user compares safety numbers and accepts it.
+    fn remember(&self, other_id: &str, public_key: &str) -> PeerTrust {
+        let mut known = self.known.lock().unwrap();
+
+        match known.get(other_id) {
+            Some(peer) if peer.public_key == public_key => return PeerTrust::Authenticated,
+            Some(peer) => {
+                println!(
+                    "\n\rWARNING: the key of {}{} changed. Someone may be intercepting the call. Press i to compare safety numbers, then /verify {} to accept the new key.",
+                    if peer.verified { "verified peer " } else { "" },
+                    other_id,
+                    other_id
+                );
+                return PeerTrust::KeyChanged(public_key.to_owned());
+            }
+            None => (),
+        }
+
+        known.insert(
+            other_id.to_owned(),
+            KnownPeer {
+                public_key: public_key.to_owned(),
+                verified: false,
+            },
+        );
+
+        if let Err(e) = self.save(&known) {
+            eprintln!("Failed to save known peers: {}", e);
+        }
+
+        PeerTrust::Authenticated
+    }
+
+    fn save(&self, known: &HashMap<String, KnownPeer>) -> Result<()> {
+        fs::write(&self.known_path, serde_json::to_string_pretty(known)?)?;
+        Ok(())
+    }
+
+    pub fn trust(&self, other_id: &str) -> PeerTrust {
+        self.trust
+            .lock()
+            .unwrap()
+            .get(other_id)
+            .cloned()
+            .unwrap_or(PeerTrust::Unknown)
+    }
+
+    /// Forgets the outcome of a connection, the next one proves itself again.
+    pub fn peer_left(&self, other_id: &str) {
+        self.trust.lock().unwrap().remove(other_id);
+    }
+
+    pub fn is_verified(&self, other_id: &str) -> bool {
+        self.known
+            .lock()
+            .unwrap()
+            .get(other_id)
+            .is_some_and(|peer| peer.verified)
+    }
+
+    /// Marks `other_id` as verified, once the user compared safety numbers.
+    /// Only an authenticated key can be verified, a changed one replaces
+    /// the one we knew.
+    pub fn verify(&self, other_id: &str) -> Result<()> {
+        let mut known = self.known.lock().unwrap();
+
+        match self.trust(other_id) {
+            PeerTrust::Authenticated => {
+                known
+                    .get_mut(other_id)
+                    .ok_or_else(|| anyhow!("No key known for {}", other_id))?
+                    .verified = true;
+            }
+            PeerTrust::KeyChanged(public_key) => {
+                known.insert(
+                    other_id.to_owned(),
+                    KnownPeer {
+                        public_key,
+                        verified: true,
+                    },
+                );
+                self.trust
+                    .lock()
+                    .unwrap()
+                    .insert(other_id.to_owned(), PeerTrust::Authenticated);
+            }
+            _ => bail!("{} is not authenticated on this connection", other_id),
+        }
+
+        self.save(&known)
+    }
+
+    /// The key `other_id` proved on this connection, or else the one we
+    /// know.
+    fn peer_key(&self, other_id: &str) -> Option<String> {
+        match self.trust(other_id) {
+            PeerTrust::KeyChanged(public_key) => Some(public_key),
+            _ => Some(self.known.lock().unwrap().get(other_id)?.public_key.clone()),
+        }
+    }
+
+    /// Digits both sides compute the same, from both ids and keys. Equal
+    /// numbers mean nobody swapped a key in between.
+    pub fn safety_number(&self, other_id: &str) -> Option<String> {
+        let other_key = self.peer_key(other_id)?;
+        Some(safety_number(
+            (&self.user_id, &self.public_key()),
+            (other_id, &other_key),
+        ))
+    }
+}
+
+/// Safety number of two `(id, public key)` sides, in either order.
+fn safety_number(a: (&str, &str), b: (&str, &str)) -> String {
+    let mut sides = [a, b];
+    sides.sort();
+
+    let mut hasher = Sha256::new();
+    for (id, key) in sides {
+        hasher.update(id.as_bytes());
+        hasher.update([0]);
+        hasher.update(key.as_bytes());
+        hasher.update([0]);
+    }
+    let digest = hasher.finalize();
+
+    let groups: Vec<_> = digest
+        .chunks(5)
+        .take(SAFETY_NUMBER_GROUPS)
+        .map(|chunk| {
+            let value = chunk.iter().fold(0u64, |value, b| value << 8 | *b as u64);
+            format!("{:05}", value % 100_000)
+        })
+        .collect();
+
+    groups.join(" ")
+}
+
+/// Creates a file only we can read.
+fn write_private(path: &Path, contents: &str) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options.open(path)?.write_all(contents.as_bytes())
+}
+
+/// Takes back access others had to a key saved by older versions.
+#[cfg(unix)]
+fn restrict_permissions(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut permissions = fs::metadata(path)?.permissions();
+    if permissions.mode() & 0o077 != 0 {
+        permissions.set_mode(0o600);
+        fs::set_permissions(path, permissions)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn restrict_permissions(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+fn proof_message(from: &str, to: &str, fingerprint: &str) -> Vec<u8> {
+    format!(
+        "{}\n{}\n{}\n{}",
+        PROOF_CONTEXT,
+        from,
+        to,
+        normalize_fingerprint(fingerprint)
+    )
+    .into_bytes()
+}
+
+/// `sha-256 AB:CD:...` in one spelling, whatever the case and separators.
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    let (algorithm, value) = fingerprint
+        .trim()
+        .split_once(' ')
+        .unwrap_or(("", fingerprint));
+
+    format!(
+        "{} {}",
+        algorithm.to_lowercase(),
+        value.replace(':', "").to_lowercase()
+    )
+}
+
+/// The SDP style fingerprint of a DER certificate.
+pub fn certificate_fingerprint(certificate: &[u8]) -> String {
+    let value: Vec<_> = Sha256::digest(certificate)
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect();
+
+    format!("sha-256 {}", value.join(":"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity(user_id: &str) -> Arc<Identity> {
+        Identity::ephemeral(user_id)
+    }
+
+    /// Runs the identity exchange of one connection from `to`'s side.
+    fn exchange(from: &Identity, to: &Identity) {
+        let fingerprint = "sha-256 AB:CD:EF";
+        let proof = from.prove(&to.user_id, fingerprint);
+        to.check(&from.user_id, &proof, "SHA-256 abcdef");
+    }
+
+    #[test]
+    fn safety_numbers_match_on_both_sides() {
+        let alice = identity("alice");
+        let bob = identity("bob");
+
+        exchange(&alice, &bob);
+        exchange(&bob, &alice);
+
+        let number = alice.safety_number("bob").unwrap();
+        assert_eq!(Some(number.clone()), bob.safety_number("alice"));
+        assert_eq!(number.split(' ').count(), SAFETY_NUMBER_GROUPS);
+        assert!(number
+            .split(' ')
+            .all(|group| group.len() == 5 && group.chars().all(|c| c.is_ascii_digit())));
+    }
+
+    #[test]
+    fn safety_numbers_change_with_the_key() {
+        let number = safety_number(("alice", "key-a"), ("bob", "key-b"));
+
+        assert_eq!(number, safety_number(("bob", "key-b"), ("alice", "key-a")));
+        assert_ne!(number, safety_number(("alice", "key-a"), ("bob", "key-c")));
+    }
+
+    #[test]
+    fn proofs_for_another_certificate_fail() {
+        let alice = identity("alice");
+        let bob = identity("bob");
+
+        let proof = alice.prove("bob", "sha-256 AB:CD:EF");
+        bob.check("alice", &proof, "sha-256 12:34:56");
+
+        assert!(matches!(bob.trust("alice"), PeerTrust::Failed(_)));
+    }
+
+    #[test]
+    fn changed_keys_wait_for_the_user() {
+        let alice = identity("alice");
+        let impostor = identity("alice");
+        let bob = identity("bob");
+
+        exchange(&alice, &bob);
+        bob.verify("alice").unwrap();
+        let number = bob.safety_number("alice");
+
+        exchange(&impostor, &bob);
+        assert_eq!(
+            bob.trust("alice"),
+            PeerTrust::KeyChanged(impostor.public_key())
+        );
+        assert_eq!(
+            bob.known.lock().unwrap()["alice"].public_key,
+            alice.public_key()
+        );
+        assert_ne!(bob.safety_number("alice"), number);
+
+        bob.verify("alice").unwrap();
+        assert_eq!(bob.trust("alice"), PeerTrust::Authenticated);
+        assert!(bob.is_verified("alice"));
+        assert_eq!(
+            bob.known.lock().unwrap()["alice"].public_key,
+            impostor.public_key()
+        );
+
+        let _ = fs::remove_file(&bob.known_path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keys_are_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("identity-{}.key", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        write_private(&path, "secret").unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
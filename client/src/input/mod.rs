@@ -1,14 +1,38 @@
+use anyhow::Result;
+use std::collections::HashMap;
 use std::io::{stdout, Write};
+use std::path::Path;
 use std::sync::mpsc::Sender;
 use std::sync::Arc;
+use std::time::Duration;
 use termion::async_stdin;
 use termion::event::Key;
 use termion::input::TermRead;
 use termion::raw::IntoRawMode;
+use tokio::sync::Mutex;
 use tokio_tungstenite::WebSocketStream;
+use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
 
+use crate::audio::controls::AudioControls;
+use crate::audio::mode::announce_audio_mode;
+use crate::audio::replay::ReplayRecorder;
+use crate::audio::soundboard::Soundboard;
+use crate::chat::message::ChatMessage;
+use crate::chat::Chat;
+use crate::commands::ClientCommand;
 use crate::config::UserConfig;
+use crate::identity::PeerTrust;
+use crate::peer::session::{PeerContext, PeerSession};
+use crate::peer::teardown::close_all_peers;
 use crate::rooms::create_room;
+use crate::share::keys::{key_bytes, PREFIX_KEY};
+
+const INPUT_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Keys the call shortcuts take, soundboard clips can't be bound to them.
+pub const CALL_KEYS: &[char] = &[
+    't', 'x', 'v', 'y', 'n', 'm', 's', '+', '-', 'r', 'p', 'i', 'l', 'q',
+];
 
 pub async fn _listen_for_input(
     tx: Sender<()>,
@@ -53,3 +77,311 @@ pub async fn _listen_for_input(
         stdout.flush().unwrap();
     }
 }
+
+/// Offers a file to everyone in the room.
+async fn send_file_to_room(
+    path: &Path,
+    peer_connections: Arc<Mutex<HashMap<String, Arc<PeerSession>>>>,
+    context: &PeerContext,
+) -> Result<()> {
+    let sessions: Vec<_> = peer_connections.lock().await.values().cloned().collect();
+
+    for session in sessions {
+        context.transfers.offer(&session, path).await?;
+        println!("\n\rOffered {} to {}", path.display(), session.other_id);
+    }
+
+    Ok(())
+}
+
+/// Sends a typed chat line. `/reply <id> text` answers an earlier message,
+/// `/edit text` replaces our last one.
+fn send_chat_line(chat: &Chat, room_id: &str, line: &str) -> Result<ChatMessage> {
+    if let Some(rest) = line.strip_prefix("/reply ") {
+        let (id, body) = rest.split_once(' ').unwrap_or((rest, ""));
+        return chat.send(
+            room_id,
+            body.trim().to_owned(),
+            Some(id.trim_start_matches('#')),
+        );
+    }
+
+    if let Some(body) = line.strip_prefix("/edit ") {
+        return chat.edit_last(room_id, body.trim().to_owned());
+    }
+
+    chat.send(room_id, line.to_owned(), None)
+}
+
+/// Key handling while in a call. Runs alongside the websocket listener, so
+/// it polls stdin instead of blocking on it.
+#[allow(clippy::too_many_arguments)]
+pub async fn listen_for_call_input(
+    tx: Sender<()>,
+    room_id: String,
+    controls: Arc<AudioControls>,
+    replay: Arc<ReplayRecorder>,
+    soundboard: Arc<Soundboard>,
+    peer_connections: Arc<Mutex<HashMap<String, Arc<PeerSession>>>>,
+    ice_candidates: Arc<Mutex<HashMap<String, Vec<RTCIceCandidateInit>>>>,
+    context: PeerContext,
+) {
+    let mut stdout = stdout().into_raw_mode().unwrap();
+    write!(
+        stdout,
+        "\n\r - Press m to toggle music mode\
+         \n\r - Press s to toggle sidetone, +/- to change its volume\
+         \n\r - Press r to save the last seconds of the call\
+         \n\r - Press p to list connected peers\
+         \n\r - Press t to write in the chat, Enter sends, Esc cancels\
+         \n\r   (/send <path> offers a file, y/n answers file offers)\
+         \n\r - Press i to show safety numbers, /verify <user id> in the chat\
+         \n\r   marks a peer whose number you compared as verified\
+         \n\r - Press x to share your terminal, Ctrl-] then d stops, g gives\
+         \n\r   control to the next viewer who asked, r takes it back\
+         \n\r - Press v to watch a shared terminal, c asks for control\
+         \n\r - Press a soundboard key to play its clip\
+         \n\r - Press l to leave the room, q to quit\n\r"
+    )
+    .unwrap();
+    stdout.flush().unwrap();
+
+    let mut stdin = async_stdin().keys();
+    // The chat line being typed, keys go there instead of the shortcuts.
+    let mut draft: Option<String> = None;
+    // Set after Ctrl-] while sharing our terminal.
+    let mut share_prefix = false;
+
+    loop {
+        let Some(Ok(key)) = stdin.next() else {
+            tokio::time::sleep(INPUT_POLL_INTERVAL).await;
+            continue;
+        };
+
+        // Our shell gets every key but the share commands.
+        if context.share.is_presenting() {
+            if share_prefix {
+                share_prefix = false;
+
+                match key {
+                    Key::Char('d') => context.share.stop(),
+                    Key::Char('g') => match context.share.grant_next() {
+                        Ok(viewer) => println!("\n\r{} can now type in your terminal", viewer),
+                        Err(e) => println!("\n\r{}", e),
+                    },
+                    Key::Char('r') => {
+                        for viewer in context.share.revoke_all() {
+                            println!("\n\rTook control back from {}", viewer);
+                        }
+                    }
+                    PREFIX_KEY => {
+                        let _ = context.share.write_input(&key_bytes(key));
+                    }
+                    _ => (),
+                }
+            } else if key == PREFIX_KEY {
+                share_prefix = true;
+            } else if let Err(e) = context.share.write_input(&key_bytes(key)) {
+                println!("\n\r{}", e);
+            }
+            continue;
+        }
+
+        if context.share.is_watching() {
+            match key {
+                Key::PageUp => context.share.scroll(true),
+                Key::PageDown => context.share.scroll(false),
+                PREFIX_KEY => context.share.stop_watching(),
+                _ if context.share.controlling() => context.share.send_input(key_bytes(key)),
+                Key::Char('c') => context.share.request_control(),
+                Key::Esc => context.share.stop_watching(),
+                _ => (),
+            }
+            continue;
+        }
+
+        if let Some(line) = draft.as_mut() {
+            match key {
+                Key::Char('\n') => {
+                    let line = draft.take().unwrap_or_default();
+                    write!(stdout, "\r{}", termion::clear::CurrentLine).unwrap();
+
+                    if let Some(other_id) = line.trim().strip_prefix("/verify ") {
+                        match context.identity.verify(other_id.trim()) {
+                            Ok(()) => write!(stdout, "Verified {}\n\r", other_id.trim()).unwrap(),
+                            Err(e) => write!(stdout, "Failed to verify: {}\n\r", e).unwrap(),
+                        }
+                    } else if let Some(path) = line.trim().strip_prefix("/send ") {
+                        if let Err(e) = send_file_to_room(
+                            Path::new(path.trim()),
+                            peer_connections.clone(),
+                            &context,
+                        )
+                        .await
+                        {
+                            write!(stdout, "Failed to send file: {}\n\r", e).unwrap();
+                        }
+                    } else if !line.trim().is_empty() {
+                        match send_chat_line(&context.chat, &room_id, line.trim()) {
+                            Ok(message) => {
+                                write!(stdout, "{}\n\r", context.chat.format(&message)).unwrap()
+                            }
+                            Err(e) => write!(stdout, "Failed to send message: {}\n\r", e).unwrap(),
+                        }
+                    }
+                }
+                Key::Esc | Key::Ctrl('c') => {
+                    draft = None;
+                    write!(stdout, "\r{}", termion::clear::CurrentLine).unwrap();
+                }
+                Key::Backspace => {
+                    line.pop();
+                    write!(stdout, "\r{}> {}", termion::clear::CurrentLine, line).unwrap();
+                }
+                Key::Char(c) => {
+                    line.push(c);
+                    write!(stdout, "{}", c).unwrap();
+                }
+                _ => (),
+            }
+
+            stdout.flush().unwrap();
+            continue;
+        }
+
+        match key {
+            Key::Char('t') => {
+                draft = Some(String::new());
+                write!(stdout, "\n\r> ").unwrap();
+            }
+            Key::Char('x') if context.capabilities.screenshare => {
+                write!(
+                    stdout,
+                    "\n\rSharing your terminal, Ctrl-] then d to stop\n\r"
+                )
+                .unwrap();
+                stdout.flush().unwrap();
+
+                if let Err(e) = context.share.start() {
+                    write!(stdout, "\n\rFailed to share the terminal: {}\n\r", e).unwrap();
+                }
+            }
+            Key::Char('v') => {
+                let watching = context.share.watch();
+                if watching.is_none() {
+                    write!(stdout, "\n\rNobody is sharing a terminal\n\r").unwrap();
+                }
+            }
+            Key::Char('y') | Key::Char('n') => {
+                if let Err(e) = context
+                    .transfers
+                    .answer_prompt(key == Key::Char('y'), peer_connections.clone())
+                    .await
+                {
+                    write!(stdout, "\n\r{}\n\r", e).unwrap();
+                }
+            }
+            Key::Char('m') => {
+                let mode = controls.toggle_mode();
+                write!(stdout, "\n\rAudio mode: {:?}\n\r", mode).unwrap();
+
+                if let Err(e) =
+                    announce_audio_mode(&room_id, mode, peer_connections.clone(), &context).await
+                {
+                    write!(stdout, "\n\r{}\n\r", e).unwrap();
+                }
+            }
+            Key::Char('s') => {
+                let enabled = controls.toggle_sidetone();
+                write!(
+                    stdout,
+                    "\n\rSidetone: {}\n\r",
+                    if enabled { "on" } else { "off" }
+                )
+                .unwrap();
+            }
+            Key::Char('+') | Key::Char('-') => {
+                let volume = controls.step_sidetone_volume(key == Key::Char('+'));
+                write!(stdout, "\n\rSidetone volume: {:.0}%\n\r", volume * 100.0).unwrap();
+            }
+            Key::Char('r') => match replay.save().await {
+                Ok(paths) => {
+                    for path in paths {
+                        write!(stdout, "\n\rSaved replay to {}\n\r", path.display()).unwrap();
+                    }
+                }
+                Err(e) => write!(stdout, "\n\rFailed to save replay: {}\n\r", e).unwrap(),
+            },
+            Key::Char('p') => {
+                let sessions: Vec<_> = peer_connections.lock().await.values().cloned().collect();
+
+                for session in sessions {
+                    write!(
+                        stdout,
+                        "\n\r{} ({:?}): {:?}, data channel {:?}\n\r  {}",
+                        session.other_id,
+                        session.role,
+                        session.connection_state(),
+                        session.data_channel.ready_state(),
+                        session.stats()
+                    )
+                    .unwrap();
+                }
+                write!(stdout, "\n\r").unwrap();
+            }
+            Key::Char('i') => {
+                let sessions: Vec<_> = peer_connections.lock().await.values().cloned().collect();
+
+                for session in sessions {
+                    let other_id = &session.other_id;
+                    let status = match context.identity.trust(other_id) {
+                        PeerTrust::Unknown => "not authenticated".to_owned(),
+                        PeerTrust::Failed(reason) => format!("AUTHENTICATION FAILED: {}", reason),
+                        PeerTrust::KeyChanged(_) => "KEY CHANGED, /verify to accept".to_owned(),
+                        PeerTrust::Authenticated if context.identity.is_verified(other_id) => {
+                            "verified".to_owned()
+                        }
+                        PeerTrust::Authenticated => "not verified".to_owned(),
+                    };
+                    let number = context
+                        .identity
+                        .safety_number(other_id)
+                        .unwrap_or_else(|| "-".to_owned());
+
+                    write!(stdout, "\n\r{} ({}): {}", other_id, status, number).unwrap();
+                }
+                write!(stdout, "\n\r").unwrap();
+            }
+            Key::Char('l') => {
+                if let Err(e) = context.send(ClientCommand::Leave).await {
+                    write!(stdout, "\n\r{}\n\r", e).unwrap();
+                }
+                context.frame_crypto.set_room(None);
+                close_all_peers(peer_connections.clone(), ice_candidates.clone()).await;
+                write!(stdout, "\n\rLeft the room\n\r").unwrap();
+                if let Some(report) = context.call_report.finish() {
+                    write!(stdout, "\n\r{}\n\r", report).unwrap();
+                }
+                stdout.flush().unwrap();
+                return;
+            }
+            Key::Char('q') | Key::Ctrl('q') | Key::Ctrl('c') => {
+                let _ = context.send(ClientCommand::Leave).await;
+                context.frame_crypto.set_room(None);
+                close_all_peers(peer_connections.clone(), ice_candidates.clone()).await;
+                if let Some(report) = context.call_report.finish() {
+                    write!(stdout, "\n\r{}\n\r", report).unwrap();
+                    stdout.flush().unwrap();
+                }
+                let _ = tx.send(());
+                return;
+            }
+            Key::Char(c) if soundboard.has_clip(c) => {
+                soundboard.trigger(c);
+            }
+            _ => (),
+        }
+
+        stdout.flush().unwrap();
+    }
+}
@@ -1,10 +1,16 @@
 mod audio;
+mod chat;
 mod commands;
 mod config;
+mod e2ee;
+mod identity;
 mod input;
 mod peer;
 mod rooms;
+mod share;
 mod socket;
+mod transfer;
+mod video;
 
 use crate::commands::wait_for_ack::wait_for_ack;
 use crate::commands::{ClientCommand, Command, CommandMessage};
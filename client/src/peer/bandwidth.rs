@@ -0,0 +1,632 @@
+use async_trait::async_trait;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Instant;
+use webrtc::interceptor::stream_info::StreamInfo;
+use webrtc::interceptor::{
+    Attributes, Error, Interceptor, InterceptorBuilder, RTCPReader, RTCPWriter, RTPReader,
+    RTPWriter,
+};
+use webrtc::rtcp::transport_feedbacks::transport_layer_cc::{
+    PacketStatusChunk, SymbolTypeTcc, TransportLayerCc,
+};
+use webrtc::sdp::extmap::TRANSPORT_CC_URI;
+use webrtc::util::MarshalSize;
+
+/// Lowest bitrate Opus can encode at.
+const MIN_BITRATE: f64 = 6_000.0;
+const MAX_BITRATE: f64 = 10_000_000.0;
+const START_BITRATE: f64 = 300_000.0;
+/// Lowest bitrate the video encoder is given, below it frames are mush.
+const MIN_VIDEO_BITRATE: u32 = 50_000;
+
+/// Sent packets remembered until their feedback arrives, by TWCC sequence
+/// number. Feedback comes every 100ms, so this covers a few seconds of
+/// audio.
+const SENT_HISTORY: usize = 1024;
+
+/// Number of delay samples the trend is fitted over.
+const TREND_WINDOW: usize = 20;
+const TREND_SMOOTHING: f64 = 0.9;
+const TREND_GAIN: f64 = 4.0;
+const THRESHOLD_GAIN_UP: f64 = 0.0087;
+const THRESHOLD_GAIN_DOWN: f64 = 0.039;
+const MIN_THRESHOLD: f64 = 6.0;
+const MAX_THRESHOLD: f64 = 600.0;
+
+/// Multiplicative increase per second while the delay is stable.
+const INCREASE_FACTOR: f64 = 1.08;
+/// Share of the received rate kept on overuse.
+const DECREASE_FACTOR: f64 = 0.85;
+/// Leaves time for a decrease to drain the queue before the next one.
+const MIN_DECREASE_INTERVAL_MS: f64 = 300.0;
+const HIGH_LOSS: f64 = 0.1;
+
+/// The bitrate the estimator currently allows towards one peer.
+pub struct TargetBitrate(AtomicU32);
+
+impl TargetBitrate {
+    pub fn get(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn set(&self, bitrate: f64) {
+        self.0.store(bitrate as u32, Ordering::Relaxed);
+    }
+}
+
+/// Splits what we send between peers: each one gets at most its estimated
+/// target, and together they stay under the configured upload cap. Audio
+/// comes first, video gets what is left.
+pub struct UploadBudget {
+    /// Bits per second for all peers together
+    cap: Option<u32>,
+    targets: Mutex<Vec<Weak<TargetBitrate>>>,
+    /// Last bitrate handed to the audio encoder
+    audio: AtomicU32,
+}
+
+impl UploadBudget {
+    pub fn new(cap: Option<u32>) -> Self {
+        Self {
+            cap,
+            targets: Mutex::new(Vec::new()),
+            audio: AtomicU32::new(0),
+        }
+    }
+
+    /// Tracks the target of a new peer connection.
+    pub fn register(&self) -> Arc<TargetBitrate> {
+        let target = Arc::new(TargetBitrate(AtomicU32::new(START_BITRATE as u32)));
+        self.targets.lock().unwrap().push(Arc::downgrade(&target));
+        target
+    }
+
+    /// Most we can send each peer: the slowest target, and the peer's share
+    /// of the cap. Every peer receives the same encoded streams, so they
+    /// have to fit the slowest one.
+    fn limit(&self) -> Option<u32> {
+        let mut targets = self.targets.lock().unwrap();
+        targets.retain(|target| target.strong_count() > 0);
+
+        let slowest = targets
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|target| target.get())
+            .min();
+
+        let share = self.cap.map(|cap| cap / targets.len().max(1) as u32);
+
+        slowest.into_iter().chain(share).min()
+    }
+
+    /// The bitrate for the shared audio track.
+    pub fn audio_bitrate(&self, preferred: i32) -> i32 {
+        let bitrate = match self.limit() {
+            Some(limit) => preferred.min(limit as i32).max(MIN_BITRATE as i32),
+            None => preferred,
+        };
+
+        self.audio.store(bitrate.max(0) as u32, Ordering::Relaxed);
+        bitrate
+    }
+
+    /// The bitrate for the shared video track, what the limit leaves once
+    /// the audio is sent.
+    pub fn video_bitrate(&self, preferred: u32) -> u32 {
+        match self.limit() {
+            Some(limit) => preferred
+                .min(limit.saturating_sub(self.audio.load(Ordering::Relaxed)))
+                .max(MIN_VIDEO_BITRATE),
+            None => preferred,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Usage {
+    Normal,
+    Overuse,
+    Underuse,
+}
+
+#[derive(Clone, Copy)]
+struct SentPacket {
+    seq: u16,
+    at: Instant,
+    size: usize,
+}
+
+/// A GCC-style estimate: a delay-based controller that backs off when the
+/// queueing delay trends up, combined with a loss-based one.
+struct Estimator {
+    estimate: f64,
+    last_update: Option<Instant>,
+    last_decrease: Option<Instant>,
+    /// Send time and arrival, in ms, of the last packet acknowledged.
+    previous: Option<(Instant, f64)>,
+    first_arrival: Option<f64>,
+    accumulated_delay: f64,
+    smoothed_delay: f64,
+    delays: VecDeque<(f64, f64)>,
+    threshold: f64,
+    last_threshold_update: Option<f64>,
+}
+
+impl Estimator {
+    fn new() -> Self {
+        Self {
+            estimate: START_BITRATE,
+            last_update: None,
+            last_decrease: None,
+            previous: None,
+            first_arrival: None,
+            accumulated_delay: 0.0,
+            smoothed_delay: 0.0,
+            delays: VecDeque::with_capacity(TREND_WINDOW),
+            threshold: 12.5,
+            last_threshold_update: None,
+        }
+    }
+
+    /// Updates the estimate from one feedback report: every packet it
+    /// covers, with its arrival time in ms when it was received.
+    fn update(&mut self, results: &[(SentPacket, Option<f64>)], now: Instant) -> f64 {
+        let mut received = 0usize;
+        let mut lost = 0usize;
+        let mut bytes = 0usize;
+        let mut arrivals: Option<(f64, f64)> = None;
+
+        for (sent, arrival) in results {
+            let Some(arrival) = *arrival else {
+                lost += 1;
+                continue;
+            };
+
+            received += 1;
+            bytes += sent.size;
+            arrivals = Some(match arrivals {
+                Some((first, _)) => (first, arrival),
+                None => (arrival, arrival),
+            });
+
+            if let Some((previous_sent, previous_arrival)) = self.previous {
+                let departure = if sent.at >= previous_sent {
+                    (sent.at - previous_sent).as_secs_f64() * 1000.0
+                } else {
+                    -((previous_sent - sent.at).as_secs_f64() * 1000.0)
+                };
+                self.add_delay((arrival - previous_arrival) - departure, arrival);
+            }
+            self.previous = Some((sent.at, arrival));
+        }
+
+        let elapsed = self
+            .last_update
+            .map(|last| (now - last).as_secs_f64())
+            .unwrap_or(0.0);
+        self.last_update = Some(now);
+
+        let received_rate = match arrivals {
+            Some((first, last)) if last > first => {
+                Some(bytes as f64 * 8.0 / (last - first) * 1000.0)
+            }
+            _ => None,
+        };
+
+        match self.usage() {
+            Usage::Overuse => {
+                let since_decrease = self
+                    .last_decrease
+                    .map(|last| (now - last).as_secs_f64() * 1000.0);
+
+                if since_decrease.is_none_or(|ms| ms >= MIN_DECREASE_INTERVAL_MS) {
+                    let base = received_rate.unwrap_or(self.estimate).min(self.estimate);
+                    self.estimate = base * DECREASE_FACTOR;
+                    self.last_decrease = Some(now);
+                }
+            }
+            Usage::Underuse => (),
+            Usage::Normal => {
+                self.estimate *= INCREASE_FACTOR.powf(elapsed.min(1.0));
+            }
+        }
+
+        if received + lost > 0 {
+            let loss = lost as f64 / (received + lost) as f64;
+            if loss > HIGH_LOSS {
+                self.estimate *= 1.0 - 0.5 * loss;
+            }
+        }
+
+        self.estimate = self.estimate.clamp(MIN_BITRATE, MAX_BITRATE);
+        self.estimate
+    }
+
+    fn add_delay(&mut self, delay: f64, arrival: f64) {
+        self.accumulated_delay += delay;
+        self.smoothed_delay = TREND_SMOOTHING * self.smoothed_delay
+            + (1.0 - TREND_SMOOTHING) * self.accumulated_delay;
+
+        let first = *self.first_arrival.get_or_insert(arrival);
+        self.delays
+            .push_back((arrival - first, self.smoothed_delay));
+        if self.delays.len() > TREND_WINDOW {
+            self.delays.pop_front();
+        }
+    }
+
+    /// Slope of the smoothed delay over arrival time, by least squares.
+    fn trend(&self) -> Option<f64> {
+        if self.delays.len() < TREND_WINDOW {
+            return None;
+        }
+
+        let n = self.delays.len() as f64;
+        let mean_x = self.delays.iter().map(|(x, _)| x).sum::<f64>() / n;
+        let mean_y = self.delays.iter().map(|(_, y)| y).sum::<f64>() / n;
+
+        let (numerator, denominator) =
+            self.delays
+                .iter()
+                .fold((0.0, 0.0), |(numerator, denominator), (x, y)| {
+                    (
+                        numerator + (x - mean_x) * (y - mean_y),
+                        denominator + (x - mean_x) * (x - mean_x),
+                    )
+                });
+
+        (denominator > 0.0).then(|| numerator / denominator)
+    }
+
+    /// Compares the delay trend against a threshold that adapts to it, so
+    /// competing TCP flows do not starve us.
+    fn usage(&mut self) -> Usage {
+        let (Some(slope), Some(&(now, _))) = (self.trend(), self.delays.back()) else {
+            return Usage::Normal;
+        };
+
+        let modified = slope * self.delays.len() as f64 * TREND_GAIN;
+
+        let elapsed = self
+            .last_threshold_update
+            .map(|last| (now - last).min(100.0))
+            .unwrap_or(0.0);
+        self.last_threshold_update = Some(now);
+
+        // Spikes far above the threshold are not worth adapting to.
+        if modified.abs() < self.threshold + 15.0 {
+            let gain = if modified.abs() < self.threshold {
+                THRESHOLD_GAIN_DOWN
+            } else {
+                THRESHOLD_GAIN_UP
+            };
+            self.threshold += gain * (modified.abs() - self.threshold) * elapsed;
+            self.threshold = self.threshold.clamp(MIN_THRESHOLD, MAX_THRESHOLD);
+        }
+
+        if modified > self.threshold {
+            Usage::Overuse
+        } else if modified < -self.threshold {
+            Usage::Underuse
+        } else {
+            Usage::Normal
+        }
+    }
+}
+
+struct State {
+    sent: Vec<Option<SentPacket>>,
+    estimator: Estimator,
+}
+
+/// Remembers when each packet left, by the sequence number the TWCC
+/// interceptor stamped on it, and feeds the transport-wide feedback coming
+/// back into the estimator.
+///
+/// Must be registered before the TWCC interceptor so the packets it sees
+/// already carry the header extension.
+pub struct CongestionControl {
+    target: Arc<TargetBitrate>,
+    state: Arc<Mutex<State>>,
+}
+
+pub struct CongestionControlBuilder {
+    target: Arc<TargetBitrate>,
+}
+
+impl CongestionControlBuilder {
+    pub fn new(target: Arc<TargetBitrate>) -> Self {
+        Self { target }
+    }
+}
+
+impl InterceptorBuilder for CongestionControlBuilder {
+    fn build(&self, _id: &str) -> Result<Arc<dyn Interceptor + Send + Sync>, Error> {
+        Ok(Arc::new(CongestionControl {
+            target: self.target.clone(),
+            state: Arc::new(Mutex::new(State {
+                sent: vec![None; SENT_HISTORY],
+                estimator: Estimator::new(),
+            })),
+        }))
+    }
+}
+
+/// Expands the feedback into sequence numbers and arrival times in ms.
+fn packet_results(feedback: &TransportLayerCc) -> Vec<(u16, Option<f64>)> {
+    let statuses = feedback
+        .packet_chunks
+        .iter()
+        .flat_map(|chunk| match chunk {
+            PacketStatusChunk::RunLengthChunk(chunk) => {
+                vec![chunk.packet_status_symbol; chunk.run_length as usize]
+            }
+            PacketStatusChunk::StatusVectorChunk(chunk) => chunk.symbol_list.clone(),
+        })
+        .take(feedback.packet_status_count as usize);
+
+    let mut deltas = feedback.recv_deltas.iter();
+    let mut arrival = feedback.reference_time as i64 * 64_000;
+
+    statuses
+        .enumerate()
+        .map(|(i, status)| {
+            let seq = feedback.base_sequence_number.wrapping_add(i as u16);
+            match status {
+                SymbolTypeTcc::PacketReceivedSmallDelta
+                | SymbolTypeTcc::PacketReceivedLargeDelta => {
+                    arrival += deltas.next().map(|delta| delta.delta).unwrap_or(0);
+                    (seq, Some(arrival as f64 / 1000.0))
+                }
+                _ => (seq, None),
+            }
+        })
+        .collect()
+}
+
+struct SentRecorder {
+    extension_id: u8,
+    state: Arc<Mutex<State>>,
+    next: Arc<dyn RTPWriter + Send + Sync>,
+}
+
+#[async_trait]
+impl RTPWriter for SentRecorder {
+    async fn write(
+        &self,
+        pkt: &webrtc::rtp::packet::Packet,
+        attributes: &Attributes,
+    ) -> Result<usize, Error> {
+        if let Some(extension) = pkt.header.get_extension(self.extension_id) {
+            if extension.len() >= 2 {
+                let seq = u16::from_be_bytes([extension[0], extension[1]]);
+                let size = pkt.header.marshal_size() + pkt.payload.len();
+
+                self.state.lock().unwrap().sent[seq as usize % SENT_HISTORY] = Some(SentPacket {
+                    seq,
+                    at: Instant::now(),
+                    size,
+                });
+            }
+        }
+
+        self.next.write(pkt, attributes).await
+    }
+}
+
+struct FeedbackReader {
+    target: Arc<TargetBitrate>,
+    state: Arc<Mutex<State>>,
+    next: Arc<dyn RTCPReader + Send + Sync>,
+}
+
+#[async_trait]
+impl RTCPReader for FeedbackReader {
+    async fn read(
+        &self,
+        buf: &mut [u8],
+        attributes: &Attributes,
+    ) -> Result<
+        (
+            Vec<Box<dyn webrtc::rtcp::packet::Packet + Send + Sync>>,
+            Attributes,
+        ),
+        Error,
+    > {
+        let (packets, attributes) = self.next.read(buf, attributes).await?;
+
+        for packet in &packets {
+            let Some(feedback) = packet.as_any().downcast_ref::<TransportLayerCc>() else {
+                continue;
+            };
+
+            let mut state = self.state.lock().unwrap();
+
+            let results: Vec<_> = packet_results(feedback)
+                .into_iter()
+                .filter_map(|(seq, arrival)| {
+                    state.sent[seq as usize % SENT_HISTORY]
+                        .filter(|sent| sent.seq == seq)
+                        .map(|sent| (sent, arrival))
+                })
+                .collect();
+
+            if !results.is_empty() {
+                let estimate = state.estimator.update(&results, Instant::now());
+                self.target.set(estimate);
+            }
+        }
+
+        Ok((packets, attributes))
+    }
+}
+
+#[async_trait]
+impl Interceptor for CongestionControl {
+    async fn bind_rtcp_reader(
+        &self,
+        reader: Arc<dyn RTCPReader + Send + Sync>,
+    ) -> Arc<dyn RTCPReader + Send + Sync> {
+        Arc::new(FeedbackReader {
+            target: self.target.clone(),
+            state: self.state.clone(),
+            next: reader,
+        })
+    }
+
+    async fn bind_rtcp_writer(
+        &self,
+        writer: Arc<dyn RTCPWriter + Send + Sync>,
+    ) -> Arc<dyn RTCPWriter + Send + Sync> {
+        writer
+    }
+
+    async fn bind_local_stream(
+        &self,
+        info: &StreamInfo,
+        writer: Arc<dyn RTPWriter + Send + Sync>,
+    ) -> Arc<dyn RTPWriter + Send + Sync> {
+        let extension = info
+            .rtp_header_extensions
+            .iter()
+            .find(|extension| extension.uri == TRANSPORT_CC_URI);
+
+        match extension {
+            Some(extension) => Arc::new(SentRecorder {
+                extension_id: extension.id as u8,
+                state: self.state.clone(),
+                next: writer,
+            }),
+            None => writer,
+        }
+    }
+
+    async fn unbind_local_stream(&self, _info: &StreamInfo) {}
+
+    async fn bind_remote_stream(
+        &self,
+        _info: &StreamInfo,
+        reader: Arc<dyn RTPReader + Send + Sync>,
+    ) -> Arc<dyn RTPReader + Send + Sync> {
+        reader
+    }
+
+    async fn unbind_remote_stream(&self, _info: &StreamInfo) {}
+
+    async fn close(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Packets of 1000 bytes sent every 20ms, each arriving `queueing` ms
+    /// later than the one before it would have on an idle link.
+    fn packets(
+        start: Instant,
+        seqs: std::ops::Range<u16>,
+        queueing: f64,
+        lost: impl Fn(u16) -> bool,
+    ) -> Vec<(SentPacket, Option<f64>)> {
+        seqs.map(|seq| {
+            let sent = SentPacket {
+                seq,
+                at: start + Duration::from_millis(seq as u64 * 20),
+                size: 1000,
+            };
+            let arrival = seq as f64 * (20.0 + queueing);
+            (sent, (!lost(seq)).then_some(arrival))
+        })
+        .collect()
+    }
+
+    #[test]
+    fn stable_delay_increases_the_estimate() {
+        let start = Instant::now();
+        let mut estimator = Estimator::new();
+
+        estimator.update(&packets(start, 0..40, 0.0, |_| false), start);
+        let estimate = estimator.update(
+            &packets(start, 40..80, 0.0, |_| false),
+            start + Duration::from_secs(1),
+        );
+
+        assert!(estimate > START_BITRATE);
+    }
+
+    #[test]
+    fn growing_delay_decreases_the_estimate() {
+        let start = Instant::now();
+        let mut estimator = Estimator::new();
+
+        let estimate = estimator.update(&packets(start, 0..40, 10.0, |_| false), start);
+
+        assert!(estimate < START_BITRATE * DECREASE_FACTOR + 1.0);
+    }
+
+    #[test]
+    fn heavy_loss_decreases_the_estimate() {
+        let start = Instant::now();
+        let mut estimator = Estimator::new();
+
+        let estimate = estimator.update(&packets(start, 0..40, 0.0, |seq| seq % 2 == 0), start);
+
+        assert!(estimate < START_BITRATE);
+    }
+
+    #[test]
+    fn estimate_stays_in_bounds() {
+        let start = Instant::now();
+        let mut estimator = Estimator::new();
+
+        for second in 0..100 {
+            let first = second * 40;
+            estimator.update(
+                &packets(start, first..first + 40, 0.0, |_| true),
+                start + Duration::from_secs(second as u64),
+            );
+        }
+
+        assert_eq!(estimator.estimate, MIN_BITRATE);
+    }
+
+    #[test]
+    fn audio_fits_the_slowest_peer_and_the_cap() {
+        let budget = UploadBudget::new(Some(100_000));
+        let fast = budget.register();
+        let slow = budget.register();
+        fast.set(80_000.0);
+        slow.set(40_000.0);
+
+        assert_eq!(budget.audio_bitrate(64_000), 40_000);
+
+        slow.set(1_000.0);
+        assert_eq!(budget.audio_bitrate(64_000), MIN_BITRATE as i32);
+
+        drop(slow);
+        assert_eq!(budget.audio_bitrate(64_000), 64_000);
+        assert_eq!(budget.audio_bitrate(200_000), 80_000);
+    }
+
+    #[test]
+    fn video_gets_what_audio_leaves() {
+        let budget = UploadBudget::new(None);
+        assert_eq!(budget.video_bitrate(500_000), 500_000);
+
+        let target = budget.register();
+        target.set(300_000.0);
+        budget.audio_bitrate(64_000);
+
+        assert_eq!(budget.video_bitrate(500_000), 236_000);
+        assert_eq!(budget.video_bitrate(100_000), 100_000);
+
+        target.set(70_000.0);
+        budget.audio_bitrate(64_000);
+        assert_eq!(budget.video_bitrate(500_000), MIN_VIDEO_BITRATE);
+    }
+}
@@ -0,0 +1,134 @@
+use anyhow::Result;
+use webrtc::api::media_engine::{MediaEngine, MIME_TYPE_H264, MIME_TYPE_OPUS, MIME_TYPE_VP8};
+use webrtc::rtp_transceiver::rtp_codec::{
+    RTCRtpCodecCapability, RTCRtpCodecParameters, RTPCodecType,
+};
+use webrtc::rtp_transceiver::RTCPFeedback;
+
+use crate::audio::capture::SAMPLE_RATE;
+use crate::audio::send::FRAME_DURATION;
+use crate::config::{CodecConfig, VideoCodec};
+
+pub const MIME_TYPE_RED: &str = "audio/red";
+
+pub const OPUS_PAYLOAD_TYPE: u8 = 111;
+pub const RED_PAYLOAD_TYPE: u8 = 63;
+
+const VIDEO_CLOCK_RATE: u32 = 90000;
+
+/// The Opus fmtp line for `config`, shared by the registered codec and the
+/// local track so they match exactly. `ptime` is the encoder's frame size.
+pub fn opus_fmtp(config: &CodecConfig) -> String {
+    let flag = |enabled: bool| if enabled { 1 } else { 0 };
+
+    let mut fmtp = format!(
+        "minptime=10;ptime={};useinbandfec={};usedtx={};stereo={};sprop-stereo={}",
+        FRAME_DURATION.as_millis(),
+        flag(config.opus_fec),
+        flag(config.opus_dtx),
+        flag(config.opus_stereo),
+        flag(config.opus_stereo),
+    );
+
+    if let Some(bitrate) = config.opus_max_average_bitrate {
+        fmtp.push_str(&format!(";maxaveragebitrate={}", bitrate));
+    }
+
+    fmtp
+}
+
+pub fn opus_capability(config: &CodecConfig) -> RTCRtpCodecCapability {
+    RTCRtpCodecCapability {
+        mime_type: MIME_TYPE_OPUS.to_owned(),
+        clock_rate: SAMPLE_RATE,
+        channels: 2,
+        sdp_fmtp_line: opus_fmtp(config),
+        rtcp_feedback: vec![],
+    }
+}
+
+fn video_feedback() -> Vec<RTCPFeedback> {
+    [
+        ("goog-remb", ""),
+        ("ccm", "fir"),
+        ("nack", ""),
+        ("nack", "pli"),
+    ]
+    .into_iter()
+    .map(|(typ, parameter)| RTCPFeedback {
+        typ: typ.to_owned(),
+        parameter: parameter.to_owned(),
+    })
+    .collect()
+}
+
+fn video_parameters(codec: VideoCodec) -> (&'static str, u8, &'static str) {
+    match codec {
+        VideoCodec::VP8 => (MIME_TYPE_VP8, 96, ""),
+        VideoCodec::H264 => (
+            MIME_TYPE_H264,
+            102,
+            "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42e01f",
+        ),
+    }
+}
+
+/// Shared by the registered codec and the local video track.
+pub fn video_capability(codec: VideoCodec) -> RTCRtpCodecCapability {
+    let (mime_type, _, sdp_fmtp_line) = video_parameters(codec);
+
+    RTCRtpCodecCapability {
+        mime_type: mime_type.to_owned(),
+        clock_rate: VIDEO_CLOCK_RATE,
+        channels: 0,
+        sdp_fmtp_line: sdp_fmtp_line.to_owned(),
+        rtcp_feedback: video_feedback(),
+    }
+}
+
+fn video_codec(codec: VideoCodec) -> RTCRtpCodecParameters {
+    RTCRtpCodecParameters {
+        capability: video_capability(codec),
+        payload_type: video_parameters(codec).1,
+        ..Default::default()
+    }
+}
+
+/// Registers only the codecs the client handles, instead of the webrtc-rs
+/// defaults, so the SDP we send matches what we actually send and decode.
+///
+/// Opus comes first since it is what we send. RED is only something we can
+/// receive, so it must not become the preferred audio codec.
+pub fn register_codecs(m: &mut MediaEngine, config: &CodecConfig) -> Result<()> {
+    m.register_codec(
+        RTCRtpCodecParameters {
+            capability: opus_capability(config),
+            payload_type: OPUS_PAYLOAD_TYPE,
+            ..Default::default()
+        },
+        RTPCodecType::Audio,
+    )?;
+
+    if config.red {
+        m.register_codec(
+            RTCRtpCodecParameters {
+                capability: RTCRtpCodecCapability {
+                    mime_type: MIME_TYPE_RED.to_owned(),
+                    clock_rate: SAMPLE_RATE,
+                    channels: 2,
+                    sdp_fmtp_line: format!("{}/{}", OPUS_PAYLOAD_TYPE, OPUS_PAYLOAD_TYPE),
+                    rtcp_feedback: vec![],
+                },
+                payload_type: RED_PAYLOAD_TYPE,
+                ..Default::default()
+            },
+            RTPCodecType::Audio,
+        )?;
+    }
+
+    for codec in &config.video {
+        m.register_codec(video_codec(*codec), RTPCodecType::Video)?;
+    }
+
+    Ok(())
+}
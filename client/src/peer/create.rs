@@ -1,52 +1,236 @@
-use anyhow::Result;
-use std::sync::mpsc::Receiver;
+use anyhow::{bail, Result};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+
+use crate::config::{CodecConfig, MdnsMode, NetworkConfig};
+use crate::peer::bandwidth::{CongestionControlBuilder, TargetBitrate};
+use crate::peer::codecs::register_codecs;
+use crate::peer::ice_servers::is_relay;
+use crate::peer::sync::{MediaClock, SenderReportsBuilder, StreamSync};
 use webrtc::{
     api::{
-        interceptor_registry::register_default_interceptors, media_engine::MediaEngine, APIBuilder,
+        interceptor_registry::{configure_nack, configure_twcc},
+        media_engine::MediaEngine,
+        setting_engine::SettingEngine,
+        APIBuilder,
+    },
+    ice::{
+        mdns::MulticastDnsMode,
+        network_type::NetworkType,
+        udp_network::{EphemeralUDP, UDPNetwork},
+    },
+    ice_transport::{ice_candidate_type::RTCIceCandidateType, ice_server::RTCIceServer},
+    interceptor::{registry::Registry, report::receiver::ReceiverReport},
+    peer_connection::{
+        configuration::RTCConfiguration, policy::ice_transport_policy::RTCIceTransportPolicy,
+        RTCPeerConnection,
     },
-    ice_transport::ice_server::RTCIceServer,
-    interceptor::registry::Registry,
-    peer_connection::{configuration::RTCConfiguration, RTCPeerConnection},
 };
 
+/// Interfaces match by name prefix, an empty include list allows all.
+fn interface_allowed(include: &[String], exclude: &[String], name: &str) -> bool {
+    (include.is_empty() || include.iter().any(|prefix| name.starts_with(prefix)))
+        && !exclude.iter().any(|prefix| name.starts_with(prefix))
+}
+
+fn network_types(network: &NetworkConfig) -> Result<Vec<NetworkType>> {
+    let mut network_types = Vec::new();
+    if network.ipv4 {
+        network_types.push(NetworkType::Udp4);
+    }
+    if network.ipv6 {
+        network_types.push(NetworkType::Udp6);
+    }
+    if network_types.is_empty() {
+        bail!("Both IPv4 and IPv6 are disabled in the network config");
+    }
+
+    Ok(network_types)
+}
+
+fn create_setting_engine(network: &NetworkConfig) -> Result<SettingEngine> {
+    let mut s = SettingEngine::default();
+
+    if let Some(ports) = network.udp_ports {
+        s.set_udp_network(UDPNetwork::Ephemeral(EphemeralUDP::new(
+            ports.min, ports.max,
+        )?));
+    }
+
+    if !network.interfaces.is_empty() || !network.exclude_interfaces.is_empty() {
+        let include = network.interfaces.clone();
+        let exclude = network.exclude_interfaces.clone();
+
+        s.set_interface_filter(Box::new(move |name: &str| {
+            interface_allowed(&include, &exclude, name)
+        }));
+    }
+
+    s.set_network_types(network_types(network)?);
+
+    s.set_ice_multicast_dns_mode(match network.mdns {
+        MdnsMode::Disabled => MulticastDnsMode::Disabled,
+        MdnsMode::QueryOnly => MulticastDnsMode::QueryOnly,
+        MdnsMode::QueryAndGather => MulticastDnsMode::QueryAndGather,
+    });
+
+    if !network.nat_1to1_ips.is_empty() {
+        s.set_nat_1to1_ips(network.nat_1to1_ips.clone(), RTCIceCandidateType::Host);
+    }
+
+    Ok(s)
+}
+
+fn has_relay(ice_servers: &[RTCIceServer]) -> bool {
+    ice_servers
+        .iter()
+        .flat_map(|server| &server.urls)
+        .any(|url| is_relay(url))
+}
+
+fn ice_transport_policy(relay_only: bool, ice_servers: &[RTCIceServer]) -> RTCIceTransportPolicy {
+    if !relay_only {
+        return RTCIceTransportPolicy::All;
+    }
+
+    if !has_relay(ice_servers) {
+        eprintln!(
+            "Relay-only mode is on but no UDP TURN server is configured, peers won't connect"
+        );
+    }
+
+    RTCIceTransportPolicy::Relay
+}
+
 pub async fn create_peer_connection(
-    _rx_audio: Arc<Mutex<Receiver<Vec<u8>>>>,
+    network: &NetworkConfig,
+    codecs: &CodecConfig,
+    ice_servers: Vec<RTCIceServer>,
+    target_bitrate: Arc<TargetBitrate>,
+    clock: Arc<MediaClock>,
+    sync: Arc<StreamSync>,
 ) -> Result<Arc<RTCPeerConnection>> {
     let mut m = MediaEngine::default();
-    m.register_default_codecs()?;
+    register_codecs(&mut m, codecs)?;
 
     let mut registry = Registry::new();
 
-    registry = register_default_interceptors(registry, &mut m)?;
+    // The default set, except TWCC runs on the sending side too so the
+    // congestion controller gets feedback. It has to come before TWCC to see
+    // the sequence numbers TWCC stamps on outgoing packets. Sender reports
+    // are our own, to put capture time in them.
+    registry = configure_nack(registry, &mut m);
+    registry.add(Box::new(ReceiverReport::builder()));
+    registry.add(Box::new(SenderReportsBuilder::new(clock, sync)));
+    registry.add(Box::new(CongestionControlBuilder::new(target_bitrate)));
+    registry = configure_twcc(registry, &mut m)?;
 
     let api = APIBuilder::new()
         .with_media_engine(m)
         .with_interceptor_registry(registry)
+        .with_setting_engine(create_setting_engine(network)?)
         .build();
 
     let config = RTCConfiguration {
-        ice_servers: vec![RTCIceServer {
-            urls: vec![
-                "stun:localhost:3479".to_owned(),
-                "stun:localhost:3478".to_owned(),
-            ],
-            ..Default::default()
-        }],
+        ice_transport_policy: ice_transport_policy(network.relay_only, &ice_servers),
+        ice_servers,
         ..Default::default()
     };
 
-    let peer_connection = api.new_peer_connection(config).await?;
+    let peer_connection = Arc::new(api.new_peer_connection(config).await?);
+
+    Ok(peer_connection)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PortRange;
 
-    peer_connection
-        .add_transceiver_from_kind(
-            webrtc::rtp_transceiver::rtp_codec::RTPCodecType::Audio,
-            None,
-        )
-        .await?;
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
 
-    let peer_connection = Arc::new(peer_connection);
+    #[test]
+    fn interfaces_match_by_prefix() {
+        let include = names(&["eth", "wl"]);
+        let exclude = names(&["docker", "wlx"]);
 
-    Ok(peer_connection)
+        assert!(interface_allowed(&include, &exclude, "eth0"));
+        assert!(interface_allowed(&include, &exclude, "wlp2s0"));
+        assert!(!interface_allowed(&include, &exclude, "wlx0013ef"));
+        assert!(!interface_allowed(&include, &exclude, "tun0"));
+
+        // No include list allows everything that isn't excluded.
+        assert!(interface_allowed(&[], &exclude, "tun0"));
+        assert!(!interface_allowed(&[], &exclude, "docker0"));
+    }
+
+    #[test]
+    fn ip_families_pick_the_network_types() {
+        let network = |ipv4, ipv6| NetworkConfig {
+            ipv4,
+            ipv6,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            network_types(&network(true, true)).unwrap(),
+            [NetworkType::Udp4, NetworkType::Udp6]
+        );
+        assert_eq!(
+            network_types(&network(false, true)).unwrap(),
+            [NetworkType::Udp6]
+        );
+        assert!(network_types(&network(false, false)).is_err());
+    }
+
+    #[test]
+    fn inverted_port_ranges_are_rejected() {
+        let network = NetworkConfig {
+            udp_ports: Some(PortRange {
+                min: 50100,
+                max: 50000,
+            }),
+            ..Default::default()
+        };
+
+        assert!(create_setting_engine(&network).is_err());
+        assert!(create_setting_engine(&NetworkConfig::default()).is_ok());
+    }
+
+    fn ice_server(urls: &[&str]) -> RTCIceServer {
+        RTCIceServer {
+            urls: urls.iter().map(|url| url.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn relay_only_sets_the_relay_policy() {
+        let servers = [ice_server(&["turn:turn.example.com:3478"])];
+
+        assert_eq!(
+            ice_transport_policy(true, &servers),
+            RTCIceTransportPolicy::Relay
+        );
+        assert_eq!(
+            ice_transport_policy(false, &servers),
+            RTCIceTransportPolicy::All
+        );
+        // Without a relay nothing can connect, but the policy still holds.
+        assert_eq!(
+            ice_transport_policy(true, &[]),
+            RTCIceTransportPolicy::Relay
+        );
+    }
+
+    #[test]
+    fn only_turn_urls_count_as_relays() {
+        assert!(!has_relay(&[]));
+        assert!(!has_relay(&[ice_server(&["stun:stun.l.google.com:19302"])]));
+        assert!(has_relay(&[
+            ice_server(&["stun:stun.l.google.com:19302"]),
+            ice_server(&["turn:turn.example.com:3478"]),
+        ]));
+    }
 }
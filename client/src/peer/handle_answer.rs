@@ -1,32 +1,23 @@
+use crate::peer::session::PeerSession;
 use anyhow::Result;
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
-use webrtc::peer_connection::RTCPeerConnection;
 
 pub async fn handle_answer(
     from_user: String,
     sdp: String,
-    peer_connections: Arc<Mutex<HashMap<String, Arc<RTCPeerConnection>>>>,
+    peer_connections: Arc<Mutex<HashMap<String, Arc<PeerSession>>>>,
 ) -> Result<()> {
-    let peer_connections = peer_connections.lock().await;
+    let answer = RTCSessionDescription::answer(sdp)?;
 
-    let answer = RTCSessionDescription::answer(sdp).unwrap();
+    let session = peer_connections.lock().await.get(&from_user).cloned();
 
-    let peer_connection = peer_connections.get(&from_user);
-
-    if peer_connection.is_none() {
+    let Some(session) = session else {
         println!("Peer connection not found for user {:?}", from_user);
         return Ok(());
-    }
-
-    let peer_connection = peer_connection.unwrap();
-
-    peer_connection
-        .set_remote_description(answer)
-        .await
-        .unwrap();
+    };
 
-    Ok(())
+    session.accept_answer(answer).await
 }
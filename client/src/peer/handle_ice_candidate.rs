@@ -1,26 +1,86 @@
+use crate::peer::session::PeerSession;
 use anyhow::Result;
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
 
+/// Hands the candidate to the session for `from_user`, or buffers it until
+/// their offer arrives and the session is created.
 pub async fn handle_ice_candidate(
     from_user: String,
     candidate_str: String,
+    peer_connections: Arc<Mutex<HashMap<String, Arc<PeerSession>>>>,
     ice_candidates: Arc<Mutex<HashMap<String, Vec<RTCIceCandidateInit>>>>,
 ) -> Result<()> {
-    let candidate: RTCIceCandidateInit = serde_json::from_str(&candidate_str).unwrap();
-    let mut ice_candidates = ice_candidates.lock().await;
+    let candidate: RTCIceCandidateInit = serde_json::from_str(&candidate_str)?;
 
-    match ice_candidates.get_mut(&from_user) {
-        Some(user_candidates) => {
-            user_candidates.push(candidate);
-        }
+    let session = peer_connections.lock().await.get(&from_user).cloned();
 
+    match session {
+        Some(session) => session.add_remote_candidate(candidate).await,
         None => {
-            ice_candidates.insert(from_user, vec![candidate]);
+            ice_candidates
+                .lock()
+                .await
+                .entry(from_user)
+                .or_default()
+                .push(candidate);
+
+            Ok(())
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CANDIDATE: &str = "candidate:1 1 udp 2130706431 192.168.1.2 50000 typ host";
+
+    fn candidate_json(candidate: &str) -> String {
+        serde_json::to_string(&RTCIceCandidateInit {
+            candidate: candidate.to_owned(),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn candidates_are_buffered_until_the_session_exists() {
+        let peer_connections = Arc::new(Mutex::new(HashMap::new()));
+        let ice_candidates = Arc::new(Mutex::new(HashMap::new()));
 
-    Ok(())
+        for candidate in [CANDIDATE, ""] {
+            handle_ice_candidate(
+                "bob".to_owned(),
+                candidate_json(candidate),
+                peer_connections.clone(),
+                ice_candidates.clone(),
+            )
+            .await
+            .unwrap();
+        }
+
+        let buffered = ice_candidates.lock().await.remove("bob").unwrap();
+        let buffered: Vec<_> = buffered.iter().map(|c| c.candidate.as_str()).collect();
+        // In order, the end of candidates included.
+        assert_eq!(buffered, [CANDIDATE, ""]);
+    }
+
+    #[tokio::test]
+    async fn malformed_candidates_are_rejected() {
+        let ice_candidates = Arc::new(Mutex::new(HashMap::new()));
+
+        let result = handle_ice_candidate(
+            "bob".to_owned(),
+            "not json".to_owned(),
+            Arc::new(Mutex::new(HashMap::new())),
+            ice_candidates.clone(),
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert!(ice_candidates.lock().await.is_empty());
+    }
 }
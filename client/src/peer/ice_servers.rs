@@ -0,0 +1,299 @@
+use std::sync::RwLock;
+use webrtc::ice_transport::ice_credential_type::RTCIceCredentialType;
+use webrtc::ice_transport::ice_server::RTCIceServer;
+
+use crate::config::IceServerConfig;
+
+/// ICE servers for new peer connections: the ones from the config, plus
+/// short-lived TURN credentials pushed by the signaling server, which are
+/// replaced every time they are refreshed.
+pub struct IceServers {
+    configured: Vec<RTCIceServer>,
+    pushed: RwLock<Vec<RTCIceServer>>,
+}
+
+/// Whether a TURN url asks for UDP, which is also what no `transport`
+/// parameter means.
+fn uses_udp(url: &str) -> bool {
+    let Some((_, query)) = url.split_once('?') else {
+        return true;
+    };
+
+    query
+        .split('&')
+        .filter_map(|param| param.strip_prefix("transport="))
+        .all(|transport| transport.eq_ignore_ascii_case("udp"))
+}
+
+/// Whether webrtc-rs can use `url`. ICE only gathers over UDP, so TCP and
+/// TLS relays (`?transport=tcp`, `turns:`) and `stuns:` are left out.
+pub fn is_supported(url: &str) -> bool {
+    url.starts_with("stun:") || is_relay(url)
+}
+
+/// Whether `url` gives us relay candidates.
+pub fn is_relay(url: &str) -> bool {
+    url.starts_with("turn:") && uses_udp(url)
+}
+
+fn to_rtc(server: &IceServerConfig) -> Option<RTCIceServer> {
+    let (urls, unsupported): (Vec<_>, Vec<_>) = server
+        .urls
+        .iter()
+        .cloned()
+        .partition(|url| is_supported(url));
+
+    for url in unsupported {
+        eprintln!(
+            "Ignoring ICE server {}: only stun: and UDP turn: urls are supported",
+            url
+        );
+    }
+
+    if urls.is_empty() {
+        return None;
+    }
+
+    Some(RTCIceServer {
+        urls,
+        username: server.username.clone(),
+        credential: server.credential.clone(),
+        // webrtc-rs turns down TURN servers without a credential type.
+        credential_type: RTCIceCredentialType::Password,
+    })
+}
+
+impl IceServers {
+    pub fn new(configured: &[IceServerConfig]) -> Self {
+        Self {
+            configured: configured.iter().filter_map(to_rtc).collect(),
+            pushed: RwLock::new(Vec::new()),
+        }
+    }
+
+    pub fn set_pushed(&self, servers: &[IceServerConfig]) {
+        *self.pushed.write().unwrap() = servers.iter().filter_map(to_rtc).collect();
+    }
+
+    pub fn get(&self) -> Vec<RTCIceServer> {
+        let mut servers = self.configured.clone();
+        servers.extend(self.pushed.read().unwrap().iter().cloned());
+        servers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+    use tokio::sync::mpsc;
+    use webrtc::api::setting_engine::SettingEngine;
+    use webrtc::api::APIBuilder;
+    use webrtc::ice::mdns::MulticastDnsMode;
+    use webrtc::peer_connection::configuration::RTCConfiguration;
+    use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+    use webrtc::peer_connection::policy::ice_transport_policy::RTCIceTransportPolicy;
+    use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+    use webrtc::peer_connection::RTCPeerConnection;
+    use webrtc::turn::auth::{generate_auth_key, AuthHandler};
+    use webrtc::turn::relay::relay_static::RelayAddressGeneratorStatic;
+    use webrtc::turn::server::config::{ConnConfig, ServerConfig};
+    use webrtc::turn::server::Server;
+    use webrtc::util::vnet::net::Net;
+
+    const REALM: &str = "deezcord";
+    const USERNAME: &str = "user";
+    const CREDENTIAL: &str = "secret";
+
+    struct StaticAuth;
+
+    impl AuthHandler for StaticAuth {
+        fn auth_handle(
+            &self,
+            username: &str,
+            realm: &str,
+            _src_addr: SocketAddr,
+        ) -> Result<Vec<u8>, webrtc::turn::Error> {
+            if username != USERNAME {
+                return Err(webrtc::turn::Error::ErrNoSuchUser);
+            }
+            Ok(generate_auth_key(username, realm, CREDENTIAL))
+        }
+    }
+
+    /// A local TURN server relaying on the loopback address.
+    async fn start_turn() -> (Server, SocketAddr) {
+        let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let address = conn.local_addr().unwrap();
+
+        let server = Server::new(ServerConfig {
+            conn_configs: vec![ConnConfig {
+                conn,
+                relay_addr_generator: Box::new(RelayAddressGeneratorStatic {
+                    relay_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    address: "127.0.0.1".to_owned(),
+                    net: Arc::new(Net::new(None)),
+                }),
+            }],
+            realm: REALM.to_owned(),
+            auth_handler: Arc::new(StaticAuth),
+            channel_bind_timeout: Duration::from_secs(0),
+            alloc_close_notify: None,
+        })
+        .await
+        .unwrap();
+
+        (server, address)
+    }
+
+    /// A bare connection that may only use the relays in `urls`.
+    async fn relay_only_peer(urls: Vec<String>) -> Arc<RTCPeerConnection> {
+        let servers = IceServers::new(&[IceServerConfig {
+            urls,
+            username: USERNAME.to_owned(),
+            credential: CREDENTIAL.to_owned(),
+        }]);
+
+        let mut settings = SettingEngine::default();
+        settings.set_ice_multicast_dns_mode(MulticastDnsMode::Disabled);
+        let api = APIBuilder::new().with_setting_engine(settings).build();
+
+        let config = RTCConfiguration {
+            ice_servers: servers.get(),
+            ice_transport_policy: RTCIceTransportPolicy::Relay,
+            ..Default::default()
+        };
+
+        Arc::new(api.new_peer_connection(config).await.unwrap())
+    }
+
+    /// Sets the description and waits for gathering, so the returned one
+    /// carries every candidate.
+    async fn gather(
+        peer: &RTCPeerConnection,
+        description: RTCSessionDescription,
+    ) -> RTCSessionDescription {
+        let mut gathered = peer.gathering_complete_promise().await;
+        peer.set_local_description(description).await.unwrap();
+        let _ = gathered.recv().await;
+        peer.local_description().await.unwrap()
+    }
+
+    fn candidate_types(sdp: &str) -> Vec<&str> {
+        sdp.lines()
+            .filter(|line| line.starts_with("a=candidate:"))
+            .filter_map(|line| line.split(" typ ").nth(1))
+            .filter_map(|rest| rest.split_whitespace().next())
+            .collect()
+    }
+
+    fn server(urls: &[&str]) -> IceServerConfig {
+        IceServerConfig {
+            urls: urls.iter().map(|url| url.to_string()).collect(),
+            username: "user".to_owned(),
+            credential: "secret".to_owned(),
+        }
+    }
+
+    #[test]
+    fn only_udp_turn_is_a_relay() {
+        assert!(is_relay("turn:turn.example.com:3478"));
+        assert!(is_relay("turn:turn.example.com:3478?transport=udp"));
+        assert!(!is_relay("turn:turn.example.com:3478?transport=tcp"));
+        assert!(!is_relay("turns:turn.example.com:5349"));
+        assert!(!is_relay("stun:stun.example.com:3478"));
+    }
+
+    #[test]
+    fn unsupported_urls_are_dropped() {
+        let servers = IceServers::new(&[
+            server(&["turns:turn.example.com:5349", "turn:turn.example.com"]),
+            server(&["turn:turn.example.com?transport=tcp"]),
+            server(&["stuns:stun.example.com"]),
+        ]);
+
+        let servers = servers.get();
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].urls, vec!["turn:turn.example.com".to_owned()]);
+        assert_eq!(servers[0].username, "user");
+        assert_eq!(servers[0].credential_type, RTCIceCredentialType::Password);
+    }
+
+    #[test]
+    fn pushed_servers_replace_each_other() {
+        let servers = IceServers::new(&[server(&["stun:stun.example.com"])]);
+
+        servers.set_pushed(&[server(&["turn:a.example.com"])]);
+        servers.set_pushed(&[server(&["turn:b.example.com"])]);
+
+        let urls: Vec<_> = servers.get().into_iter().flat_map(|s| s.urls).collect();
+        assert_eq!(urls, vec!["stun:stun.example.com", "turn:b.example.com"]);
+    }
+
+    #[tokio::test]
+    async fn relay_only_peers_connect_through_turn() {
+        let (server, turn) = start_turn().await;
+        let offerer = relay_only_peer(vec![format!("turn:{}", turn)]).await;
+        let answerer = relay_only_peer(vec![format!("turn:{}", turn)]).await;
+
+        let (connected_tx, mut connected_rx) = mpsc::channel(1);
+        offerer.on_peer_connection_state_change(Box::new(move |state| {
+            if state == RTCPeerConnectionState::Connected {
+                let _ = connected_tx.try_send(());
+            }
+            Box::pin(async {})
+        }));
+
+        offerer.create_data_channel("data", None).await.unwrap();
+
+        let offer = offerer.create_offer(None).await.unwrap();
+        let offer = gather(&offerer, offer).await;
+        answerer
+            .set_remote_description(offer.clone())
+            .await
+            .unwrap();
+
+        let answer = answerer.create_answer(None).await.unwrap();
+        let answer = gather(&answerer, answer).await;
+        offerer
+            .set_remote_description(answer.clone())
+            .await
+            .unwrap();
+
+        for description in [&offer, &answer] {
+            let types = candidate_types(&description.sdp);
+            assert!(!types.is_empty());
+            assert!(types.iter().all(|typ| *typ == "relay"), "{:?}", types);
+        }
+
+        tokio::time::timeout(Duration::from_secs(10), connected_rx.recv())
+            .await
+            .expect("peers did not connect through the relay");
+
+        offerer.close().await.unwrap();
+        answerer.close().await.unwrap();
+        server.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn tcp_and_tls_relays_give_no_candidates() {
+        let (server, turn) = start_turn().await;
+        let peer = relay_only_peer(vec![
+            format!("turns:{}", turn),
+            format!("turn:{}?transport=tcp", turn),
+        ])
+        .await;
+
+        peer.create_data_channel("data", None).await.unwrap();
+        let offer = peer.create_offer(None).await.unwrap();
+        let offer = gather(&peer, offer).await;
+
+        assert!(candidate_types(&offer.sdp).is_empty());
+
+        peer.close().await.unwrap();
+        server.close().await.unwrap();
+    }
+}
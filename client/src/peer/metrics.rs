@@ -0,0 +1,123 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::Mutex;
+
+use crate::peer::session::PeerSession;
+use crate::peer::stats::PeerStats;
+
+/// Serves the latest stats of every peer in the Prometheus text format, on
+/// any path.
+pub async fn serve_metrics(
+    address: String,
+    peer_connections: Arc<Mutex<HashMap<String, Arc<PeerSession>>>>,
+) -> Result<()> {
+    let listener = TcpListener::bind(&address).await?;
+
+    println!("\n\rServing metrics on http://{}/metrics", address);
+
+    loop {
+        let (mut socket, _) = listener.accept().await?;
+        let peer_connections = peer_connections.clone();
+
+        tokio::spawn(async move {
+            // The request itself does not matter, only wait for it.
+            let mut request = [0u8; 1024];
+            let _ = socket.read(&mut request).await;
+
+            let body = render_metrics(peer_connections).await;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\n\
+                 Content-Type: text/plain; version=0.0.4\r\n\
+                 Content-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+
+            let _ = socket.write_all(response.as_bytes()).await;
+        });
+    }
+}
+
+async fn render_metrics(peer_connections: Arc<Mutex<HashMap<String, Arc<PeerSession>>>>) -> String {
+    let sessions: Vec<_> = peer_connections.lock().await.values().cloned().collect();
+    let stats: Vec<_> = sessions
+        .iter()
+        .map(|session| (session.other_id.as_str(), session.stats()))
+        .collect();
+
+    let mut body = String::new();
+
+    let mut metric = |name: &str, help: &str, value: &dyn Fn(&PeerStats) -> Option<f64>| {
+        let _ = writeln!(body, "# HELP {} {}", name, help);
+        let _ = writeln!(body, "# TYPE {} gauge", name);
+
+        for (peer, stats) in &stats {
+            if let Some(value) = value(stats) {
+                let _ = writeln!(body, "{}{{peer=\"{}\"}} {}", name, peer, value);
+            }
+        }
+    };
+
+    metric(
+        "deezcord_peer_rtt_seconds",
+        "Round trip time of the selected candidate pair",
+        &|stats| stats.rtt.map(|rtt| rtt.as_secs_f64()),
+    );
+    metric(
+        "deezcord_peer_jitter_seconds",
+        "Interarrival jitter of received audio",
+        &|stats| Some(stats.jitter.as_secs_f64()),
+    );
+    metric(
+        "deezcord_peer_loss_ratio",
+        "Share of received packets lost",
+        &|stats| Some(stats.loss),
+    );
+    metric(
+        "deezcord_peer_packets_received",
+        "Audio packets received",
+        &|stats| Some(stats.packets_received as f64),
+    );
+    metric(
+        "deezcord_peer_packets_lost",
+        "Audio packets lost on the way to us",
+        &|stats| Some(stats.packets_lost as f64),
+    );
+    metric(
+        "deezcord_peer_remote_loss_ratio",
+        "Share of sent packets the peer reports lost",
+        &|stats| stats.remote_loss,
+    );
+    metric(
+        "deezcord_peer_concealment_ratio",
+        "Share of received audio frames missing and not recovered",
+        &|stats| Some(stats.concealment),
+    );
+    metric(
+        "deezcord_peer_mos",
+        "Estimated mean opinion score of received audio",
+        &|stats| stats.quality.map(|quality| quality.mos),
+    );
+    metric(
+        "deezcord_peer_send_bits_per_second",
+        "Outgoing bitrate",
+        &|stats| Some(stats.send_bitrate as f64),
+    );
+    metric(
+        "deezcord_peer_receive_bits_per_second",
+        "Incoming bitrate",
+        &|stats| Some(stats.receive_bitrate as f64),
+    );
+    metric(
+        "deezcord_peer_target_bits_per_second",
+        "Outgoing bitrate allowed by congestion control",
+        &|stats| Some(stats.target_bitrate as f64),
+    );
+
+    body
+}
@@ -1,5 +1,16 @@
+pub mod bandwidth;
+pub mod codecs;
 pub mod connect_peer;
 pub mod create;
 pub mod handle_answer;
 pub mod handle_ice_candidate;
 pub mod handle_offer;
+pub mod ice_servers;
+pub mod metrics;
+pub mod quality;
+pub mod reconnect;
+pub mod session;
+pub mod stats;
+pub mod sync;
+pub mod teardown;
+pub mod tracks;
@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::peer::stats::PeerStats;
+
+/// Delay added by Opus framing and decoding, on top of the network.
+const CODEC_DELAY_MS: f64 = 20.0;
+/// R factor of a perfect narrowband call, the E-model default.
+const MAX_R: f64 = 93.2;
+/// R factor lost per percent of lost or concealed audio.
+const LOSS_PENALTY: f64 = 2.5;
+const GOOD_MOS: f64 = 4.0;
+const FAIR_MOS: f64 = 3.6;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Grade {
+    Good,
+    Fair,
+    Poor,
+}
+
+impl fmt::Display for Grade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Grade::Good => "good",
+            Grade::Fair => "fair",
+            Grade::Poor => "poor",
+        })
+    }
+}
+
+/// How a participant likely hears us, or we hear them.
+#[derive(Clone, Copy, Debug)]
+pub struct Quality {
+    /// Estimated mean opinion score, from 1 to 4.5
+    pub mos: f64,
+    pub grade: Grade,
+}
+
+impl fmt::Display for Quality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (MOS {:.1})", self.grade, self.mos)
+    }
+}
+
+impl Quality {
+    /// A simplified ITU-T G.107 E-model: one-way delay from the round trip
+    /// time and jitter, and impairment from lost or concealed audio,
+    /// whichever is worse.
+    pub fn estimate(rtt: Option<Duration>, jitter: Duration, loss: f64, concealment: f64) -> Self {
+        let rtt_ms = rtt.unwrap_or_default().as_secs_f64() * 1000.0;
+        let jitter_ms = jitter.as_secs_f64() * 1000.0;
+        let delay = rtt_ms / 2.0 + jitter_ms * 2.0 + CODEC_DELAY_MS;
+
+        let delay_impairment = if delay < 160.0 {
+            delay / 40.0
+        } else {
+            (delay - 120.0) / 10.0
+        };
+        let loss_impairment = loss.max(concealment) * 100.0 * LOSS_PENALTY;
+
+        let r = (MAX_R - delay_impairment - loss_impairment).clamp(0.0, 100.0);
+        Self::from_mos((1.0 + 0.035 * r + 7e-6 * r * (r - 60.0) * (100.0 - r)).clamp(1.0, 4.5))
+    }
+
+    pub fn from_mos(mos: f64) -> Self {
+        let grade = if mos >= GOOD_MOS {
+            Grade::Good
+        } else if mos >= FAIR_MOS {
+            Grade::Fair
+        } else {
+            Grade::Poor
+        };
+
+        Self { mos, grade }
+    }
+}
+
+#[derive(Default)]
+struct ParticipantReport {
+    samples: u32,
+    mos_total: f64,
+    worst_mos: Option<f64>,
+    grades: HashMap<Grade, u32>,
+    rtt_total: Duration,
+    rtt_samples: u32,
+    worst_jitter: Duration,
+    loss_total: f64,
+    concealment_total: f64,
+}
+
+/// Quality of every participant over a whole call, printed when we leave.
+#[derive(Default)]
+pub struct CallReport {
+    started: Mutex<Option<Instant>>,
+    participants: Mutex<HashMap<String, ParticipantReport>>,
+}
+
+impl CallReport {
+    /// Adds one stats collection of the connection to `user_id`.
+    pub fn record(&self, user_id: &str, stats: &PeerStats) {
+        let Some(quality) = stats.quality else {
+            return;
+        };
+
+        self.started
+            .lock()
+            .unwrap()
+            .get_or_insert_with(Instant::now);
+
+        let mut participants = self.participants.lock().unwrap();
+        let report = participants.entry(user_id.to_owned()).or_default();
+
+        report.samples += 1;
+        report.mos_total += quality.mos;
+        report.worst_mos = Some(
+            report
+                .worst_mos
+                .map_or(quality.mos, |mos| mos.min(quality.mos)),
+        );
+        *report.grades.entry(quality.grade).or_default() += 1;
+
+        if let Some(rtt) = stats.rtt {
+            report.rtt_total += rtt;
+            report.rtt_samples += 1;
+        }
+        report.worst_jitter = report.worst_jitter.max(stats.jitter);
+        report.loss_total += stats.loss;
+        report.concealment_total += stats.concealment;
+    }
+
+    /// The report of the call so far, starting a new one. `None` when no
+    /// audio was received.
+    pub fn finish(&self) -> Option<String> {
+        let started = self.started.lock().unwrap().take()?;
+        let participants = std::mem::take(&mut *self.participants.lock().unwrap());
+
+        let mut out = format!("Call report, {} minutes:", started.elapsed().as_secs() / 60);
+
+        let mut participants: Vec<_> = participants.into_iter().collect();
+        participants.sort_by(|a, b| a.0.cmp(&b.0));
+
+        for (user_id, report) in participants {
+            let samples = report.samples.max(1) as f64;
+            let average = Quality::from_mos(report.mos_total / samples);
+            let share =
+                |grade: Grade| *report.grades.get(&grade).unwrap_or(&0) as f64 / samples * 100.0;
+
+            out.push_str(&format!(
+                "\n\r- {}: {}, worst MOS {:.1}, {:.0}% good / {:.0}% fair / {:.0}% poor",
+                user_id,
+                average,
+                report.worst_mos.unwrap_or(average.mos),
+                share(Grade::Good),
+                share(Grade::Fair),
+                share(Grade::Poor),
+            ));
+
+            match report.rtt_total.checked_div(report.rtt_samples) {
+                Some(rtt) => out.push_str(&format!("\n\r  rtt {}ms", rtt.as_millis())),
+                None => out.push_str("\n\r  rtt -"),
+            }
+
+            out.push_str(&format!(
+                ", worst jitter {:.1}ms, loss {:.1}%, concealed {:.1}%",
+                report.worst_jitter.as_secs_f64() * 1000.0,
+                report.loss_total / samples * 100.0,
+                report.concealment_total / samples * 100.0,
+            ));
+        }
+
+        Some(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grade(rtt_ms: u64, loss: f64) -> Grade {
+        Quality::estimate(
+            Some(Duration::from_millis(rtt_ms)),
+            Duration::ZERO,
+            loss,
+            0.0,
+        )
+        .grade
+    }
+
+    #[test]
+    fn clean_calls_are_good() {
+        let quality = Quality::estimate(Some(Duration::from_millis(20)), Duration::ZERO, 0.0, 0.0);
+
+        assert_eq!(quality.grade, Grade::Good);
+        assert!(quality.mos > 4.3 && quality.mos <= 4.5);
+    }
+
+    #[test]
+    fn loss_lowers_the_grade() {
+        assert_eq!(grade(20, 0.05), Grade::Good);
+        assert_eq!(grade(20, 0.06), Grade::Fair);
+        assert_eq!(grade(20, 0.1), Grade::Poor);
+    }
+
+    #[test]
+    fn delay_lowers_the_grade() {
+        assert_eq!(grade(200, 0.0), Grade::Good);
+        assert_eq!(grade(600, 0.0), Grade::Fair);
+        assert_eq!(grade(800, 0.0), Grade::Poor);
+    }
+
+    #[test]
+    fn jitter_counts_as_delay() {
+        let jittery = Quality::estimate(None, Duration::from_millis(200), 0.0, 0.0);
+        let delayed = Quality::estimate(Some(Duration::from_millis(800)), Duration::ZERO, 0.0, 0.0);
+
+        assert!((jittery.mos - delayed.mos).abs() < 1e-9);
+    }
+
+    #[test]
+    fn concealment_counts_like_loss() {
+        let concealed = Quality::estimate(None, Duration::ZERO, 0.01, 0.1);
+        let lost = Quality::estimate(None, Duration::ZERO, 0.1, 0.0);
+
+        assert_eq!(concealed.mos, lost.mos);
+    }
+
+    #[test]
+    fn mos_stays_in_range() {
+        let quality = Quality::estimate(Some(Duration::from_secs(5)), Duration::ZERO, 1.0, 1.0);
+
+        assert_eq!(quality.mos, 1.0);
+        assert_eq!(quality.grade, Grade::Poor);
+    }
+
+    #[test]
+    fn grades_start_at_their_threshold() {
+        assert_eq!(Quality::from_mos(GOOD_MOS).grade, Grade::Good);
+        assert_eq!(Quality::from_mos(GOOD_MOS - 0.01).grade, Grade::Fair);
+        assert_eq!(Quality::from_mos(FAIR_MOS).grade, Grade::Fair);
+        assert_eq!(Quality::from_mos(FAIR_MOS - 0.01).grade, Grade::Poor);
+    }
+}
@@ -0,0 +1,292 @@
+use crate::peer::session::{PeerContext, PeerRole, PeerSession};
+use crate::peer::teardown::close_peer;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Mutex};
+use tokio::time::{sleep, timeout};
+use webrtc::ice_transport::ice_connection_state::RTCIceConnectionState;
+
+/// How long a `Disconnected` connection gets to come back on its own.
+const DISCONNECT_GRACE: Duration = Duration::from_secs(3);
+/// How long an ICE restart or a rebuilt connection gets to connect.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Rebuilt connections tried before the peer is dropped.
+const MAX_REBUILD_ATTEMPTS: u32 = 6;
+
+/// Watches a session and brings it back when the network drops: first with
+/// an ICE restart on the same connection, then by replacing the connection
+/// with a new one, retrying with backoff.
+///
+/// Only the impolite side drives recovery, so both ends do not race each
+/// other. The polite side answers whatever the other one sends, a rebuilt
+/// connection replaces its session in `handle_offer`.
+pub fn supervise(
+    session: Arc<PeerSession>,
+    peer_connections: Arc<Mutex<HashMap<String, Arc<PeerSession>>>>,
+    context: PeerContext,
+) {
+    tokio::spawn(supervise_session(session, peer_connections, context));
+}
+
+async fn supervise_session(
+    mut session: Arc<PeerSession>,
+    peer_connections: Arc<Mutex<HashMap<String, Arc<PeerSession>>>>,
+    context: PeerContext,
+) {
+    let mut states = session.ice_states();
+
+    loop {
+        if !wait_for_failure(&mut states).await {
+            return;
+        }
+
+        if !is_current(&session, &peer_connections).await {
+            return;
+        }
+
+        if session.polite {
+            println!(
+                "\n\rConnection to {} lost, waiting for them to reconnect",
+                session.other_id
+            );
+            if wait_for_connected(&mut states, MAX_BACKOFF).await {
+                println!("\n\rReconnected to {}", session.other_id);
+            }
+            continue;
+        }
+
+        println!(
+            "\n\rConnection to {} lost, restarting ICE",
+            session.other_id
+        );
+
+        match session.restart_ice().await {
+            Ok(()) if wait_for_connected(&mut states, CONNECT_TIMEOUT).await => {
+                println!("\n\rReconnected to {}", session.other_id);
+                continue;
+            }
+            Ok(()) => (),
+            Err(e) => eprintln!("ICE restart with {} failed: {}", session.other_id, e),
+        }
+
+        let Some(rebuilt) = rebuild(&session, &peer_connections, &context).await else {
+            return;
+        };
+
+        session = rebuilt;
+        states = session.ice_states();
+    }
+}
+
+/// Replaces the session with fresh peer connections until one connects.
+/// Returns `None` once the session is no longer wanted, e.g. after the
+/// other user left, or after too many attempts, closing it.
+///
+/// While a new connection can't even be set up, the closed one stays in
+/// `peer_connections`, so leaving the room still stops the retries.
+async fn rebuild(
+    session: &Arc<PeerSession>,
+    peer_connections: &Arc<Mutex<HashMap<String, Arc<PeerSession>>>>,
+    context: &PeerContext,
+) -> Option<Arc<PeerSession>> {
+    let mut current = Arc::clone(session);
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 1;
+
+    loop {
+        if attempt > MAX_REBUILD_ATTEMPTS {
+            println!("\n\rGiving up on {}", current.other_id);
+            if is_current(&current, peer_connections).await {
+                close_peer(&current.other_id, peer_connections.clone()).await;
+            }
+            return None;
+        }
+
+        println!(
+            "\n\rReconnecting to {} in {}s (attempt {})",
+            current.other_id,
+            backoff.as_secs(),
+            attempt
+        );
+
+        sleep(backoff).await;
+
+        if !is_current(&current, peer_connections).await {
+            return None;
+        }
+
+        current.close().await;
+
+        let next = match PeerSession::new(
+            PeerRole::Offerer,
+            current.other_id.clone(),
+            current.room_id.clone(),
+            context.clone(),
+        )
+        .await
+        {
+            Ok(next) => next,
+            Err(e) => {
+                eprintln!(
+                    "Couldn't set up a connection to {}: {}",
+                    current.other_id, e
+                );
+                backoff = next_backoff(backoff);
+                attempt += 1;
+                continue;
+            }
+        };
+
+        peer_connections
+            .lock()
+            .await
+            .insert(next.other_id.clone(), Arc::clone(&next));
+
+        let mut states = next.ice_states();
+
+        match next.offer().await {
+            Ok(()) if wait_for_connected(&mut states, CONNECT_TIMEOUT).await => {
+                println!("\n\rReconnected to {}", next.other_id);
+                return Some(next);
+            }
+            Ok(()) => (),
+            Err(e) => eprintln!("Reconnecting to {} failed: {}", next.other_id, e),
+        }
+
+        current = next;
+        backoff = next_backoff(backoff);
+        attempt += 1;
+    }
+}
+
+fn next_backoff(backoff: Duration) -> Duration {
+    (backoff * 2).min(MAX_BACKOFF)
+}
+
+async fn is_current(
+    session: &Arc<PeerSession>,
+    peer_connections: &Arc<Mutex<HashMap<String, Arc<PeerSession>>>>,
+) -> bool {
+    peer_connections
+        .lock()
+        .await
+        .get(&session.other_id)
+        .is_some_and(|current| Arc::ptr_eq(current, session))
+}
+
+/// Waits until the connection fails, or stays disconnected for longer than
+/// the grace period. Returns `false` once the session is closed.
+async fn wait_for_failure(states: &mut watch::Receiver<RTCIceConnectionState>) -> bool {
+    loop {
+        let state = *states.borrow_and_update();
+
+        match state {
+            RTCIceConnectionState::Failed => return true,
+            RTCIceConnectionState::Closed => return false,
+            RTCIceConnectionState::Disconnected => {
+                match timeout(DISCONNECT_GRACE, states.changed()).await {
+                    Err(_) => return true,
+                    Ok(Err(_)) => return false,
+                    Ok(Ok(())) => continue,
+                }
+            }
+            _ => {
+                if states.changed().await.is_err() {
+                    return false;
+                }
+            }
+        }
+    }
+}
+
+async fn wait_for_connected(
+    states: &mut watch::Receiver<RTCIceConnectionState>,
+    limit: Duration,
+) -> bool {
+    let connected = states.wait_for(|state| {
+        matches!(
+            state,
+            RTCIceConnectionState::Connected
+                | RTCIceConnectionState::Completed
+                | RTCIceConnectionState::Closed
+        )
+    });
+
+    matches!(
+        timeout(limit, connected).await,
+        Ok(Ok(state)) if *state != RTCIceConnectionState::Closed
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHORT: Duration = Duration::from_millis(20);
+
+    #[test]
+    fn backoff_doubles_up_to_the_limit() {
+        let mut backoff = INITIAL_BACKOFF;
+        let mut waits = Vec::new();
+        for _ in 0..MAX_REBUILD_ATTEMPTS {
+            waits.push(backoff.as_secs());
+            backoff = next_backoff(backoff);
+        }
+
+        assert_eq!(waits, [1, 2, 4, 8, 16, 30]);
+    }
+
+    #[tokio::test]
+    async fn failures_are_reported_right_away() {
+        let (_states_tx, mut states) = watch::channel(RTCIceConnectionState::Failed);
+
+        assert!(wait_for_failure(&mut states).await);
+    }
+
+    #[tokio::test]
+    async fn closing_stops_the_wait_for_failure() {
+        let (states_tx, mut states) = watch::channel(RTCIceConnectionState::Connected);
+
+        let waiting = tokio::spawn(async move { wait_for_failure(&mut states).await });
+        states_tx.send_replace(RTCIceConnectionState::Closed);
+
+        assert!(!waiting.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn short_disconnects_are_ridden_out() {
+        let (states_tx, mut states) = watch::channel(RTCIceConnectionState::Disconnected);
+
+        let waiting = tokio::spawn(async move { wait_for_failure(&mut states).await });
+        states_tx.send_replace(RTCIceConnectionState::Connected);
+        tokio::time::sleep(SHORT).await;
+        assert!(!waiting.is_finished());
+
+        // The connection failing later still counts.
+        states_tx.send_replace(RTCIceConnectionState::Failed);
+        assert!(waiting.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn connecting_in_time_counts() {
+        let (states_tx, mut states) = watch::channel(RTCIceConnectionState::Checking);
+
+        let waiting =
+            tokio::spawn(async move { wait_for_connected(&mut states, CONNECT_TIMEOUT).await });
+        states_tx.send_replace(RTCIceConnectionState::Connected);
+
+        assert!(waiting.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn timing_out_or_closing_does_not_count_as_connected() {
+        let (_states_tx, mut states) = watch::channel(RTCIceConnectionState::Checking);
+        assert!(!wait_for_connected(&mut states, SHORT).await);
+
+        let (_states_tx, mut states) = watch::channel(RTCIceConnectionState::Closed);
+        assert!(!wait_for_connected(&mut states, CONNECT_TIMEOUT).await);
+    }
+}
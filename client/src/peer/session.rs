@@ -0,0 +1,809 @@
+use anyhow::{anyhow, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::sync::{watch, Mutex, Notify};
+use webrtc::data_channel::data_channel_init::RTCDataChannelInit;
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::ice_transport::ice_candidate::{RTCIceCandidate, RTCIceCandidateInit};
+use webrtc::ice_transport::ice_candidate_pair::RTCIceCandidatePair;
+use webrtc::ice_transport::ice_candidate_type::RTCIceCandidateType;
+use webrtc::ice_transport::ice_connection_state::RTCIceConnectionState;
+use webrtc::peer_connection::offer_answer_options::{RTCAnswerOptions, RTCOfferOptions};
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::sdp::sdp_type::RTCSdpType;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::signaling_state::RTCSignalingState;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtcp::payload_feedbacks::full_intra_request::FullIntraRequest;
+use webrtc::rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
+use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+use webrtc::rtp_transceiver::rtp_sender::RTCRtpSender;
+use webrtc::rtp_transceiver::rtp_transceiver_direction::RTCRtpTransceiverDirection;
+use webrtc::rtp_transceiver::RTCRtpTransceiverInit;
+use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
+use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
+use webrtc::track::track_local::TrackLocal;
+
+use crate::audio::controls::AudioControls;
+use crate::audio::mixer::Mixer;
+use crate::audio::mode::AudioMode;
+use crate::audio::replay::ReplayRecorder;
+use crate::chat::channel::attach_chat;
+use crate::chat::Chat;
+use crate::commands::{ClientCommand, Command, CommandMessage};
+use crate::config::{CodecConfig, NetworkConfig, StatsConfig, UserCapabilities, VideoConfig};
+use crate::e2ee::channel::attach_frame_keys;
+use crate::e2ee::FrameCrypto;
+use crate::identity::channel::attach_identity;
+use crate::identity::Identity;
+use crate::peer::bandwidth::{TargetBitrate, UploadBudget};
+use crate::peer::create::create_peer_connection;
+use crate::peer::ice_servers::IceServers;
+use crate::peer::quality::CallReport;
+use crate::peer::stats::{collect_stats, PeerStats, ReceiveStats};
+use crate::peer::sync::{MediaClock, StreamSync};
+use crate::peer::tracks::receive_tracks;
+use crate::share::channel::attach_share;
+use crate::share::TerminalShare;
+use crate::socket::send::send_message;
+use crate::transfer::{attach_transfers, Transfers, TRANSFER_LABEL_PREFIX};
+use crate::video::terminal::VideoScreen;
+
+/// Both sides create the main data channel with this id, so it is usable
+/// without waiting for the in-band announcement.
+const DATA_CHANNEL_ID: u16 = 0;
+const CHAT_CHANNEL_ID: u16 = 1;
+const SHARE_CHANNEL_ID: u16 = 2;
+const IDENTITY_CHANNEL_ID: u16 = 3;
+const KEYS_CHANNEL_ID: u16 = 4;
+
+/// Everything a peer session needs from the rest of the client.
+#[derive(Clone)]
+pub struct PeerContext {
+    pub user_id: String,
+    pub ws_stream: Arc<
+        Mutex<
+            tokio_tungstenite::WebSocketStream<
+                tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+            >,
+        >,
+    >,
+    pub watch_tx: tokio::sync::watch::Sender<()>,
+    pub capabilities: UserCapabilities,
+    pub audio_track: Arc<TrackLocalStaticRTP>,
+    pub audio_controls: Arc<AudioControls>,
+    /// Only set when a video source is configured
+    pub video_track: Option<Arc<TrackLocalStaticSample>>,
+    pub video: Arc<VideoConfig>,
+    pub video_screen: Arc<VideoScreen>,
+    /// Woken when a peer asks for a video keyframe
+    pub keyframe_requests: Arc<Notify>,
+    pub mixer: Arc<Mixer>,
+    pub replay: Arc<ReplayRecorder>,
+    pub ice_servers: Arc<IceServers>,
+    pub network: Arc<NetworkConfig>,
+    pub codecs: Arc<CodecConfig>,
+    pub stats_config: Arc<StatsConfig>,
+    pub upload: Arc<UploadBudget>,
+    pub chat: Arc<Chat>,
+    pub transfers: Arc<Transfers>,
+    pub share: Arc<TerminalShare>,
+    pub identity: Arc<Identity>,
+    pub frame_crypto: Arc<FrameCrypto>,
+    pub call_report: Arc<CallReport>,
+    pub media_clock: Arc<MediaClock>,
+}
+
+/// Negotiated channels are opened by both sides with the same id instead
+/// of being announced, so they don't depend on which side offers.
+fn negotiated(id: u16) -> RTCDataChannelInit {
+    RTCDataChannelInit {
+        negotiated: Some(id),
+        ..Default::default()
+    }
+}
+
+/// Interceptors (NACK, reports, congestion control) only run while RTCP is
+/// being read.
+fn read_rtcp(sender: Arc<RTCRtpSender>) {
+    tokio::spawn(async move {
+        let mut rtcp_buf = vec![0u8; 1500];
+        while sender.read(&mut rtcp_buf).await.is_ok() {}
+    });
+}
+
+/// Like [`read_rtcp`], and passes on keyframe requests to the video source.
+fn read_video_rtcp(sender: Arc<RTCRtpSender>, keyframe_requests: Arc<Notify>) {
+    tokio::spawn(async move {
+        let mut rtcp_buf = vec![0u8; 1500];
+        while let Ok((packets, _)) = sender.read(&mut rtcp_buf).await {
+            let wants_keyframe = packets.iter().any(|packet| {
+                packet.as_any().is::<PictureLossIndication>()
+                    || packet.as_any().is::<FullIntraRequest>()
+            });
+
+            if wants_keyframe {
+                keyframe_requests.notify_one();
+            }
+        }
+    });
+}
+
+/// The relay policy already keeps other candidates out of the connection,
+/// this also keeps them out of signaling. `None` (end of gathering) is
+/// always passed on.
+fn should_signal(relay_only: bool, candidate: Option<&RTCIceCandidate>) -> bool {
+    !relay_only || candidate.is_none_or(|c| c.typ == RTCIceCandidateType::Relay)
+}
+
+/// Exactly one side of every pair is polite, whoever has the smaller id.
+fn is_polite(user_id: &str, other_id: &str) -> bool {
+    user_id < other_id
+}
+
+/// An incoming offer crosses ours when we are making one, or haven't
+/// finished the last exchange.
+fn offer_collides(making_offer: bool, signaling_state: RTCSignalingState) -> bool {
+    making_offer || signaling_state != RTCSignalingState::Stable
+}
+
+impl PeerContext {
+    pub async fn send(&self, command: ClientCommand) -> Result<()> {
+        send_message(
+            self.ws_stream.clone(),
+            &CommandMessage {
+                user_id: self.user_id.clone(),
+                command: Command::Client(command),
+            },
+        )
+        .await
+        .map_err(|e| anyhow!("Failed to send message: {}", e))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerRole {
+    Offerer,
+    Answerer,
+}
+
+/// A connection to one other user in the room, with its tracks and data
+/// channels. Offerer and answerer share the same setup and only differ in
+/// which side of the SDP exchange they drive.
+///
+/// Offers follow the "perfect negotiation" pattern: when offers cross, the
+/// polite side rolls its own back and answers, the impolite side ignores
+/// the incoming one. Which side is polite only depends on the two user ids,
+/// so both agree on it without talking.
+pub struct PeerSession {
+    pub other_id: String,
+    pub room_id: String,
+    pub role: PeerRole,
+    pub polite: bool,
+    making_offer: AtomicBool,
+    ignore_offer: AtomicBool,
+    /// Set once the first offer/answer exchange is done. Until then
+    /// negotiation-needed events are covered by that exchange.
+    negotiated: AtomicBool,
+    ice_state: watch::Sender<RTCIceConnectionState>,
+    /// Flipped once on close, stops the tasks reading from this connection.
+    closed: watch::Sender<bool>,
+    pub receive_stats: Arc<ReceiveStats>,
+    stats: watch::Sender<PeerStats>,
+    /// What the congestion controller allows us to send to this peer.
+    pub target_bitrate: Arc<TargetBitrate>,
+    /// What the peer last announced its microphone is used for.
+    audio_mode: watch::Sender<AudioMode>,
+    pub peer_connection: Arc<RTCPeerConnection>,
+    pub data_channel: Arc<RTCDataChannel>,
+    /// Remote candidates received before the remote description was set.
+    /// Holding the lock while setting the description keeps candidates from
+    /// slipping in between.
+    pending_candidates: Mutex<Vec<RTCIceCandidateInit>>,
+    context: PeerContext,
+}
+
+impl PeerSession {
+    pub async fn new(
+        role: PeerRole,
+        other_id: String,
+        room_id: String,
+        context: PeerContext,
+    ) -> Result<Arc<Self>> {
+        let target_bitrate = context.upload.register();
+        let sync = Arc::new(StreamSync::default());
+
+        let peer_connection = create_peer_connection(
+            &context.network,
+            &context.codecs,
+            context.ice_servers.get(),
+            target_bitrate.clone(),
+            context.media_clock.clone(),
+            sync.clone(),
+        )
+        .await?;
+
+        println!("Setting up audio for {:?}", peer_connection.get_stats_id());
+
+        let audio_sender = peer_connection
+            .add_track(Arc::clone(&context.audio_track) as Arc<dyn TrackLocal + Send + Sync>)
+            .await?;
+
+        read_rtcp(audio_sender);
+
+        if let Some(video_track) = &context.video_track {
+            let video_sender = peer_connection
+                .add_track(Arc::clone(video_track) as Arc<dyn TrackLocal + Send + Sync>)
+                .await?;
+            read_video_rtcp(video_sender, context.keyframe_requests.clone());
+        } else if context.capabilities.video && !context.codecs.video.is_empty() {
+            // Still ask for the peer's video when we have none to send.
+            peer_connection
+                .add_transceiver_from_kind(
+                    RTPCodecType::Video,
+                    Some(RTCRtpTransceiverInit {
+                        direction: RTCRtpTransceiverDirection::Recvonly,
+                        send_encodings: vec![],
+                    }),
+                )
+                .await?;
+        }
+
+        let data_channel = peer_connection
+            .create_data_channel("data", Some(negotiated(DATA_CHANNEL_ID)))
+            .await?;
+
+        // Ordered and reliable, the defaults.
+        let chat_channel = peer_connection
+            .create_data_channel("chat", Some(negotiated(CHAT_CHANNEL_ID)))
+            .await?;
+
+        // Ordered too, shell output only makes sense in sequence.
+        let share_channel = peer_connection
+            .create_data_channel("terminal", Some(negotiated(SHARE_CHANNEL_ID)))
+            .await?;
+
+        let identity_channel = peer_connection
+            .create_data_channel("identity", Some(negotiated(IDENTITY_CHANNEL_ID)))
+            .await?;
+
+        let keys_channel = peer_connection
+            .create_data_channel("keys", Some(negotiated(KEYS_CHANNEL_ID)))
+            .await?;
+
+        let (closed, closed_rx) = watch::channel(false);
+
+        attach_chat(
+            context.chat.clone(),
+            chat_channel,
+            other_id.clone(),
+            room_id.clone(),
+            closed_rx.clone(),
+        );
+
+        attach_share(
+            context.share.clone(),
+            share_channel,
+            other_id.clone(),
+            closed_rx.clone(),
+        );
+
+        attach_identity(
+            context.identity.clone(),
+            identity_channel,
+            Arc::downgrade(&peer_connection),
+            other_id.clone(),
+            closed_rx.clone(),
+        );
+
+        attach_frame_keys(
+            context.frame_crypto.clone(),
+            keys_channel,
+            other_id.clone(),
+            room_id.clone(),
+            closed_rx.clone(),
+        );
+
+        let receive_stats = Arc::new(ReceiveStats::default());
+        let (audio_mode, audio_mode_rx) = watch::channel(AudioMode::default());
+
+        receive_tracks(
+            &peer_connection,
+            other_id.clone(),
+            &context,
+            audio_mode_rx,
+            receive_stats.clone(),
+            sync,
+            closed_rx.clone(),
+        );
+
+        let polite = is_polite(&context.user_id, &other_id);
+
+        let session = Arc::new(Self {
+            other_id,
+            room_id,
+            role,
+            polite,
+            making_offer: AtomicBool::new(false),
+            ignore_offer: AtomicBool::new(false),
+            negotiated: AtomicBool::new(false),
+            ice_state: watch::channel(RTCIceConnectionState::New).0,
+            closed,
+            receive_stats,
+            stats: watch::channel(PeerStats::default()).0,
+            target_bitrate,
+            audio_mode,
+            peer_connection,
+            data_channel,
+            pending_candidates: Mutex::new(Vec::new()),
+            context,
+        });
+
+        session.register_handlers();
+        attach_transfers(session.context.transfers.clone(), &session);
+
+        tokio::spawn(collect_stats(
+            Arc::downgrade(&session),
+            session.context.call_report.clone(),
+            closed_rx,
+            Duration::from_secs(session.context.stats_config.interval_seconds.max(1)),
+            session.context.stats_config.log,
+        ));
+
+        Ok(session)
+    }
+
+    fn register_handlers(self: &Arc<Self>) {
+        let watch_tx = self.context.watch_tx.clone();
+        let session = Arc::downgrade(self);
+
+        self.peer_connection
+            .on_peer_connection_state_change(Box::new(move |state| {
+                println!("\n\rPeer connection state changed to {:?}", state);
+                let _ = watch_tx.send(());
+
+                // Peers only hear about mode switches made while they are
+                // connected, so a new one is told the current mode.
+                let session = session.upgrade();
+                Box::pin(async move {
+                    if let Some(session) = session {
+                        if state == RTCPeerConnectionState::Connected {
+                            session.announce_audio_mode().await;
+                        }
+                    }
+                })
+            }));
+
+        let session = Arc::downgrade(self);
+
+        self.peer_connection
+            .on_ice_connection_state_change(Box::new(move |state: RTCIceConnectionState| {
+                println!("\n\rICE connection state changed to {:?}", state);
+                if let Some(session) = session.upgrade() {
+                    session.ice_state.send_replace(state);
+                }
+                Box::pin(async {})
+            }));
+
+        let session = Arc::downgrade(self);
+
+        self.peer_connection
+            .on_data_channel(Box::new(move |d: Arc<RTCDataChannel>| {
+                let d_label = d.label().to_owned();
+                let d_id = d.id();
+                println!("\n\rDataChannel {d_label} {d_id}");
+
+                if let Some(session) = session.upgrade() {
+                    if d_label.starts_with(TRANSFER_LABEL_PREFIX) {
+                        let transfers = session.context.transfers.clone();
+                        transfers.receive_channel(session, d);
+                    }
+                }
+
+                Box::pin(async {})
+            }));
+
+        let session = Arc::downgrade(self);
+
+        self.peer_connection
+            .on_negotiation_needed(Box::new(move || {
+                let session = Weak::clone(&session);
+
+                // Offering from inside the callback would wait on the
+                // operation that is running it.
+                tokio::spawn(async move {
+                    let Some(session) = session.upgrade() else {
+                        return;
+                    };
+
+                    if !session.negotiated.load(Ordering::Relaxed) {
+                        return;
+                    }
+
+                    println!("\n\rRenegotiating with {}", session.other_id);
+
+                    if let Err(e) = session.offer().await {
+                        eprintln!("Failed to renegotiate with {}: {}", session.other_id, e);
+                    }
+                });
+
+                Box::pin(async {})
+            }));
+
+        let other_id = self.other_id.clone();
+
+        self.peer_connection
+            .sctp()
+            .transport()
+            .ice_transport()
+            .on_selected_candidate_pair_change(Box::new(move |pair: RTCIceCandidatePair| {
+                println!("\n\rConnected to {} over {}", other_id, pair);
+                Box::pin(async {})
+            }));
+
+        let context = self.context.clone();
+        let other_id = self.other_id.clone();
+        let room_id = self.room_id.clone();
+
+        self.peer_connection
+            .on_ice_candidate(Box::new(move |candidate| {
+                let context = context.clone();
+                let other_id = other_id.clone();
+                let room_id = room_id.clone();
+
+                Box::pin(async move {
+                    if !should_signal(context.network.relay_only, candidate.as_ref()) {
+                        return;
+                    }
+
+                    // `None` means gathering is done, which is passed on as a
+                    // candidate with an empty `candidate` field.
+                    let candidate = match candidate.map(|c| c.to_json()).transpose() {
+                        Ok(candidate) => candidate.unwrap_or_default(),
+                        Err(e) => {
+                            eprintln!("Failed to serialize ICE candidate: {:?}", e);
+                            return;
+                        }
+                    };
+
+                    let candidate_str = serde_json::to_string(&candidate).unwrap();
+
+                    if let Err(e) = context
+                        .send(ClientCommand::SendIceCandidate(
+                            other_id,
+                            room_id,
+                            candidate_str,
+                        ))
+                        .await
+                    {
+                        eprintln!("{}", e);
+                    }
+                })
+            }));
+    }
+
+    async fn announce_audio_mode(&self) {
+        let mode = *self.context.audio_controls.mode.borrow();
+
+        if let Err(e) = self
+            .context
+            .send(ClientCommand::SendAudioMode(
+                self.other_id.clone(),
+                self.room_id.clone(),
+                mode,
+            ))
+            .await
+        {
+            eprintln!("Failed to tell {} our audio mode: {}", self.other_id, e);
+        }
+    }
+
+    /// Records the mode the peer switched to. Its audio is no longer ducked
+    /// while it plays music.
+    pub fn set_audio_mode(&self, mode: AudioMode) {
+        self.audio_mode.send_replace(mode);
+    }
+
+    pub fn connection_state(&self) -> RTCPeerConnectionState {
+        self.peer_connection.connection_state()
+    }
+
+    /// The latest collected connection statistics.
+    pub fn stats(&self) -> PeerStats {
+        self.stats.borrow().clone()
+    }
+
+    pub(crate) fn set_stats(&self, stats: PeerStats) {
+        self.stats.send_replace(stats);
+    }
+
+    /// Follows the ICE connection state, `Closed` once the session is closed.
+    pub fn ice_states(&self) -> watch::Receiver<RTCIceConnectionState> {
+        self.ice_state.subscribe()
+    }
+
+    /// Whether `offer` comes from a new peer connection on the other side
+    /// rather than from the one this session is talking to.
+    pub async fn remote_restarted(&self, offer: &RTCSessionDescription) -> bool {
+        let Some(current) = self.peer_connection.remote_description().await else {
+            return false;
+        };
+
+        fingerprint(&current.sdp) != fingerprint(&offer.sdp)
+    }
+
+    /// Closes the connection and stops everything attached to it. Decoders
+    /// and mixer sources go away with the tasks that own them.
+    pub async fn close(&self) {
+        if self.closed.send_replace(true) {
+            return;
+        }
+
+        self.pending_candidates.lock().await.clear();
+
+        if let Err(e) = self.peer_connection.close().await {
+            eprintln!("Failed to close connection to {}: {}", self.other_id, e);
+        }
+
+        self.ice_state.send_replace(RTCIceConnectionState::Closed);
+    }
+
+    /// Adds a remote candidate, or queues it until the remote description is
+    /// set. An empty candidate marks the end of the remote candidates.
+    pub async fn add_remote_candidate(&self, candidate: RTCIceCandidateInit) -> Result<()> {
+        let mut pending = self.pending_candidates.lock().await;
+
+        if self.peer_connection.remote_description().await.is_none() {
+            pending.push(candidate);
+            return Ok(());
+        }
+
+        self.apply_candidate(candidate).await
+    }
+
+    async fn apply_candidate(&self, candidate: RTCIceCandidateInit) -> Result<()> {
+        if candidate.candidate.is_empty() {
+            println!("\n\rEnd of ICE candidates from {}", self.other_id);
+        }
+
+        match self.peer_connection.add_ice_candidate(candidate).await {
+            // Candidates for an offer we ignored are expected to fail.
+            Err(_) if self.ignore_offer.load(Ordering::Relaxed) => Ok(()),
+            result => Ok(result?),
+        }
+    }
+
+    /// Sets the remote description and applies every candidate queued
+    /// before it.
+    async fn set_remote_description(&self, description: RTCSessionDescription) -> Result<()> {
+        let mut pending = self.pending_candidates.lock().await;
+
+        self.peer_connection
+            .set_remote_description(description)
+            .await?;
+
+        for candidate in pending.drain(..) {
+            if let Err(e) = self.apply_candidate(candidate).await {
+                eprintln!("Failed to add ICE candidate from {}: {}", self.other_id, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends an offer right away, candidates follow as they are gathered.
+    /// Used for the first exchange and for renegotiation.
+    pub async fn offer(&self) -> Result<()> {
+        self.making_offer.store(true, Ordering::Relaxed);
+        let result = self.send_offer(false).await;
+        self.making_offer.store(false, Ordering::Relaxed);
+
+        result
+    }
+
+    /// Offers new ICE credentials, so both sides gather and check
+    /// candidates again over whatever network is now available.
+    pub async fn restart_ice(&self) -> Result<()> {
+        self.making_offer.store(true, Ordering::Relaxed);
+        let result = self.send_offer(true).await;
+        self.making_offer.store(false, Ordering::Relaxed);
+
+        result
+    }
+
+    async fn send_offer(&self, ice_restart: bool) -> Result<()> {
+        let offer = self
+            .peer_connection
+            .create_offer(Some(RTCOfferOptions {
+                voice_activity_detection: true,
+                ice_restart,
+            }))
+            .await?;
+
+        self.peer_connection
+            .set_local_description(offer.clone())
+            .await?;
+
+        self.context
+            .send(ClientCommand::SendOffer(
+                self.other_id.clone(),
+                self.room_id.clone(),
+                offer.sdp,
+            ))
+            .await?;
+
+        println!("\n\r*** Sent offer message ***\n\r");
+
+        Ok(())
+    }
+
+    /// Handles an offer for a session that already exists, either a
+    /// renegotiation or an offer crossing our own.
+    pub async fn receive_offer(&self, offer: RTCSessionDescription) -> Result<()> {
+        let signaling_state = self.peer_connection.signaling_state();
+        let collision = offer_collides(self.making_offer.load(Ordering::Relaxed), signaling_state);
+
+        let ignore = collision && !self.polite;
+        self.ignore_offer.store(ignore, Ordering::Relaxed);
+
+        if ignore {
+            println!("\n\rIgnoring colliding offer from {}", self.other_id);
+            return Ok(());
+        }
+
+        if signaling_state == RTCSignalingState::HaveLocalOffer {
+            println!("\n\rRolling back our offer to {}", self.other_id);
+            self.rollback().await?;
+        }
+
+        self.answer(offer, Vec::new()).await
+    }
+
+    async fn rollback(&self) -> Result<()> {
+        // webrtc-rs refuses a local description without SDP, even for a
+        // rollback, so the pending offer is passed back in.
+        let mut rollback = self
+            .peer_connection
+            .pending_local_description()
+            .await
+            .ok_or_else(|| anyhow!("No pending offer to roll back"))?;
+        rollback.sdp_type = RTCSdpType::Rollback;
+
+        self.peer_connection.set_local_description(rollback).await?;
+
+        Ok(())
+    }
+
+    /// Answerer side: applies the remote offer and any candidates received
+    /// before it, then sends our answer.
+    pub async fn answer(
+        &self,
+        offer: RTCSessionDescription,
+        candidates: Vec<RTCIceCandidateInit>,
+    ) -> Result<()> {
+        self.pending_candidates.lock().await.extend(candidates);
+
+        self.set_remote_description(offer).await?;
+
+        let answer = self
+            .peer_connection
+            .create_answer(Some(RTCAnswerOptions {
+                voice_activity_detection: true,
+            }))
+            .await?;
+
+        self.peer_connection
+            .set_local_description(answer.clone())
+            .await?;
+
+        self.context
+            .send(ClientCommand::SendAnswer(
+                self.other_id.clone(),
+                self.room_id.clone(),
+                answer.sdp,
+            ))
+            .await?;
+
+        self.negotiated.store(true, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    /// Offerer side: completes the exchange with the remote answer. Answers
+    /// to an offer we rolled back are dropped.
+    pub async fn accept_answer(&self, answer: RTCSessionDescription) -> Result<()> {
+        if self.peer_connection.signaling_state() != RTCSignalingState::HaveLocalOffer {
+            println!("\n\rIgnoring unexpected answer from {}", self.other_id);
+            return Ok(());
+        }
+
+        self.set_remote_description(answer).await?;
+
+        self.negotiated.store(true, Ordering::Relaxed);
+
+        Ok(())
+    }
+}
+
+pub(crate) fn fingerprint(sdp: &str) -> Option<&str> {
+    sdp.lines()
+        .find_map(|line| line.strip_prefix("a=fingerprint:"))
+        .map(str::trim_end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(typ: RTCIceCandidateType) -> RTCIceCandidate {
+        RTCIceCandidate {
+            typ,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn negotiated_channels_have_distinct_ids() {
+        let ids = [
+            DATA_CHANNEL_ID,
+            CHAT_CHANNEL_ID,
+            SHARE_CHANNEL_ID,
+            IDENTITY_CHANNEL_ID,
+            KEYS_CHANNEL_ID,
+        ];
+
+        for (i, id) in ids.iter().enumerate() {
+            assert!(!ids[i + 1..].contains(id), "id {} is used twice", id);
+            assert_eq!(negotiated(*id).negotiated, Some(*id));
+        }
+    }
+
+    #[test]
+    fn negotiated_channels_are_ordered_and_reliable() {
+        let init = negotiated(CHAT_CHANNEL_ID);
+
+        assert_eq!(init.ordered, None);
+        assert_eq!(init.max_retransmits, None);
+        assert_eq!(init.max_packet_life_time, None);
+    }
+
+    #[test]
+    fn exactly_one_side_is_polite() {
+        assert!(is_polite("alice", "bob"));
+        assert!(!is_polite("bob", "alice"));
+        assert!(!is_polite("alice", "alice"));
+    }
+
+    #[test]
+    fn offers_collide_unless_stable_and_idle() {
+        assert!(!offer_collides(false, RTCSignalingState::Stable));
+        assert!(offer_collides(true, RTCSignalingState::Stable));
+        assert!(offer_collides(false, RTCSignalingState::HaveLocalOffer));
+        assert!(offer_collides(false, RTCSignalingState::HaveRemoteOffer));
+    }
+
+    #[test]
+    fn fingerprints_are_read_from_the_sdp() {
+        let sdp = "v=0\r\na=group:BUNDLE 0\r\na=fingerprint:sha-256 AB:CD:EF\r\nm=audio 9\r\n";
+
+        assert_eq!(fingerprint(sdp), Some("sha-256 AB:CD:EF"));
+        assert_eq!(fingerprint("v=0\r\nm=audio 9\r\n"), None);
+    }
+
+    #[test]
+    fn relay_only_signals_relay_candidates_only() {
+        let host = candidate(RTCIceCandidateType::Host);
+        let srflx = candidate(RTCIceCandidateType::Srflx);
+        let relay = candidate(RTCIceCandidateType::Relay);
+
+        assert!(!should_signal(true, Some(&host)));
+        assert!(!should_signal(true, Some(&srflx)));
+        assert!(should_signal(true, Some(&relay)));
+        assert!(should_signal(true, None));
+
+        assert!(should_signal(false, Some(&host)));
+        assert!(should_signal(false, Some(&srflx)));
+        assert!(should_signal(false, None));
+    }
+}
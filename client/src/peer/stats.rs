@@ -0,0 +1,529 @@
+use std::fmt;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::time::{interval, MissedTickBehavior};
+use webrtc::ice_transport::ice_candidate_type::RTCIceCandidateType;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtp_transceiver::rtp_codec::{RTCRtpCodecCapability, RTPCodecType};
+use webrtc::stats::{StatsReport, StatsReportType};
+
+use crate::peer::quality::{CallReport, Quality};
+use crate::peer::session::PeerSession;
+
+/// A snapshot of one peer connection, refreshed by [`collect_stats`].
+#[derive(Clone, Debug, Default)]
+pub struct PeerStats {
+    /// Round trip time of the selected candidate pair
+    pub rtt: Option<Duration>,
+    /// Interarrival jitter of the audio we receive (RFC 3550)
+    pub jitter: Duration,
+    pub packets_received: u64,
+    pub packets_lost: u64,
+    /// Share of their packets lost since the previous collection
+    pub loss: f64,
+    /// Share of their audio frames lost and not recovered from RED since
+    /// the previous collection, heard as gaps
+    pub concealment: f64,
+    /// Share of our packets they report lost
+    pub remote_loss: Option<f64>,
+    /// Bits per second over the selected candidate pair
+    pub send_bitrate: u64,
+    pub receive_bitrate: u64,
+    /// Most the congestion controller lets us send them
+    pub target_bitrate: u64,
+    pub local_candidate: Option<RTCIceCandidateType>,
+    pub remote_candidate: Option<RTCIceCandidateType>,
+    pub codec: Option<String>,
+    /// Set once their audio is flowing
+    pub quality: Option<Quality>,
+}
+
+impl fmt::Display for PeerStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.rtt {
+            Some(rtt) => write!(f, "rtt {}ms", rtt.as_millis())?,
+            None => write!(f, "rtt -")?,
+        }
+
+        write!(
+            f,
+            ", jitter {:.1}ms, loss {:.1}%",
+            self.jitter.as_secs_f64() * 1000.0,
+            self.loss * 100.0
+        )?;
+
+        if let Some(remote_loss) = self.remote_loss {
+            write!(f, " (theirs {:.1}%)", remote_loss * 100.0)?;
+        }
+
+        write!(
+            f,
+            ", {} kbps up, {} kbps down",
+            self.send_bitrate / 1000,
+            self.receive_bitrate / 1000
+        )?;
+
+        write!(f, " (target {} kbps)", self.target_bitrate / 1000)?;
+
+        if let (Some(local), Some(remote)) = (self.local_candidate, self.remote_candidate) {
+            write!(f, ", {} -> {}", local, remote)?;
+        }
+
+        if let Some(codec) = &self.codec {
+            write!(f, ", {}", codec)?;
+        }
+
+        if let Some(quality) = self.quality {
+            write!(f, ", quality {}", quality)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct ReceiveState {
+    first_arrival: Option<Instant>,
+    base_seq: Option<u32>,
+    /// Highest sequence number seen, extended with the number of wraps.
+    highest_seq: u32,
+    received: u64,
+    last_transit: Option<i64>,
+    /// Of the stream's codec, the unit of its RTP timestamps.
+    clock_rate: u32,
+    /// In RTP timestamp units, scaled by 16 as in RFC 3550 A.8.
+    jitter: i64,
+    frames_played: u64,
+    frames_concealed: u64,
+}
+
+/// Loss and jitter of an incoming RTP stream, measured from the packets
+/// themselves since webrtc-rs does not report them for inbound streams.
+#[derive(Default)]
+pub struct ReceiveStats(Mutex<ReceiveState>);
+
+impl ReceiveStats {
+    /// Records a packet of a stream whose codec runs at `clock_rate`.
+    pub fn record(&self, header: &rtp::header::Header, clock_rate: u32) {
+        self.record_at(header, clock_rate, Instant::now());
+    }
+
+    fn record_at(&self, header: &rtp::header::Header, clock_rate: u32, now: Instant) {
+        let mut state = self.0.lock().unwrap();
+
+        // Jitter in one clock's units means nothing in another's.
+        if state.clock_rate != clock_rate {
+            state.clock_rate = clock_rate;
+            state.last_transit = None;
+            state.jitter = 0;
+        }
+
+        let first_arrival = *state.first_arrival.get_or_insert(now);
+
+        let seq = match state.base_seq {
+            None => {
+                state.base_seq = Some(header.sequence_number as u32);
+                header.sequence_number as u32
+            }
+            Some(_) => {
+                let cycles = state.highest_seq & !0xffff;
+                let candidate = cycles | header.sequence_number as u32;
+                let highest = state.highest_seq;
+
+                // Pick the wrap closest to the highest sequence number seen.
+                if candidate + 0x8000 < highest {
+                    candidate + 0x10000
+                } else if candidate > highest + 0x8000 && cycles > 0 {
+                    candidate - 0x10000
+                } else {
+                    candidate
+                }
+            }
+        };
+
+        state.highest_seq = state.highest_seq.max(seq);
+        state.received += 1;
+
+        let arrival = (now - first_arrival).as_secs_f64() * clock_rate as f64;
+        let transit = arrival as i64 - header.timestamp as i64;
+
+        if let Some(last_transit) = state.last_transit {
+            let d = (transit - last_transit).abs();
+            state.jitter += d - ((state.jitter + 8) >> 4);
+        }
+        state.last_transit = Some(transit);
+    }
+
+    /// Counts decoded audio frames, and missing ones nothing could stand
+    /// in for.
+    pub fn record_frames(&self, played: u64, concealed: u64) {
+        let mut state = self.0.lock().unwrap();
+        state.frames_played += played;
+        state.frames_concealed += concealed;
+    }
+
+    /// Frames played and concealed so far.
+    fn frames(&self) -> (u64, u64) {
+        let state = self.0.lock().unwrap();
+        (state.frames_played, state.frames_concealed)
+    }
+
+    /// Packets received, packets lost and jitter so far.
+    fn snapshot(&self) -> (u64, u64, Duration) {
+        let state = self.0.lock().unwrap();
+
+        let Some(base_seq) = state.base_seq else {
+            return (0, 0, Duration::ZERO);
+        };
+
+        let expected = (state.highest_seq - base_seq) as u64 + 1;
+        let lost = expected.saturating_sub(state.received);
+        let jitter = match state.clock_rate {
+            0 => Duration::ZERO,
+            clock_rate => Duration::from_secs_f64((state.jitter >> 4) as f64 / clock_rate as f64),
+        };
+
+        (state.received, lost, jitter)
+    }
+}
+
+#[derive(Default)]
+struct Previous {
+    at: Option<Instant>,
+    bytes_sent: u64,
+    bytes_received: u64,
+    received: u64,
+    lost: u64,
+    frames_played: u64,
+    frames_concealed: u64,
+}
+
+fn candidate_type(report: &StatsReport, id: &str) -> Option<RTCIceCandidateType> {
+    match report.reports.get(id)? {
+        StatsReportType::LocalCandidate(candidate)
+        | StatsReportType::RemoteCandidate(candidate) => Some(candidate.candidate_type.into()),
+        _ => None,
+    }
+}
+
+/// The codec of the audio we send, or else of the audio we receive.
+async fn audio_codec(peer_connection: &RTCPeerConnection) -> Option<RTCRtpCodecCapability> {
+    for transceiver in peer_connection.get_transceivers().await {
+        if transceiver.kind() != RTPCodecType::Audio {
+            continue;
+        }
+
+        let parameters = transceiver.sender().await.get_parameters().await;
+        let sending = parameters.encodings.first().and_then(|encoding| {
+            parameters
+                .rtp_parameters
+                .codecs
+                .iter()
+                .find(|codec| codec.payload_type == encoding.payload_type)
+        });
+
+        if let Some(codec) = sending {
+            return Some(codec.capability.clone());
+        }
+
+        for track in transceiver.receiver().await.tracks().await {
+            let codec = track.codec();
+            if !codec.capability.mime_type.is_empty() {
+                return Some(codec.capability);
+            }
+        }
+    }
+
+    None
+}
+
+fn codec_name(codec: &RTCRtpCodecCapability) -> String {
+    match codec.channels {
+        0 | 1 => format!("{} {}Hz", codec.mime_type, codec.clock_rate),
+        channels => format!("{} {}Hz {}ch", codec.mime_type, codec.clock_rate, channels),
+    }
+}
+
+/// Bits per second, from a byte counter read `elapsed` seconds apart.
+fn bitrate(bytes: u64, previous: u64, elapsed: f64) -> u64 {
+    (bytes.saturating_sub(previous) as f64 * 8.0 / elapsed) as u64
+}
+
+/// Share of `part` out of `part + rest`, 0 when both are.
+fn share(part: u64, rest: u64) -> f64 {
+    match part + rest {
+        0 => 0.0,
+        total => part as f64 / total as f64,
+    }
+}
+
+fn build_stats(
+    report: &StatsReport,
+    receive: &ReceiveStats,
+    previous: &mut Previous,
+    now: Instant,
+) -> PeerStats {
+    let mut stats = PeerStats::default();
+
+    let elapsed = previous
+        .at
+        .map(|at| (now - at).as_secs_f64())
+        .filter(|elapsed| *elapsed > 0.0);
+
+    for entry in report.reports.values() {
+        match entry {
+            StatsReportType::CandidatePair(pair) if pair.nominated => {
+                if pair.current_round_trip_time > 0.0 {
+                    stats.rtt = Some(Duration::from_secs_f64(pair.current_round_trip_time));
+                }
+
+                if let Some(elapsed) = elapsed {
+                    stats.send_bitrate = bitrate(pair.bytes_sent, previous.bytes_sent, elapsed);
+                    stats.receive_bitrate =
+                        bitrate(pair.bytes_received, previous.bytes_received, elapsed);
+                }
+
+                previous.bytes_sent = pair.bytes_sent;
+                previous.bytes_received = pair.bytes_received;
+
+                stats.local_candidate = candidate_type(report, &pair.local_candidate_id);
+                stats.remote_candidate = candidate_type(report, &pair.remote_candidate_id);
+            }
+            StatsReportType::RemoteInboundRTP(remote) => {
+                stats.remote_loss = Some(remote.fraction_lost);
+            }
+            _ => (),
+        }
+    }
+
+    let (received, lost, jitter) = receive.snapshot();
+
+    let interval_received = received.saturating_sub(previous.received);
+    let interval_lost = lost.saturating_sub(previous.lost);
+
+    stats.loss = share(interval_lost, interval_received);
+
+    stats.packets_received = received;
+    stats.packets_lost = lost;
+    stats.jitter = jitter;
+
+    let (played, concealed) = receive.frames();
+    let interval_played = played.saturating_sub(previous.frames_played);
+    let interval_concealed = concealed.saturating_sub(previous.frames_concealed);
+
+    stats.concealment = share(interval_concealed, interval_played);
+
+    if interval_received > 0 {
+        stats.quality = Some(Quality::estimate(
+            stats.rtt,
+            stats.jitter,
+            stats.loss,
+            stats.concealment,
+        ));
+    }
+
+    previous.received = received;
+    previous.lost = lost;
+    previous.frames_played = played;
+    previous.frames_concealed = concealed;
+    previous.at = Some(now);
+
+    stats
+}
+
+/// Refreshes the session's stats every `every` until it is closed, adding
+/// each collection to the call report.
+pub async fn collect_stats(
+    session: Weak<PeerSession>,
+    report: Arc<CallReport>,
+    mut closed: watch::Receiver<bool>,
+    every: Duration,
+    log: bool,
+) {
+    let mut ticker = interval(every);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut previous = Previous::default();
+    let mut grade = None;
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => (),
+            _ = closed.wait_for(|closed| *closed) => return,
+        }
+
+        let Some(session) = session.upgrade() else {
+            return;
+        };
+
+        let stats_report = session.peer_connection.get_stats().await;
+        let mut stats = build_stats(
+            &stats_report,
+            &session.receive_stats,
+            &mut previous,
+            Instant::now(),
+        );
+        stats.target_bitrate = session.target_bitrate.get() as u64;
+        stats.codec = audio_codec(&session.peer_connection)
+            .await
+            .map(|codec| codec_name(&codec));
+
+        if log {
+            println!("\n\r[stats] {}: {}", session.other_id, stats);
+        }
+
+        report.record(&session.other_id, &stats);
+
+        if let Some(quality) = stats.quality {
+            if grade.is_some_and(|grade| grade != quality.grade) {
+                println!(
+                    "\n\rConnection quality with {} is now {}",
+                    session.other_id, quality
+                );
+            }
+            grade = Some(quality.grade);
+        }
+
+        session.set_stats(stats);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn header(sequence_number: u16, timestamp: u32) -> rtp::header::Header {
+        rtp::header::Header {
+            sequence_number,
+            timestamp,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn bitrate_counts_bits_per_second_since_the_last_read() {
+        assert_eq!(bitrate(125_000, 0, 1.0), 1_000_000);
+        assert_eq!(bitrate(250_000, 125_000, 2.0), 500_000);
+        // A counter that went backwards (a rebuilt connection) is no traffic.
+        assert_eq!(bitrate(1_000, 125_000, 1.0), 0);
+    }
+
+    #[test]
+    fn share_is_zero_without_anything_counted() {
+        assert_eq!(share(0, 0), 0.0);
+        assert_eq!(share(1, 3), 0.25);
+        assert_eq!(share(2, 0), 1.0);
+    }
+
+    #[test]
+    fn loss_counts_gaps_across_sequence_wraps() {
+        let stats = ReceiveStats::default();
+        let now = Instant::now();
+
+        for seq in [65533, 65534, 0, 1, 3] {
+            stats.record_at(&header(seq, 0), 48000, now);
+        }
+
+        let (received, lost, _) = stats.snapshot();
+        assert_eq!(received, 5);
+        // 65535 and 2 never arrived.
+        assert_eq!(lost, 2);
+    }
+
+    #[test]
+    fn jitter_is_converted_with_the_stream_clock_rate() {
+        for clock_rate in [48000, 90000] {
+            let stats = ReceiveStats::default();
+            let start = Instant::now();
+            let ticks = |ms: u32| clock_rate / 1000 * ms;
+
+            // The second packet is stamped 20ms after the first but
+            // arrives 30ms after it, 10ms late.
+            stats.record_at(&header(0, 0), clock_rate, start);
+            stats.record_at(
+                &header(1, ticks(20)),
+                clock_rate,
+                start + Duration::from_millis(30),
+            );
+
+            // A single 10ms deviation moves the estimate by 1/16 of it.
+            let (_, _, jitter) = stats.snapshot();
+            let expected = 10.0 / 16.0;
+            let jitter = jitter.as_secs_f64() * 1000.0;
+            assert!(
+                (jitter - expected).abs() < 0.05,
+                "{} at {}",
+                jitter,
+                clock_rate
+            );
+        }
+    }
+
+    #[test]
+    fn loss_and_concealment_cover_only_the_last_interval() {
+        let report = StatsReport {
+            reports: HashMap::new(),
+        };
+        let receive = ReceiveStats::default();
+        let mut previous = Previous::default();
+        let start = Instant::now();
+
+        for seq in 0..10 {
+            receive.record_at(&header(seq, 0), 48000, start);
+        }
+        receive.record_frames(10, 0);
+
+        let first = build_stats(&report, &receive, &mut previous, start);
+        assert_eq!(first.loss, 0.0);
+        assert_eq!(first.concealment, 0.0);
+        assert!(first.quality.is_some());
+
+        // 10 to 19 with 12 and 13 missing, two frames concealed.
+        for seq in (10..20).filter(|seq| *seq != 12 && *seq != 13) {
+            receive.record_at(&header(seq, 0), 48000, start);
+        }
+        receive.record_frames(6, 2);
+
+        let second = build_stats(
+            &report,
+            &receive,
+            &mut previous,
+            start + Duration::from_secs(1),
+        );
+        assert_eq!(second.packets_received, 18);
+        assert_eq!(second.packets_lost, 2);
+        assert_eq!(second.loss, 0.2);
+        assert_eq!(second.concealment, 0.25);
+
+        // Nothing new arrived, so there is no loss and nothing to grade.
+        let third = build_stats(
+            &report,
+            &receive,
+            &mut previous,
+            start + Duration::from_secs(2),
+        );
+        assert_eq!(third.loss, 0.0);
+        assert!(third.quality.is_none());
+    }
+
+    #[test]
+    fn codec_names_show_channels_only_when_there_are_several() {
+        let opus = RTCRtpCodecCapability {
+            mime_type: "audio/opus".to_owned(),
+            clock_rate: 48000,
+            channels: 2,
+            ..Default::default()
+        };
+        let pcmu = RTCRtpCodecCapability {
+            mime_type: "audio/PCMU".to_owned(),
+            clock_rate: 8000,
+            channels: 1,
+            ..Default::default()
+        };
+
+        assert_eq!(codec_name(&opus), "audio/opus 48000Hz 2ch");
+        assert_eq!(codec_name(&pcmu), "audio/PCMU 8000Hz");
+    }
+}
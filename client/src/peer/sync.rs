@@ -0,0 +1,412 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+use tokio::time::{interval, MissedTickBehavior};
+use webrtc::interceptor::stream_info::StreamInfo;
+use webrtc::interceptor::{
+    Attributes, Error, Interceptor, InterceptorBuilder, RTCPReader, RTCPWriter, RTPReader,
+    RTPWriter,
+};
+use webrtc::rtcp::sender_report::SenderReport;
+
+/// Seconds from 1900, where NTP time starts, to 1970.
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+const SENDER_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+/// Roughly how long decoded audio waits in the mixer before it is heard.
+const AUDIO_PLAYOUT_DELAY: Duration = Duration::from_millis(40);
+/// Longest a video frame is held back to wait for its audio.
+const MAX_VIDEO_DELAY: Duration = Duration::from_millis(500);
+
+/// 64-bit NTP time: seconds since 1900 and a 32-bit fraction.
+pub fn ntp_timestamp(time: SystemTime) -> u64 {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since.as_secs() + NTP_UNIX_OFFSET;
+    let fraction = ((since.subsec_nanos() as u64) << 32) / 1_000_000_000;
+
+    seconds << 32 | fraction
+}
+
+fn ntp_to_system(ntp: u64) -> SystemTime {
+    let seconds = (ntp >> 32).saturating_sub(NTP_UNIX_OFFSET);
+    let nanos = ((ntp & 0xffff_ffff) * 1_000_000_000) >> 32;
+
+    UNIX_EPOCH + Duration::new(seconds, nanos as u32)
+}
+
+/// An RTP timestamp and the wall clock time it stands for.
+#[derive(Clone, Copy)]
+struct Anchor {
+    rtp: u32,
+    wall: SystemTime,
+}
+
+impl Anchor {
+    /// The wall clock time of `rtp`, on a `clock_rate` clock.
+    fn wall_at(&self, rtp: u32, clock_rate: u32) -> SystemTime {
+        // Differences over half the timestamp range are taken as going back.
+        let seconds = rtp.wrapping_sub(self.rtp) as i32 as f64 / clock_rate as f64;
+
+        if seconds >= 0.0 {
+            self.wall + Duration::from_secs_f64(seconds)
+        } else {
+            self.wall - Duration::from_secs_f64(-seconds)
+        }
+    }
+
+    /// The RTP timestamp of `wall`, on a `clock_rate` clock.
+    fn rtp_at(&self, wall: SystemTime, clock_rate: u32) -> u32 {
+        let ticks = match wall.duration_since(self.wall) {
+            Ok(ahead) => (ahead.as_secs_f64() * clock_rate as f64) as i64,
+            Err(behind) => -((behind.duration().as_secs_f64() * clock_rate as f64) as i64),
+        };
+
+        self.rtp.wrapping_add(ticks as u32)
+    }
+}
+
+/// Ties the RTP timestamps of our outgoing audio to when it was captured,
+/// so sender reports give peers capture time rather than send time. Shared
+/// by every connection, the audio track is.
+#[derive(Default)]
+pub struct MediaClock {
+    audio: Mutex<Option<Anchor>>,
+}
+
+impl MediaClock {
+    pub fn anchor_audio(&self, rtp: u32, captured: SystemTime) {
+        *self.audio.lock().unwrap() = Some(Anchor {
+            rtp,
+            wall: captured,
+        });
+    }
+
+    fn audio_rtp(&self, wall: SystemTime, clock_rate: u32) -> Option<u32> {
+        self.audio
+            .lock()
+            .unwrap()
+            .map(|anchor| anchor.rtp_at(wall, clock_rate))
+    }
+}
+
+struct RemoteStream {
+    clock_rate: u32,
+    /// From the latest sender report.
+    report: Option<Anchor>,
+}
+
+/// The clocks of a peer's incoming streams, learned from its sender
+/// reports. Its audio and video share its wall clock, which is what lines
+/// them up.
+#[derive(Default)]
+pub struct StreamSync {
+    remote: Mutex<HashMap<u32, RemoteStream>>,
+    /// Capture time of the audio decoded last, and when that was.
+    audio: Mutex<Option<(SystemTime, Instant)>>,
+}
+
+impl StreamSync {
+    fn add_stream(&self, ssrc: u32, clock_rate: u32) {
+        self.remote.lock().unwrap().insert(
+            ssrc,
+            RemoteStream {
+                clock_rate,
+                report: None,
+            },
+        );
+    }
+
+    fn remove_stream(&self, ssrc: u32) {
+        self.remote.lock().unwrap().remove(&ssrc);
+    }
+
+    fn sender_report(&self, report: &SenderReport) {
+        if let Some(stream) = self.remote.lock().unwrap().get_mut(&report.ssrc) {
+            stream.report = Some(Anchor {
+                rtp: report.rtp_time,
+                wall: ntp_to_system(report.ntp_time),
+            });
+        }
+    }
+
+    /// When the peer captured the media with RTP timestamp `rtp`, on its
+    /// wall clock. `None` until the stream's first sender report.
+    pub fn capture_time(&self, ssrc: u32, rtp: u32) -> Option<SystemTime> {
+        let remote = self.remote.lock().unwrap();
+        let stream = remote.get(&ssrc)?;
+
+        Some(stream.report?.wall_at(rtp, stream.clock_rate))
+    }
+
+    pub fn audio_decoded(&self, captured: SystemTime) {
+        *self.audio.lock().unwrap() = Some((captured, Instant::now()));
+    }
+
+    /// How long to hold a video frame captured at `captured` so it shows
+    /// along with the audio captured at the same time.
+    pub fn video_delay(&self, captured: SystemTime) -> Duration {
+        let Some((audio_captured, decoded_at)) = *self.audio.lock().unwrap() else {
+            return Duration::ZERO;
+        };
+
+        // The capture time of what is coming out of the speakers now.
+        let heard = audio_captured + decoded_at.elapsed();
+        let heard = heard.checked_sub(AUDIO_PLAYOUT_DELAY).unwrap_or(heard);
+
+        captured
+            .duration_since(heard)
+            .unwrap_or_default()
+            .min(MAX_VIDEO_DELAY)
+    }
+}
+
+struct LocalStream {
+    clock_rate: u32,
+    audio: bool,
+    packets: u32,
+    octets: u32,
+    /// The latest packet and when it was sent.
+    last: Option<Anchor>,
+}
+
+/// Sends RTCP sender reports for our streams and reads the peer's.
+///
+/// Replaces the webrtc-rs sender reports, which map RTP time to the time
+/// packets were written. Audio uses the capture time anchored in
+/// [`MediaClock`] instead, other streams still fall back to write time.
+pub struct SenderReports {
+    clock: Arc<MediaClock>,
+    sync: Arc<StreamSync>,
+    local: Arc<Mutex<HashMap<u32, LocalStream>>>,
+    closed: watch::Sender<bool>,
+}
+
+pub struct SenderReportsBuilder {
+    clock: Arc<MediaClock>,
+    sync: Arc<StreamSync>,
+}
+
+impl SenderReportsBuilder {
+    pub fn new(clock: Arc<MediaClock>, sync: Arc<StreamSync>) -> Self {
+        Self { clock, sync }
+    }
+}
+
+impl InterceptorBuilder for SenderReportsBuilder {
+    fn build(&self, _id: &str) -> Result<Arc<dyn Interceptor + Send + Sync>, Error> {
+        Ok(Arc::new(SenderReports {
+            clock: self.clock.clone(),
+            sync: self.sync.clone(),
+            local: Arc::new(Mutex::new(HashMap::new())),
+            closed: watch::channel(false).0,
+        }))
+    }
+}
+
+/// What a sender report for each stream that sent something says now.
+fn sender_reports(
+    local: &Mutex<HashMap<u32, LocalStream>>,
+    clock: &MediaClock,
+) -> Vec<Box<dyn webrtc::rtcp::packet::Packet + Send + Sync>> {
+    let now = SystemTime::now();
+
+    local
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|(ssrc, stream)| {
+            let last = stream.last?;
+            let captured = if stream.audio {
+                clock.audio_rtp(now, stream.clock_rate)
+            } else {
+                None
+            };
+            let rtp_time = captured.unwrap_or_else(|| last.rtp_at(now, stream.clock_rate));
+
+            Some(Box::new(SenderReport {
+                ssrc: *ssrc,
+                ntp_time: ntp_timestamp(now),
+                rtp_time,
+                packet_count: stream.packets,
+                octet_count: stream.octets,
+                ..Default::default()
+            })
+                as Box<dyn webrtc::rtcp::packet::Packet + Send + Sync>)
+        })
+        .collect()
+}
+
+struct SentCounter {
+    local: Arc<Mutex<HashMap<u32, LocalStream>>>,
+    next: Arc<dyn RTPWriter + Send + Sync>,
+}
+
+#[async_trait]
+impl RTPWriter for SentCounter {
+    async fn write(
+        &self,
+        pkt: &webrtc::rtp::packet::Packet,
+        attributes: &Attributes,
+    ) -> Result<usize, Error> {
+        if let Some(stream) = self.local.lock().unwrap().get_mut(&pkt.header.ssrc) {
+            stream.packets = stream.packets.wrapping_add(1);
+            stream.octets = stream.octets.wrapping_add(pkt.payload.len() as u32);
+            stream.last = Some(Anchor {
+                rtp: pkt.header.timestamp,
+                wall: SystemTime::now(),
+            });
+        }
+
+        self.next.write(pkt, attributes).await
+    }
+}
+
+struct ReportReader {
+    sync: Arc<StreamSync>,
+    next: Arc<dyn RTCPReader + Send + Sync>,
+}
+
+#[async_trait]
+impl RTCPReader for ReportReader {
+    async fn read(
+        &self,
+        buf: &mut [u8],
+        attributes: &Attributes,
+    ) -> Result<
+        (
+            Vec<Box<dyn webrtc::rtcp::packet::Packet + Send + Sync>>,
+            Attributes,
+        ),
+        Error,
+    > {
+        let (packets, attributes) = self.next.read(buf, attributes).await?;
+
+        for packet in &packets {
+            if let Some(report) = packet.as_any().downcast_ref::<SenderReport>() {
+                self.sync.sender_report(report);
+            }
+        }
+
+        Ok((packets, attributes))
+    }
+}
+
+#[async_trait]
+impl Interceptor for SenderReports {
+    async fn bind_rtcp_reader(
+        &self,
+        reader: Arc<dyn RTCPReader + Send + Sync>,
+    ) -> Arc<dyn RTCPReader + Send + Sync> {
+        Arc::new(ReportReader {
+            sync: self.sync.clone(),
+            next: reader,
+        })
+    }
+
+    async fn bind_rtcp_writer(
+        &self,
+        writer: Arc<dyn RTCPWriter + Send + Sync>,
+    ) -> Arc<dyn RTCPWriter + Send + Sync> {
+        let local = self.local.clone();
+        let clock = self.clock.clone();
+        let mut closed = self.closed.subscribe();
+        let sender = writer.clone();
+
+        tokio::spawn(async move {
+            let mut ticker = interval(SENDER_REPORT_INTERVAL);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => (),
+                    _ = closed.wait_for(|closed| *closed) => break,
+                }
+
+                let reports = sender_reports(&local, &clock);
+                if reports.is_empty() {
+                    continue;
+                }
+
+                if let Err(e) = sender.write(&reports, &Attributes::new()).await {
+                    eprintln!("Failed to send sender reports: {}", e);
+                }
+            }
+        });
+
+        writer
+    }
+
+    async fn bind_local_stream(
+        &self,
+        info: &StreamInfo,
+        writer: Arc<dyn RTPWriter + Send + Sync>,
+    ) -> Arc<dyn RTPWriter + Send + Sync> {
+        self.local.lock().unwrap().insert(
+            info.ssrc,
+            LocalStream {
+                clock_rate: info.clock_rate,
+                audio: info.mime_type.starts_with("audio/"),
+                packets: 0,
+                octets: 0,
+                last: None,
+            },
+        );
+
+        Arc::new(SentCounter {
+            local: self.local.clone(),
+            next: writer,
+        })
+    }
+
+    async fn unbind_local_stream(&self, info: &StreamInfo) {
+        self.local.lock().unwrap().remove(&info.ssrc);
+    }
+
+    async fn bind_remote_stream(
+        &self,
+        info: &StreamInfo,
+        reader: Arc<dyn RTPReader + Send + Sync>,
+    ) -> Arc<dyn RTPReader + Send + Sync> {
+        self.sync.add_stream(info.ssrc, info.clock_rate);
+        reader
+    }
+
+    async fn unbind_remote_stream(&self, info: &StreamInfo) {
+        self.sync.remove_stream(info.ssrc);
+    }
+
+    async fn close(&self) -> Result<(), Error> {
+        self.closed.send_replace(true);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ntp_time_starts_in_1900() {
+        assert_eq!(ntp_timestamp(UNIX_EPOCH), NTP_UNIX_OFFSET << 32);
+        assert_eq!(
+            ntp_timestamp(UNIX_EPOCH + Duration::from_millis(1500)),
+            (NTP_UNIX_OFFSET + 1) << 32 | 0x8000_0000
+        );
+    }
+
+    #[test]
+    fn ntp_time_round_trips() {
+        let time = UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+
+        let back = ntp_to_system(ntp_timestamp(time));
+
+        assert!(back <= time);
+        assert!(time.duration_since(back).unwrap() < Duration::from_nanos(2));
+    }
+
+    #[test]
+    fn ntp_times_before_1970_clamp_to_the_epoch() {
+        assert_eq!(ntp_to_system(0), UNIX_EPOCH);
+    }
+}
@@ -0,0 +1,144 @@
+use crate::peer::session::PeerSession;
+use crate::rooms::Room;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+
+/// Forgets the session with `other_id` and closes it, if there is one.
+pub async fn close_peer(
+    other_id: &str,
+    peer_connections: Arc<Mutex<HashMap<String, Arc<PeerSession>>>>,
+) {
+    let session = peer_connections.lock().await.remove(other_id);
+
+    if let Some(session) = session {
+        println!("\n\rClosing connection to {}", other_id);
+        session.close().await;
+    }
+}
+
+/// Closes every session and drops every buffered candidate, when leaving
+/// the room or quitting.
+pub async fn close_all_peers(
+    peer_connections: Arc<Mutex<HashMap<String, Arc<PeerSession>>>>,
+    ice_candidates: Arc<Mutex<HashMap<String, Vec<RTCIceCandidateInit>>>>,
+) {
+    ice_candidates.lock().await.clear();
+
+    let sessions: Vec<_> = peer_connections.lock().await.drain().collect();
+
+    for (other_id, session) in sessions {
+        println!("\n\rClosing connection to {}", other_id);
+        session.close().await;
+    }
+}
+
+/// Whether a session with `other_id` in `room_id` should go: they left the
+/// room, or we are now in another one.
+fn is_stale(rooms: &[Room], current_room: Option<&Room>, room_id: &str, other_id: &str) -> bool {
+    let in_room = rooms
+        .iter()
+        .find(|room| room.id == room_id)
+        .is_some_and(|room| room.users.iter().any(|u| u == other_id));
+    let moved = current_room.is_some_and(|room| room.id != room_id);
+
+    !in_room || moved
+}
+
+/// Closes sessions with users who left their room, or that belong to a
+/// room we are no longer in, and drops candidates buffered for users who
+/// are gone.
+pub async fn sync_peers(
+    user_id: &str,
+    rooms: &[Room],
+    peer_connections: Arc<Mutex<HashMap<String, Arc<PeerSession>>>>,
+    ice_candidates: Arc<Mutex<HashMap<String, Vec<RTCIceCandidateInit>>>>,
+) {
+    let current_room = rooms
+        .iter()
+        .find(|room| room.users.iter().any(|u| u == user_id));
+
+    let stale: Vec<String> = peer_connections
+        .lock()
+        .await
+        .values()
+        .filter(|session| is_stale(rooms, current_room, &session.room_id, &session.other_id))
+        .map(|session| session.other_id.clone())
+        .collect();
+
+    for other_id in stale {
+        close_peer(&other_id, peer_connections.clone()).await;
+    }
+
+    ice_candidates
+        .lock()
+        .await
+        .retain(|other_id, _| current_room.is_some_and(|room| room.users.contains(other_id)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn room(id: &str, users: &[&str]) -> Room {
+        Room {
+            id: id.to_owned(),
+            name: id.to_owned(),
+            users: users.iter().map(|user| user.to_string()).collect(),
+        }
+    }
+
+    fn candidates(users: &[&str]) -> Arc<Mutex<HashMap<String, Vec<RTCIceCandidateInit>>>> {
+        Arc::new(Mutex::new(
+            users
+                .iter()
+                .map(|user| (user.to_string(), vec![RTCIceCandidateInit::default()]))
+                .collect(),
+        ))
+    }
+
+    #[test]
+    fn sessions_go_stale_when_the_peer_leaves() {
+        let rooms = [room("a", &["me", "bob"])];
+
+        assert!(!is_stale(&rooms, Some(&rooms[0]), "a", "bob"));
+        assert!(is_stale(&rooms, Some(&rooms[0]), "a", "carol"));
+        // The room itself is gone.
+        assert!(is_stale(&[], None, "a", "bob"));
+    }
+
+    #[test]
+    fn sessions_go_stale_when_we_move_rooms() {
+        let rooms = [room("a", &["bob"]), room("b", &["me", "carol"])];
+
+        assert!(is_stale(&rooms, Some(&rooms[1]), "a", "bob"));
+        assert!(!is_stale(&rooms, Some(&rooms[1]), "b", "carol"));
+    }
+
+    #[tokio::test]
+    async fn only_candidates_for_our_room_are_kept() {
+        let ice_candidates = candidates(&["bob", "carol", "dave"]);
+        let rooms = [room("a", &["me", "bob"]), room("b", &["carol"])];
+
+        sync_peers(
+            "me",
+            &rooms,
+            Arc::new(Mutex::new(HashMap::new())),
+            ice_candidates.clone(),
+        )
+        .await;
+
+        let kept: Vec<_> = ice_candidates.lock().await.keys().cloned().collect();
+        assert_eq!(kept, ["bob"]);
+    }
+
+    #[tokio::test]
+    async fn leaving_drops_every_candidate() {
+        let ice_candidates = candidates(&["bob", "carol"]);
+
+        close_all_peers(Arc::new(Mutex::new(HashMap::new())), ice_candidates.clone()).await;
+
+        assert!(ice_candidates.lock().await.is_empty());
+    }
+}
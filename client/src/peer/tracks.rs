@@ -0,0 +1,79 @@
+use std::sync::Arc;
+use tokio::sync::watch;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtp_transceiver::{
+    rtp_codec::RTPCodecType, rtp_receiver::RTCRtpReceiver, RTCRtpTransceiver,
+};
+use webrtc::track::track_remote::TrackRemote;
+
+use crate::audio::mode::AudioMode;
+use crate::audio::receive::play_track;
+use crate::peer::session::PeerContext;
+use crate::peer::stats::ReceiveStats;
+use crate::peer::sync::StreamSync;
+use crate::video::receive::receive_video;
+
+/// Plays the peer's audio and hands its video to the video receiver, as
+/// tracks show up on the connection. RTCP is read from every receiver, so
+/// the peer's sender reports reach `sync`.
+pub fn receive_tracks(
+    peer_connection: &Arc<RTCPeerConnection>,
+    user_id: String,
+    context: &PeerContext,
+    audio_mode: watch::Receiver<AudioMode>,
+    stats: Arc<ReceiveStats>,
+    sync: Arc<StreamSync>,
+    closed: watch::Receiver<bool>,
+) {
+    println!("Receiving tracks from {:?}", peer_connection.get_stats_id());
+
+    let mixer = context.mixer.clone();
+    let replay = context.replay.clone();
+    let frame_crypto = context.frame_crypto.clone();
+    let audio_controls = context.audio_controls.clone();
+    let video = context.video.clone();
+    let video_screen = context.video_screen.clone();
+    let video_enabled = context.capabilities.video;
+    let weak_connection = Arc::downgrade(peer_connection);
+
+    peer_connection.on_track(Box::new(
+        move |track: Arc<TrackRemote>,
+              receiver: Arc<RTCRtpReceiver>,
+              _transceiver: Arc<RTCRtpTransceiver>| {
+            println!("\n\rReceived remote track: {:?}", track.ssrc());
+
+            tokio::spawn(async move { while receiver.read_rtcp().await.is_ok() {} });
+
+            match track.kind() {
+                RTPCodecType::Audio => {
+                    tokio::spawn(play_track(
+                        track,
+                        user_id.clone(),
+                        mixer.clone(),
+                        replay.clone(),
+                        frame_crypto.clone(),
+                        audio_controls.clone(),
+                        audio_mode.clone(),
+                        stats.clone(),
+                        sync.clone(),
+                        closed.clone(),
+                    ));
+                }
+                RTPCodecType::Video if video_enabled => {
+                    tokio::spawn(receive_video(
+                        track,
+                        weak_connection.clone(),
+                        user_id.clone(),
+                        video.clone(),
+                        video_screen.clone(),
+                        sync.clone(),
+                        closed.clone(),
+                    ));
+                }
+                _ => (),
+            }
+
+            Box::pin(async {})
+        },
+    ));
+}
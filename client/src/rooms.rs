@@ -2,7 +2,7 @@ use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::io::stdin;
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::Sender;
 use std::{
     io::{stdout, Write},
     sync::Arc,
@@ -12,9 +12,10 @@ use termion::raw::IntoRawMode;
 use termion::{event::Key, input::TermRead};
 use tokio::sync::Mutex;
 use tokio_tungstenite::WebSocketStream;
-use webrtc::peer_connection::RTCPeerConnection;
 
 use crate::peer::connect_peer::connect_peer;
+use crate::peer::quality::Quality;
+use crate::peer::session::{PeerContext, PeerSession};
 use crate::{
     commands::{ClientCommand, Command, CommandMessage},
     config::UserConfig,
@@ -28,13 +29,18 @@ pub struct Room {
     pub users: Vec<String>,
 }
 
-pub fn display_room(room: Room, index: usize) {
+/// Lists the users of `room`, with the quality of our connection to those
+/// we are in a call with.
+pub fn display_room(room: Room, index: usize, qualities: &HashMap<String, Quality>) {
     let mut stdout = stdout().into_raw_mode().unwrap();
     write!(stdout, "\n\r{}) Room {} :", index, room.name).unwrap();
     stdout.flush().unwrap();
 
     for user in room.users {
-        write!(stdout, "\n\r- {}", user).unwrap();
+        match qualities.get(&user) {
+            Some(quality) => write!(stdout, "\n\r- {} [{}]", user, quality).unwrap(),
+            None => write!(stdout, "\n\r- {}", user).unwrap(),
+        }
         stdout.flush().unwrap();
     }
 
@@ -44,53 +50,36 @@ pub fn display_room(room: Room, index: usize) {
 pub async fn join_room(
     room: Room,
     user: Arc<UserConfig>,
-    peer_connections: Arc<Mutex<HashMap<String, Arc<RTCPeerConnection>>>>,
-    ws_stream: Arc<
-        tokio::sync::Mutex<
-            WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>,
-        >,
-    >,
-    watch_tx: tokio::sync::watch::Sender<()>,
-    rx_audio: Arc<Mutex<Receiver<Vec<u8>>>>,
+    peer_connections: Arc<Mutex<HashMap<String, Arc<PeerSession>>>>,
+    context: PeerContext,
 ) -> Result<()> {
     let mut stdout = stdout().into_raw_mode().unwrap();
     write!(stdout, "\n\rJoining room {}\n\r", room.name).unwrap();
     stdout.flush().unwrap();
     drop(stdout);
 
+    context.frame_crypto.set_room(Some(&room.id));
+
+    // Join first, so the room list others see already has us in it when
+    // our offers reach them.
+    let _ = context.send(ClientCommand::Join(room.id.clone())).await;
+
     connect_to_room_users(
         room.clone(),
         user.clone(),
         peer_connections.clone(),
-        ws_stream.clone(),
-        watch_tx.clone(),
-        rx_audio.clone(),
+        context.clone(),
     )
     .await?;
 
-    let _ = send_message(
-        ws_stream.clone(),
-        &CommandMessage {
-            user_id: user.id.clone(),
-            command: Command::Client(ClientCommand::Join(room.id)),
-        },
-    )
-    .await;
-
     Ok(())
 }
 
 pub async fn display_rooms(
     rooms: Vec<Room>,
     user: Arc<UserConfig>,
-    peer_connections: Arc<Mutex<HashMap<String, Arc<RTCPeerConnection>>>>,
-    ws_stream: Arc<
-        tokio::sync::Mutex<
-            WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>,
-        >,
-    >,
-    watch_tx: tokio::sync::watch::Sender<()>,
-    rx_audio: Arc<Mutex<Receiver<Vec<u8>>>>,
+    peer_connections: Arc<Mutex<HashMap<String, Arc<PeerSession>>>>,
+    context: PeerContext,
 ) -> Result<()> {
     let mut stdout = std::io::stdout().into_raw_mode().unwrap();
 
@@ -100,7 +89,7 @@ pub async fn display_rooms(
 
     for index in 0..rooms.len() {
         let room: Room = rooms[index].clone();
-        display_room(room, index);
+        display_room(room, index, &HashMap::new());
     }
 
     let mut stdout = std::io::stdout().into_raw_mode().unwrap();
@@ -123,9 +112,7 @@ pub async fn display_rooms(
                             room,
                             user.clone(),
                             peer_connections.clone(),
-                            ws_stream.clone(),
-                            watch_tx.clone(),
-                            rx_audio.clone(),
+                            context.clone(),
                         )
                         .await?;
                         break;
@@ -243,14 +230,8 @@ pub async fn create_room(
 pub async fn connect_to_room_users(
     room: Room,
     user: Arc<UserConfig>,
-    peer_connections: Arc<Mutex<HashMap<String, Arc<RTCPeerConnection>>>>,
-    ws_stream: Arc<
-        tokio::sync::Mutex<
-            WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>,
-        >,
-    >,
-    watch_tx: tokio::sync::watch::Sender<()>,
-    rx_audio: Arc<Mutex<Receiver<Vec<u8>>>>,
+    peer_connections: Arc<Mutex<HashMap<String, Arc<PeerSession>>>>,
+    context: PeerContext,
 ) -> Result<()> {
     if room.users.len() > 0 {
         for index in 0..room.users.len() {
@@ -261,13 +242,10 @@ pub async fn connect_to_room_users(
             }
 
             connect_peer(
-                user.id.clone(),
                 other_id,
                 room.id.clone(),
                 peer_connections.clone(),
-                ws_stream.clone(),
-                watch_tx.clone(),
-                rx_audio.clone(),
+                context.clone(),
             )
             .await?;
         }
@@ -0,0 +1,111 @@
+use std::sync::Arc;
+use tokio::sync::{broadcast, watch};
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use webrtc::data_channel::data_channel_state::RTCDataChannelState;
+use webrtc::data_channel::RTCDataChannel;
+
+use super::protocol::ShareMessage;
+use super::{Payload, TerminalShare};
+
+/// Scrollback is sent in pieces of this size, well under the SCTP message
+/// limit. Viewers feed output to their emulator as a stream, so the cuts
+/// don't matter.
+const SNAPSHOT_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Wires a peer's terminal channel to the share: catches a new viewer up
+/// when it opens, hands over what arrives and sends what is meant for this
+/// peer, until the session closes.
+pub fn attach_share(
+    share: Arc<TerminalShare>,
+    channel: Arc<RTCDataChannel>,
+    other_id: String,
+    mut closed: watch::Receiver<bool>,
+) {
+    {
+        let share = share.clone();
+        let sender = channel.clone();
+
+        channel.on_open(Box::new(move || {
+            Box::pin(async move {
+                let Some((started, scrollback)) = share.snapshot() else {
+                    return;
+                };
+
+                let text = match serde_json::to_string(&started) {
+                    Ok(text) => text,
+                    Err(e) => {
+                        eprintln!("Failed to serialize terminal message: {}", e);
+                        return;
+                    }
+                };
+
+                if let Err(e) = sender.send_text(text).await {
+                    eprintln!("Failed to share the terminal: {}", e);
+                    return;
+                }
+                for chunk in scrollback.chunks(SNAPSHOT_CHUNK_SIZE) {
+                    if let Err(e) = sender.send(&scrollback.slice_ref(chunk)).await {
+                        eprintln!("Failed to send the terminal scrollback: {}", e);
+                        return;
+                    }
+                }
+            })
+        }));
+    }
+
+    {
+        let share = share.clone();
+        let other_id = other_id.clone();
+
+        channel.on_message(Box::new(move |message: DataChannelMessage| {
+            if !message.is_string {
+                share.handle_output(&other_id, &message.data);
+                return Box::pin(async {});
+            }
+
+            match serde_json::from_slice::<ShareMessage>(&message.data) {
+                Ok(message) => share.handle_message(&other_id, message),
+                Err(e) => eprintln!("Dropping terminal message from {}: {}", other_id, e),
+            }
+
+            Box::pin(async {})
+        }));
+    }
+
+    let mut outgoing = share.subscribe();
+
+    tokio::spawn(async move {
+        loop {
+            let message = tokio::select! {
+                message = outgoing.recv() => message,
+                _ = closed.wait_for(|closed| *closed) => break,
+            };
+
+            let message = match message {
+                Ok(message) => message,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    eprintln!("Dropped {} terminal updates to {}", skipped, other_id);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+
+            if message.to.as_ref().is_some_and(|to| to != &other_id)
+                || channel.ready_state() != RTCDataChannelState::Open
+            {
+                continue;
+            }
+
+            let sent = match message.payload {
+                Payload::Text(text) => channel.send_text(text.as_str().to_owned()).await,
+                Payload::Binary(data) => channel.send(&data).await,
+            };
+
+            if let Err(e) = sent {
+                eprintln!("Failed to send terminal update to {}: {}", other_id, e);
+            }
+        }
+
+        share.peer_left(&other_id);
+    });
+}
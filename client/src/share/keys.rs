@@ -0,0 +1,67 @@
+use termion::event::Key;
+
+/// Ctrl-], as termion reports it. Leads the share commands, so it never
+/// reaches the shared shell on its own.
+pub const PREFIX_KEY: Key = Key::Ctrl('5');
+
+/// The bytes a terminal sends for `key`, to pass it on to a shell.
+pub fn key_bytes(key: Key) -> Vec<u8> {
+    match key {
+        Key::Char('\n') => b"\r".to_vec(),
+        Key::Char(c) => c.to_string().into_bytes(),
+        // termion reports the control codes after Ctrl-Z as Ctrl-4 to Ctrl-7.
+        Key::Ctrl(c @ '4'..='7') => vec![c as u8 - b'4' + 0x1c],
+        Key::Ctrl(c) => vec![(c as u8) & 0x1f],
+        Key::Alt(c) => {
+            let mut bytes = vec![0x1b];
+            bytes.extend(c.to_string().into_bytes());
+            bytes
+        }
+        Key::Backspace => vec![0x7f],
+        Key::Esc => vec![0x1b],
+        Key::Null => vec![0],
+        Key::Left => b"\x1b[D".to_vec(),
+        Key::Right => b"\x1b[C".to_vec(),
+        Key::Up => b"\x1b[A".to_vec(),
+        Key::Down => b"\x1b[B".to_vec(),
+        Key::Home => b"\x1b[H".to_vec(),
+        Key::End => b"\x1b[F".to_vec(),
+        Key::PageUp => b"\x1b[5~".to_vec(),
+        Key::PageDown => b"\x1b[6~".to_vec(),
+        Key::Delete => b"\x1b[3~".to_vec(),
+        Key::Insert => b"\x1b[2~".to_vec(),
+        Key::BackTab => b"\x1b[Z".to_vec(),
+        Key::F(n @ 1..=4) => format!("\x1bO{}", (b'P' + n - 1) as char).into_bytes(),
+        Key::F(n @ 5..=12) => {
+            let code = [15, 17, 18, 19, 20, 21, 23, 24][(n - 5) as usize];
+            format!("\x1b[{}~", code).into_bytes()
+        }
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_become_terminal_input() {
+        assert_eq!(key_bytes(Key::Char('a')), b"a");
+        assert_eq!(key_bytes(Key::Char('é')), "é".as_bytes());
+        assert_eq!(key_bytes(Key::Char('\n')), b"\r");
+        assert_eq!(key_bytes(Key::Alt('x')), b"\x1bx");
+        assert_eq!(key_bytes(Key::Up), b"\x1b[A");
+        assert_eq!(key_bytes(Key::F(1)), b"\x1bOP");
+        assert_eq!(key_bytes(Key::F(12)), b"\x1b[24~");
+    }
+
+    #[test]
+    fn control_keys_become_control_codes() {
+        assert_eq!(key_bytes(Key::Ctrl('c')), [0x03]);
+        assert_eq!(key_bytes(Key::Ctrl('d')), [0x04]);
+        // Ctrl-\ to Ctrl-_ come through as Ctrl-4 to Ctrl-7.
+        assert_eq!(key_bytes(Key::Ctrl('4')), [0x1c]);
+        assert_eq!(key_bytes(PREFIX_KEY), [0x1d]);
+        assert_eq!(key_bytes(Key::Ctrl('7')), [0x1f]);
+    }
+}
@@ -0,0 +1,557 @@
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use portable_pty::{native_pty_system, Child, CommandBuilder, MasterPty, PtySize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{stdout, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+use protocol::ShareMessage;
+
+pub mod channel;
+pub mod keys;
+pub mod protocol;
+pub mod view;
+
+/// Output kept for viewers who connect in the middle of a share.
+const SCROLLBACK_BYTES: usize = 256 * 1024;
+/// Lines viewers can scroll back through.
+const SCROLLBACK_LINES: usize = 1000;
+const RESIZE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const READ_BUFFER_SIZE: usize = 8192;
+const OUTGOING_CAPACITY: usize = 256;
+/// Largest terminal a peer can make us emulate, each cell costs memory.
+const MAX_ROWS: u16 = 500;
+const MAX_COLS: u16 = 1000;
+
+/// Keeps a peer's terminal size within what the emulator can hold.
+fn clamp_size(rows: u16, cols: u16) -> (u16, u16) {
+    (rows.clamp(1, MAX_ROWS), cols.clamp(1, MAX_COLS))
+}
+
+#[derive(Clone)]
+pub enum Payload {
+    Text(Arc<String>),
+    Binary(Bytes),
+}
+
+/// A message for the terminal channel of one peer, or of all of them.
+#[derive(Clone)]
+pub struct Outgoing {
+    pub to: Option<String>,
+    pub payload: Payload,
+}
+
+struct Presenter {
+    /// Tells the output of this share from the one of an earlier share.
+    generation: u64,
+    master: Box<dyn MasterPty + Send>,
+    writer: Box<dyn Write + Send>,
+    child: Box<dyn Child + Send + Sync>,
+    cols: u16,
+    rows: u16,
+    scrollback: VecDeque<u8>,
+    /// Viewers allowed to type.
+    controllers: HashSet<String>,
+    /// Viewers who asked for control, oldest first.
+    requests: VecDeque<String>,
+}
+
+/// A terminal someone else shares with us.
+pub struct RemoteTerminal {
+    pub parser: vt100::Parser,
+    pub controlling: bool,
+}
+
+/// Our shared terminal if we present one, and the ones peers share with
+/// us. Viewers replay the output into a terminal emulator, so they can
+/// show it cropped to their own size and scroll back through it.
+pub struct TerminalShare {
+    presenter: Mutex<Option<Presenter>>,
+    generations: AtomicU64,
+    remotes: Mutex<HashMap<String, RemoteTerminal>>,
+    watching: Mutex<Option<String>>,
+    outgoing: broadcast::Sender<Outgoing>,
+}
+
+impl TerminalShare {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            presenter: Mutex::new(None),
+            generations: AtomicU64::new(0),
+            remotes: Mutex::new(HashMap::new()),
+            watching: Mutex::new(None),
+            outgoing: broadcast::channel(OUTGOING_CAPACITY).0,
+        })
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Outgoing> {
+        self.outgoing.subscribe()
+    }
+
+    fn send(&self, to: Option<&str>, message: &ShareMessage) {
+        match serde_json::to_string(message) {
+            Ok(text) => {
+                let _ = self.outgoing.send(Outgoing {
+                    to: to.map(str::to_owned),
+                    payload: Payload::Text(Arc::new(text)),
+                });
+            }
+            Err(e) => eprintln!("Failed to serialize terminal message: {}", e),
+        }
+    }
+
+    pub fn is_presenting(&self) -> bool {
+        self.presenter.lock().unwrap().is_some()
+    }
+
+    /// Starts a shell in a PTY the size of our terminal and shares it. Its
+    /// output is shown here too, the caller forwards our keys to it.
+    pub fn start(self: &Arc<Self>) -> Result<()> {
+        let (cols, rows) = termion::terminal_size()?;
+
+        let pair = native_pty_system().openpty(PtySize {
+            rows,
+            cols,
+            pixel_width: 0,
+            pixel_height: 0,
+        })?;
+
+        let shell = std::env::var("SHELL").unwrap_or_else(|_| "sh".to_owned());
+        let child = pair.slave.spawn_command(CommandBuilder::new(shell))?;
+        let mut reader = pair.master.try_clone_reader()?;
+        let writer = pair.master.take_writer()?;
+
+        let generation = self.generations.fetch_add(1, Ordering::Relaxed);
+
+        *self.presenter.lock().unwrap() = Some(Presenter {
+            generation,
+            master: pair.master,
+            writer,
+            child,
+            cols,
+            rows,
+            scrollback: VecDeque::new(),
+            controllers: HashSet::new(),
+            requests: VecDeque::new(),
+        });
+
+        self.send(None, &ShareMessage::Started { cols, rows });
+
+        // PTY reads block, they get their own thread.
+        let share = self.clone();
+        std::thread::spawn(move || {
+            let mut buffer = [0u8; READ_BUFFER_SIZE];
+            while let Ok(read @ 1..) = reader.read(&mut buffer) {
+                share.on_output(generation, &buffer[..read]);
+            }
+            share.stop_generation(generation);
+        });
+
+        let share = Arc::downgrade(self);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(RESIZE_POLL_INTERVAL).await;
+
+                match share.upgrade() {
+                    Some(share) if share.sync_size(generation) => (),
+                    _ => break,
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    fn on_output(&self, generation: u64, data: &[u8]) {
+        {
+            let mut presenter = self.presenter.lock().unwrap();
+            let Some(presenter) = presenter
+                .as_mut()
+                .filter(|presenter| presenter.generation == generation)
+            else {
+                return;
+            };
+
+            presenter.scrollback.extend(data);
+            let excess = presenter.scrollback.len().saturating_sub(SCROLLBACK_BYTES);
+            presenter.scrollback.drain(..excess);
+        }
+
+        let mut stdout = stdout().lock();
+        let _ = stdout.write_all(data);
+        let _ = stdout.flush();
+
+        let _ = self.outgoing.send(Outgoing {
+            to: None,
+            payload: Payload::Binary(Bytes::copy_from_slice(data)),
+        });
+    }
+
+    /// Follows our terminal size. Returns `false` once the share is over.
+    fn sync_size(&self, generation: u64) -> bool {
+        let Ok((cols, rows)) = termion::terminal_size() else {
+            return true;
+        };
+
+        {
+            let mut presenter = self.presenter.lock().unwrap();
+            let Some(presenter) = presenter
+                .as_mut()
+                .filter(|presenter| presenter.generation == generation)
+            else {
+                return false;
+            };
+
+            if (presenter.cols, presenter.rows) == (cols, rows) {
+                return true;
+            }
+
+            let size = PtySize {
+                rows,
+                cols,
+                pixel_width: 0,
+                pixel_height: 0,
+            };
+            if let Err(e) = presenter.master.resize(size) {
+                eprintln!("Failed to resize the shared terminal: {}", e);
+                return true;
+            }
+
+            presenter.cols = cols;
+            presenter.rows = rows;
+        }
+
+        self.send(None, &ShareMessage::Resize { cols, rows });
+        true
+    }
+
+    /// What a viewer connecting now needs: the size and recent output.
+    pub fn snapshot(&self) -> Option<(ShareMessage, Bytes)> {
+        let presenter = self.presenter.lock().unwrap();
+        let presenter = presenter.as_ref()?;
+
+        Some((
+            ShareMessage::Started {
+                cols: presenter.cols,
+                rows: presenter.rows,
+            },
+            Bytes::from(presenter.scrollback.iter().copied().collect::<Vec<_>>()),
+        ))
+    }
+
+    pub fn write_input(&self, bytes: &[u8]) -> Result<()> {
+        let mut presenter = self.presenter.lock().unwrap();
+        let presenter = presenter
+            .as_mut()
+            .ok_or_else(|| anyhow!("Not sharing a terminal"))?;
+
+        presenter.writer.write_all(bytes)?;
+        presenter.writer.flush()?;
+        Ok(())
+    }
+
+    pub fn stop(&self) {
+        let generation = self
+            .presenter
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|presenter| presenter.generation);
+
+        if let Some(generation) = generation {
+            self.stop_generation(generation);
+        }
+    }
+
+    fn stop_generation(&self, generation: u64) {
+        let presenter = {
+            let mut presenter = self.presenter.lock().unwrap();
+            if presenter
+                .as_ref()
+                .is_none_or(|presenter| presenter.generation != generation)
+            {
+                return;
+            }
+            presenter.take()
+        };
+
+        if let Some(mut presenter) = presenter {
+            let _ = presenter.child.kill();
+            self.send(None, &ShareMessage::Stopped);
+            println!("\n\rStopped sharing the terminal");
+        }
+    }
+
+    /// Lets the viewer who asked first type into our terminal.
+    pub fn grant_next(&self) -> Result<String> {
+        let viewer = {
+            let mut presenter = self.presenter.lock().unwrap();
+            let presenter = presenter
+                .as_mut()
+                .ok_or_else(|| anyhow!("Not sharing a terminal"))?;
+            let viewer = presenter
+                .requests
+                .pop_front()
+                .ok_or_else(|| anyhow!("Nobody asked for control"))?;
+            presenter.controllers.insert(viewer.clone());
+            viewer
+        };
+
+        self.send(Some(&viewer), &ShareMessage::Granted);
+        Ok(viewer)
+    }
+
+    /// Takes control back from every viewer.
+    pub fn revoke_all(&self) -> Vec<String> {
+        let viewers: Vec<_> = match self.presenter.lock().unwrap().as_mut() {
+            Some(presenter) => presenter.controllers.drain().collect(),
+            None => Vec::new(),
+        };
+
+        for viewer in &viewers {
+            self.send(Some(viewer), &ShareMessage::Revoked);
+        }
+
+        viewers
+    }
+
+    pub fn handle_message(&self, from: &str, message: ShareMessage) {
+        match message {
+            ShareMessage::Started { cols, rows } => {
+                let (rows, cols) = clamp_size(rows, cols);
+                self.remotes.lock().unwrap().insert(
+                    from.to_owned(),
+                    RemoteTerminal {
+                        parser: vt100::Parser::new(rows, cols, SCROLLBACK_LINES),
+                        controlling: false,
+                    },
+                );
+
+                if self.watching_id().as_deref() != Some(from) {
+                    println!("\n\r{} is sharing their terminal, press v to watch", from);
+                }
+            }
+            ShareMessage::Resize { cols, rows } => {
+                let (rows, cols) = clamp_size(rows, cols);
+                if let Some(remote) = self.remotes.lock().unwrap().get_mut(from) {
+                    remote.parser.set_size(rows, cols);
+                }
+                self.redraw(from);
+            }
+            ShareMessage::Stopped => self.remove_remote(from),
+            ShareMessage::Granted | ShareMessage::Revoked => {
+                let controlling = matches!(message, ShareMessage::Granted);
+                if let Some(remote) = self.remotes.lock().unwrap().get_mut(from) {
+                    remote.controlling = controlling;
+                }
+                self.redraw(from);
+            }
+            ShareMessage::RequestControl => {
+                let mut presenter = self.presenter.lock().unwrap();
+                if let Some(presenter) = presenter.as_mut() {
+                    if !presenter.requests.iter().any(|viewer| viewer == from) {
+                        presenter.requests.push_back(from.to_owned());
+                        println!(
+                            "\n\r{} asks to control your terminal, press Ctrl-] then g to allow",
+                            from
+                        );
+                    }
+                }
+            }
+            ShareMessage::Input(bytes) => {
+                let allowed = self
+                    .presenter
+                    .lock()
+                    .unwrap()
+                    .as_ref()
+                    .is_some_and(|presenter| presenter.controllers.contains(from));
+
+                if allowed {
+                    if let Err(e) = self.write_input(&bytes) {
+                        eprintln!("Failed to pass on input from {}: {}", from, e);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn handle_output(&self, from: &str, data: &[u8]) {
+        if let Some(remote) = self.remotes.lock().unwrap().get_mut(from) {
+            remote.parser.process(data);
+        }
+        self.redraw(from);
+    }
+
+    /// Forgets what `from` shared with us and what we allowed it to do.
+    pub fn peer_left(&self, from: &str) {
+        if let Some(presenter) = self.presenter.lock().unwrap().as_mut() {
+            presenter.controllers.remove(from);
+            presenter.requests.retain(|viewer| viewer != from);
+        }
+        self.remove_remote(from);
+    }
+
+    fn remove_remote(&self, from: &str) {
+        if self.remotes.lock().unwrap().remove(from).is_none() {
+            return;
+        }
+
+        let mut watching = self.watching.lock().unwrap();
+        if watching.as_deref() == Some(from) {
+            *watching = None;
+            view::clear();
+        }
+        println!("\n\r{} stopped sharing their terminal", from);
+    }
+
+    fn watching_id(&self) -> Option<String> {
+        self.watching.lock().unwrap().clone()
+    }
+
+    fn redraw(&self, from: &str) {
+        if self.watching_id().as_deref() != Some(from) {
+            return;
+        }
+
+        if let Some(remote) = self.remotes.lock().unwrap().get(from) {
+            view::draw(from, remote);
+        }
+    }
+
+    /// Starts showing a shared terminal, returning whose.
+    pub fn watch(&self) -> Option<String> {
+        let from = self.remotes.lock().unwrap().keys().next().cloned()?;
+        *self.watching.lock().unwrap() = Some(from.clone());
+        self.redraw(&from);
+        Some(from)
+    }
+
+    /// Whether we are still watching, the share may have ended meanwhile.
+    pub fn is_watching(&self) -> bool {
+        self.watching.lock().unwrap().is_some()
+    }
+
+    pub fn stop_watching(&self) {
+        if self.watching.lock().unwrap().take().is_some() {
+            view::clear();
+        }
+    }
+
+    /// Scrolls the watched terminal by half a screen.
+    pub fn scroll(&self, up: bool) {
+        let Some(from) = self.watching_id() else {
+            return;
+        };
+
+        if let Some(remote) = self.remotes.lock().unwrap().get_mut(&from) {
+            let page = (remote.parser.screen().size().0 / 2).max(1) as usize;
+            let current = remote.parser.screen().scrollback();
+            let next = if up {
+                current + page
+            } else {
+                current.saturating_sub(page)
+            };
+            remote.parser.set_scrollback(next);
+        }
+        self.redraw(&from);
+    }
+
+    /// Whether keys typed while watching go to the presenter.
+    pub fn controlling(&self) -> bool {
+        let Some(from) = self.watching_id() else {
+            return false;
+        };
+
+        self.remotes
+            .lock()
+            .unwrap()
+            .get(&from)
+            .is_some_and(|remote| remote.controlling)
+    }
+
+    pub fn request_control(&self) {
+        if let Some(from) = self.watching_id() {
+            self.send(Some(&from), &ShareMessage::RequestControl);
+        }
+    }
+
+    /// Sends keystrokes to the terminal we are watching.
+    pub fn send_input(&self, bytes: Vec<u8>) {
+        if let Some(from) = self.watching_id() {
+            // Typing jumps back to the live screen.
+            if let Some(remote) = self.remotes.lock().unwrap().get_mut(&from) {
+                remote.parser.set_scrollback(0);
+            }
+            self.send(Some(&from), &ShareMessage::Input(bytes));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn started(share: &TerminalShare, from: &str, cols: u16, rows: u16) {
+        share.handle_message(from, ShareMessage::Started { cols, rows });
+    }
+
+    #[test]
+    fn peer_sizes_are_clamped() {
+        assert_eq!(clamp_size(0, 0), (1, 1));
+        assert_eq!(clamp_size(24, 80), (24, 80));
+        assert_eq!(clamp_size(u16::MAX, u16::MAX), (MAX_ROWS, MAX_COLS));
+    }
+
+    #[test]
+    fn shared_output_is_replayed_at_the_peer_size() {
+        let share = TerminalShare::new();
+        started(&share, "alice", u16::MAX, 10);
+        share.handle_output("alice", b"hello");
+
+        let remotes = share.remotes.lock().unwrap();
+        let screen = remotes["alice"].parser.screen();
+        assert_eq!(screen.size(), (10, MAX_COLS));
+        assert_eq!(screen.contents(), "hello");
+    }
+
+    #[test]
+    fn stopping_or_leaving_forgets_the_terminal() {
+        let share = TerminalShare::new();
+        started(&share, "alice", 80, 24);
+        started(&share, "bob", 80, 24);
+
+        share.handle_message("alice", ShareMessage::Stopped);
+        share.peer_left("bob");
+
+        assert!(share.remotes.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn control_is_only_granted_while_presenting() {
+        let share = TerminalShare::new();
+
+        // Not presenting, so requests and input go nowhere.
+        share.handle_message("alice", ShareMessage::RequestControl);
+        share.handle_message("alice", ShareMessage::Input(b"rm -rf /".to_vec()));
+
+        assert!(share.grant_next().is_err());
+        assert!(share.revoke_all().is_empty());
+    }
+
+    #[test]
+    fn grants_only_apply_to_shared_terminals() {
+        let share = TerminalShare::new();
+        started(&share, "alice", 80, 24);
+
+        share.handle_message("alice", ShareMessage::Granted);
+        share.handle_message("bob", ShareMessage::Granted);
+        assert!(share.remotes.lock().unwrap()["alice"].controlling);
+        assert!(!share.remotes.lock().unwrap().contains_key("bob"));
+
+        share.handle_message("alice", ShareMessage::Revoked);
+        assert!(!share.remotes.lock().unwrap()["alice"].controlling);
+    }
+}
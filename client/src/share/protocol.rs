@@ -0,0 +1,24 @@
+use serde::{Deserialize, Serialize};
+
+/// Control messages of a terminal share, sent as text on the terminal data
+/// channel. The shell output itself goes as binary messages.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ShareMessage {
+    /// Sharing started, or a viewer connected in the middle of it. The
+    /// scrollback follows as output.
+    Started {
+        cols: u16,
+        rows: u16,
+    },
+    Resize {
+        cols: u16,
+        rows: u16,
+    },
+    Stopped,
+    /// A viewer asks to type into the shared terminal.
+    RequestControl,
+    Granted,
+    Revoked,
+    /// Keystrokes from a viewer that has control.
+    Input(Vec<u8>),
+}
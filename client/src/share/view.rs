@@ -0,0 +1,75 @@
+use std::io::{stdout, Write};
+
+use super::RemoteTerminal;
+
+/// Draws a shared terminal over the whole screen, cropped to our size, with
+/// a status line at the bottom.
+pub fn draw(user_id: &str, remote: &RemoteTerminal) {
+    let (cols, rows) = termion::terminal_size().unwrap_or((80, 24));
+    let screen = remote.parser.screen();
+    let (remote_rows, remote_cols) = screen.size();
+    let visible_rows = rows.saturating_sub(1);
+
+    let mut out = Vec::new();
+    out.extend_from_slice(b"\x1b[?25l");
+
+    let mut lines = screen.rows_formatted(0, cols);
+    for row in 0..visible_rows {
+        let _ = write!(out, "{}\x1b[0m\x1b[2K", termion::cursor::Goto(1, row + 1));
+        if let Some(line) = lines.next() {
+            out.extend_from_slice(&line);
+        }
+    }
+
+    let scrolled = match screen.scrollback() {
+        0 => String::new(),
+        lines => format!(", {} lines up", lines),
+    };
+    let control = if remote.controlling {
+        "you have control"
+    } else {
+        "c to ask for control"
+    };
+    let status = format!(
+        " {}'s terminal ({}x{}{}) - PgUp/PgDn scroll, {}, Ctrl-] to stop watching",
+        user_id, remote_cols, remote_rows, scrolled, control
+    );
+    let status: String = status.chars().take(cols as usize).collect();
+
+    let _ = write!(
+        out,
+        "{}\x1b[0m\x1b[2K\x1b[7m{}\x1b[0m",
+        termion::cursor::Goto(1, rows),
+        status
+    );
+
+    // Put the cursor where the remote one is when it is on our screen.
+    let (cursor_row, cursor_col) = screen.cursor_position();
+    if !screen.hide_cursor()
+        && screen.scrollback() == 0
+        && cursor_row < visible_rows
+        && cursor_col < cols
+    {
+        let _ = write!(
+            out,
+            "{}\x1b[?25h",
+            termion::cursor::Goto(cursor_col + 1, cursor_row + 1)
+        );
+    }
+
+    let mut stdout = stdout().lock();
+    let _ = stdout.write_all(&out);
+    let _ = stdout.flush();
+}
+
+/// Leaves the full screen view.
+pub fn clear() {
+    let mut stdout = stdout().lock();
+    let _ = write!(
+        stdout,
+        "\x1b[0m{}{}\x1b[?25h",
+        termion::clear::All,
+        termion::cursor::Goto(1, 1)
+    );
+    let _ = stdout.flush();
+}
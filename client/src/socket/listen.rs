@@ -1,25 +1,48 @@
-use crate::audio::capture::capture_audio;
-use crate::commands::{Command, CommandMessage, ServerCommand};
+use crate::audio::capture::{capture_audio, SAMPLE_RATE};
+use crate::audio::controls::AudioControls;
+use crate::audio::mixer::Mixer;
+use crate::audio::replay::ReplayRecorder;
+use crate::audio::send::{create_audio_track, send_audio, AudioSender};
+use crate::audio::soundboard::Soundboard;
+use crate::chat::Chat;
+use crate::commands::{ClientCommand, Command, CommandMessage, ServerCommand};
 use crate::config::UserConfig;
+use crate::e2ee::FrameCrypto;
+use crate::identity::Identity;
+use crate::input::listen_for_call_input;
 use crate::peer::{
-    handle_answer::handle_answer, handle_ice_candidate::handle_ice_candidate,
+    bandwidth::UploadBudget,
+    handle_answer::handle_answer,
+    handle_ice_candidate::handle_ice_candidate,
     handle_offer::handle_offer,
+    ice_servers::IceServers,
+    metrics::serve_metrics,
+    quality::CallReport,
+    session::{PeerContext, PeerSession},
+    sync::MediaClock,
+    teardown::sync_peers,
 };
 use crate::rooms::{display_empty_room, display_room, display_rooms};
+use crate::share::TerminalShare;
+use crate::transfer::Transfers;
+use crate::video::source::{create_video_track, send_video};
+use crate::video::terminal::VideoScreen;
 
 use anyhow::Result;
 use futures_util::StreamExt;
+use rtrb::RingBuffer;
 use std::collections::HashMap;
 use std::io::{stdout, Write};
-use std::sync::mpsc::{self, Sender};
+use std::sync::mpsc::Sender;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
     Arc,
 };
-use tokio::sync::{watch, Mutex};
+use std::time::Duration;
+use tokio::sync::{watch, Mutex, Notify};
 use tokio_tungstenite::WebSocketStream;
 use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
-use webrtc::peer_connection::{sdp::session_description::RTCSessionDescription, RTCPeerConnection};
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 
 pub async fn listen_for_ws(
     tx: Sender<()>,
@@ -30,21 +53,98 @@ pub async fn listen_for_ws(
         >,
     >,
 ) -> Result<()> {
-    let peer_connections: Arc<Mutex<HashMap<String, Arc<RTCPeerConnection>>>> =
+    let peer_connections: Arc<Mutex<HashMap<String, Arc<PeerSession>>>> =
         Arc::new(Mutex::new(HashMap::new()));
 
     let ice_candidates: Arc<Mutex<HashMap<String, Vec<RTCIceCandidateInit>>>> =
         Arc::new(Mutex::new(HashMap::new()));
 
-    let (tx_audio, rx_audio): (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) = mpsc::channel();
-
-    let rx_audio = Arc::new(Mutex::new(rx_audio));
-    let tx_audio = Arc::new(Mutex::new(tx_audio));
+    let audio_track = create_audio_track(&user.codecs);
+    let video_track = if user.capabilities.video {
+        create_video_track(&user.video, &user.codecs)
+            .await
+            .unwrap_or_else(|e| {
+                eprintln!("Continuing without sending video: {:#}", e);
+                None
+            })
+    } else {
+        None
+    };
+    let audio_controls = AudioControls::new(&user.audio);
+    let mixer = Mixer::start().await;
+    let replay = ReplayRecorder::start(&user.audio).unwrap_or_else(|e| {
+        eprintln!("Continuing without the replay buffer: {:#}", e);
+        ReplayRecorder::disabled()
+    });
+    let (soundboard, soundboard_player) = Soundboard::load(&user.audio.soundboard).await;
+    let transfers = Transfers::new(&user.transfers).unwrap_or_else(|e| {
+        let download_dir = std::env::temp_dir();
+        eprintln!(
+            "{:#}, received files are saved to {}",
+            e,
+            download_dir.display()
+        );
+        Transfers::in_dir(download_dir)
+    });
+    let identity = Identity::load(&user.id).unwrap_or_else(|e| {
+        eprintln!("Using a key for this run only: {:#}", e);
+        Identity::ephemeral(&user.id)
+    });
 
     let audio_capture_started = Arc::new(AtomicBool::new(false));
+    let call_input_started = Arc::new(AtomicBool::new(false));
 
     let (watch_tx, mut watch_rx) = watch::channel(());
 
+    let context = PeerContext {
+        user_id: user.id.clone(),
+        ws_stream: ws_stream.clone(),
+        watch_tx: watch_tx.clone(),
+        capabilities: user.capabilities.clone(),
+        audio_track: audio_track.clone(),
+        audio_controls: audio_controls.clone(),
+        video_track: video_track.clone(),
+        video: Arc::new(user.video.clone()),
+        video_screen: VideoScreen::new(),
+        keyframe_requests: Arc::new(Notify::new()),
+        mixer: mixer.clone(),
+        replay: replay.clone(),
+        ice_servers: Arc::new(IceServers::new(&user.network.ice_servers)),
+        network: Arc::new(user.network.clone()),
+        codecs: Arc::new(user.codecs.clone()),
+        stats_config: Arc::new(user.stats.clone()),
+        upload: Arc::new(UploadBudget::new(user.network.upload_bitrate)),
+        chat: Chat::new(&user.id, &user.name),
+        transfers,
+        share: TerminalShare::new(),
+        identity,
+        frame_crypto: FrameCrypto::new(user.audio.frame_encryption),
+        call_report: Arc::new(CallReport::default()),
+        media_clock: Arc::new(MediaClock::default()),
+    };
+
+    if let Some(address) = user.stats.metrics_address.clone() {
+        let peer_connections = peer_connections.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = serve_metrics(address, peer_connections).await {
+                eprintln!("Metrics endpoint stopped: {}", e);
+            }
+        });
+    }
+
+    if let Some(video_track) = video_track {
+        let config = user.video.clone();
+        let upload = context.upload.clone();
+        let keyframe_requests = context.keyframe_requests.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = send_video(video_track, config, upload, keyframe_requests).await {
+                eprintln!("Video source stopped: {}", e);
+            }
+        });
+    }
+
     tokio::spawn(async move {
         while watch_rx.changed().await.is_ok() {
             println!("\n\rAudio capture changed");
@@ -54,7 +154,28 @@ pub async fn listen_for_ws(
     if !audio_capture_started.load(Ordering::SeqCst) {
         println!("\n\rStarting audio capture");
         audio_capture_started.store(true, Ordering::SeqCst);
-        tokio::spawn(capture_audio(tx_audio));
+
+        // One second of audio, whatever the device channel count.
+        let (producer, consumer) = RingBuffer::new(SAMPLE_RATE as usize * 8);
+        match capture_audio(producer).await {
+            Ok(format) => {
+                let sidetone = mixer.add_source(audio_controls.sidetone_volume.clone())?;
+
+                tokio::spawn(send_audio(AudioSender {
+                    consumer,
+                    format,
+                    audio_track: audio_track.clone(),
+                    controls: audio_controls.clone(),
+                    upload: context.upload.clone(),
+                    crypto: context.frame_crypto.clone(),
+                    clock: context.media_clock.clone(),
+                    config: user.audio.clone(),
+                    sidetone,
+                    soundboard: soundboard_player,
+                }));
+            }
+            Err(e) => eprintln!("No microphone, joining without sending audio: {:#}", e),
+        }
     }
 
     loop {
@@ -97,7 +218,13 @@ pub async fn listen_for_ws(
 
         let text = text.unwrap();
 
-        let command_message: CommandMessage = serde_json::from_str(text).unwrap();
+        let command_message: CommandMessage = match serde_json::from_str(text) {
+            Ok(command_message) => command_message,
+            Err(e) => {
+                eprintln!("Skipping message the client doesn't understand: {}", e);
+                continue;
+            }
+        };
 
         if command_message.user_id != user.id {
             continue;
@@ -108,20 +235,50 @@ pub async fn listen_for_ws(
 
         match command_message.command {
             Command::Server(ServerCommand::RoomList(rooms)) => {
+                sync_peers(
+                    &user.id,
+                    &rooms,
+                    peer_connections.clone(),
+                    ice_candidates.clone(),
+                )
+                .await;
+
                 let current_room = rooms.iter().find(|room| room.users.contains(&user.id));
 
                 if let Some(current_room) = current_room {
-                    display_room(current_room.clone(), 0);
+                    context.frame_crypto.set_room(Some(&current_room.id));
+
+                    let qualities: HashMap<_, _> = peer_connections
+                        .lock()
+                        .await
+                        .iter()
+                        .filter_map(|(id, session)| Some((id.clone(), session.stats().quality?)))
+                        .collect();
+
+                    display_room(current_room.clone(), 0, &qualities);
+
+                    if !call_input_started.swap(true, Ordering::SeqCst) {
+                        tokio::spawn(listen_for_call_input(
+                            tx.clone(),
+                            current_room.id.clone(),
+                            audio_controls.clone(),
+                            replay.clone(),
+                            soundboard.clone(),
+                            peer_connections.clone(),
+                            ice_candidates.clone(),
+                            context.clone(),
+                        ));
+                    }
                 } else if rooms.is_empty() {
+                    call_input_started.store(false, Ordering::SeqCst);
                     display_empty_room(tx.clone(), user.clone(), ws_stream.clone()).await?;
                 } else {
+                    call_input_started.store(false, Ordering::SeqCst);
                     display_rooms(
                         rooms,
                         user.clone(),
                         peer_connections.clone(),
-                        ws_stream.clone(),
-                        watch_tx.clone(),
-                        rx_audio.clone(),
+                        context.clone(),
                     )
                     .await?;
                 }
@@ -132,7 +289,45 @@ pub async fn listen_for_ws(
                 _room_id,
                 candidate,
             )) => {
-                handle_ice_candidate(from_user, candidate, ice_candidates.clone()).await?;
+                handle_ice_candidate(
+                    from_user,
+                    candidate,
+                    peer_connections.clone(),
+                    ice_candidates.clone(),
+                )
+                .await?;
+            }
+
+            Command::Server(ServerCommand::IncomingAudioMode(from_user, _room_id, mode)) => {
+                write!(stdout, "\n\r{} switched to {:?} mode\n\r", from_user, mode).unwrap();
+                stdout.flush().unwrap();
+
+                if let Some(session) = peer_connections.lock().await.get(&from_user) {
+                    session.set_audio_mode(mode);
+                }
+            }
+
+            Command::Server(ServerCommand::IceServers(servers, ttl)) => {
+                write!(
+                    stdout,
+                    "\n\rReceived TURN credentials valid for {}s\n\r",
+                    ttl
+                )
+                .unwrap();
+                stdout.flush().unwrap();
+
+                context.ice_servers.set_pushed(&servers);
+
+                // Ask for new ones well before these expire, but not in a
+                // loop when the server hands out very short-lived ones.
+                let context = context.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(Duration::from_secs((ttl * 3 / 4).max(30))).await;
+
+                    if let Err(e) = context.send(ClientCommand::RefreshIceServers).await {
+                        eprintln!("Failed to refresh TURN credentials: {}", e);
+                    }
+                });
             }
 
             Command::Server(ServerCommand::IncomingAnswer(from_user, _room_id, sdp)) => {
@@ -146,12 +341,9 @@ pub async fn listen_for_ws(
                     from_user,
                     room_id,
                     offer,
-                    &user.id,
                     peer_connections.clone(),
                     ice_candidates.clone(),
-                    ws_stream.clone(),
-                    watch_tx.clone(),
-                    rx_audio.clone(),
+                    context.clone(),
                 )
                 .await?;
             }
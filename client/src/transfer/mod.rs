@@ -0,0 +1,518 @@
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Mutex as AsyncMutex;
+use uuid::Uuid;
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use webrtc::data_channel::RTCDataChannel;
+
+use crate::config::{get_app_dir, TransferConfig};
+use crate::peer::session::PeerSession;
+use receive::receive_file;
+use send::send_file;
+
+pub mod receive;
+pub mod send;
+
+/// Data channels carrying a file are labelled with this and the transfer id.
+pub const TRANSFER_LABEL_PREFIX: &str = "file:";
+const HASH_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Messages about transfers, sent over the session's main data channel.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum TransferControl {
+    Offer {
+        id: String,
+        name: String,
+        size: u64,
+        sha256: String,
+    },
+    /// `offset` is how much of the file the receiver already has.
+    Accept {
+        id: String,
+        offset: u64,
+    },
+    Decline {
+        id: String,
+    },
+    /// Sent by the receiver once the whole file is in and checked.
+    Done {
+        id: String,
+        verified: bool,
+    },
+}
+
+#[derive(Clone)]
+pub struct OutgoingTransfer {
+    pub to: String,
+    pub path: PathBuf,
+    pub name: String,
+    pub size: u64,
+    sha256: String,
+}
+
+#[derive(Clone)]
+pub struct IncomingTransfer {
+    pub from: String,
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+    accepted: bool,
+}
+
+#[derive(Default)]
+struct TransfersState {
+    outgoing: HashMap<String, OutgoingTransfer>,
+    incoming: HashMap<String, IncomingTransfer>,
+    /// Offers waiting for a yes or no, oldest first.
+    prompts: VecDeque<String>,
+}
+
+/// Prints progress every tenth of the file.
+pub struct Progress {
+    reported: u64,
+}
+
+impl Progress {
+    pub fn new(done: u64, size: u64) -> Self {
+        Self {
+            reported: tenths(done, size),
+        }
+    }
+
+    pub fn update(&mut self, done: u64, size: u64, describe: impl FnOnce() -> String) {
+        let current = tenths(done, size);
+        if current > self.reported {
+            self.reported = current;
+            println!("\n\r{}: {}%", describe(), current * 10);
+        }
+    }
+}
+
+fn tenths(done: u64, size: u64) -> u64 {
+    (done * 10).checked_div(size).unwrap_or(10)
+}
+
+/// Files being sent to and received from peers. Lives as long as the
+/// client, so transfers pick up where they stopped once a dropped
+/// connection comes back.
+pub struct Transfers {
+    download_dir: PathBuf,
+    state: Mutex<TransfersState>,
+}
+
+impl Transfers {
+    pub fn new(config: &TransferConfig) -> Result<Arc<Self>> {
+        let download_dir = match &config.download_dir {
+            Some(dir) => dir.clone(),
+            None => match dirs::download_dir() {
+                Some(dir) => dir,
+                None => get_app_dir()?.join("downloads"),
+            },
+        };
+
+        Ok(Self::in_dir(download_dir))
+    }
+
+    /// Saves received files to `download_dir`.
+    pub fn in_dir(download_dir: PathBuf) -> Arc<Self> {
+        Arc::new(Self {
+            download_dir,
+            state: Mutex::new(TransfersState::default()),
+        })
+    }
+
+    /// Offers a file to the peer of `session`.
+    pub async fn offer(&self, session: &PeerSession, path: &Path) -> Result<()> {
+        let metadata = tokio::fs::metadata(path).await?;
+        if !metadata.is_file() {
+            bail!("{} is not a file", path.display());
+        }
+        if metadata.len() == 0 {
+            bail!("{} is empty", path.display());
+        }
+
+        let name = path
+            .file_name()
+            .ok_or_else(|| anyhow!("{} has no file name", path.display()))?
+            .to_string_lossy()
+            .into_owned();
+
+        let sha256 = hash_file(path.to_owned()).await?;
+        let id = Uuid::new_v4().to_string();
+
+        self.state.lock().unwrap().outgoing.insert(
+            id.clone(),
+            OutgoingTransfer {
+                to: session.other_id.clone(),
+                path: path.to_owned(),
+                name: name.clone(),
+                size: metadata.len(),
+                sha256: sha256.clone(),
+            },
+        );
+
+        send_control(
+            &session.data_channel,
+            &TransferControl::Offer {
+                id,
+                name,
+                size: metadata.len(),
+                sha256,
+            },
+        )
+        .await
+    }
+
+    /// Offers the unfinished transfers to a peer whose connection was
+    /// rebuilt, the receiver accepts them again from where it stopped.
+    async fn resume(&self, session: &PeerSession) -> Result<()> {
+        let outgoing: Vec<_> = self
+            .state
+            .lock()
+            .unwrap()
+            .outgoing
+            .iter()
+            .filter(|(_, transfer)| transfer.to == session.other_id)
+            .map(|(id, transfer)| (id.clone(), transfer.clone()))
+            .collect();
+
+        for (id, transfer) in outgoing {
+            println!("\n\rResuming {} to {}", transfer.name, transfer.to);
+
+            send_control(
+                &session.data_channel,
+                &TransferControl::Offer {
+                    id,
+                    name: transfer.name.clone(),
+                    size: transfer.size,
+                    sha256: transfer.sha256.clone(),
+                },
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn handle_control(
+        &self,
+        session: Arc<PeerSession>,
+        control: TransferControl,
+    ) -> Result<()> {
+        match control {
+            TransferControl::Offer {
+                id,
+                name,
+                size,
+                sha256,
+            } => {
+                // The id ends up in file names and channel labels.
+                if !is_transfer_id(&id) {
+                    bail!("Invalid transfer id {:?}", id);
+                }
+
+                // Only keep the file name, whatever path the peer sent.
+                let name = Path::new(&name)
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .ok_or_else(|| anyhow!("Invalid file name {:?}", name))?;
+
+                let resumed = {
+                    let mut state = self.state.lock().unwrap();
+
+                    match state.incoming.get(&id) {
+                        Some(transfer) if transfer.from == session.other_id => transfer.accepted,
+                        Some(_) => bail!("Transfer {} belongs to another peer", id),
+                        None => {
+                            state.incoming.insert(
+                                id.clone(),
+                                IncomingTransfer {
+                                    from: session.other_id.clone(),
+                                    name: name.clone(),
+                                    size,
+                                    sha256,
+                                    accepted: false,
+                                },
+                            );
+                            state.prompts.push_back(id.clone());
+                            false
+                        }
+                    }
+                };
+
+                if resumed {
+                    self.accept(&session, &id).await?;
+                } else {
+                    println!(
+                        "\n\r{} wants to send you {} ({}). Press y to accept, n to decline",
+                        session.other_id,
+                        name,
+                        format_size(size)
+                    );
+                }
+            }
+            TransferControl::Accept { id, offset } => {
+                let transfer = self
+                    .state
+                    .lock()
+                    .unwrap()
+                    .outgoing
+                    .get(&id)
+                    .filter(|transfer| transfer.to == session.other_id)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("Unknown transfer {}", id))?;
+
+                tokio::spawn(async move {
+                    if let Err(e) = send_file(session, id, transfer.clone(), offset).await {
+                        eprintln!("Sending {} paused: {}", transfer.name, e);
+                    }
+                });
+            }
+            TransferControl::Decline { id } => {
+                if let Some(transfer) = self.state.lock().unwrap().outgoing.remove(&id) {
+                    println!("\n\r{} declined {}", transfer.to, transfer.name);
+                }
+            }
+            TransferControl::Done { id, verified } => {
+                if let Some(transfer) = self.state.lock().unwrap().outgoing.remove(&id) {
+                    if verified {
+                        println!("\n\rSent {} to {}", transfer.name, transfer.to);
+                    } else {
+                        println!(
+                            "\n\r{} received a corrupted copy of {}",
+                            transfer.to, transfer.name
+                        );
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Answers the oldest offer still waiting.
+    pub async fn answer_prompt(
+        &self,
+        accept: bool,
+        peer_connections: Arc<AsyncMutex<HashMap<String, Arc<PeerSession>>>>,
+    ) -> Result<()> {
+        let (id, from) = {
+            let mut state = self.state.lock().unwrap();
+            let id = state
+                .prompts
+                .pop_front()
+                .ok_or_else(|| anyhow!("No file offers waiting"))?;
+            let from = state
+                .incoming
+                .get(&id)
+                .map(|transfer| transfer.from.clone())
+                .ok_or_else(|| anyhow!("Offer {} is gone", id))?;
+            (id, from)
+        };
+
+        let session = peer_connections
+            .lock()
+            .await
+            .get(&from)
+            .cloned()
+            .ok_or_else(|| anyhow!("{} is not connected anymore", from))?;
+
+        if accept {
+            self.accept(&session, &id).await
+        } else {
+            self.state.lock().unwrap().incoming.remove(&id);
+            send_control(&session.data_channel, &TransferControl::Decline { id }).await
+        }
+    }
+
+    async fn accept(&self, session: &PeerSession, id: &str) -> Result<()> {
+        tokio::fs::create_dir_all(&self.download_dir).await?;
+
+        // Whatever a previous attempt got through is kept.
+        let offset = match tokio::fs::metadata(self.partial_path(id)).await {
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        };
+
+        if let Some(transfer) = self.state.lock().unwrap().incoming.get_mut(id) {
+            transfer.accepted = true;
+        }
+
+        send_control(
+            &session.data_channel,
+            &TransferControl::Accept {
+                id: id.to_owned(),
+                offset,
+            },
+        )
+        .await
+    }
+
+    fn partial_path(&self, id: &str) -> PathBuf {
+        self.download_dir.join(format!("{}.part", id))
+    }
+
+    /// A name in the download directory that is not taken yet.
+    fn download_path(&self, name: &str) -> PathBuf {
+        let path = Path::new(name);
+        let stem = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let extension = path
+            .extension()
+            .map(|extension| format!(".{}", extension.to_string_lossy()))
+            .unwrap_or_default();
+
+        let mut candidate = self.download_dir.join(name);
+        let mut copy = 1;
+
+        while candidate.exists() {
+            candidate = self
+                .download_dir
+                .join(format!("{} ({}){}", stem, copy, extension));
+            copy += 1;
+        }
+
+        candidate
+    }
+
+    fn finish_incoming(&self, id: &str) {
+        self.state.lock().unwrap().incoming.remove(id);
+    }
+
+    /// Handles a per-transfer data channel opened by the peer.
+    pub fn receive_channel(
+        self: &Arc<Self>,
+        session: Arc<PeerSession>,
+        channel: Arc<RTCDataChannel>,
+    ) {
+        let Some(id) = channel.label().strip_prefix(TRANSFER_LABEL_PREFIX) else {
+            return;
+        };
+
+        let transfer = self
+            .state
+            .lock()
+            .unwrap()
+            .incoming
+            .get(id)
+            .filter(|transfer| transfer.accepted && transfer.from == session.other_id)
+            .cloned();
+
+        let Some(transfer) = transfer else {
+            eprintln!("Ignoring transfer {} we did not accept", id);
+            return;
+        };
+
+        receive_file(self.clone(), session, id.to_owned(), transfer, channel);
+    }
+}
+
+/// Whether `id` is a UUID in the form we generate, so that a peer can't
+/// slip a path into the partial file name.
+fn is_transfer_id(id: &str) -> bool {
+    Uuid::parse_str(id).is_ok_and(|uuid| uuid.to_string() == id)
+}
+
+/// Sends the transfer messages arriving on the session's main data channel
+/// to `transfers`, and resumes unfinished transfers once it opens.
+pub fn attach_transfers(transfers: Arc<Transfers>, session: &Arc<PeerSession>) {
+    {
+        let transfers = transfers.clone();
+        let weak = Arc::downgrade(session);
+
+        session.data_channel.on_open(Box::new(move || {
+            let transfers = transfers.clone();
+            let session = weak.clone();
+
+            Box::pin(async move {
+                let Some(session) = session.upgrade() else {
+                    return;
+                };
+
+                if let Err(e) = transfers.resume(&session).await {
+                    eprintln!("Failed to resume transfers: {}", e);
+                }
+            })
+        }));
+    }
+
+    let weak = Arc::downgrade(session);
+
+    session
+        .data_channel
+        .on_message(Box::new(move |message: DataChannelMessage| {
+            let transfers = transfers.clone();
+            let session = weak.clone();
+
+            Box::pin(async move {
+                let Some(session) = session.upgrade() else {
+                    return;
+                };
+
+                let control = match serde_json::from_slice(&message.data) {
+                    Ok(control) => control,
+                    Err(e) => {
+                        eprintln!("Dropping data channel message: {}", e);
+                        return;
+                    }
+                };
+
+                if let Err(e) = transfers.handle_control(session, control).await {
+                    eprintln!("Transfer failed: {}", e);
+                }
+            })
+        }));
+}
+
+async fn send_control(channel: &RTCDataChannel, control: &TransferControl) -> Result<()> {
+    channel.send_text(serde_json::to_string(control)?).await?;
+    Ok(())
+}
+
+/// Hex SHA-256 of a file, read off the async runtime.
+pub async fn hash_file(path: PathBuf) -> Result<String> {
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0u8; HASH_BUFFER_SIZE];
+
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+
+        Ok(format!("{:x}", hasher.finalize()))
+    })
+    .await?
+}
+
+pub fn format_size(bytes: u64) -> String {
+    match bytes {
+        0..=1023 => format!("{} B", bytes),
+        1024..=1_048_575 => format!("{:.1} KB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MB", bytes as f64 / 1_048_576.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_generated_ids_are_accepted() {
+        assert!(is_transfer_id(&Uuid::new_v4().to_string()));
+        assert!(!is_transfer_id("../../.bashrc"));
+        assert!(!is_transfer_id("67E55044-10B1-426F-9247-BB680E5FE0C8"));
+        assert!(!is_transfer_id("{67e55044-10b1-426f-9247-bb680e5fe0c8}"));
+    }
+}
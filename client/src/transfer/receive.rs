@@ -0,0 +1,140 @@
+use anyhow::Result;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use webrtc::data_channel::RTCDataChannel;
+
+use super::{hash_file, send_control, IncomingTransfer, Progress, TransferControl, Transfers};
+use crate::peer::session::PeerSession;
+
+enum Receiving {
+    /// Nothing arrived yet, the partial file is opened on the first chunk.
+    Waiting,
+    Writing {
+        file: tokio::fs::File,
+        received: u64,
+        progress: Progress,
+    },
+    Finished,
+}
+
+async fn open_partial(path: &Path, size: u64) -> Result<Receiving> {
+    let file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    let received = file.metadata().await?.len();
+
+    Ok(Receiving::Writing {
+        file,
+        received,
+        progress: Progress::new(received, size),
+    })
+}
+
+/// Appends what arrives on the transfer's channel to its partial file, and
+/// once it is complete checks the hash and moves it into the download
+/// directory.
+///
+/// The handler is set right away, chunks arriving before it would be lost.
+pub fn receive_file(
+    transfers: Arc<Transfers>,
+    session: Arc<PeerSession>,
+    id: String,
+    transfer: IncomingTransfer,
+    channel: Arc<RTCDataChannel>,
+) {
+    let receiving = Arc::new(Mutex::new(Receiving::Waiting));
+    let partial_path = transfers.partial_path(&id);
+    let control_channel = session.data_channel.clone();
+    let closing_channel = Arc::downgrade(&channel);
+
+    channel.on_message(Box::new(move |message: DataChannelMessage| {
+        let receiving = receiving.clone();
+        let transfers = transfers.clone();
+        let transfer = transfer.clone();
+        let id = id.clone();
+        let partial_path = partial_path.clone();
+        let control_channel = control_channel.clone();
+        let closing_channel = closing_channel.clone();
+
+        Box::pin(async move {
+            let mut receiving = receiving.lock().await;
+
+            if let Receiving::Waiting = *receiving {
+                match open_partial(&partial_path, transfer.size).await {
+                    Ok(writing) => *receiving = writing,
+                    Err(e) => {
+                        eprintln!("Failed to open {}: {}", partial_path.display(), e);
+                        *receiving = Receiving::Finished;
+                    }
+                }
+            }
+
+            let Receiving::Writing {
+                file,
+                received,
+                progress,
+            } = &mut *receiving
+            else {
+                return;
+            };
+
+            if let Err(e) = file.write_all(&message.data).await {
+                eprintln!("Failed to write {}: {}", transfer.name, e);
+                *receiving = Receiving::Finished;
+                return;
+            }
+
+            *received += message.data.len() as u64;
+            progress.update(*received, transfer.size, || {
+                format!("Receiving {} from {}", transfer.name, transfer.from)
+            });
+
+            if *received < transfer.size {
+                return;
+            }
+
+            let flushed = file.flush().await;
+            *receiving = Receiving::Finished;
+
+            if let Err(e) = flushed {
+                eprintln!("Failed to write {}: {}", transfer.name, e);
+                return;
+            }
+
+            let verified = match hash_file(partial_path.clone()).await {
+                Ok(sha256) => sha256 == transfer.sha256,
+                Err(e) => {
+                    eprintln!("Failed to check {}: {}", transfer.name, e);
+                    false
+                }
+            };
+
+            if verified {
+                let path = transfers.download_path(&transfer.name);
+                match tokio::fs::rename(&partial_path, &path).await {
+                    Ok(()) => println!("\n\rSaved {} to {}", transfer.name, path.display()),
+                    Err(e) => eprintln!("Failed to save {}: {}", transfer.name, e),
+                }
+            } else {
+                println!("\n\r{} is corrupted, discarding it", transfer.name);
+                let _ = tokio::fs::remove_file(&partial_path).await;
+            }
+
+            transfers.finish_incoming(&id);
+
+            let done = TransferControl::Done { id, verified };
+            if let Err(e) = send_control(&control_channel, &done).await {
+                eprintln!("Failed to confirm {}: {}", transfer.name, e);
+            }
+
+            if let Some(channel) = closing_channel.upgrade() {
+                let _ = channel.close().await;
+            }
+        })
+    }));
+}
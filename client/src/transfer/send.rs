@@ -0,0 +1,106 @@
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use std::io::SeekFrom;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::Notify;
+use tokio::time::{sleep, timeout};
+
+use super::{OutgoingTransfer, Progress, TRANSFER_LABEL_PREFIX};
+use crate::peer::session::PeerSession;
+
+/// Stays under the 64KiB message size every SCTP stack accepts.
+const CHUNK_SIZE: usize = 16 * 1024;
+/// Sending pauses above this much queued data and resumes below the low
+/// threshold, so a slow link does not buffer the whole file in memory.
+const HIGH_BUFFERED_AMOUNT: usize = 4 * 1024 * 1024;
+const LOW_BUFFERED_AMOUNT: usize = 1024 * 1024;
+const OPEN_TIMEOUT: Duration = Duration::from_secs(10);
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Streams the file from `offset` over a new data channel for the
+/// transfer. The receiver closes it once the file is checked.
+pub async fn send_file(
+    session: Arc<PeerSession>,
+    id: String,
+    transfer: OutgoingTransfer,
+    offset: u64,
+) -> Result<()> {
+    if offset > transfer.size {
+        bail!("Receiver has more of {} than there is", transfer.name);
+    }
+
+    let channel = session
+        .peer_connection
+        .create_data_channel(&format!("{}{}", TRANSFER_LABEL_PREFIX, id), None)
+        .await?;
+
+    let opened = Arc::new(Notify::new());
+    {
+        let opened = opened.clone();
+        channel.on_open(Box::new(move || {
+            opened.notify_one();
+            Box::pin(async {})
+        }));
+    }
+
+    let drained = Arc::new(Notify::new());
+    channel
+        .set_buffered_amount_low_threshold(LOW_BUFFERED_AMOUNT)
+        .await;
+    {
+        let drained = drained.clone();
+        channel
+            .on_buffered_amount_low(Box::new(move || {
+                drained.notify_one();
+                Box::pin(async {})
+            }))
+            .await;
+    }
+
+    timeout(OPEN_TIMEOUT, opened.notified()).await?;
+
+    let mut file = tokio::fs::File::open(&transfer.path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+
+    if offset > 0 {
+        println!(
+            "\n\rSending {} to {} from {}%",
+            transfer.name,
+            transfer.to,
+            offset * 100 / transfer.size.max(1)
+        );
+    }
+
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    let mut sent = offset;
+    let mut progress = Progress::new(sent, transfer.size);
+
+    while sent < transfer.size {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            bail!("{} got shorter while sending it", transfer.name);
+        }
+
+        if channel.buffered_amount().await > HIGH_BUFFERED_AMOUNT {
+            drained.notified().await;
+        }
+
+        channel
+            .send(&Bytes::copy_from_slice(&buffer[..read]))
+            .await?;
+
+        sent += read as u64;
+        progress.update(sent, transfer.size, || {
+            format!("Sending {} to {}", transfer.name, transfer.to)
+        });
+    }
+
+    // Keep the channel around until everything queued went out.
+    while channel.buffered_amount().await > 0 {
+        sleep(DRAIN_POLL_INTERVAL).await;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,6 @@
+pub mod pattern;
+pub mod receive;
+pub mod record;
+pub mod render;
+pub mod source;
+pub mod terminal;
@@ -0,0 +1,114 @@
+use anyhow::Result;
+use openh264::encoder::{Encoder, EncoderConfig};
+use openh264::formats::{RgbSliceU8, YUVBuffer};
+use openh264::OpenH264API;
+
+use crate::config::VideoConfig;
+
+/// 75% color bars, left to right.
+const BARS: [[u8; 3]; 7] = [
+    [191, 191, 191],
+    [191, 191, 0],
+    [0, 191, 191],
+    [0, 191, 0],
+    [191, 0, 191],
+    [191, 0, 0],
+    [0, 0, 191],
+];
+
+/// Pixels the square below the bars moves per frame.
+const SQUARE_SPEED: usize = 4;
+
+fn encoder(bitrate: u32, fps: u32) -> Result<Encoder> {
+    let config = EncoderConfig::new()
+        .set_bitrate_bps(bitrate)
+        .max_frame_rate(fps as f32);
+    Ok(Encoder::with_api_config(
+        OpenH264API::from_source(),
+        config,
+    )?)
+}
+
+/// Color bars with a square moving below them, so frozen or dropped
+/// frames are easy to spot on the other end.
+pub struct TestPattern {
+    encoder: Encoder,
+    bitrate: u32,
+    fps: u32,
+    width: usize,
+    height: usize,
+    rgb: Vec<u8>,
+    frame: usize,
+}
+
+impl TestPattern {
+    pub fn new(config: &VideoConfig) -> Result<Self> {
+        // H.264 works on even dimensions.
+        let width = (config.width.max(64) & !1) as usize;
+        let height = (config.height.max(64) & !1) as usize;
+
+        let fps = config.fps.max(1);
+
+        Ok(Self {
+            encoder: encoder(config.bitrate, fps)?,
+            bitrate: config.bitrate,
+            fps,
+            width,
+            height,
+            rgb: vec![0; width * height * 3],
+            frame: 0,
+        })
+    }
+
+    /// Re-creates the encoder when the bitrate moved by more than a tenth,
+    /// which starts over with a keyframe.
+    pub fn set_bitrate(&mut self, bitrate: u32) -> Result<()> {
+        if bitrate.abs_diff(self.bitrate) <= self.bitrate / 10 {
+            return Ok(());
+        }
+
+        self.encoder = encoder(bitrate, self.fps)?;
+        self.bitrate = bitrate;
+        Ok(())
+    }
+
+    /// Makes the next frame a keyframe, for a receiver that lost track.
+    pub fn force_keyframe(&mut self) {
+        self.encoder.force_intra_frame();
+    }
+
+    /// Draws and encodes the next frame, as an Annex B access unit.
+    pub fn next_frame(&mut self) -> Result<Vec<u8>> {
+        self.draw();
+
+        let yuv = YUVBuffer::from_rgb_source(RgbSliceU8::new(&self.rgb, (self.width, self.height)));
+        let bitstream = self.encoder.encode(&yuv)?;
+        self.frame += 1;
+
+        Ok(bitstream.to_vec())
+    }
+
+    fn draw(&mut self) {
+        let bars_height = self.height * 2 / 3;
+        let square = self.height / 6;
+        let square_x = (self.frame * SQUARE_SPEED) % (self.width - square);
+        let square_y = bars_height + (self.height - bars_height - square) / 2;
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let color = if y < bars_height {
+                    BARS[x * BARS.len() / self.width]
+                } else if (square_x..square_x + square).contains(&x)
+                    && (square_y..square_y + square).contains(&y)
+                {
+                    [255, 255, 255]
+                } else {
+                    [16, 16, 16]
+                };
+
+                let offset = (y * self.width + x) * 3;
+                self.rgb[offset..offset + 3].copy_from_slice(&color);
+            }
+        }
+    }
+}
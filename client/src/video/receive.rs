@@ -0,0 +1,170 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::{interval, sleep_until, Instant, MissedTickBehavior};
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
+use webrtc::rtp::packet::Packet;
+use webrtc::track::track_remote::TrackRemote;
+
+use super::record::VideoRecorder;
+use super::render::VideoRenderer;
+use super::terminal::VideoScreen;
+use crate::config::VideoConfig;
+use crate::peer::sync::StreamSync;
+
+/// How often a keyframe is asked for, so the stream recovers from losses
+/// without waiting for the sender's next one.
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_secs(3);
+/// Packets held back for lip sync before they are shown regardless.
+const MAX_HELD_PACKETS: usize = 1024;
+
+fn show(renderer: &mut Option<VideoRenderer>, packet: &Packet) {
+    if let Some(view) = renderer.as_mut() {
+        // Decoding and drawing are too slow to hold a worker.
+        if let Err(e) = tokio::task::block_in_place(|| view.push(packet)) {
+            eprintln!("Stopped showing video: {}", e);
+            *renderer = None;
+        }
+    }
+}
+
+/// Reads a remote video track until the session closes, showing it in the
+/// terminal and saving it when a record directory is configured.
+///
+/// Frames captured ahead of the audio being heard are held back until it
+/// catches up, going by the capture times `sync` gets from sender reports.
+pub async fn receive_video(
+    track: Arc<TrackRemote>,
+    peer_connection: Weak<RTCPeerConnection>,
+    user_id: String,
+    config: Arc<VideoConfig>,
+    screen: Arc<VideoScreen>,
+    sync: Arc<StreamSync>,
+    mut closed: watch::Receiver<bool>,
+) {
+    let mime_type = track.codec().capability.mime_type;
+    println!("\n\rReceiving {} video from {}", mime_type, user_id);
+
+    let mut recorder = match &config.record_dir {
+        Some(dir) => match VideoRecorder::create(dir, &user_id, &mime_type) {
+            Ok(Some(recorder)) => Some(recorder),
+            Ok(None) => {
+                eprintln!("Can't record {} video", mime_type);
+                None
+            }
+            Err(e) => {
+                eprintln!("Failed to start recording video: {}", e);
+                None
+            }
+        },
+        None => None,
+    };
+
+    let mut renderer = match VideoRenderer::new(screen, &user_id, &config, &mime_type) {
+        Ok(renderer) => renderer,
+        Err(e) => {
+            eprintln!("{}", e);
+            None
+        }
+    };
+
+    let mut keyframe_requests = interval(KEYFRAME_REQUEST_INTERVAL);
+    keyframe_requests.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut buffer = vec![0u8; 1500];
+    // Packets waiting to be shown, with when.
+    let mut held: VecDeque<(Instant, Packet)> = VecDeque::new();
+    // The frame being received and when it is due, all its packets wait
+    // the same.
+    let mut frame: Option<(u32, Instant)> = None;
+    let mut wants_keyframe = false;
+
+    loop {
+        while held.front().is_some_and(|(due, _)| *due <= Instant::now()) {
+            if let Some((_, packet)) = held.pop_front() {
+                show(&mut renderer, &packet);
+            }
+        }
+
+        let next_due = held.front().map(|(due, _)| *due);
+
+        tokio::select! {
+            read = track.read(&mut buffer) => match read {
+                Ok((packet, _)) => {
+                    let captured = sync.capture_time(track.ssrc(), packet.header.timestamp);
+
+                    if let Some(writer) = recorder.as_mut() {
+                        if let Err(e) = writer.write(&packet, captured) {
+                            eprintln!("Stopped recording video: {}", e);
+                            recorder = None;
+                        }
+                    }
+
+                    if renderer.is_none() {
+                        continue;
+                    }
+
+                    let due = match frame {
+                        Some((timestamp, due)) if timestamp == packet.header.timestamp => due,
+                        _ => {
+                            let delay = captured
+                                .map(|captured| sync.video_delay(captured))
+                                .unwrap_or_default();
+                            // Never ahead of a frame already waiting.
+                            let due = (Instant::now() + delay)
+                                .max(held.back().map(|(due, _)| *due).unwrap_or_else(Instant::now));
+                            frame = Some((packet.header.timestamp, due));
+                            due
+                        }
+                    };
+
+                    // Holding too much to wait any longer, show it all.
+                    if held.len() >= MAX_HELD_PACKETS {
+                        for (_, packet) in held.drain(..) {
+                            show(&mut renderer, &packet);
+                        }
+                    }
+
+                    if held.is_empty() && due <= Instant::now() {
+                        show(&mut renderer, &packet);
+                    } else {
+                        held.push_back((due, packet));
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Error reading from video track: {:?}", e);
+                    break;
+                }
+            },
+            _ = keyframe_requests.tick() => wants_keyframe = true,
+            _ = sleep_until(next_due.unwrap_or_else(Instant::now)), if next_due.is_some() => (),
+            _ = closed.wait_for(|closed| *closed) => break,
+        }
+
+        // Written outside the select, which holds the closed flag's read
+        // guard until it is done.
+        if std::mem::take(&mut wants_keyframe) {
+            let Some(peer_connection) = peer_connection.upgrade() else {
+                break;
+            };
+
+            let pli = PictureLossIndication {
+                sender_ssrc: 0,
+                media_ssrc: track.ssrc(),
+            };
+            if let Err(e) = peer_connection.write_rtcp(&[Box::new(pli)]).await {
+                eprintln!("Failed to request a keyframe: {}", e);
+            }
+        }
+    }
+
+    if let Some(recorder) = recorder {
+        let path = recorder.path.clone();
+        match recorder.close() {
+            Ok(()) => println!("\n\rSaved video from {} to {}", user_id, path.display()),
+            Err(e) => eprintln!("Failed to save video: {}", e),
+        }
+    }
+}
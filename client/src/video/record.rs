@@ -0,0 +1,116 @@
+use anyhow::Result;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use webrtc::api::media_engine::{MIME_TYPE_H264, MIME_TYPE_VP8};
+use webrtc::media::io::h264_writer::H264Writer;
+use webrtc::media::io::ivf_reader::IVFFileHeader;
+use webrtc::media::io::ivf_writer::IVFWriter;
+use webrtc::media::io::Writer;
+
+fn ivf_header(four_cc: [u8; 4]) -> IVFFileHeader {
+    IVFFileHeader {
+        signature: *b"DKIF",
+        version: 0,
+        header_size: 32,
+        four_cc,
+        // Players take the real size from the bitstream.
+        width: 640,
+        height: 480,
+        timebase_denominator: 30,
+        timebase_numerator: 1,
+        num_frames: 0,
+        unused: 0,
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis())
+        .unwrap_or(0)
+}
+
+/// Saves a received video track as it arrives, depacketized into IVF for
+/// VP8, or an Annex B stream for H.264.
+///
+/// A `.timestamps.csv` file next to it gives each frame's capture time on
+/// the sender's wall clock, from its sender reports, so recordings of
+/// different streams can be lined up.
+pub struct VideoRecorder {
+    writer: Box<dyn Writer + Send + Sync>,
+    timestamps: BufWriter<File>,
+    last_timestamp: Option<u32>,
+    pub path: PathBuf,
+}
+
+impl VideoRecorder {
+    /// `None` for codecs there is no writer for.
+    pub fn create(dir: &Path, user_id: &str, mime_type: &str) -> Result<Option<Self>> {
+        std::fs::create_dir_all(dir)?;
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or(0);
+        let name = format!("video-{}-{}", timestamp, user_id);
+
+        let (path, writer): (PathBuf, Box<dyn Writer + Send + Sync>) =
+            if mime_type.eq_ignore_ascii_case(MIME_TYPE_VP8) {
+                let path = dir.join(format!("{}.ivf", name));
+                let file = BufWriter::new(File::create(&path)?);
+                (path, Box::new(IVFWriter::new(file, &ivf_header(*b"VP80"))?))
+            } else if mime_type.eq_ignore_ascii_case(MIME_TYPE_H264) {
+                let path = dir.join(format!("{}.h264", name));
+                let file = BufWriter::new(File::create(&path)?);
+                (path, Box::new(H264Writer::new(file)))
+            } else {
+                return Ok(None);
+            };
+
+        let mut timestamps =
+            BufWriter::new(File::create(dir.join(format!("{}.timestamps.csv", name)))?);
+        writeln!(timestamps, "rtp_timestamp,capture_unix_ms,arrival_unix_ms")?;
+
+        Ok(Some(Self {
+            writer,
+            timestamps,
+            last_timestamp: None,
+            path,
+        }))
+    }
+
+    /// `captured` is unknown until the sender's first report, the capture
+    /// column stays empty until then.
+    pub fn write(
+        &mut self,
+        packet: &webrtc::rtp::packet::Packet,
+        captured: Option<SystemTime>,
+    ) -> Result<()> {
+        let timestamp = packet.header.timestamp;
+
+        if self.last_timestamp != Some(timestamp) {
+            self.last_timestamp = Some(timestamp);
+
+            let captured = captured
+                .map(|captured| unix_millis(captured).to_string())
+                .unwrap_or_default();
+            writeln!(
+                self.timestamps,
+                "{},{},{}",
+                timestamp,
+                captured,
+                unix_millis(SystemTime::now())
+            )?;
+        }
+
+        self.writer.write_rtp(packet)?;
+        Ok(())
+    }
+
+    pub fn close(mut self) -> Result<()> {
+        self.writer.close()?;
+        self.timestamps.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,125 @@
+use anyhow::{bail, Result};
+use openh264::decoder::Decoder;
+use openh264::formats::YUVSource;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use webrtc::api::media_engine::MIME_TYPE_H264;
+use webrtc::rtp::codecs::h264::H264Packet;
+use webrtc::rtp::packetizer::Depacketizer;
+
+use super::terminal::{draw, fit, resolve_mode, scale, VideoScreen};
+use crate::config::{RenderMode, VideoConfig};
+
+/// Lowest frame rate the view drops to for large panes.
+const MIN_RENDER_FPS: u32 = 5;
+/// Panes up to this many cells are drawn at the full frame rate.
+const FULL_RATE_CELLS: u32 = 4000;
+
+/// Decodes a received H.264 track and draws it into the user's pane.
+pub struct VideoRenderer {
+    decoder: Decoder,
+    depacketizer: H264Packet,
+    access_unit: Vec<u8>,
+    screen: Arc<VideoScreen>,
+    user_id: String,
+    image_id: u32,
+    mode: RenderMode,
+    max_fps: u32,
+    last_draw: Option<Instant>,
+    rgb: Vec<u8>,
+}
+
+impl VideoRenderer {
+    /// `None` when rendering is turned off.
+    pub fn new(
+        screen: Arc<VideoScreen>,
+        user_id: &str,
+        config: &VideoConfig,
+        mime_type: &str,
+    ) -> Result<Option<Self>> {
+        if config.render == RenderMode::Off {
+            return Ok(None);
+        }
+
+        // openh264 is the only decoder we bundle.
+        if !mime_type.eq_ignore_ascii_case(MIME_TYPE_H264) {
+            bail!("Can't show {} video in the terminal, only H.264", mime_type);
+        }
+
+        let image_id = screen.show(user_id);
+
+        Ok(Some(Self {
+            decoder: Decoder::new()?,
+            depacketizer: H264Packet::default(),
+            access_unit: Vec::new(),
+            screen,
+            user_id: user_id.to_owned(),
+            image_id,
+            mode: resolve_mode(config.render),
+            max_fps: config.render_fps.max(1),
+            last_draw: None,
+            rgb: Vec::new(),
+        }))
+    }
+
+    /// Feeds one RTP packet, drawing the frame it completes if the pane is
+    /// due for one.
+    pub fn push(&mut self, packet: &webrtc::rtp::packet::Packet) -> Result<()> {
+        let nals = self.depacketizer.depacketize(&packet.payload)?;
+        self.access_unit.extend_from_slice(&nals);
+
+        if !packet.header.marker {
+            return Ok(());
+        }
+
+        let access_unit = std::mem::take(&mut self.access_unit);
+
+        // Every frame is decoded to keep the decoder's references intact,
+        // only some are drawn.
+        let Some(yuv) = self.decoder.decode(&access_unit)? else {
+            return Ok(());
+        };
+
+        let Some(pane) = self.screen.pane(&self.user_id) else {
+            return Ok(());
+        };
+
+        let cells = pane.cols as u32 * pane.rows as u32;
+        let fps = (self.max_fps * FULL_RATE_CELLS / cells.max(1))
+            .clamp(MIN_RENDER_FPS.min(self.max_fps), self.max_fps);
+        let frame_interval = Duration::from_secs(1) / fps;
+
+        if self
+            .last_draw
+            .is_some_and(|last| last.elapsed() < frame_interval)
+        {
+            return Ok(());
+        }
+        self.last_draw = Some(Instant::now());
+
+        let (width, height) = yuv.dimensions();
+        self.rgb.resize(width * height * 3, 0);
+        yuv.write_rgb8(&mut self.rgb);
+
+        let (to_width, to_height) = fit(width, height, pane, self.mode);
+        let scaled = scale(&self.rgb, width, height, to_width, to_height);
+
+        draw(
+            pane,
+            self.image_id,
+            &format!(" {} {}x{} ", self.user_id, width, height),
+            &scaled,
+            to_width,
+            to_height,
+            self.mode,
+        );
+
+        Ok(())
+    }
+}
+
+impl Drop for VideoRenderer {
+    fn drop(&mut self) {
+        self.screen.hide(&self.user_id);
+    }
+}
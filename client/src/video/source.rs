@@ -0,0 +1,450 @@
+use anyhow::{bail, Result};
+use bytes::{Bytes, BytesMut};
+use std::collections::VecDeque;
+use std::io::Cursor;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio::time::{interval, sleep_until, MissedTickBehavior};
+use webrtc::media::io::h264_reader::{H264Reader, NalUnitType, NAL};
+use webrtc::media::io::ivf_reader::IVFReader;
+use webrtc::media::Sample;
+use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
+use webrtc::track::track_local::TrackLocal;
+
+use super::pattern::TestPattern;
+use crate::config::{CodecConfig, VideoCodec, VideoConfig, VideoSource};
+use crate::peer::bandwidth::UploadBudget;
+use crate::peer::codecs::video_capability;
+
+/// Largest NAL unit expected in an H.264 file.
+const H264_READ_BUFFER: usize = 1024 * 1024;
+const ANNEX_B_START_CODE: [u8; 4] = [0, 0, 0, 1];
+/// For a file's last frame when there is nothing to time it by.
+const DEFAULT_FRAME_DURATION: Duration = Duration::from_millis(33);
+/// Frames with the same timestamp still get a moment, so a broken file
+/// can't spin.
+const MIN_FRAME_DURATION: Duration = Duration::from_millis(1);
+/// How far behind file playback may fall before it skips ahead instead.
+const MAX_CATCH_UP: Duration = Duration::from_millis(200);
+
+/// Keeps files, which can't be re-encoded, under the video budget. Once
+/// over it, frames are dropped up to the next keyframe, since the decoder
+/// can't make sense of anything in between.
+struct FrameDropper {
+    upload: Arc<UploadBudget>,
+    /// Sizes of the frames sent in the last second
+    sent: VecDeque<(Instant, usize)>,
+    skipping: bool,
+}
+
+impl FrameDropper {
+    fn new(upload: Arc<UploadBudget>) -> Self {
+        Self {
+            upload,
+            sent: VecDeque::new(),
+            skipping: false,
+        }
+    }
+
+    /// Whether a frame of `size` bytes can be sent now.
+    fn admit(&mut self, size: usize, keyframe: bool) -> bool {
+        let now = Instant::now();
+        while self
+            .sent
+            .front()
+            .is_some_and(|(at, _)| now - *at > Duration::from_secs(1))
+        {
+            self.sent.pop_front();
+        }
+
+        let sent: usize = self.sent.iter().map(|(_, size)| size).sum();
+        let bitrate = self.upload.video_bitrate(u32::MAX) as usize;
+
+        // A keyframe bigger than the whole budget still goes out on its
+        // own, or the video would never resume.
+        let over = (sent + size) * 8 > bitrate && !self.sent.is_empty();
+
+        if over || (self.skipping && !keyframe) {
+            self.skipping = true;
+            return false;
+        }
+
+        self.skipping = false;
+        self.sent.push_back((now, size));
+        true
+    }
+}
+
+fn is_h264_file(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "h264" || extension == "264")
+}
+
+fn ivf_codec(four_cc: &[u8; 4]) -> Result<VideoCodec> {
+    match four_cc {
+        b"VP80" => Ok(VideoCodec::VP8),
+        _ => bail!("Unsupported IVF codec {}", String::from_utf8_lossy(four_cc)),
+    }
+}
+
+async fn source_codec(source: &VideoSource) -> Result<Option<VideoCodec>> {
+    match source {
+        VideoSource::None => Ok(None),
+        VideoSource::TestPattern => Ok(Some(VideoCodec::H264)),
+        VideoSource::File(path) if is_h264_file(path) => Ok(Some(VideoCodec::H264)),
+        VideoSource::File(path) => {
+            let data = tokio::fs::read(path).await?;
+            let (_, header) = IVFReader::new(Cursor::new(data))?;
+            Ok(Some(ivf_codec(&header.four_cc)?))
+        }
+    }
+}
+
+/// The local video track, shared by every peer connection like the audio
+/// one. `None` when no video source is configured.
+pub async fn create_video_track(
+    config: &VideoConfig,
+    codecs: &CodecConfig,
+) -> Result<Option<Arc<TrackLocalStaticSample>>> {
+    let Some(codec) = source_codec(&config.source).await? else {
+        return Ok(None);
+    };
+
+    if !codecs.video.contains(&codec) {
+        bail!(
+            "The video source needs {:?} in the codecs.video config",
+            codec
+        );
+    }
+
+    Ok(Some(Arc::new(TrackLocalStaticSample::new(
+        video_capability(codec),
+        "video_track".to_owned(),
+        "webrtc-rs".to_owned(),
+    ))))
+}
+
+/// One frame of a video file, ready to be sent as a sample.
+struct Frame {
+    data: Bytes,
+    keyframe: bool,
+    /// Time until the next frame
+    duration: Duration,
+}
+
+/// Writes the configured source to `video_track` in real time, looping
+/// files, within what `upload` leaves for video. A keyframe is sent
+/// whenever `keyframe_requests` is woken.
+pub async fn send_video(
+    video_track: Arc<TrackLocalStaticSample>,
+    config: VideoConfig,
+    upload: Arc<UploadBudget>,
+    keyframe_requests: Arc<Notify>,
+) -> Result<()> {
+    println!("\n\rSending video on track {:?}", video_track.id());
+
+    match &config.source {
+        VideoSource::None => Ok(()),
+        VideoSource::TestPattern => {
+            send_test_pattern(&video_track, &config, &upload, &keyframe_requests).await
+        }
+        VideoSource::File(path) => {
+            let data = tokio::fs::read(path).await?;
+            let frames = if is_h264_file(path) {
+                h264_frames(data, &config)
+            } else {
+                ivf_frames(data, path)?
+            };
+
+            if frames.is_empty() {
+                bail!("{} has no frames", path.display());
+            }
+
+            let mut dropper = FrameDropper::new(upload);
+            play_frames(&video_track, &frames, &mut dropper, &keyframe_requests).await
+        }
+    }
+}
+
+/// Plays `frames` in a loop, paced by their durations. A keyframe request
+/// replays from the last keyframe, the only place decoding can start over.
+async fn play_frames(
+    video_track: &TrackLocalStaticSample,
+    frames: &[Frame],
+    dropper: &mut FrameDropper,
+    keyframe_requests: &Notify,
+) -> Result<()> {
+    let mut next_at = tokio::time::Instant::now();
+    let mut index = 0;
+
+    loop {
+        let frame = &frames[index];
+
+        tokio::select! {
+            _ = sleep_until(next_at) => (),
+            _ = keyframe_requests.notified() => {
+                if let Some(keyframe) = frames[..index].iter().rposition(|frame| frame.keyframe) {
+                    index = keyframe;
+                }
+                continue;
+            }
+        }
+
+        // Don't burst to catch up after a stall.
+        let now = tokio::time::Instant::now();
+        next_at = next_at.max(now - MAX_CATCH_UP) + frame.duration;
+        index = (index + 1) % frames.len();
+
+        if !dropper.admit(frame.data.len(), frame.keyframe) {
+            continue;
+        }
+
+        video_track
+            .write_sample(&Sample {
+                data: frame.data.clone(),
+                duration: frame.duration,
+                ..Default::default()
+            })
+            .await?;
+    }
+}
+
+/// Reads an IVF file, timing each frame by the timestamp in its header.
+fn ivf_frames(data: Vec<u8>, path: &Path) -> Result<Vec<Frame>> {
+    let (mut reader, header) = IVFReader::new(Cursor::new(data))?;
+
+    if header.timebase_numerator == 0 || header.timebase_denominator == 0 {
+        bail!("{} has no timebase", path.display());
+    }
+
+    let to_duration = |ticks: u64| {
+        let nanos = ticks as u128 * header.timebase_numerator as u128 * 1_000_000_000
+            / header.timebase_denominator as u128;
+        Duration::from_nanos(nanos as u64)
+    };
+
+    // Any error means the end of the file.
+    let mut parsed = Vec::new();
+    while let Ok((frame, frame_header)) = reader.parse_next_frame() {
+        parsed.push((frame.freeze(), frame_header.timestamp));
+    }
+
+    let mut frames: Vec<Frame> = Vec::with_capacity(parsed.len());
+    for (i, (data, timestamp)) in parsed.iter().enumerate() {
+        let duration = match parsed.get(i + 1) {
+            Some((_, next)) => to_duration(next.saturating_sub(*timestamp)),
+            // The last frame lasts as long as the one before it.
+            None => frames
+                .last()
+                .map_or(DEFAULT_FRAME_DURATION, |frame| frame.duration),
+        };
+
+        frames.push(Frame {
+            // VP8 keyframes have the lowest bit of the frame tag cleared.
+            keyframe: data.first().is_some_and(|tag| tag & 1 == 0),
+            data: data.clone(),
+            duration: duration.max(MIN_FRAME_DURATION),
+        });
+    }
+
+    Ok(frames)
+}
+
+/// Whether `nal` starts a new access unit after a picture: a delimiter,
+/// parameter sets and SEI come before the slices of the next picture, and
+/// a picture's first slice starts at macroblock 0.
+fn starts_access_unit(nal: &NAL) -> bool {
+    match nal.unit_type {
+        NalUnitType::AUD | NalUnitType::SPS | NalUnitType::PPS | NalUnitType::SEI => true,
+        // first_mb_in_slice is 0 when the Exp-Golomb code is a single 1 bit.
+        NalUnitType::CodedSliceIdr | NalUnitType::CodedSliceNonIdr => {
+            nal.data.get(1).is_some_and(|byte| byte & 0x80 != 0)
+        }
+        _ => false,
+    }
+}
+
+/// Reads an Annex B file into access units, each one picture with the
+/// parameter sets that come before it, paced at the configured frame rate.
+fn h264_frames(data: Vec<u8>, config: &VideoConfig) -> Vec<Frame> {
+    let mut reader = H264Reader::new(Cursor::new(data), H264_READ_BUFFER);
+    let duration = Duration::from_secs(1) / config.fps.max(1);
+
+    let mut frames = Vec::new();
+    let mut access_unit = BytesMut::new();
+    let mut has_slice = false;
+    let mut keyframe = false;
+
+    // Any error means the end of the file.
+    while let Ok(nal) = reader.next_nal() {
+        if has_slice && starts_access_unit(&nal) {
+            frames.push(Frame {
+                data: access_unit.split().freeze(),
+                keyframe,
+                duration,
+            });
+            has_slice = false;
+            keyframe = false;
+        }
+
+        match nal.unit_type {
+            NalUnitType::CodedSliceIdr => {
+                has_slice = true;
+                keyframe = true;
+            }
+            NalUnitType::CodedSliceNonIdr => has_slice = true,
+            _ => (),
+        }
+
+        access_unit.extend_from_slice(&ANNEX_B_START_CODE);
+        access_unit.extend_from_slice(&nal.data);
+    }
+
+    if has_slice {
+        frames.push(Frame {
+            data: access_unit.freeze(),
+            keyframe,
+            duration,
+        });
+    }
+
+    frames
+}
+
+async fn send_test_pattern(
+    video_track: &TrackLocalStaticSample,
+    config: &VideoConfig,
+    upload: &UploadBudget,
+    keyframe_requests: &Notify,
+) -> Result<()> {
+    let mut pattern = TestPattern::new(config)?;
+
+    let frame_duration = Duration::from_secs(1) / config.fps.max(1);
+    let mut ticker = interval(frame_duration);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut frames_since_update = 0;
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => (),
+            _ = keyframe_requests.notified() => {
+                pattern.force_keyframe();
+                continue;
+            }
+        }
+
+        // Once a second, like the audio encoder.
+        frames_since_update += 1;
+        if frames_since_update >= config.fps.max(1) {
+            frames_since_update = 0;
+            pattern.set_bitrate(upload.video_bitrate(config.bitrate))?;
+        }
+
+        // Encoding takes a few milliseconds, let the runtime move other
+        // tasks off this worker meanwhile.
+        let frame = tokio::task::block_in_place(|| pattern.next_frame())?;
+
+        video_track
+            .write_sample(&Sample {
+                data: Bytes::from(frame),
+                duration: frame_duration,
+                ..Default::default()
+            })
+            .await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ivf(timebase: (u32, u32), frames: &[(&[u8], u64)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(b"DKIF");
+        data.extend_from_slice(&0u16.to_le_bytes());
+        data.extend_from_slice(&32u16.to_le_bytes());
+        data.extend_from_slice(b"VP80");
+        data.extend_from_slice(&64u16.to_le_bytes());
+        data.extend_from_slice(&64u16.to_le_bytes());
+        data.extend_from_slice(&timebase.1.to_le_bytes());
+        data.extend_from_slice(&timebase.0.to_le_bytes());
+        data.extend_from_slice(&(frames.len() as u32).to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+
+        for (frame, timestamp) in frames {
+            data.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            data.extend_from_slice(&timestamp.to_le_bytes());
+            data.extend_from_slice(frame);
+        }
+
+        data
+    }
+
+    #[test]
+    fn ivf_frames_are_timed_by_their_timestamps() {
+        let data = ivf(
+            (1, 90_000),
+            &[(&[0x10, 1], 0), (&[0x11, 2], 3000), (&[0x11, 3], 9000)],
+        );
+
+        let frames = ivf_frames(data, Path::new("test.ivf")).unwrap();
+
+        let durations: Vec<_> = frames.iter().map(|frame| frame.duration).collect();
+        assert_eq!(
+            durations,
+            [
+                Duration::from_nanos(33_333_333),
+                Duration::from_nanos(66_666_666),
+                Duration::from_nanos(66_666_666),
+            ]
+        );
+
+        let keyframes: Vec<_> = frames.iter().map(|frame| frame.keyframe).collect();
+        assert_eq!(keyframes, [true, false, false]);
+    }
+
+    #[test]
+    fn repeated_timestamps_never_give_a_zero_duration() {
+        let data = ivf((1, 90_000), &[(&[0x10], 0), (&[0x11], 0)]);
+
+        let frames = ivf_frames(data, Path::new("test.ivf")).unwrap();
+
+        assert!(frames
+            .iter()
+            .all(|frame| frame.duration >= MIN_FRAME_DURATION));
+    }
+
+    #[test]
+    fn h264_is_split_into_access_units() {
+        let nals: [&[u8]; 7] = [
+            &[0x67, 0x42],       // SPS
+            &[0x68, 0xce],       // PPS
+            &[0x65, 0x88, 0x01], // IDR, first slice
+            &[0x65, 0x40, 0x02], // IDR, second slice of the same picture
+            &[0x41, 0x9a, 0x03], // non-IDR, first slice
+            &[0x41, 0x9a, 0x04], // non-IDR, first slice
+            &[0x09, 0xf0],       // access unit delimiter
+        ];
+
+        let mut data = Vec::new();
+        for nal in nals {
+            data.extend_from_slice(&ANNEX_B_START_CODE);
+            data.extend_from_slice(nal);
+        }
+
+        let frames = h264_frames(data, &VideoConfig::default());
+
+        let keyframes: Vec<_> = frames.iter().map(|frame| frame.keyframe).collect();
+        assert_eq!(keyframes, [true, false, false]);
+
+        let mut first = Vec::new();
+        for nal in &nals[..4] {
+            first.extend_from_slice(&ANNEX_B_START_CODE);
+            first.extend_from_slice(nal);
+        }
+        assert_eq!(frames[0].data, first);
+    }
+}
@@ -0,0 +1,468 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use std::fmt::Write as _;
+use std::io::{stdout, Write};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::config::RenderMode;
+
+/// Cell size assumed when the terminal does not report its pixel size.
+const DEFAULT_CELL_WIDTH: u32 = 8;
+const DEFAULT_CELL_HEIGHT: u32 = 16;
+/// Kitty graphics payloads are sent in chunks of at most this many bytes.
+const KITTY_CHUNK_SIZE: usize = 4096;
+/// Levels per channel of the sixel palette.
+const SIXEL_LEVELS: u32 = 6;
+
+/// A rectangle of the terminal, in cells, 1-based like cursor positions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pane {
+    pub x: u16,
+    pub y: u16,
+    pub cols: u16,
+    pub rows: u16,
+}
+
+/// Lays out the videos being shown: they share the right half of the
+/// terminal, stacked, so the call output keeps the left half.
+#[derive(Default)]
+pub struct VideoScreen {
+    shown: Mutex<Vec<String>>,
+    /// Image ids for the kitty protocol, one per video shown.
+    next_id: AtomicU32,
+}
+
+impl VideoScreen {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Makes room for `user_id`'s video, returning an id for its image.
+    pub fn show(&self, user_id: &str) -> u32 {
+        let mut shown = self.shown.lock().unwrap();
+        if !shown.iter().any(|shown| shown == user_id) {
+            shown.push(user_id.to_owned());
+        }
+        self.next_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn hide(&self, user_id: &str) {
+        if let Some(pane) = self.pane(user_id) {
+            clear(pane);
+        }
+        self.shown.lock().unwrap().retain(|shown| shown != user_id);
+    }
+
+    /// Where `user_id`'s video goes at the current terminal size.
+    pub fn pane(&self, user_id: &str) -> Option<Pane> {
+        let shown = self.shown.lock().unwrap();
+        let index = shown.iter().position(|shown| shown == user_id)?;
+        let (cols, rows) = termion::terminal_size().ok()?;
+
+        let rows_each = rows / shown.len() as u16;
+        if cols < 4 || rows_each < 3 {
+            return None;
+        }
+
+        Some(Pane {
+            x: cols / 2 + 1,
+            y: 1 + index as u16 * rows_each,
+            cols: cols - cols / 2,
+            rows: rows_each,
+        })
+    }
+}
+
+/// Picks a concrete mode for `Auto` from what the terminal says it is.
+pub fn resolve_mode(mode: RenderMode) -> RenderMode {
+    if mode != RenderMode::Auto {
+        return mode;
+    }
+
+    let term = std::env::var("TERM").unwrap_or_default();
+    let program = std::env::var("TERM_PROGRAM").unwrap_or_default();
+
+    if std::env::var_os("KITTY_WINDOW_ID").is_some()
+        || term.contains("kitty")
+        || matches!(program.as_str(), "WezTerm" | "ghostty")
+    {
+        RenderMode::Kitty
+    } else if term.contains("sixel") || matches!(term.as_str(), "foot" | "mlterm") {
+        RenderMode::Sixel
+    } else {
+        RenderMode::HalfBlocks
+    }
+}
+
+fn cell_size() -> (u32, u32) {
+    match (termion::terminal_size(), termion::terminal_size_pixels()) {
+        (Ok((cols, rows)), Ok((width, height))) if cols > 0 && rows > 0 && width > 0 => {
+            (width as u32 / cols as u32, height as u32 / rows as u32)
+        }
+        _ => (DEFAULT_CELL_WIDTH, DEFAULT_CELL_HEIGHT),
+    }
+}
+
+/// The image size that fits the pane below its title row, keeping the
+/// frame's aspect ratio. Half blocks have two square pixels per cell.
+pub fn fit(
+    frame_width: usize,
+    frame_height: usize,
+    pane: Pane,
+    mode: RenderMode,
+) -> (usize, usize) {
+    let image_rows = pane.rows.saturating_sub(1) as u32;
+
+    let (max_width, max_height) = match mode {
+        RenderMode::Sixel | RenderMode::Kitty => {
+            let (cell_width, cell_height) = cell_size();
+            (pane.cols as u32 * cell_width, image_rows * cell_height)
+        }
+        _ => (pane.cols as u32, image_rows * 2),
+    };
+
+    let scale = f64::min(
+        max_width as f64 / frame_width.max(1) as f64,
+        max_height as f64 / frame_height.max(1) as f64,
+    );
+
+    let width = ((frame_width as f64 * scale) as usize).max(1);
+    let height = ((frame_height as f64 * scale) as usize).max(1);
+
+    // Sixels are drawn in bands of six rows.
+    match mode {
+        RenderMode::Sixel => (width, (height / 6 * 6).max(6)),
+        _ => (width, height),
+    }
+}
+
+/// Nearest neighbour scaling of an RGB image.
+pub fn scale(
+    rgb: &[u8],
+    width: usize,
+    height: usize,
+    to_width: usize,
+    to_height: usize,
+) -> Vec<u8> {
+    let mut scaled = vec![0u8; to_width * to_height * 3];
+
+    for y in 0..to_height {
+        let source_y = y * height / to_height;
+        for x in 0..to_width {
+            let source_x = x * width / to_width;
+            let from = (source_y * width + source_x) * 3;
+            let to = (y * to_width + x) * 3;
+            scaled[to..to + 3].copy_from_slice(&rgb[from..from + 3]);
+        }
+    }
+
+    scaled
+}
+
+/// Draws a scaled frame and its title into the pane, leaving the cursor
+/// where it was.
+pub fn draw(
+    pane: Pane,
+    id: u32,
+    title: &str,
+    rgb: &[u8],
+    width: usize,
+    height: usize,
+    mode: RenderMode,
+) {
+    let mut out = String::new();
+    out.push_str("\x1b7");
+
+    let title: String = title.chars().take(pane.cols as usize).collect();
+    let _ = write!(
+        out,
+        "{}\x1b[0m\x1b[7m{:<width$}\x1b[0m",
+        termion::cursor::Goto(pane.x, pane.y),
+        title,
+        width = pane.cols as usize
+    );
+
+    let top = termion::cursor::Goto(pane.x, pane.y + 1);
+
+    match mode {
+        RenderMode::Sixel => {
+            out.push_str(&top.to_string());
+            out.push_str(&sixel(rgb, width, height));
+        }
+        RenderMode::Kitty => {
+            out.push_str(&top.to_string());
+            out.push_str(&kitty(rgb, width, height, id));
+        }
+        _ => half_blocks(&mut out, pane, rgb, width, height),
+    }
+
+    out.push_str("\x1b8");
+
+    let mut stdout = stdout().lock();
+    let _ = stdout.write_all(out.as_bytes());
+    let _ = stdout.flush();
+}
+
+fn clear(pane: Pane) {
+    let mut out = String::from("\x1b7\x1b[0m");
+    for row in 0..pane.rows {
+        let _ = write!(
+            out,
+            "{}{:width$}",
+            termion::cursor::Goto(pane.x, pane.y + row),
+            "",
+            width = pane.cols as usize
+        );
+    }
+    out.push_str("\x1b8");
+
+    let mut stdout = stdout().lock();
+    let _ = stdout.write_all(out.as_bytes());
+    let _ = stdout.flush();
+}
+
+/// Two pixels per cell: the upper one as the foreground of `▀`, the lower
+/// one as the background.
+fn half_blocks(out: &mut String, pane: Pane, rgb: &[u8], width: usize, height: usize) {
+    let pixel = |x: usize, y: usize| -> [u8; 3] {
+        if y >= height {
+            return [0, 0, 0];
+        }
+        let offset = (y * width + x) * 3;
+        [rgb[offset], rgb[offset + 1], rgb[offset + 2]]
+    };
+
+    let left = pane.x + (pane.cols.saturating_sub(width as u16)) / 2;
+
+    for row in 0..height.div_ceil(2) {
+        let _ = write!(
+            out,
+            "{}",
+            termion::cursor::Goto(left, pane.y + 1 + row as u16)
+        );
+        let mut previous: Option<([u8; 3], [u8; 3])> = None;
+
+        for x in 0..width {
+            let colors = (pixel(x, row * 2), pixel(x, row * 2 + 1));
+
+            if previous != Some(colors) {
+                let ([tr, tg, tb], [br, bg, bb]) = colors;
+                let _ = write!(
+                    out,
+                    "\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m",
+                    tr, tg, tb, br, bg, bb
+                );
+                previous = Some(colors);
+            }
+
+            out.push('▀');
+        }
+
+        out.push_str("\x1b[0m");
+    }
+}
+
+/// Encodes the image as sixels with a fixed 6x6x6 color cube.
+fn sixel(rgb: &[u8], width: usize, height: usize) -> String {
+    let level = |value: u8| value as u32 * SIXEL_LEVELS / 256;
+    let colors = (SIXEL_LEVELS * SIXEL_LEVELS * SIXEL_LEVELS) as usize;
+
+    let indexes: Vec<usize> = rgb
+        .chunks_exact(3)
+        .map(|pixel| {
+            (level(pixel[0]) * SIXEL_LEVELS * SIXEL_LEVELS
+                + level(pixel[1]) * SIXEL_LEVELS
+                + level(pixel[2])) as usize
+        })
+        .collect();
+
+    let mut out = format!("\x1bP0;1;0q\"1;1;{};{}", width, height);
+
+    for index in 0..colors {
+        let component = |value: usize| value as u32 * 100 / (SIXEL_LEVELS - 1);
+        let levels = SIXEL_LEVELS as usize;
+        let _ = write!(
+            out,
+            "#{};2;{};{};{}",
+            index,
+            component(index / (levels * levels)),
+            component(index / levels % levels),
+            component(index % levels)
+        );
+    }
+
+    for band in (0..height).step_by(6) {
+        let band_rows = (height - band).min(6);
+        let mut used = vec![false; colors];
+        for y in band..band + band_rows {
+            for x in 0..width {
+                used[indexes[y * width + x]] = true;
+            }
+        }
+
+        for color in (0..colors).filter(|color| used[*color]) {
+            let _ = write!(out, "#{}", color);
+
+            let mut run: Option<(char, usize)> = None;
+            for x in 0..width {
+                let mut bits = 0u8;
+                for row in 0..band_rows {
+                    if indexes[(band + row) * width + x] == color {
+                        bits |= 1 << row;
+                    }
+                }
+                let sixel = (63 + bits) as char;
+
+                run = match run {
+                    Some((previous, count)) if previous == sixel => Some((previous, count + 1)),
+                    Some((previous, count)) => {
+                        push_run(&mut out, previous, count);
+                        Some((sixel, 1))
+                    }
+                    None => Some((sixel, 1)),
+                };
+            }
+            if let Some((previous, count)) = run {
+                push_run(&mut out, previous, count);
+            }
+
+            out.push('$');
+        }
+
+        out.push('-');
+    }
+
+    out.push_str("\x1b\\");
+    out
+}
+
+fn push_run(out: &mut String, sixel: char, count: usize) {
+    if count > 3 {
+        let _ = write!(out, "!{}{}", count, sixel);
+    } else {
+        out.extend(std::iter::repeat_n(sixel, count));
+    }
+}
+
+/// Sends the image with the kitty graphics protocol, replacing the one
+/// shown before under the same id.
+fn kitty(rgb: &[u8], width: usize, height: usize, id: u32) -> String {
+    let payload = BASE64.encode(rgb);
+    let mut out = format!("\x1b_Ga=d,d=i,i={},q=2\x1b\\", id);
+
+    let chunks: Vec<_> = payload.as_bytes().chunks(KITTY_CHUNK_SIZE).collect();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = if i + 1 < chunks.len() { 1 } else { 0 };
+        let chunk = std::str::from_utf8(chunk).unwrap_or_default();
+
+        if i == 0 {
+            let _ = write!(
+                out,
+                "\x1b_Ga=T,f=24,s={},v={},i={},C=1,q=2,m={};{}\x1b\\",
+                width, height, id, more, chunk
+            );
+        } else {
+            let _ = write!(out, "\x1b_Gm={};{}\x1b\\", more, chunk);
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PANE: Pane = Pane {
+        x: 41,
+        y: 1,
+        cols: 40,
+        rows: 11,
+    };
+
+    #[test]
+    fn fit_keeps_the_aspect_ratio_below_the_title() {
+        // 10 image rows are 20 half-block pixels high, the width has room.
+        assert_eq!(fit(640, 480, PANE, RenderMode::HalfBlocks), (26, 20));
+        // A wide frame is bounded by the 40 columns instead.
+        assert_eq!(fit(1280, 360, PANE, RenderMode::HalfBlocks), (40, 11));
+    }
+
+    #[test]
+    fn fit_never_gives_an_empty_image() {
+        let tiny = Pane { rows: 1, ..PANE };
+        assert_eq!(fit(640, 480, tiny, RenderMode::HalfBlocks), (1, 1));
+        assert_eq!(fit(0, 0, PANE, RenderMode::HalfBlocks), (1, 1));
+    }
+
+    #[test]
+    fn scale_picks_the_nearest_pixels() {
+        // 2x2 red, green / blue, white.
+        let rgb = [255, 0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255];
+
+        let up = scale(&rgb, 2, 2, 4, 4);
+        assert_eq!(up.len(), 4 * 4 * 3);
+        assert_eq!(&up[0..3], &[255, 0, 0]);
+        assert_eq!(&up[3..6], &[255, 0, 0]);
+        assert_eq!(&up[6..9], &[0, 255, 0]);
+        assert_eq!(&up[(3 * 4 + 3) * 3..], &[255, 255, 255]);
+
+        let down = scale(&rgb, 2, 2, 1, 1);
+        assert_eq!(down, vec![255, 0, 0]);
+    }
+
+    #[test]
+    fn half_blocks_put_two_rows_in_each_cell() {
+        // 2x3: red over green, then blue over nothing.
+        let rgb = [
+            255, 0, 0, 255, 0, 0, //
+            0, 255, 0, 0, 255, 0, //
+            0, 0, 255, 0, 0, 255,
+        ];
+        let mut out = String::new();
+        half_blocks(&mut out, PANE, &rgb, 2, 3);
+
+        let lines: Vec<&str> = out.split("\x1b[0m").filter(|l| !l.is_empty()).collect();
+        assert_eq!(lines.len(), 2);
+
+        // Centered in the pane, below its title.
+        let left = PANE.x + (PANE.cols - 2) / 2;
+        assert!(lines[0].starts_with(&termion::cursor::Goto(left, 2).to_string()));
+        assert!(lines[1].starts_with(&termion::cursor::Goto(left, 3).to_string()));
+
+        // Colors are set once per run of equal cells.
+        assert!(lines[0].ends_with("\x1b[38;2;255;0;0m\x1b[48;2;0;255;0m▀▀"));
+        assert!(lines[1].ends_with("\x1b[38;2;0;0;255m\x1b[48;2;0;0;0m▀▀"));
+    }
+
+    #[test]
+    fn sixel_runs_are_compressed() {
+        let mut out = String::new();
+        push_run(&mut out, '~', 3);
+        push_run(&mut out, '@', 5);
+        assert_eq!(out, "~~~!5@");
+    }
+
+    #[test]
+    fn sixel_draws_each_band_of_six_rows() {
+        let rgb = vec![255u8; 2 * 12 * 3];
+        let image = sixel(&rgb, 2, 12);
+
+        assert!(image.starts_with("\x1bP0;1;0q\"1;1;2;12"));
+        assert!(image.ends_with("\x1b\\"));
+        // White is the last color of the cube, every pixel of both bands set.
+        assert_eq!(image.matches("#215~~$-").count(), 2);
+    }
+
+    #[test]
+    fn kitty_images_are_sent_in_chunks() {
+        let rgb = vec![0u8; KITTY_CHUNK_SIZE * 2];
+        let image = kitty(&rgb, 64, 32, 7);
+
+        assert!(image.starts_with("\x1b_Ga=d,d=i,i=7,q=2\x1b\\"));
+        assert!(image.contains("\x1b_Ga=T,f=24,s=64,v=32,i=7,C=1,q=2,m=1;"));
+        assert_eq!(image.matches("\x1b_Gm=1;").count(), 1);
+        assert_eq!(image.matches("\x1b_Gm=0;").count(), 1);
+    }
+}
@@ -0,0 +1,11 @@
+import type { WSContext } from 'hono/ws';
+
+const connectionUsers = new WeakMap<object, string>();
+
+/** Remembers which user a socket belongs to, so it can be cleaned up on close. */
+export const setConnectionUser = (ws: WSContext, userId: string) => {
+  connectionUsers.set(ws.raw as object, userId);
+};
+
+export const getConnectionUser = (ws: WSContext) =>
+  connectionUsers.get(ws.raw as object);
@@ -1,5 +1,7 @@
 import { createUser, getUserById, setUserAlive } from '../../db';
 import { sendAck } from '../ack';
+import { setConnectionUser } from '../connections';
+import { sendIceServers } from '../ice-servers';
 import type { WSContext } from 'hono/ws';
 import type { ClientCommand } from '../mod-client';
 
@@ -15,5 +17,7 @@ export const handleConnect = (ws: WSContext, cmd: ClientCommand<'Connect'>) => {
     user = setUserAlive(userId, true);
   }
 
+  setConnectionUser(ws, userId);
   sendAck(ws, userId);
+  sendIceServers(ws, userId);
 };
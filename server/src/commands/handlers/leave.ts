@@ -0,0 +1,15 @@
+import { clearRooms, removeUserFromRooms } from '../../db';
+import type { WSContext } from 'hono/ws';
+import type { ServerWebSocket } from 'bun';
+
+export const handleLeave = (ws: WSContext, userId: string) => {
+  const roomIds = removeUserFromRooms(userId);
+
+  const rawWs = ws.raw as ServerWebSocket;
+
+  for (const roomId of roomIds) {
+    rawWs.unsubscribe(roomId);
+  }
+
+  clearRooms();
+};
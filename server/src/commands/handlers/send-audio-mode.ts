@@ -0,0 +1,37 @@
+import { getRoomById } from '../../db';
+import { prepareCommand } from '../send';
+import type { WSContext } from 'hono/ws';
+import type { ClientCommand } from '../mod-client';
+import type { ServerWebSocket } from 'bun';
+
+export const handleSendAudioMode = (
+  ws: WSContext,
+  cmd: ClientCommand<'SendAudioMode'>,
+) => {
+  const data = cmd.command.Client.SendAudioMode;
+  const roomId = data[1];
+
+  if (!getRoomById(roomId)?.users.includes(cmd.user_id)) {
+    throw new Error('Not a member of the room');
+  }
+
+  const rws = ws.raw as ServerWebSocket;
+
+  rws.subscribe(roomId);
+
+  const res = rws.publish(
+    roomId,
+    prepareCommand({
+      user_id: data[0],
+      command: {
+        Server: {
+          IncomingAudioMode: [cmd.user_id, roomId, data[2]],
+        },
+      },
+    }),
+  );
+
+  if (res === 0) {
+    throw new Error('Failed to publish audio mode');
+  }
+};
@@ -0,0 +1,40 @@
+import { createHmac } from 'node:crypto';
+import { prepareCommand } from './send';
+import type { WSContext } from 'hono/ws';
+import type { IceServer } from './mod-server';
+
+const DEFAULT_TTL = 3600;
+
+/**
+ * Sends short-lived TURN credentials, following the TURN REST API scheme
+ * used by coturn's `use-auth-secret`: the username is the expiry timestamp
+ * and the user id, the credential an HMAC of it with the shared secret.
+ *
+ * Does nothing unless `TURN_URLS` and `TURN_SECRET` are set.
+ */
+export const sendIceServers = (ws: WSContext, userId: string) => {
+  const urls = process.env.TURN_URLS?.split(',')
+    .map(url => url.trim())
+    .filter(Boolean);
+  const secret = process.env.TURN_SECRET;
+
+  if (!urls?.length || !secret) {
+    return;
+  }
+
+  const ttl = Number.parseInt(process.env.TURN_TTL ?? `${DEFAULT_TTL}`, 10);
+  const expiry = Math.floor(Date.now() / 1000) + ttl;
+  const username = `${expiry}:${userId}`;
+  const credential = createHmac('sha1', secret)
+    .update(username)
+    .digest('base64');
+
+  const servers: IceServer[] = [{ urls, username, credential }];
+
+  ws.send(
+    prepareCommand({
+      user_id: userId,
+      command: { Server: { IceServers: [servers, ttl] } },
+    }),
+  );
+};
@@ -1,10 +1,15 @@
+import { setUserAlive } from '../db';
 import { handleConnect } from './handlers/connect';
 import { handleCreateRoom } from './handlers/create-room';
 import { handleJoin } from './handlers/join';
+import { handleLeave } from './handlers/leave';
 import { handleListRooms } from './handlers/list-rooms';
 import { handleSendAnswer } from './handlers/send-answer';
+import { handleSendAudioMode } from './handlers/send-audio-mode';
 import { handleSendIceCandidate } from './handlers/send-ice-candidate';
 import { handleSendOffer } from './handlers/send-offer';
+import { getConnectionUser } from './connections';
+import { sendIceServers } from './ice-servers';
 import type { ClientCommand, ClientCommandKeys } from './mod-client';
 import type { WSContext, WSMessageReceive } from 'hono/ws';
 
@@ -47,6 +52,12 @@ export const onMessage = (
       break;
     }
 
+    case 'Leave': {
+      handleLeave(ws, commandMessage.user_id);
+
+      break;
+    }
+
     case 'SendOffer': {
       handleSendOffer(ws, commandMessage as ClientCommand<'SendOffer'>);
 
@@ -68,6 +79,21 @@ export const onMessage = (
       break;
     }
 
+    case 'SendAudioMode': {
+      handleSendAudioMode(
+        ws,
+        commandMessage as ClientCommand<'SendAudioMode'>,
+      );
+
+      break;
+    }
+
+    case 'RefreshIceServers': {
+      sendIceServers(ws, commandMessage.user_id);
+
+      break;
+    }
+
     default: {
       console.log('Unknown command');
 
@@ -75,3 +101,12 @@ export const onMessage = (
     }
   }
 };
+
+export const onClose = (_evt: CloseEvent, ws: WSContext): void => {
+  const userId = getConnectionUser(ws);
+
+  if (userId) {
+    handleLeave(ws, userId);
+    setUserAlive(userId, false);
+  }
+};
@@ -6,16 +6,18 @@ export type ClientCommandKeys =
   | 'Connect'
   | 'SendOffer'
   | 'SendAnswer'
-  | 'SendIceCandidate';
+  | 'SendIceCandidate'
+  | 'SendAudioMode'
+  | 'RefreshIceServers';
+
+export type AudioMode = 'Voice' | 'Music';
 
 export type ClientCommandData = {
   ListRooms: null;
   Connect: string;
   CreateRoom: string;
   Join: string;
-  Leave: {
-    room: string;
-  };
+  Leave: null;
   SendOffer: [
     string, // user_id
     string, // room_id
@@ -31,6 +33,12 @@ export type ClientCommandData = {
     string, // room_id
     string, // candidate
   ];
+  SendAudioMode: [
+    string, // user_id
+    string, // room_id
+    AudioMode,
+  ];
+  RefreshIceServers: null;
 };
 
 export type ClientCommand<K extends ClientCommandKeys> = {
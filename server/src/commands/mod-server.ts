@@ -1,4 +1,5 @@
 import type { Room } from '../db';
+import type { AudioMode } from './mod-client';
 
 export type ServerCommandKeys =
   | 'Ack'
@@ -7,7 +8,15 @@ export type ServerCommandKeys =
   | 'RoomList'
   | 'IncomingOffer'
   | 'IncomingAnswer'
-  | 'IncomingIceCandidate';
+  | 'IncomingIceCandidate'
+  | 'IncomingAudioMode'
+  | 'IceServers';
+
+export type IceServer = {
+  urls: string[];
+  username: string;
+  credential: string;
+};
 
 export type ServerCommandData = {
   Ack: null;
@@ -29,6 +38,15 @@ export type ServerCommandData = {
     string, // room_id
     string, // candidate
   ];
+  IncomingAudioMode: [
+    string, // user_id
+    string, // room_id
+    AudioMode,
+  ];
+  IceServers: [
+    IceServer[],
+    number, // ttl in seconds
+  ];
 };
 
 export type ServerCommand<K extends ServerCommandKeys> = {
@@ -206,6 +206,22 @@ export const addUserToRoom = (roomId: string, userId: string) => {
   });
 };
 
+/** Removes the user from every room and returns the ids of those rooms. */
+export const removeUserFromRooms = (userId: string): string[] => {
+  const rooms = getRoomsQuery
+    .all()
+    .filter(room => room.users.includes(userId));
+
+  for (const room of rooms) {
+    addUserToRoomQuery.get({
+      usersIds: room.users.filter(id => id !== userId).join(','),
+      roomId: room.id,
+    });
+  }
+
+  return rooms.map(room => room.id);
+};
+
 const getSharedSecretQuery = db.query(`
 	SELECT secret
 	FROM shared_secrets
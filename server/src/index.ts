@@ -1,7 +1,7 @@
 import { Hono } from 'hono';
 import { createBunWebSocket } from 'hono/bun';
 import { initStunServer } from './stun';
-import { onMessage } from './commands';
+import { onClose, onMessage } from './commands';
 import { clearRooms, setAllUsersDead } from './db';
 
 initStunServer();
@@ -14,6 +14,7 @@ app.get(
   '/ws',
   upgradeWebSocket(_ctx => ({
     onMessage,
+    onClose,
   })),
 );
 